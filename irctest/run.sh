@@ -0,0 +1,16 @@
+#!/bin/sh
+# Runs the irctest conformance suite against rust_irc and scores it against expected_failures.txt.
+# IRCTEST has to point at a checkout of https://github.com/progval/irctest, extra arguments go to pytest.
+# With UPDATE=1, expected_failures.txt is rewritten to whatever failed this time.
+set -eu
+here=$(cd "$(dirname "$0")" && pwd)
+: "${IRCTEST:?Point IRCTEST at an irctest checkout}"
+
+cargo build --manifest-path "$here/../Cargo.toml"
+cp "$here/rust_irc.py" "$IRCTEST/irctest/controllers/rust_irc.py"
+cd "$IRCTEST"
+# Failures are expected, the score below is what counts
+PATH="$here/../target/debug:$PATH" python3 -m pytest -p no:cacheprovider \
+    --controller irctest.controllers.rust_irc -m 'not services' \
+    --junit-xml "$here/results.xml" irctest/server_tests "$@" || true
+python3 "$here/score.py" ${UPDATE:+--update} "$here/results.xml" "$here/expected_failures.txt"
@@ -0,0 +1,54 @@
+# irctest controller for rust_irc, copied into irctest/controllers/ by run.sh.
+# See https://github.com/progval/irctest
+
+from typing import Optional, Set, Type
+
+from irctest.basecontrollers import (
+    BaseServerController,
+    DirectoryBasedController,
+    NotImplementedByController,
+)
+
+TEMPLATE = """
+listen = "{hostname}:{port}"
+
+# irctest sends everything as fast as it can
+[fakelag]
+enabled = false
+"""
+
+
+class RustIrcController(BaseServerController, DirectoryBasedController):
+    software_name = "rust_irc"
+    # Only EXTERNAL, which needs TLS client certificates irctest doesn't use
+    supported_sasl_mechanisms: Set[str] = set()
+    supports_sts = False
+
+    def run(
+        self,
+        hostname: str,
+        port: int,
+        *,
+        password: Optional[str],
+        ssl: bool,
+        run_services: bool,
+        faketime: Optional[str],
+    ) -> None:
+        # PASS logs into an account here, there's no server password
+        if password is not None:
+            raise NotImplementedByController("PASS command")
+        if ssl:
+            raise NotImplementedByController("TLS")
+        if run_services:
+            raise NotImplementedByController("services")
+        assert self.proc is None
+        self.port = port
+        self.create_config()
+        with self.open_file("server.toml") as fd:
+            fd.write(TEMPLATE.format(hostname=hostname, port=port))
+        assert self.directory
+        self.proc = self.execute(["rust_irc", str(self.directory / "server.toml")])
+
+
+def get_irctest_controller_class() -> Type[RustIrcController]:
+    return RustIrcController
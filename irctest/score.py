@@ -0,0 +1,70 @@
+#!/usr/bin/env python3
+# Scores an irctest run: how many tests passed, and which results don't match expected_failures.txt.
+# With --update it rewrites expected_failures.txt to be exactly what failed, one test id per line.
+# Usage: score.py [--update] results.xml expected_failures.txt
+
+import sys
+import xml.etree.ElementTree as ET
+
+
+def expected(path):
+    with open(path) as fd:
+        lines = (line.split("#")[0].strip() for line in fd)
+        return [line for line in lines if line]
+
+
+def update(path, failed):
+    # Keep the comment at the top, the rest is the run's failures
+    with open(path) as fd:
+        header = []
+        for line in fd:
+            if not line.startswith("#"):
+                break
+            header.append(line)
+    with open(path, "w") as fd:
+        fd.writelines(header)
+        fd.write("\n")
+        fd.writelines(test + "\n" for test in sorted(failed))
+
+
+def main(results, expected_path, rewrite=False):
+    patterns = expected(expected_path)
+    passed, failed, skipped = [], [], []
+    for case in ET.parse(results).iter("testcase"):
+        test = "{}.{}".format(case.get("classname"), case.get("name"))
+        if case.find("skipped") is not None:
+            skipped.append(test)
+        elif case.find("failure") is not None or case.find("error") is not None:
+            failed.append(test)
+        else:
+            passed.append(test)
+
+    def is_expected(test):
+        return any(test.startswith(x) for x in patterns)
+
+    unexpected_failures = [x for x in failed if not is_expected(x)]
+    unexpected_passes = [x for x in passed if is_expected(x)]
+    ran = len(passed) + len(failed)
+    print(
+        "{}/{} passed ({:.1f}%), {} skipped".format(
+            len(passed), ran, 100 * len(passed) / max(ran, 1), len(skipped)
+        )
+    )
+    for test in unexpected_passes:
+        print("Passed, take it off the list: {}".format(test))
+    for test in unexpected_failures:
+        print("Unexpected failure: {}".format(test))
+    if rewrite:
+        update(expected_path, failed)
+        print("Wrote {} failures to {}".format(len(failed), expected_path))
+        return 0
+    return 1 if unexpected_failures else 0
+
+
+if __name__ == "__main__":
+    args = sys.argv[1:]
+    rewrite = "--update" in args
+    args = [x for x in args if x != "--update"]
+    if len(args) != 2:
+        sys.exit("usage: score.py [--update] results.xml expected_failures.txt")
+    sys.exit(main(args[0], args[1], rewrite))
@@ -0,0 +1,90 @@
+//! Accepting connections without a failed accept() taking the whole server down. Clients that hang up before we get
+//! to them are just skipped. Anything else, like running out of file descriptors, usually passes once some
+//! connections close, so we wait a bit and try again, backing off while it keeps happening. Every failure is counted
+//! in `/metrics` by why it happened.
+
+use crate::{log, metrics::Metrics};
+use std::{io, time::Duration};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    time::Instant,
+};
+
+/// How long to wait after the first failure, doubled for each one after that
+const FIRST_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Why an accept failed, for the metrics
+pub fn reason(error: &io::Error) -> &'static str {
+    // EMFILE and ENFILE, which std doesn't have kinds for
+    #[cfg(unix)]
+    if matches!(error.raw_os_error(), Some(23 | 24)) {
+        return "fd_limit";
+    }
+    match error.kind() {
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::NotConnected
+        | io::ErrorKind::Interrupted => "aborted",
+        io::ErrorKind::OutOfMemory => "memory",
+        _ => "other",
+    }
+}
+
+/// Where a listener is at with backing off. Kept outside `next` since that gets cancelled whenever something else
+/// happens first in a `select!`.
+#[derive(Debug, Default)]
+pub struct Backoff {
+    delay: Option<Duration>,
+    until: Option<Instant>,
+}
+
+/// The next client to connect to `listener`, however long it takes.
+pub async fn next(listener: &TcpListener, metrics: &Metrics, backoff: &mut Backoff) -> TcpStream {
+    loop {
+        if let Some(until) = backoff.until {
+            tokio::time::sleep_until(until).await;
+            backoff.until = None;
+        }
+        // A client that's gone before we can even ask who it is isn't worth setting up
+        let error = match listener.accept().await {
+            Ok((socket, _)) => match socket.peer_addr() {
+                Ok(_) => {
+                    backoff.delay = None;
+                    return socket;
+                }
+                Err(e) => e,
+            },
+            Err(e) => e,
+        };
+        let reason = reason(&error);
+        metrics.accept_failure(reason);
+        if reason == "aborted" {
+            continue;
+        }
+        let delay = backoff
+            .delay
+            .map_or(FIRST_BACKOFF, |x| (x * 2).min(MAX_BACKOFF));
+        log::error!(
+            "Failed to accept a connection, retrying in {:?}: {}",
+            delay,
+            error
+        );
+        backoff.delay = Some(delay);
+        backoff.until = Some(Instant::now() + delay);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reasons() {
+        let error = io::Error::from(io::ErrorKind::ConnectionAborted);
+        assert_eq!(reason(&error), "aborted");
+        #[cfg(unix)]
+        assert_eq!(reason(&io::Error::from_raw_os_error(24)), "fd_limit");
+        assert_eq!(reason(&io::Error::other("meow")), "other");
+    }
+}
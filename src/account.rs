@@ -0,0 +1,225 @@
+use crate::{config::Config, password, server::lock, tls};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug, Clone)]
+pub struct Account {
+    /// argon2id hash
+    pub password: String,
+    /// Client certificate fingerprints that can log in, normalized
+    pub certfps: Vec<String>,
+    /// draft/read-marker timestamps, keyed by casefolded target
+    pub read_markers: HashMap<String, String>,
+    /// Where highlights get POSTed while the account isn't around
+    pub webhook: Option<String>,
+}
+
+/// Shared handle to every account the server knows about, cheap to clone into each connection.
+#[derive(Debug, Clone, Default)]
+pub struct AccountStore {
+    accounts: Arc<Mutex<HashMap<String, Account>>>,
+}
+
+impl AccountStore {
+    /// Seeds the store with the accounts listed in the config.
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            accounts: Arc::new(Mutex::new(accounts(config))),
+        }
+    }
+
+    /// Swaps in the accounts listed in `config`, for REHASH. Accounts that stay keep their read markers. Returns
+    /// the names of the ones that are new and the ones that are gone.
+    pub fn reload(&self, config: &Config) -> (Vec<String>, Vec<String>) {
+        let mut new = accounts(config);
+        let mut accounts = lock(&self.accounts);
+        let dropped = accounts
+            .keys()
+            .filter(|x| !new.contains_key(*x))
+            .cloned()
+            .collect();
+        let mut added = Vec::new();
+        for (name, account) in &mut new {
+            match accounts.remove(name) {
+                Some(old) => account.read_markers = old.read_markers,
+                None => added.push(name.clone()),
+            }
+        }
+        *accounts = new;
+        (added, dropped)
+    }
+
+    /// Returns `true` if `name` is an account and `password` is its password.
+    pub fn authenticate(&self, name: &str, password: &str) -> bool {
+        let accounts = lock(&self.accounts);
+        matches!(accounts.get(name), Some(account) if password::verify(&account.password, password))
+    }
+
+    /// Finds the account a client certificate with `certfp` can log into, for SASL EXTERNAL. If the client asked
+    /// for a particular account with `name` it has to be that one.
+    pub fn authenticate_certfp(&self, name: Option<&str>, certfp: &str) -> Option<String> {
+        let accounts = lock(&self.accounts);
+        accounts
+            .iter()
+            .find(|(account, x)| {
+                name.is_none_or(|name| name == *account) && x.certfps.iter().any(|x| x == certfp)
+            })
+            .map(|(account, _)| account.clone())
+    }
+
+    /// The account called `nick`, ignoring case, if there is one. That nick is theirs.
+    pub fn owner(&self, nick: &str) -> Option<String> {
+        let accounts = lock(&self.accounts);
+        accounts
+            .keys()
+            .find(|x| x.eq_ignore_ascii_case(nick))
+            .cloned()
+    }
+
+    /// The highlight webhook for `name`, if it has one.
+    pub fn webhook(&self, name: &str) -> Option<String> {
+        let accounts = lock(&self.accounts);
+        accounts.get(name)?.webhook.clone()
+    }
+
+    /// The last read timestamp `name` has set for `target`, if any.
+    pub fn read_marker(&self, name: &str, target: &str) -> Option<String> {
+        let accounts = lock(&self.accounts);
+        accounts
+            .get(name)?
+            .read_markers
+            .get(&target.to_ascii_lowercase())
+            .cloned()
+    }
+
+    /// Moves `name`'s read marker for `target` forward to `timestamp`.
+    /// Markers never go backwards, so this returns `false` if `timestamp` is older than what we had.
+    pub fn set_read_marker(&self, name: &str, target: &str, timestamp: &str) -> bool {
+        let mut accounts = lock(&self.accounts);
+        let account = match accounts.get_mut(name) {
+            Some(account) => account,
+            None => return false,
+        };
+        let marker = account
+            .read_markers
+            .entry(target.to_ascii_lowercase())
+            .or_default();
+        // Timestamps are fixed width, so string order is time order.
+        if timestamp > marker.as_str() {
+            *marker = timestamp.to_string();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+fn accounts(config: &Config) -> HashMap<String, Account> {
+    config
+        .accounts
+        .iter()
+        .map(|a| {
+            (
+                a.name.clone(),
+                Account {
+                    password: a.password.clone(),
+                    certfps: a
+                        .certfps
+                        .iter()
+                        .map(|x| tls::normalize_fingerprint(x))
+                        .collect(),
+                    read_markers: HashMap::new(),
+                    webhook: a.webhook.clone(),
+                },
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::AccountConfig;
+
+    fn store() -> AccountStore {
+        let mut config = Config::default();
+        config.accounts.push(AccountConfig {
+            name: "tiger".to_string(),
+            password: password::hash("hunter2").unwrap(),
+            certfps: vec!["AA:BB".to_string()],
+            webhook: None,
+        });
+        AccountStore::from_config(&config)
+    }
+
+    #[test]
+    fn authenticate() {
+        let accounts = store();
+        assert!(accounts.authenticate("tiger", "hunter2"));
+        assert!(!accounts.authenticate("tiger", "hunter3"));
+        assert!(!accounts.authenticate("nobody", "hunter2"));
+    }
+
+    #[test]
+    fn authenticate_certfp() {
+        let accounts = store();
+        assert_eq!(
+            accounts.authenticate_certfp(None, "aabb").as_deref(),
+            Some("tiger")
+        );
+        assert_eq!(
+            accounts
+                .authenticate_certfp(Some("tiger"), "aabb")
+                .as_deref(),
+            Some("tiger")
+        );
+        assert_eq!(accounts.authenticate_certfp(Some("cat"), "aabb"), None);
+        assert_eq!(accounts.authenticate_certfp(None, "ccdd"), None);
+    }
+
+    #[test]
+    fn read_markers_only_move_forward() {
+        let accounts = store();
+        assert_eq!(accounts.read_marker("tiger", "#meow"), None);
+        assert!(accounts.set_read_marker("tiger", "#meow", "2021-06-13T17:25:41.123Z"));
+        assert!(!accounts.set_read_marker("tiger", "#MEOW", "2020-01-01T00:00:00.000Z"));
+        assert_eq!(
+            accounts.read_marker("tiger", "#Meow").as_deref(),
+            Some("2021-06-13T17:25:41.123Z")
+        );
+    }
+
+    #[test]
+    fn read_markers_need_an_account() {
+        let accounts = store();
+        assert!(!accounts.set_read_marker("nobody", "#meow", "2021-06-13T17:25:41.123Z"));
+        assert_eq!(accounts.read_marker("nobody", "#meow"), None);
+    }
+
+    #[test]
+    fn reload() {
+        let accounts = store();
+        accounts.set_read_marker("tiger", "#meow", "2021-06-13T17:25:41.123Z");
+        let mut config = Config::default();
+        for name in ["tiger", "cat"] {
+            config.accounts.push(AccountConfig {
+                name: name.to_string(),
+                password: String::new(),
+                certfps: Vec::new(),
+                webhook: None,
+            });
+        }
+        assert_eq!(
+            accounts.reload(&config),
+            (vec!["cat".to_string()], Vec::new())
+        );
+        assert!(accounts.read_marker("tiger", "#meow").is_some());
+        config.accounts.truncate(1);
+        assert_eq!(
+            accounts.reload(&config),
+            (Vec::new(), vec!["cat".to_string()])
+        );
+    }
+}
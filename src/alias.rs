@@ -0,0 +1,80 @@
+//! Command aliases, shorthand for messaging a service: `NS IDENTIFY hunter2` is `PRIVMSG NickServ :IDENTIFY hunter2`.
+//! NS, CS, MS and HS are built in, more can be added (or the built in ones pointed elsewhere) in the config:
+//!
+//! ```toml
+//! [aliases]
+//! BS = "BotServ"
+//! NS = "services/NickServ"
+//! ```
+//! Aliases are expanded on the raw line, before it's parsed.
+
+use std::collections::HashMap;
+
+pub const BUILTIN: &[(&str, &str)] = &[
+    ("NS", "NickServ"),
+    ("CS", "ChanServ"),
+    ("MS", "MemoServ"),
+    ("HS", "HostServ"),
+];
+
+/// The PRIVMSG `line` stands for if it starts with an alias, `None` if it doesn't or there's nothing to send.
+pub fn expand(line: &str, configured: &HashMap<String, String>) -> Option<String> {
+    // Keep any tags as they are
+    let (tags, rest) = match line.strip_prefix('@') {
+        Some(_) => {
+            let (tags, rest) = line.split_once(' ')?;
+            (format!("{} ", tags), rest)
+        }
+        None => (String::new(), line),
+    };
+    let (command, text) = rest.split_once(' ')?;
+    let target = match configured
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(command))
+    {
+        Some((_, target)) => target.as_str(),
+        None => {
+            BUILTIN
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(command))?
+                .1
+        }
+    };
+    let text = text.strip_prefix(':').unwrap_or(text);
+    if text.is_empty() {
+        return None;
+    }
+    Some(format!("{}PRIVMSG {} :{}", tags, target, text))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn expansion() {
+        let mut configured = HashMap::new();
+        assert_eq!(
+            expand("ns IDENTIFY hunter2", &configured).as_deref(),
+            Some("PRIVMSG NickServ :IDENTIFY hunter2")
+        );
+        assert_eq!(
+            expand("@+typing=done CS :OP #meow", &configured).as_deref(),
+            Some("@+typing=done PRIVMSG ChanServ :OP #meow")
+        );
+        assert_eq!(expand("NS", &configured), None);
+        assert_eq!(expand("NS :", &configured), None);
+        assert_eq!(expand("PRIVMSG #meow :hi", &configured), None);
+
+        configured.insert("NS".to_string(), "services/NickServ".to_string());
+        configured.insert("BS".to_string(), "BotServ".to_string());
+        assert_eq!(
+            expand("NS help", &configured).as_deref(),
+            Some("PRIVMSG services/NickServ :help")
+        );
+        assert_eq!(
+            expand("bs help", &configured).as_deref(),
+            Some("PRIVMSG BotServ :help")
+        );
+    }
+}
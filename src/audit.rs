@@ -0,0 +1,248 @@
+//! Audit log of what opers do, for working out what happened after the fact. Every OPER attempt and lockout, KILL, K-line,
+//! D-line, spam filter, CHGHOST, REHASH, DIE and SQUIT, and every look at someone's real address, is appended to the file as a line of JSON:
+//!
+//! ```json
+//! {"timestamp":"2026-10-16T13:37:00Z","actor":"tiger!tiger@127.0.0.1","action":"KILL","target":"spammer","reason":"bye"}
+//! ```
+//! Opers with snomask `o` get a notice for the ones that don't already have a snomask of their own.
+//!
+//! ```toml
+//! audit_log = "/var/log/rust_irc/audit.log"
+//! ```
+
+use crate::{ban::BanKind, event::Event, log, Shutdown};
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+use std::path::Path;
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::broadcast};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Entry {
+    pub timestamp: String,
+    /// Whoever did it, usually a hostmask
+    pub actor: String,
+    pub action: &'static str,
+    pub target: Option<String>,
+    pub reason: Option<String>,
+}
+
+fn ban_action(kind: BanKind, adding: bool) -> &'static str {
+    match (kind, adding) {
+        (BanKind::Kline, true) => "KLINE",
+        (BanKind::Dline, true) => "DLINE",
+        (BanKind::Kline, false) => "UNKLINE",
+        (BanKind::Dline, false) => "UNDLINE",
+    }
+}
+
+/// The audit log entry for `event`, if it's a privileged action.
+pub fn entry(event: &Event) -> Option<Entry> {
+    let (actor, action, target, reason) = match event {
+        Event::OperAttempt { by, name, success } => (
+            by.clone(),
+            if *success { "OPER" } else { "OPER_FAILED" },
+            Some(name.clone()),
+            None,
+        ),
+        Event::UserKilled { by, nick, reason } => {
+            (by.clone(), "KILL", Some(nick.clone()), Some(reason.clone()))
+        }
+        Event::BanAdded { ban } => (
+            ban.set_by.clone(),
+            ban_action(ban.kind, true),
+            Some(ban.mask.clone()),
+            Some(ban.reason.clone()),
+        ),
+        Event::BanRemoved { by, kind, mask } => (
+            by.clone(),
+            ban_action(*kind, false),
+            Some(mask.clone()),
+            None,
+        ),
+        Event::OperLockout { by, ip, dropped } => (
+            by.clone(),
+            "OPER_LOCKOUT",
+            Some(ip.clone()),
+            Some(
+                if *dropped {
+                    "disconnected"
+                } else {
+                    "address locked out"
+                }
+                .to_string(),
+            ),
+        ),
+        Event::RealIpShown { by, nick, command } => {
+            (by.clone(), *command, Some(nick.clone()), None)
+        }
+        Event::OperSpied {
+            by,
+            command,
+            target,
+        } => (by.clone(), *command, Some(target.clone()), None),
+        Event::HostChanged { by, nick, host } => (
+            by.clone(),
+            "CHGHOST",
+            Some(nick.clone()),
+            Some(host.clone()),
+        ),
+        Event::Rehashed { by } => (by.clone(), "REHASH", None, None),
+        Event::DefconChanged {
+            by: Some(by),
+            level,
+        } => (by.clone(), "DEFCON", Some(level.to_string()), None),
+        Event::Died { by } => (by.clone(), "DIE", None, None),
+        Event::Squit { by, node, reason } => {
+            (by.clone(), "SQUIT", Some(node.clone()), reason.clone())
+        }
+        Event::FilterAdded {
+            by,
+            action,
+            pattern,
+        } => (
+            by.clone(),
+            "FILTER_ADD",
+            Some(pattern.clone()),
+            Some(action.to_string()),
+        ),
+        Event::FilterRemoved { by, pattern } => {
+            (by.clone(), "FILTER_DEL", Some(pattern.clone()), None)
+        }
+        _ => return None,
+    };
+    Some(Entry {
+        timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        actor,
+        action,
+        target,
+        reason,
+    })
+}
+
+/// Appends an entry for every privileged action to the file at `path` until shutdown.
+pub async fn watch(mut events: broadcast::Receiver<Event>, path: &Path, mut shutdown: Shutdown) {
+    let mut file = match OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+    {
+        Ok(file) => file,
+        Err(e) => {
+            log::error!("Failed to open audit log {}: {}", path.display(), e);
+            return;
+        }
+    };
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            // DIE is published right before the shutdown, so catch up on anything still waiting
+            _ = shutdown.recv() => match events.try_recv() {
+                Ok(event) => Ok(event),
+                Err(_) => return,
+            },
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                log::error!("Audit log missed {} events", missed);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let Some(entry) = entry(&event) else {
+            continue;
+        };
+        let mut line = serde_json::to_string(&entry).expect("entries always serialize");
+        line.push('\n');
+        let written = async {
+            file.write_all(line.as_bytes()).await?;
+            file.flush().await
+        };
+        if let Err(e) = written.await {
+            log::error!("Failed to write to the audit log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn entries() {
+        let oper = entry(&Event::OperAttempt {
+            by: "tiger!tiger@127.0.0.1".to_string(),
+            name: "root".to_string(),
+            success: false,
+        })
+        .unwrap();
+        assert_eq!(oper.action, "OPER_FAILED");
+        assert_eq!(oper.target.as_deref(), Some("root"));
+
+        let unban = entry(&Event::BanRemoved {
+            by: "tiger!tiger@127.0.0.1".to_string(),
+            kind: BanKind::Dline,
+            mask: "10.0.0.0/8".to_string(),
+        })
+        .unwrap();
+        assert_eq!(unban.action, "UNDLINE");
+
+        let userip = entry(&Event::RealIpShown {
+            by: "tiger!tiger@127.0.0.1".to_string(),
+            nick: "cat".to_string(),
+            command: "USERIP",
+        })
+        .unwrap();
+        assert_eq!(
+            (userip.action, userip.target.as_deref()),
+            ("USERIP", Some("cat"))
+        );
+
+        let spied = entry(&Event::OperSpied {
+            by: "tiger!tiger@127.0.0.1".to_string(),
+            command: "LIST",
+            target: "#secret,#hidden".to_string(),
+        })
+        .unwrap();
+        assert_eq!(spied.target.as_deref(), Some("#secret,#hidden"));
+
+        let squit = entry(&Event::Squit {
+            by: "tiger!tiger@127.0.0.1".to_string(),
+            node: "1234-5678".to_string(),
+            reason: Some("bye".to_string()),
+        })
+        .unwrap();
+        assert_eq!(
+            (
+                squit.action,
+                squit.target.as_deref(),
+                squit.reason.as_deref()
+            ),
+            ("SQUIT", Some("1234-5678"), Some("bye"))
+        );
+
+        let filter = entry(&Event::FilterAdded {
+            by: "tiger!tiger@127.0.0.1".to_string(),
+            action: "kill",
+            pattern: "buy now".to_string(),
+        })
+        .unwrap();
+        assert_eq!(
+            (
+                filter.action,
+                filter.target.as_deref(),
+                filter.reason.as_deref()
+            ),
+            ("FILTER_ADD", Some("buy now"), Some("kill"))
+        );
+
+        assert_eq!(
+            entry(&Event::NickChanged {
+                old: "a".to_string(),
+                new: "b".to_string()
+            }),
+            None
+        );
+    }
+}
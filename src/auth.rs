@@ -0,0 +1,120 @@
+//! Where PASS logins get checked. The server's own `[[account]]` blocks are the default, but the config can point
+//! it somewhere else so it fits in with whatever already knows who your users are:
+//!
+//! ```toml
+//! # A plain list of argon2id hashes, no read markers or webhooks
+//! [auth]
+//! provider = "static"
+//! users = { tiger = "$argon2id$..." }
+//!
+//! # POSTs `{"name": ..., "password": ...}`, any 2xx means yes and 401/403 mean no (needs the `http-auth` feature)
+//! [auth]
+//! provider = "http"
+//! url = "https://id.example.com/irc"
+//! ```
+
+use crate::{account::AccountStore, password, Result};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::{collections::HashMap, sync::Arc};
+
+#[async_trait]
+pub trait AuthProvider: Send + Sync + std::fmt::Debug {
+    /// Returns `true` if `password` is right for the account `name`. Errors are for when we couldn't find out.
+    async fn authenticate(&self, name: &str, password: &str) -> Result<bool>;
+}
+
+/// `[auth]` in the config
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "provider", rename_all = "lowercase")]
+pub enum AuthConfig {
+    /// The `[[account]]` blocks
+    #[default]
+    Accounts,
+    Static {
+        /// Account name to argon2id hash
+        users: HashMap<String, String>,
+    },
+    #[cfg(feature = "http-auth")]
+    Http { url: String },
+}
+
+/// Builds the provider the config asks for.
+pub fn from_config(config: &AuthConfig, accounts: &AccountStore) -> Arc<dyn AuthProvider> {
+    match config {
+        AuthConfig::Accounts => Arc::new(accounts.clone()),
+        AuthConfig::Static { users } => Arc::new(StaticProvider {
+            users: users.clone(),
+        }),
+        #[cfg(feature = "http-auth")]
+        AuthConfig::Http { url } => Arc::new(HttpProvider {
+            url: url.clone(),
+            client: reqwest::Client::new(),
+        }),
+    }
+}
+
+#[async_trait]
+impl AuthProvider for AccountStore {
+    async fn authenticate(&self, name: &str, password: &str) -> Result<bool> {
+        Ok(AccountStore::authenticate(self, name, password))
+    }
+}
+
+#[derive(Debug)]
+pub struct StaticProvider {
+    users: HashMap<String, String>,
+}
+
+#[async_trait]
+impl AuthProvider for StaticProvider {
+    async fn authenticate(&self, name: &str, password: &str) -> Result<bool> {
+        Ok(self
+            .users
+            .get(name)
+            .is_some_and(|hash| password::verify(hash, password)))
+    }
+}
+
+#[cfg(feature = "http-auth")]
+#[derive(Debug)]
+pub struct HttpProvider {
+    url: String,
+    client: reqwest::Client,
+}
+
+#[cfg(feature = "http-auth")]
+#[async_trait]
+impl AuthProvider for HttpProvider {
+    async fn authenticate(&self, name: &str, password: &str) -> Result<bool> {
+        let res = self
+            .client
+            .post(&self.url)
+            .json(&serde_json::json!({ "name": name, "password": password }))
+            .send()
+            .await?;
+        match res.status() {
+            status if status.is_success() => Ok(true),
+            reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => Ok(false),
+            status => Err(format!("{} answered {}", self.url, status).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn static_provider() {
+        let config: AuthConfig = toml::from_str(&format!(
+            "provider = \"static\"\nusers = {{ tiger = {:?} }}",
+            password::hash("hunter2").unwrap()
+        ))
+        .unwrap();
+        let auth = from_config(&config, &AccountStore::default());
+        assert!(auth.authenticate("tiger", "hunter2").await.unwrap());
+        assert!(!auth.authenticate("tiger", "hunter3").await.unwrap());
+        assert!(!auth.authenticate("cat", "hunter2").await.unwrap());
+    }
+}
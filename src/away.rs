@@ -0,0 +1,66 @@
+//! Keeping away-notify from flooding anyone. Away changes go out straight away unless the last one went out less than
+//! `away_notify_interval` seconds ago, in which case they're held until it's been long enough and only whatever the
+//! user ended up as goes out. Toggling away and back in that time sends nothing at all, and neither does setting the
+//! same away message again.
+
+use std::time::{Duration, Instant};
+
+/// What away-notify has last said about a user
+#[derive(Debug, Default, Clone)]
+pub struct AwayNotified {
+    /// The away message everyone heard last, `None` if they heard the user's back
+    told: Option<String>,
+    /// When they heard it
+    at: Option<Instant>,
+    /// Set while a change is waiting on the interval
+    pending: bool,
+}
+
+impl AwayNotified {
+    /// Returns `true` if the user being `away` should go out now. If it's too soon it's held until `ready_at`.
+    pub fn send(&mut self, away: &Option<String>, interval: Duration, now: Instant) -> bool {
+        if *away == self.told {
+            self.pending = false;
+            return false;
+        }
+        if self.at.is_some_and(|x| now < x + interval) {
+            self.pending = true;
+            return false;
+        }
+        self.told = away.clone();
+        self.at = Some(now);
+        self.pending = false;
+        true
+    }
+
+    /// When a held back change can go out, if there is one.
+    pub fn ready_at(&self, interval: Duration) -> Option<Instant> {
+        self.at.filter(|_| self.pending).map(|x| x + interval)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn coalesces() {
+        let interval = Duration::from_secs(5);
+        let now = Instant::now();
+        let mut notified = AwayNotified::default();
+        let lunch = Some("lunch".to_string());
+        // Nothing's changed
+        assert!(!notified.send(&None, interval, now));
+        assert!(notified.send(&lunch, interval, now));
+        assert!(!notified.send(&lunch, interval, now));
+        // Back and away again before anyone needs to know
+        assert!(!notified.send(&None, interval, now + Duration::from_secs(1)));
+        assert_eq!(notified.ready_at(interval), Some(now + interval));
+        assert!(!notified.send(&lunch, interval, now + Duration::from_secs(2)));
+        assert_eq!(notified.ready_at(interval), None);
+        // Back for good
+        assert!(!notified.send(&None, interval, now + Duration::from_secs(3)));
+        assert!(notified.send(&None, interval, now + interval));
+        assert_eq!(notified.ready_at(interval), None);
+    }
+}
@@ -0,0 +1,406 @@
+//! Server bans set by opers: K-lines match `user@host` masks at registration, D-lines match IPs as soon as they
+//! connect. Masks can use `*` and `?` wildcards.
+//!
+//! K-lines can also be extbans, which match on something other than `user@host`: `$a:mask` for accounts (or `$a`
+//! for anyone logged in), `$r:mask` for realnames, and `$z` for TLS users. `$~` in front matches everyone the rest
+//! doesn't, so `$~a` bans everyone who isn't logged in. Extbans only apply once the user has registered.
+//!
+//! Bans are saved to the file set by `bans` in the config (if any) every time they change, so they survive restarts.
+
+use crate::log;
+use crate::server::lock;
+use crate::Result;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BanKind {
+    /// `user@host`
+    Kline,
+    /// IP
+    Dline,
+}
+
+impl BanKind {
+    /// What the ban is called when we tell people about it
+    pub fn name(&self) -> &'static str {
+        match self {
+            BanKind::Kline => "K-line",
+            BanKind::Dline => "D-line",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ban {
+    pub kind: BanKind,
+    pub mask: String,
+    pub reason: String,
+    /// Oper that set it
+    pub set_by: String,
+    /// Unix timestamp
+    pub set_at: i64,
+    /// Unix timestamp, permanent if unset
+    pub expires: Option<i64>,
+}
+
+impl Ban {
+    /// A ban starting now, lasting `duration` seconds (or forever).
+    pub fn new(
+        kind: BanKind,
+        mask: String,
+        reason: String,
+        set_by: String,
+        duration: Option<i64>,
+    ) -> Self {
+        let now = Utc::now().timestamp();
+        Self {
+            kind,
+            mask,
+            reason,
+            set_by,
+            set_at: now,
+            expires: duration.map(|x| now + x),
+        }
+    }
+
+    /// Returns `true` if this ban covers `subject`.
+    pub fn matches(&self, subject: &Subject) -> bool {
+        match self.kind {
+            BanKind::Kline => match self.mask.strip_prefix('$') {
+                Some(extban) => subject.registered && extban_matches(extban, subject),
+                None => glob_match(&self.mask, &format!("{}@{}", subject.username, subject.ip)),
+            },
+            BanKind::Dline => glob_match(&self.mask, &subject.ip),
+        }
+    }
+}
+
+/// Who a ban is being checked against
+#[derive(Debug, Clone, Default)]
+pub struct Subject {
+    pub username: String,
+    pub ip: String,
+    pub realname: String,
+    pub account: Option<String>,
+    pub tls: bool,
+    /// Unset while they're still connecting, when all we know is `ip`
+    pub registered: bool,
+}
+
+impl Subject {
+    /// Someone who has only just connected from `ip`
+    pub fn connecting(ip: String) -> Self {
+        Self {
+            ip,
+            ..Default::default()
+        }
+    }
+}
+
+/// An extban type, `$<letter>` or `$<letter>:<arg>`
+pub struct Extban {
+    pub letter: char,
+    /// Gets the argument, if there was one
+    pub matcher: fn(Option<&str>, &Subject) -> bool,
+}
+
+/// Every extban type. New ones just need adding here.
+pub const EXTBANS: &[Extban] = &[
+    Extban {
+        letter: 'a',
+        matcher: |arg, subject| match (arg, &subject.account) {
+            (_, None) => false,
+            (None, Some(_)) => true,
+            (Some(mask), Some(account)) => glob_match(mask, account),
+        },
+    },
+    Extban {
+        letter: 'r',
+        matcher: |arg, subject| arg.is_some_and(|mask| glob_match(mask, &subject.realname)),
+    },
+    Extban {
+        letter: 'z',
+        matcher: |_, subject| subject.tls,
+    },
+];
+
+/// Splits an extban (without the `$`) into whether it's negated, its type and its argument. `None` if we don't
+/// know the type.
+pub fn parse_extban(extban: &str) -> Option<(bool, &'static Extban, Option<&str>)> {
+    let (negated, extban) = match extban.strip_prefix('~') {
+        Some(rest) => (true, rest),
+        None => (false, extban),
+    };
+    let (letter, arg) = match extban.split_once(':') {
+        Some((letter, arg)) => (letter, Some(arg)),
+        None => (extban, None),
+    };
+    let mut chars = letter.chars();
+    let letter = match (chars.next(), chars.next()) {
+        (Some(letter), None) => letter,
+        _ => return None,
+    };
+    let extban = EXTBANS.iter().find(|x| x.letter == letter)?;
+    Some((negated, extban, arg))
+}
+
+fn extban_matches(extban: &str, subject: &Subject) -> bool {
+    match parse_extban(extban) {
+        Some((negated, extban, arg)) => (extban.matcher)(arg, subject) != negated,
+        // Can't be set, but could have been hand edited into the bans file
+        None => false,
+    }
+}
+
+/// The ISUPPORT token advertising extbans
+pub fn isupport() -> String {
+    let letters: String = EXTBANS.iter().map(|x| x.letter).collect();
+    format!("EXTBAN=$,{}", letters)
+}
+
+/// Shared handle to every ban, cheap to clone into each connection.
+#[derive(Debug, Clone, Default)]
+pub struct Bans {
+    bans: Arc<Mutex<Vec<Ban>>>,
+    /// Where bans get saved, if anywhere
+    path: Option<Arc<PathBuf>>,
+}
+
+impl Bans {
+    /// Loads whatever bans were saved at `path`. A file that doesn't exist yet is just no bans.
+    pub fn load(path: Option<PathBuf>) -> Result<Self> {
+        let bans = match &path {
+            Some(path) if path.exists() => serde_json::from_str(&std::fs::read_to_string(path)?)?,
+            _ => Vec::new(),
+        };
+        Ok(Self {
+            bans: Arc::new(Mutex::new(bans)),
+            path: path.map(Arc::new),
+        })
+    }
+
+    /// Adds `ban`, replacing any ban of the same kind on the same mask.
+    pub fn add(&self, ban: Ban) {
+        let mut bans = lock(&self.bans);
+        bans.retain(|x| !(x.kind == ban.kind && x.mask == ban.mask));
+        bans.push(ban);
+        self.save(&bans);
+    }
+
+    /// Lifts the ban of `kind` on `mask`, returning it if there was one.
+    pub fn remove(&self, kind: BanKind, mask: &str) -> Option<Ban> {
+        let mut bans = lock(&self.bans);
+        let index = bans.iter().position(|x| x.kind == kind && x.mask == mask)?;
+        let ban = bans.remove(index);
+        self.save(&bans);
+        Some(ban)
+    }
+
+    /// Drops every ban that has run out, returning them.
+    pub fn expire(&self) -> Vec<Ban> {
+        let now = Utc::now().timestamp();
+        let mut bans = lock(&self.bans);
+        let (expired, kept): (Vec<Ban>, Vec<Ban>) = bans
+            .drain(..)
+            .partition(|x| x.expires.is_some_and(|x| x <= now));
+        *bans = kept;
+        if !expired.is_empty() {
+            self.save(&bans);
+        }
+        expired
+    }
+
+    /// Every ban of `kind`, oldest first.
+    pub fn list(&self, kind: BanKind) -> Vec<Ban> {
+        let bans = lock(&self.bans);
+        bans.iter().filter(|x| x.kind == kind).cloned().collect()
+    }
+
+    /// The first ban covering `subject`.
+    pub fn find(&self, subject: &Subject) -> Option<Ban> {
+        let bans = lock(&self.bans);
+        bans.iter().find(|x| x.matches(subject)).cloned()
+    }
+
+    /// Writes `bans` out, if we have somewhere to. A ban we couldn't save is still in effect until restart.
+    fn save(&self, bans: &[Ban]) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let res = serde_json::to_string_pretty(bans)
+            .map_err(|e| e.into())
+            .and_then(|json| std::fs::write(path.as_ref(), json));
+        if let Err(e) = res {
+            log::error!("Failed to save bans to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Parses a ban duration like `30m` or `2d` into seconds. A bare number is minutes.
+pub fn parse_duration(s: &str) -> Option<i64> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(0) => return None,
+        Some(i) => s.split_at(i),
+        None => (s, "m"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    number.parse::<i64>().ok()?.checked_mul(multiplier)
+}
+
+/// Matches `text` against `mask`, where `*` is any run of characters and `?` is any one. Ignores ASCII case.
+pub fn glob_match(mask: &str, text: &str) -> bool {
+    let mask = mask.as_bytes();
+    let text = text.as_bytes();
+    let (mut m, mut t) = (0, 0);
+    // Where to go back to if we need the last star to eat another character
+    let mut star = None;
+    while t < text.len() {
+        if m < mask.len() && (mask[m] == b'?' || mask[m].eq_ignore_ascii_case(&text[t])) {
+            m += 1;
+            t += 1;
+        } else if m < mask.len() && mask[m] == b'*' {
+            star = Some((m, t));
+            m += 1;
+        } else if let Some((star_m, star_t)) = star {
+            m = star_m + 1;
+            t = star_t + 1;
+            star = Some((star_m, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    mask[m..].iter().all(|&x| x == b'*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("30m"), Some(30 * 60));
+        assert_eq!(parse_duration("2d"), Some(2 * 24 * 60 * 60));
+        assert_eq!(parse_duration("90"), Some(90 * 60));
+        assert_eq!(parse_duration("1y"), None);
+        assert_eq!(parse_duration("*@1.2.3.4"), None);
+        assert_eq!(parse_duration("m"), None);
+    }
+
+    #[test]
+    fn masks() {
+        assert!(glob_match("*@127.0.0.1", "tiger@127.0.0.1"));
+        assert!(glob_match("TIGER@127.0.0.?", "tiger@127.0.0.1"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "aXXbYYbc"));
+        assert!(!glob_match("*@127.0.0.1", "tiger@127.0.0.10"));
+        assert!(!glob_match("tiger", "tigercat"));
+    }
+
+    #[test]
+    fn bans_expire_and_persist() {
+        let path = std::env::temp_dir().join(format!("rust_irc_bans_{}.json", std::process::id()));
+        let bans = Bans::load(Some(path.clone())).unwrap();
+        bans.add(Ban::new(
+            BanKind::Kline,
+            "*@10.*".to_string(),
+            "spam".to_string(),
+            "tiger".to_string(),
+            None,
+        ));
+        bans.add(Ban::new(
+            BanKind::Dline,
+            "10.0.0.1".to_string(),
+            "more spam".to_string(),
+            "tiger".to_string(),
+            Some(-1),
+        ));
+        let anyone = Subject {
+            username: "anyone".to_string(),
+            ip: "10.0.0.1".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(bans.find(&anyone).unwrap().kind, BanKind::Kline);
+
+        let expired = bans.expire();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].kind, BanKind::Dline);
+
+        let reloaded = Bans::load(Some(path.clone())).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(reloaded.list(BanKind::Kline), bans.list(BanKind::Kline));
+        assert!(reloaded.list(BanKind::Dline).is_empty());
+        assert!(reloaded.remove(BanKind::Kline, "*@10.*").is_some());
+        assert!(reloaded.find(&anyone).is_none());
+    }
+
+    #[tokio::test]
+    async fn panicking_handlers_leave_bans_usable() {
+        let bans = Bans::load(None).unwrap();
+        let caught = crate::isolate::catching(async {
+            let _bans = lock(&bans.bans);
+            panic!("meow");
+        })
+        .await;
+        assert!(caught.is_err() && bans.bans.is_poisoned());
+
+        bans.add(Ban::new(
+            BanKind::Kline,
+            "*@10.*".to_string(),
+            "spam".to_string(),
+            "tiger".to_string(),
+            None,
+        ));
+        assert_eq!(bans.list(BanKind::Kline).len(), 1);
+    }
+
+    #[test]
+    fn extbans() {
+        let kline = |mask: &str| {
+            Ban::new(
+                BanKind::Kline,
+                mask.to_string(),
+                String::new(),
+                String::new(),
+                None,
+            )
+        };
+        let mut tiger = Subject {
+            username: "tiger".to_string(),
+            ip: "127.0.0.1".to_string(),
+            realname: "Tiger Cat".to_string(),
+            account: Some("tiger".to_string()),
+            tls: false,
+            registered: true,
+        };
+        assert!(kline("$a").matches(&tiger));
+        assert!(kline("$a:TIG*").matches(&tiger));
+        assert!(!kline("$a:cat").matches(&tiger));
+        assert!(!kline("$~a").matches(&tiger));
+        assert!(kline("$r:*cat").matches(&tiger));
+        assert!(!kline("$r").matches(&tiger));
+        assert!(!kline("$z").matches(&tiger));
+        assert!(kline("$~z").matches(&tiger));
+        assert!(!kline("$q:tiger").matches(&tiger));
+
+        tiger.account = None;
+        assert!(kline("$~a").matches(&tiger));
+        assert!(!kline("$~a").matches(&Subject::connecting("127.0.0.1".to_string())));
+
+        assert!(parse_extban("ab").is_none());
+        assert_eq!(isupport(), "EXTBAN=$,arz");
+    }
+}
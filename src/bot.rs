@@ -0,0 +1,240 @@
+//! Bots compiled into the server. Every bot is a puppet on the `bots` virtual server (see `bridge`), so they show
+//! up in channels like any other user without needing a connection of their own.
+//!
+//! ```ignore
+//! let bots = Bots::new().register(Box::new(MyBot));
+//! let server = ServerBuilder::new().bridge(Box::new(bots)).start().await?;
+//! ```
+//! Bots don't hear each other, since they all share the one bridge.
+
+use crate::{
+    bridge::{Bridge, BridgeEvent, BridgeLink, ServerEvent},
+    log, Result,
+};
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use tokio::sync::mpsc;
+
+/// Name of the virtual server bots live on
+const BOTS_SERVER: &str = "bots";
+
+#[async_trait]
+pub trait Bot: Send {
+    /// The bot's nickname.
+    fn nick(&self) -> &str;
+
+    /// Channels the bot joins as soon as it starts.
+    fn channels(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Someone said something in a channel the bot is in.
+    async fn on_message(
+        &mut self,
+        _ctx: &mut BotContext,
+        _source: &str,
+        _channel: &str,
+        _text: &str,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Someone joined a channel the bot is in.
+    async fn on_join(
+        &mut self,
+        _ctx: &mut BotContext,
+        _source: &str,
+        _channel: &str,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// What a bot gets to do things with
+#[derive(Debug)]
+pub struct BotContext {
+    nick: String,
+    channels: HashSet<String>,
+    tx: mpsc::Sender<BridgeEvent>,
+}
+
+impl BotContext {
+    /// Sends `text` to `channel` as the bot.
+    pub async fn say(&self, channel: &str, text: &str) -> Result<()> {
+        self.tx
+            .send(BridgeEvent::Message {
+                puppet: self.nick.clone(),
+                channel: channel.to_string(),
+                text: text.to_string(),
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Joins the bot to `channel`.
+    pub async fn join(&mut self, channel: &str) -> Result<()> {
+        self.channels.insert(channel.to_string());
+        self.tx
+            .send(BridgeEvent::Join {
+                puppet: self.nick.clone(),
+                channel: channel.to_string(),
+            })
+            .await?;
+        Ok(())
+    }
+}
+
+/// Every bot the server is hosting, run as a single bridge.
+#[derive(Default)]
+pub struct Bots {
+    bots: Vec<Box<dyn Bot>>,
+}
+
+impl Bots {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a bot to be started along with the server.
+    pub fn register(mut self, bot: Box<dyn Bot>) -> Self {
+        self.bots.push(bot);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bots.is_empty()
+    }
+}
+
+#[async_trait]
+impl Bridge for Bots {
+    fn name(&self) -> &str {
+        BOTS_SERVER
+    }
+
+    async fn run(self: Box<Self>, mut link: BridgeLink) -> Result<()> {
+        let mut bots = Vec::new();
+        for bot in self.bots {
+            let mut ctx = BotContext {
+                nick: bot.nick().to_string(),
+                channels: HashSet::new(),
+                tx: link.tx.clone(),
+            };
+            for channel in bot.channels() {
+                ctx.join(&channel).await?;
+            }
+            bots.push((bot, ctx));
+        }
+
+        while let Some(event) = link.rx.recv().await {
+            for (bot, ctx) in bots.iter_mut() {
+                let res = match &event {
+                    ServerEvent::Message {
+                        source,
+                        channel,
+                        text,
+                    } if ctx.channels.contains(channel) => {
+                        bot.on_message(ctx, source, channel, text).await
+                    }
+                    ServerEvent::Join { source, channel } if ctx.channels.contains(channel) => {
+                        bot.on_join(ctx, source, channel).await
+                    }
+                    _ => Ok(()),
+                };
+                // One broken bot shouldn't take the rest down with it.
+                if let Err(e) = res {
+                    log::error!("Bot {} failed: {}", ctx.nick, e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Built in bot that answers canned triggers, like `!rules`, set up from the config.
+#[derive(Debug, Clone)]
+pub struct ReplyBot {
+    pub nick: String,
+    pub channels: Vec<String>,
+    /// Trigger (the whole message) to reply
+    pub replies: HashMap<String, String>,
+}
+
+#[async_trait]
+impl Bot for ReplyBot {
+    fn nick(&self) -> &str {
+        &self.nick
+    }
+
+    fn channels(&self) -> Vec<String> {
+        self.channels.clone()
+    }
+
+    async fn on_message(
+        &mut self,
+        ctx: &mut BotContext,
+        _source: &str,
+        channel: &str,
+        text: &str,
+    ) -> Result<()> {
+        if let Some(reply) = self.replies.get(text.trim()) {
+            ctx.say(channel, reply).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn reply_bot_answers_triggers() {
+        let bot = ReplyBot {
+            nick: "HelpBot".to_string(),
+            channels: vec!["#help".to_string()],
+            replies: HashMap::from([("!rules".to_string(), "Be nice.".to_string())]),
+        };
+        let (bridge_tx, mut from_bridge) = mpsc::channel(8);
+        let (to_bridge, bridge_rx) = mpsc::channel(8);
+        let bots = Box::new(Bots::new().register(Box::new(bot)));
+        let run = tokio::spawn(bots.run(BridgeLink {
+            tx: bridge_tx,
+            rx: bridge_rx,
+        }));
+
+        assert_eq!(
+            from_bridge.recv().await,
+            Some(BridgeEvent::Join {
+                puppet: "HelpBot".to_string(),
+                channel: "#help".to_string()
+            })
+        );
+        for (channel, text) in [
+            ("#help", "hello"),
+            ("#elsewhere", "!rules"),
+            ("#help", "!rules"),
+        ] {
+            to_bridge
+                .send(ServerEvent::Message {
+                    source: "tiger".to_string(),
+                    channel: channel.to_string(),
+                    text: text.to_string(),
+                })
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            from_bridge.recv().await,
+            Some(BridgeEvent::Message {
+                puppet: "HelpBot".to_string(),
+                channel: "#help".to_string(),
+                text: "Be nice.".to_string()
+            })
+        );
+
+        drop(to_bridge);
+        run.await.unwrap().unwrap();
+        assert_eq!(from_bridge.recv().await, None);
+    }
+}
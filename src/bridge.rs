@@ -0,0 +1,313 @@
+//! Bridges let something that isn't IRC (Matrix, XMPP, Discord...) show up as a virtual server full of puppet
+//! users, instead of every bridge inventing its own bot connection.
+//!
+//! A bridge gets a [`BridgeLink`], which is just a pair of channels:
+//! - it sends [`BridgeEvent`]s on `tx` to make its puppets join channels and talk,
+//! - it receives [`ServerEvent`]s on `rx` for everything that happens in channels its puppets have joined.
+//!
+//! Puppets appear to IRC users as `puppet!puppet@<bridge name>`, and never hear their own messages back.
+//!
+//! External processes can use [`ProcessBridge`], which speaks the same events as one JSON object per line over the
+//! process's stdin (server events) and stdout (bridge events), e.g.
+//! ```json
+//! {"type":"join","puppet":"alice","channel":"#meow"}
+//! {"type":"message","puppet":"alice","channel":"#meow","text":"hi from matrix"}
+//! ```
+
+use crate::{
+    log,
+    message_parse::{Command, Message, Side},
+    server::{ClientToServerPacket, ServerToClientPacket},
+    Result, Shutdown,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, process::Stdio};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process,
+    sync::{broadcast, mpsc},
+};
+
+/// Things a bridge can make its puppets do
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BridgeEvent {
+    Join {
+        puppet: String,
+        channel: String,
+    },
+    Message {
+        puppet: String,
+        channel: String,
+        text: String,
+    },
+}
+
+/// Things that happened in a channel one of the bridge's puppets is in
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    Join {
+        source: String,
+        channel: String,
+    },
+    Message {
+        source: String,
+        channel: String,
+        text: String,
+    },
+}
+
+/// The bridge's end of the connection to the server
+#[derive(Debug)]
+pub struct BridgeLink {
+    pub tx: mpsc::Sender<BridgeEvent>,
+    pub rx: mpsc::Receiver<ServerEvent>,
+}
+
+#[async_trait]
+pub trait Bridge: Send {
+    /// Name of the virtual server the bridge's puppets live on.
+    fn name(&self) -> &str;
+
+    /// Runs the bridge until it's done. Returning ends the bridge, but its puppets stay wherever they were.
+    async fn run(self: Box<Self>, link: BridgeLink) -> Result<()>;
+}
+
+/// Runs `bridge`, shuttling its events into the server as `origin` and handing it everything said in its
+/// puppets' channels, until either the bridge finishes or the server shuts down.
+pub(crate) async fn link(
+    bridge: Box<dyn Bridge>,
+    origin: usize,
+    server_tx: mpsc::Sender<ClientToServerPacket>,
+    mut client_rx: broadcast::Receiver<ServerToClientPacket>,
+    mut shutdown: Shutdown,
+) -> Result<()> {
+    let name = bridge.name().to_string();
+    let (bridge_tx, mut from_bridge) = mpsc::channel(64);
+    let (to_bridge, bridge_rx) = mpsc::channel(64);
+    let run = bridge.run(BridgeLink {
+        tx: bridge_tx,
+        rx: bridge_rx,
+    });
+    tokio::pin!(run);
+
+    // Every channel any of our puppets has joined
+    let mut channels = HashSet::new();
+    let mut bridge_open = true;
+
+    loop {
+        tokio::select! {
+            res = &mut run => return res,
+            event = from_bridge.recv(), if bridge_open => {
+                let event = match event {
+                    Some(event) => event,
+                    // The bridge doesn't want to talk anymore, but it might still be listening.
+                    None => {
+                        bridge_open = false;
+                        continue;
+                    }
+                };
+                let message = match event {
+                    BridgeEvent::Join { puppet, channel } => {
+                        channels.insert(channel.clone());
+                        puppet_message(&name, &puppet, Command::JOIN(vec![channel], None))
+                    }
+                    BridgeEvent::Message { puppet, channel, text } => {
+                        puppet_message(&name, &puppet, Command::PRIVMSG(vec![channel], text))
+                    }
+                };
+                server_tx
+                    .send(ClientToServerPacket::BlindBroadcast { origin, message })
+                    .await?;
+            }
+            packet = client_rx.recv() => {
+                let packet = match packet {
+                    Ok(packet) => packet,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        log::error!("Bridge {} missed {} messages", name, missed);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                };
+                for event in server_events(packet, origin, &channels) {
+                    // A bridge that can't keep up loses messages rather than stalling the server.
+                    if to_bridge.try_send(event).is_err() {
+                        log::error!("Bridge {} is full, dropping a message", name);
+                    }
+                }
+            }
+            _ = shutdown.recv() => return Ok(()),
+        }
+    }
+}
+
+fn puppet_message(bridge: &str, puppet: &str, command: Command) -> Message {
+    Message {
+        tags: None,
+        source: Some(format!("{}!{}@{}", puppet, puppet, bridge)),
+        command,
+        side: Side::Server,
+    }
+}
+
+/// Translates a broadcast into whatever the bridge cares about, skipping anything it said itself.
+fn server_events(
+    packet: ServerToClientPacket,
+    origin: usize,
+    channels: &HashSet<String>,
+) -> Vec<ServerEvent> {
+    let message = match packet {
+        ServerToClientPacket::Route(route) if route.origin != origin => route.message,
+        _ => return Vec::new(),
+    };
+    let source = message.source.unwrap_or_default();
+    match message.command {
+        Command::PRIVMSG(targets, text) => targets
+            .into_iter()
+            .filter(|x| channels.contains(x))
+            .map(|channel| ServerEvent::Message {
+                source: source.clone(),
+                channel,
+                text: text.clone(),
+            })
+            .collect(),
+        Command::JOIN(targets, _) => targets
+            .into_iter()
+            .filter(|x| channels.contains(x))
+            .map(|channel| ServerEvent::Join {
+                source: source.clone(),
+                channel,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// A bridge living in another process, speaking JSON lines over stdin/stdout.
+#[derive(Debug, Clone)]
+pub struct ProcessBridge {
+    pub name: String,
+    pub command: String,
+    pub args: Vec<String>,
+}
+
+#[async_trait]
+impl Bridge for ProcessBridge {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn run(self: Box<Self>, mut link: BridgeLink) -> Result<()> {
+        let mut child = process::Command::new(&self.command)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let mut stdin = child.stdin.take().expect("stdin was piped");
+        let mut stdout = BufReader::new(child.stdout.take().expect("stdout was piped")).lines();
+
+        loop {
+            tokio::select! {
+                line = stdout.next_line() => {
+                    let line = match line? {
+                        Some(line) => line,
+                        // Process went away
+                        None => return Ok(()),
+                    };
+                    match serde_json::from_str(&line) {
+                        Ok(event) => link.tx.send(event).await?,
+                        Err(e) => log::error!("Bridge {} sent garbage: {}", self.name, e),
+                    }
+                }
+                event = link.rx.recv() => {
+                    let event = match event {
+                        Some(event) => event,
+                        None => return Ok(()),
+                    };
+                    let mut line = serde_json::to_string(&event)?;
+                    line.push('\n');
+                    stdin.write_all(line.as_bytes()).await?;
+                    stdin.flush().await?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::Route;
+
+    fn privmsg(source: &str, channel: &str, text: &str) -> Message {
+        Message {
+            tags: None,
+            source: Some(source.to_string()),
+            command: Command::PRIVMSG(vec![channel.to_string()], text.to_string()),
+            side: Side::Server,
+        }
+    }
+
+    #[test]
+    fn bridge_events_are_json_lines() {
+        let event: BridgeEvent = serde_json::from_str(
+            r##"{"type":"message","puppet":"alice","channel":"#meow","text":"hi"}"##,
+        )
+        .unwrap();
+        assert_eq!(
+            event,
+            BridgeEvent::Message {
+                puppet: "alice".to_string(),
+                channel: "#meow".to_string(),
+                text: "hi".to_string()
+            }
+        );
+        let event = ServerEvent::Join {
+            source: "tiger".to_string(),
+            channel: "#meow".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r##"{"type":"join","source":"tiger","channel":"#meow"}"##
+        );
+    }
+
+    #[test]
+    fn only_joined_channels_reach_the_bridge() {
+        let channels = HashSet::from(["#meow".to_string()]);
+        let packet = ServerToClientPacket::Route(Route::new(
+            1,
+            HashSet::new(),
+            privmsg("tiger", "#meow", "hi"),
+        ));
+        assert_eq!(
+            server_events(packet, 0, &channels),
+            vec![ServerEvent::Message {
+                source: "tiger".to_string(),
+                channel: "#meow".to_string(),
+                text: "hi".to_string()
+            }]
+        );
+
+        let packet = ServerToClientPacket::Route(Route::new(
+            1,
+            HashSet::new(),
+            privmsg("tiger", "#blep", "hi"),
+        ));
+        assert!(server_events(packet, 0, &channels).is_empty());
+    }
+
+    #[test]
+    fn bridge_doesnt_hear_itself() {
+        let channels = HashSet::from(["#meow".to_string()]);
+        let packet = ServerToClientPacket::Route(Route::new(
+            0,
+            HashSet::new(),
+            privmsg("alice!alice@matrix", "#meow", "hi"),
+        ));
+        assert!(server_events(packet, 0, &channels).is_empty());
+    }
+}
@@ -0,0 +1,117 @@
+//! The burst a client gets when it registers, or attaches to a session that's already going. `plan` works out every
+//! part of it, in order, from what the connection knows, and `ClientConnection::write_burst` just writes out what it
+//! says, so nothing added later can end up out of order or sent twice. Parts only some clients get are decided in
+//! `plan` too, off the caps they asked for, like read markers for `draft/read-marker`.
+
+use crate::{capability, ClientInfo};
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Part {
+    /// 001 to 005
+    Welcome,
+    Lusers,
+    Motd,
+    /// The user modes they start with, like `+Bs`
+    UserModes(String),
+    /// The session is using another nick than the one they asked for
+    Nick {
+        requested: String,
+    },
+    /// A channel the session is already in
+    Join(String),
+    /// Where they've read up to in a channel
+    ReadMarker(String),
+    /// What this device missed while it was detached, see `history`
+    Playback,
+}
+
+/// Everything `info` gets, in order. `requested` is the nick they registered with if they're attaching to a session.
+pub fn plan(info: &ClientInfo, caps: &HashSet<String>, requested: Option<&str>) -> Vec<Part> {
+    let mut parts = vec![Part::Welcome, Part::Lusers, Part::Motd];
+    let modes = user_modes(info);
+    if !modes.is_empty() {
+        parts.push(Part::UserModes(format!("+{}", modes)));
+    }
+    let Some(requested) = requested else {
+        return parts;
+    };
+    if requested != info.nickname {
+        parts.push(Part::Nick {
+            requested: requested.to_string(),
+        });
+    }
+    for channel in &info.channels {
+        parts.push(Part::Join(channel.clone()));
+        if caps.contains(capability::READ_MARKER) {
+            parts.push(Part::ReadMarker(channel.clone()));
+        }
+    }
+    parts.push(Part::Playback);
+    parts
+}
+
+/// The user modes `info` has set.
+fn user_modes(info: &ClientInfo) -> String {
+    let mut modes = String::new();
+    if info.bot {
+        modes.push('B');
+    }
+    if info.hide_oper {
+        modes.push('H');
+    }
+    if !info.snomasks.is_empty() {
+        modes.push('s');
+    }
+    modes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ordering() {
+        let mut info = ClientInfo {
+            nickname: "tiger".to_string(),
+            ..Default::default()
+        };
+        let caps = HashSet::new();
+        assert_eq!(
+            plan(&info, &caps, None),
+            [Part::Welcome, Part::Lusers, Part::Motd]
+        );
+
+        // Attaching to a session that's a bot in #meow, under another nick than asked for
+        info.bot = true;
+        info.channels.push("#meow".to_string());
+        let caps = HashSet::from([capability::READ_MARKER.to_string()]);
+        assert_eq!(
+            plan(&info, &caps, Some("cat")),
+            [
+                Part::Welcome,
+                Part::Lusers,
+                Part::Motd,
+                Part::UserModes("+B".to_string()),
+                Part::Nick {
+                    requested: "cat".to_string()
+                },
+                Part::Join("#meow".to_string()),
+                Part::ReadMarker("#meow".to_string()),
+                Part::Playback,
+            ]
+        );
+        // Same nick and no read markers
+        assert_eq!(
+            plan(&info, &HashSet::new(), Some("tiger")),
+            [
+                Part::Welcome,
+                Part::Lusers,
+                Part::Motd,
+                Part::UserModes("+B".to_string()),
+                Part::Join("#meow".to_string()),
+                Part::Playback,
+            ]
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! IRCv3 capabilities, see https://ircv3.net/specs/extensions/capability-negotiation
+//!
+//! Clients that start with `CAP LS 302` get capability values like `sasl=EXTERNAL`, and long lists split over
+//! several lines. What we offer only changes on restart (REHASH doesn't touch TLS or the language catalogs), so
+//! there's no cap-notify, there would never be a NEW or DEL to send.
+//! Clients can log in with SASL after registering too, and anyone sharing a channel with them who has account-notify
+//! hears about it. account-tag gets message tags like message-tags does, for the `account` tag.
+//! `rust_irc/languages` lists the numeric text languages there are (see `catalog`), for LANGUAGE.
+
+use crate::{catalog::DEFAULT_LANGUAGE, config::Config};
+
+pub const ACCOUNT_NOTIFY: &str = "account-notify";
+pub const ACCOUNT_TAG: &str = "account-tag";
+pub const AWAY_NOTIFY: &str = "away-notify";
+pub const CHGHOST: &str = "chghost";
+pub const LANGUAGES: &str = "rust_irc/languages";
+pub const MESSAGE_TAGS: &str = "message-tags";
+pub const READ_MARKER: &str = "draft/read-marker";
+pub const SASL: &str = "sasl";
+pub const STS: &str = "sts";
+
+/// Everything we'll ACK in a CAP REQ, in the order we advertise them.
+pub const SUPPORTED: &[&str] = &[
+    ACCOUNT_NOTIFY,
+    ACCOUNT_TAG,
+    AWAY_NOTIFY,
+    CHGHOST,
+    MESSAGE_TAGS,
+    READ_MARKER,
+    SASL,
+    LANGUAGES,
+];
+
+/// The CAP version clients that don't give one get
+pub const DEFAULT_VERSION: u32 = 301;
+
+/// Most we put in one line of a capability list, leaving room for the rest of the line
+const MAX_LIST_LEN: usize = 400;
+
+/// Returns `true` if we know how to speak `cap`.
+pub fn is_supported(cap: &str) -> bool {
+    SUPPORTED.contains(&cap)
+}
+
+/// What we answer CAP LS with, values and all for 302. `tls` is whether the connection asking is already TLS.
+pub fn ls(version: u32, tls: bool, config: &Config) -> Vec<String> {
+    let mut caps: Vec<String> = SUPPORTED
+        .iter()
+        .map(|&cap| match cap {
+            SASL if version >= 302 => format!("{}=EXTERNAL", SASL),
+            LANGUAGES if version >= 302 => {
+                let mut languages: Vec<&str> = config
+                    .language
+                    .catalogs
+                    .keys()
+                    .map(|x| x.as_str())
+                    .collect();
+                if !languages.contains(&DEFAULT_LANGUAGE) {
+                    languages.push(DEFAULT_LANGUAGE);
+                }
+                languages.sort();
+                format!("{}={}", LANGUAGES, languages.join(","))
+            }
+            _ => cap.to_string(),
+        })
+        .collect();
+    // Just a policy, not something anyone can REQ
+    let sts = config
+        .tls
+        .as_ref()
+        .and_then(|x| Some((x.listen.rsplit(':').next()?, x.sts_duration?)));
+    if let (true, Some((port, duration))) = (version >= 302, sts) {
+        caps.push(match tls {
+            true => format!("{}=duration={}", STS, duration),
+            false => format!("{}=port={},duration={}", STS, port, duration),
+        });
+    }
+    caps
+}
+
+/// Splits `caps` into lines short enough to send, for 302's multiline replies.
+pub fn lines(caps: &[String]) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for cap in caps {
+        match lines.last_mut() {
+            Some(line) if line.len() + 1 + cap.len() <= MAX_LIST_LEN => {
+                line.push(' ');
+                line.push_str(cap);
+            }
+            _ => lines.push(cap.clone()),
+        }
+    }
+    if lines.is_empty() {
+        lines.push(String::new());
+    }
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tls::TlsConfig;
+
+    #[test]
+    fn values() {
+        let mut config = Config::default();
+        assert!(ls(301, false, &config).contains(&"sasl".to_string()));
+        assert!(ls(302, false, &config).contains(&"sasl=EXTERNAL".to_string()));
+        config
+            .language
+            .catalogs
+            .insert("de".to_string(), "de.toml".into());
+        assert!(ls(302, false, &config).contains(&"rust_irc/languages=de,en".to_string()));
+
+        config.tls = Some(TlsConfig {
+            listen: "0.0.0.0:6697".to_string(),
+            cert: "cert.pem".into(),
+            key: "key.pem".into(),
+            sts_duration: Some(300),
+            hello_timeout: 5,
+            handshake_timeout: 10,
+        });
+        let caps = ls(302, false, &config);
+        assert_eq!(caps.last().unwrap(), "sts=port=6697,duration=300");
+        let caps = ls(302, true, &config);
+        assert_eq!(caps.last().unwrap(), "sts=duration=300");
+        assert!(!ls(301, false, &config).iter().any(|x| x.starts_with("sts")));
+    }
+
+    #[test]
+    fn multiline() {
+        let caps: Vec<String> = (0..100).map(|x| format!("cap-{:02}", x)).collect();
+        let lines = lines(&caps);
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|x| x.len() <= MAX_LIST_LEN));
+        assert_eq!(lines.join(" "), caps.join(" "));
+        assert_eq!(super::lines(&[]), vec![String::new()]);
+    }
+}
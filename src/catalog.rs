@@ -0,0 +1,296 @@
+//! The human readable text of numeric replies, so it can be translated or rebranded. Everything has an English
+//! default in `ENGLISH`, and catalogs from the config replace whichever of those they list, by numeric:
+//!
+//! ```toml
+//! [language]
+//! # What clients get unless they pick something else with LANGUAGE
+//! default = "de"
+//!
+//! [language.catalogs]
+//! de = "/etc/rust_irc/de.toml"
+//! # Overriding en changes the defaults
+//! en = "/etc/rust_irc/branding.toml"
+//! ```
+//! With a catalog like:
+//! ```toml
+//! 1 = "Willkommen im Internet Relay Network {}"
+//! 433 = "Der Nick ist schon vergeben"
+//! ```
+//! `{}` is filled in with the same things, in the same order, as the English text. Clients can find the languages in
+//! the `rust_irc/languages` cap and pick one with `LANGUAGE <code>`.
+
+use crate::Result;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    path::PathBuf,
+    sync::Arc,
+};
+
+/// The language we always have
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+/// Every numeric's text, by number. Only the human readable part, parameters stay with the code that sends them.
+pub const ENGLISH: &[(u16, &str)] = &[
+    (1, "Welcome to the Internet Relay Network {}"),
+    (2, "Your host is {}, running version {}"),
+    (3, "This server was created {}"),
+    (5, "are available on this server"),
+    (8, "Server notice mask"),
+    (219, "End of /STATS report"),
+    (242, "Server Up {} days {}:{:02}:{:02}"),
+    (251, "There are {} users and {} invisible on {} servers"),
+    (252, "IRC Operators online"),
+    (254, "channels formed"),
+    (255, "I have {} clients and {} servers"),
+    (256, "Administrative info"),
+    (262, "End of TRACE"),
+    (263, "Please wait a while and try again."),
+    (305, "You are no longer marked as being away"),
+    (308, "- {} Server rules -"),
+    (309, "End of RULES command"),
+    (306, "You have been marked as being away"),
+    (313, "is an IRC operator"),
+    (315, "End of WHO list"),
+    (317, "seconds idle, signon time"),
+    (318, "End of /WHOIS list"),
+    (323, "End of /LIST"),
+    (330, "is logged in as"),
+    (331, "No topic is set"),
+    (335, "is a bot"),
+    (338, "Actual user@host, actual IP"),
+    (344, "is connecting from {}"),
+    (365, "End of /LINKS list"),
+    (366, "End of /NAMES list"),
+    (368, "End of channel ban list"),
+    (375, "- {} Message of the day - "),
+    (376, "End of /MOTD command"),
+    (381, "You are now an IRC operator"),
+    (382, "Rehashing"),
+    (396, "is now your displayed host"),
+    (401, "No such nick/channel"),
+    (402, "No such server"),
+    (403, "No such channel"),
+    (404, "Cannot send to channel"),
+    (410, "Invalid CAP command"),
+    (416, "Output too large, truncated"),
+    (421, "Unknown command"),
+    (432, "Erroneous nickname"),
+    (433, "Nickname is already in use"),
+    (434, "RULES File is missing"),
+    (437, "Nick/channel is temporarily unavailable"),
+    (441, "They aren't on that channel"),
+    (442, "You're not on that channel"),
+    (464, "Password incorrect"),
+    (472, "is unknown mode char to me"),
+    (474, "Cannot join channel"),
+    (476, "Bad Channel Mask"),
+    (
+        477,
+        "You need to be logged into an account to create channels",
+    ),
+    (481, "Permission Denied- You're not an IRC operator"),
+    (482, "You're not channel operator"),
+    (501, "Unknown MODE flag"),
+    (502, "Can't change mode for other users"),
+    (524, "No help available on this topic"),
+    (687, "is now your language"),
+    (706, "End of /HELP"),
+    (723, "Insufficient oper privileges."),
+    (729, "End of channel quiet list"),
+    (900, "You are now logged in as {}"),
+    (903, "SASL authentication successful"),
+    (904, "SASL authentication failed"),
+    (906, "SASL authentication aborted"),
+    (907, "You have already authenticated using SASL"),
+    (908, "are available SASL mechanisms"),
+    (982, "No such language"),
+];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LanguageConfig {
+    pub default: String,
+    /// Language code to the catalog file for it
+    pub catalogs: BTreeMap<String, PathBuf>,
+}
+
+impl Default for LanguageConfig {
+    fn default() -> Self {
+        Self {
+            default: DEFAULT_LANGUAGE.to_string(),
+            catalogs: BTreeMap::new(),
+        }
+    }
+}
+
+/// Shared handle to every loaded language, cheap to clone into each connection.
+#[derive(Debug, Clone)]
+pub struct Catalog {
+    languages: Arc<HashMap<String, HashMap<u16, String>>>,
+    default: String,
+}
+
+impl Default for Catalog {
+    fn default() -> Self {
+        Self::from_texts(HashMap::new(), DEFAULT_LANGUAGE).expect("English is always there")
+    }
+}
+
+/// How many `{}`-style placeholders `text` has
+fn placeholders(text: &str) -> usize {
+    text.matches('{').count()
+}
+
+/// The English text for `numeric`
+fn english(numeric: u16) -> Option<&'static str> {
+    ENGLISH.iter().find(|x| x.0 == numeric).map(|x| x.1)
+}
+
+impl Catalog {
+    /// Reads every catalog in `config`.
+    pub fn load(config: &LanguageConfig) -> Result<Self> {
+        let mut texts = HashMap::new();
+        for (language, path) in &config.catalogs {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read catalog {}: {}", path.display(), e))?;
+            let catalog: HashMap<u16, String> = toml::from_str(&text)
+                .map_err(|e| format!("Invalid catalog {}: {}", path.display(), e))?;
+            texts.insert(language.clone(), catalog);
+        }
+        Self::from_texts(texts, &config.default)
+    }
+
+    /// Checks `texts` against the English and fills in anything they're missing from it.
+    fn from_texts(mut texts: HashMap<String, HashMap<u16, String>>, default: &str) -> Result<Self> {
+        for (language, catalog) in &texts {
+            for (numeric, text) in catalog {
+                let expected = match english(*numeric) {
+                    Some(english) => placeholders(english),
+                    None => {
+                        return Err(format!(
+                            "Catalog {} has text for {}, which has none",
+                            language, numeric
+                        )
+                        .into())
+                    }
+                };
+                if placeholders(text) != expected {
+                    return Err(format!(
+                        "Catalog {} has {} placeholders for {}, it needs {}",
+                        language,
+                        placeholders(text),
+                        numeric,
+                        expected
+                    )
+                    .into());
+                }
+            }
+        }
+        texts.entry(DEFAULT_LANGUAGE.to_string()).or_default();
+        for catalog in texts.values_mut() {
+            for (numeric, text) in ENGLISH {
+                catalog.entry(*numeric).or_insert_with(|| text.to_string());
+            }
+        }
+        if !texts.contains_key(default) {
+            return Err(format!("The default language {} has no catalog", default).into());
+        }
+        Ok(Self {
+            languages: Arc::new(texts),
+            default: default.to_string(),
+        })
+    }
+
+    /// Whether there's a catalog for `language`.
+    pub fn has(&self, language: &str) -> bool {
+        self.languages.contains_key(language)
+    }
+
+    /// Every language there's a catalog for, sorted.
+    pub fn languages(&self) -> Vec<String> {
+        let mut languages: Vec<String> = self.languages.keys().cloned().collect();
+        languages.sort();
+        languages
+    }
+
+    /// The text for `numeric` in `language` (or the default language), with each `{}` replaced by the next of
+    /// `args`. Placeholders like `{:02}` pad numbers.
+    pub fn render(
+        &self,
+        language: Option<&str>,
+        numeric: u16,
+        args: &[&(dyn Display + Sync)],
+    ) -> String {
+        let language = language.unwrap_or(&self.default);
+        let template = self
+            .languages
+            .get(language)
+            .and_then(|x| x.get(&numeric))
+            .map(|x| x.as_str())
+            .or_else(|| english(numeric))
+            .unwrap_or_default();
+        let mut out = String::with_capacity(template.len());
+        let mut args = args.iter();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let Some(len) = rest[start..].find('}') else {
+                break;
+            };
+            out.push_str(&rest[..start]);
+            let arg = args.next().map(|x| x.to_string()).unwrap_or_default();
+            match &rest[start + 1..start + len] {
+                ":02" => out.push_str(&format!("{:0>2}", arg)),
+                _ => out.push_str(&arg),
+            }
+            rest = &rest[start + len + 1..];
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rendering() {
+        let catalog = Catalog::default();
+        assert_eq!(
+            catalog.render(None, 1, &[&"tiger!tiger@host"]),
+            "Welcome to the Internet Relay Network tiger!tiger@host"
+        );
+        assert_eq!(
+            catalog.render(None, 242, &[&1, &2, &3, &4]),
+            "Server Up 1 days 2:03:04"
+        );
+        assert_eq!(
+            catalog.render(Some("xx"), 433, &[]),
+            "Nickname is already in use"
+        );
+    }
+
+    #[test]
+    fn translations() {
+        let german = HashMap::from([
+            (1, "Willkommen, {}".to_string()),
+            (433, "Der Nick ist schon vergeben".to_string()),
+        ]);
+        let catalog =
+            Catalog::from_texts(HashMap::from([("de".to_string(), german)]), "de").unwrap();
+        assert_eq!(catalog.render(None, 1, &[&"tiger"]), "Willkommen, tiger");
+        assert_eq!(
+            catalog.render(Some("en"), 433, &[]),
+            "Nickname is already in use"
+        );
+        // Falls back to English for anything it doesn't have
+        assert_eq!(catalog.render(None, 403, &[]), "No such channel");
+        assert_eq!(catalog.languages(), ["de", "en"]);
+
+        let broken = HashMap::from([(1, "Willkommen".to_string())]);
+        assert!(Catalog::from_texts(HashMap::from([("de".to_string(), broken)]), "de").is_err());
+        assert!(Catalog::from_texts(HashMap::new(), "fr").is_err());
+    }
+}
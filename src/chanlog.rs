@@ -0,0 +1,481 @@
+//! Channel logs on disk, one file per channel per day, for communities that want public logs. This has nothing to
+//! do with `history`: it doesn't care who saw what or how long things are kept, it just writes everything down.
+//!
+//! ```toml
+//! [channel_log]
+//! dir = "/var/log/rust_irc/channels"
+//! # text, like `[13:37:00] <tiger> meow`, or jsonl
+//! format = "text"
+//! # Only these channels. Without it every channel is logged, except secret (+s) and +N ones
+//! channels = ["#rust", "#help"]
+//!
+//! # Channels written in another format
+//! [channel_log.formats]
+//! "#help" = "jsonl"
+//! ```
+//! Files go in `<dir>/<channel>/<YYYY-MM-DD>.log` (or `.jsonl`), days going by UTC.
+//!
+//! With `public = true` and the HTTP API running, they can be read at `/logs/<channel>` (like `/logs/%23rust`),
+//! which links to a page for each day. Every line there has an anchor, `#L12` for the twelfth, to link people to.
+//! Only channels named in `channels` are served: whether a channel is secret now says nothing about what it was when
+//! its logs were written, or once it's gone, so `public` needs the list.
+
+use crate::{
+    channel::{self, Channels},
+    event::Event,
+    log,
+    message_parse::source_nick,
+    Result, Shutdown,
+};
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+use tokio::{fs, io::AsyncWriteExt, sync::broadcast};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Text,
+    Jsonl,
+}
+
+impl Format {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Text => "log",
+            Format::Jsonl => "jsonl",
+        }
+    }
+}
+
+/// `[channel_log]` in the config
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChannelLogConfig {
+    pub dir: PathBuf,
+    #[serde(default)]
+    pub format: Format,
+    /// Every channel if it's empty
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// Channels that don't use `format`
+    #[serde(default)]
+    pub formats: HashMap<String, Format>,
+    /// Serve the logs on the HTTP API
+    #[serde(default)]
+    pub public: bool,
+}
+
+impl ChannelLogConfig {
+    /// Returns `true` if `channel` is one we were asked to log by name.
+    fn listed(&self, channel: &str) -> bool {
+        self.channels
+            .iter()
+            .any(|x| x.eq_ignore_ascii_case(channel))
+    }
+
+    /// Returns `true` if `channel`'s logs can be read on the HTTP API.
+    pub fn served(&self, channel: &str) -> bool {
+        self.public && self.listed(channel)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.public && self.channels.is_empty() {
+            return Err("channel_log.public needs channels, to say which logs are public".into());
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if `channel` is logged: it's listed, or none are and it isn't secret or +N.
+    pub fn logged(&self, channels: &Channels, channel: &str) -> bool {
+        match self.channels.is_empty() {
+            true => !channels.is_secret(channel) && channels.keeps_history(channel),
+            false => self.listed(channel),
+        }
+    }
+
+    /// The format `channel` is written in.
+    pub fn format(&self, channel: &str) -> Format {
+        self.formats
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(channel))
+            .map_or(self.format, |(_, format)| *format)
+    }
+}
+
+/// One line of a channel's log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Line {
+    #[serde(skip)]
+    pub time: DateTime<Utc>,
+    /// `message`, `action`, `join` or `part`
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub nick: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+impl Line {
+    /// The line as it's written out, without the line ending.
+    pub fn render(&self, format: Format) -> String {
+        match format {
+            Format::Text => {
+                let time = self.time.format("%H:%M:%S");
+                let text = self.text.as_deref().unwrap_or_default();
+                match self.kind {
+                    "join" => format!("[{}] *** Joins: {}", time, self.nick),
+                    "part" => format!("[{}] *** Parts: {}", time, self.nick),
+                    "action" => format!("[{}] * {} {}", time, self.nick, text),
+                    _ => format!("[{}] <{}> {}", time, self.nick, text),
+                }
+            }
+            Format::Jsonl => {
+                let mut json = serde_json::to_value(self).expect("lines always serialize");
+                json["time"] = self
+                    .time
+                    .to_rfc3339_opts(SecondsFormat::Millis, true)
+                    .into();
+                json.to_string()
+            }
+        }
+    }
+}
+
+/// The log line for `event` at `now`, and the channel it goes in, if it's something that happened in one.
+pub fn line(event: &Event, now: DateTime<Utc>) -> Option<(String, Line)> {
+    let (channel, kind, nick, text) = match event {
+        Event::ChannelMessage {
+            source,
+            channel,
+            text,
+        } => {
+            let nick = source_nick(source).to_string();
+            match text
+                .strip_prefix("\x01ACTION ")
+                .map(|x| x.trim_end_matches('\x01'))
+            {
+                Some(action) => (channel, "action", nick, Some(action.to_string())),
+                None => (channel, "message", nick, Some(text.clone())),
+            }
+        }
+        Event::UserJoined { nick, channel } => (channel, "join", nick.clone(), None),
+        Event::UserParted { nick, channel } => (channel, "part", nick.clone(), None),
+        _ => return None,
+    };
+    // Messages only some of the channel saw, like to its ops, aren't for the public log
+    if channel::split_status(channel).0.is_some() {
+        return None;
+    }
+    Some((
+        channel.clone(),
+        Line {
+            time: now,
+            kind,
+            nick,
+            text,
+        },
+    ))
+}
+
+/// Where `channel`'s log for `day` goes.
+pub fn path(dir: &Path, channel: &str, day: NaiveDate, format: Format) -> PathBuf {
+    channel_dir(dir, channel).join(format!("{}.{}", day.format("%Y-%m-%d"), format.extension()))
+}
+
+/// Where `channel`'s logs go. Channel names are lowercased, and anything that could get out of `dir` is escaped
+/// like `%2F`.
+fn channel_dir(dir: &Path, channel: &str) -> PathBuf {
+    let name: String = channel
+        .to_ascii_lowercase()
+        .bytes()
+        .map(|x| match x {
+            b'a'..=b'z' | b'0'..=b'9' | b'#' | b'&' | b'-' | b'_' | b'+' => (x as char).to_string(),
+            _ => format!("%{:02X}", x),
+        })
+        .collect();
+    dir.join(name)
+}
+
+/// A line as written in JSONL, back again.
+fn parse_jsonl(line: &str) -> Option<Line> {
+    let json: serde_json::Value = serde_json::from_str(line).ok()?;
+    let kind = match json["type"].as_str()? {
+        "action" => "action",
+        "join" => "join",
+        "part" => "part",
+        _ => "message",
+    };
+    Some(Line {
+        time: DateTime::parse_from_rfc3339(json["time"].as_str()?)
+            .ok()?
+            .with_timezone(&Utc),
+        kind,
+        nick: json["nick"].as_str()?.to_string(),
+        text: json["text"].as_str().map(String::from),
+    })
+}
+
+/// Every day `channel` has a log for, newest first.
+pub async fn days(config: &ChannelLogConfig, channel: &str) -> Vec<NaiveDate> {
+    let format = config.format(channel);
+    let Ok(mut entries) = fs::read_dir(channel_dir(&config.dir, channel)).await else {
+        return Vec::new();
+    };
+    let mut days = Vec::new();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name();
+        let day = name
+            .to_str()
+            .and_then(|x| x.strip_suffix(&format!(".{}", format.extension())))
+            .and_then(|x| NaiveDate::parse_from_str(x, "%Y-%m-%d").ok());
+        days.extend(day);
+    }
+    days.sort_by(|a, b| b.cmp(a));
+    days
+}
+
+/// `channel`'s log for `day` as text, whichever format it was written in. `None` if there isn't one.
+pub async fn read(config: &ChannelLogConfig, channel: &str, day: NaiveDate) -> Option<Vec<String>> {
+    let format = config.format(channel);
+    let contents = fs::read_to_string(path(&config.dir, channel, day, format))
+        .await
+        .ok()?;
+    let lines = contents.lines();
+    Some(match format {
+        Format::Text => lines.map(String::from).collect(),
+        Format::Jsonl => lines
+            .filter_map(parse_jsonl)
+            .map(|x| x.render(Format::Text))
+            .collect(),
+    })
+}
+
+/// Escapes `text` for putting in HTML.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// `channel` escaped for a URL path.
+fn url_escape(channel: &str) -> String {
+    channel
+        .bytes()
+        .map(|x| match x {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' => (x as char).to_string(),
+            _ => format!("%{:02X}", x),
+        })
+        .collect()
+}
+
+fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body><h1>{0}</h1>\n{1}</body></html>\n",
+        escape(title),
+        body
+    )
+}
+
+/// The page linking to each of `days`.
+pub fn index_page(channel: &str, days: &[NaiveDate]) -> String {
+    let links: String = days
+        .iter()
+        .map(|day| {
+            format!(
+                "<li><a href=\"/logs/{}/{}\">{}</a></li>\n",
+                url_escape(channel),
+                day,
+                day
+            )
+        })
+        .collect();
+    page(channel, &format!("<ul>\n{}</ul>\n", links))
+}
+
+/// The page for one day of `channel`, each line with an anchor like `#L1`.
+pub fn day_page(channel: &str, day: NaiveDate, lines: &[String]) -> String {
+    let lines: String = lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| {
+            format!(
+                "<a id=\"L{0}\" href=\"#L{0}\">{0:>4}</a> {1}\n",
+                i + 1,
+                escape(line)
+            )
+        })
+        .collect();
+    page(
+        &format!("{} on {}", channel, day),
+        &format!("<pre>\n{}</pre>\n", lines),
+    )
+}
+
+/// Writes a line for everything that happens in a logged channel until shutdown.
+pub async fn watch(
+    mut events: broadcast::Receiver<Event>,
+    channels: Channels,
+    config: ChannelLogConfig,
+    mut shutdown: Shutdown,
+) {
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            _ = shutdown.recv() => return,
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                log::error!("Channel logs missed {} events", missed);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let Some((channel, line)) = self::line(&event, Utc::now()) else {
+            continue;
+        };
+        if !config.logged(&channels, &channel) {
+            continue;
+        }
+        let format = config.format(&channel);
+        let path = path(&config.dir, &channel, line.time.date_naive(), format);
+        if let Err(e) = append(&path, &line.render(format)).await {
+            log::error!("Failed to write to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Appends `text` as a line to the file at `path`, making it and its directory if need be.
+async fn append(path: &Path, text: &str) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(format!("{}\n", text).as_bytes()).await?;
+    file.flush().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn lines() {
+        let now = Utc.with_ymd_and_hms(2026, 10, 16, 13, 37, 0).unwrap();
+        let message = |text: &str| Event::ChannelMessage {
+            source: "tiger!tiger@127.0.0.1".to_string(),
+            channel: "#meow".to_string(),
+            text: text.to_string(),
+        };
+        let (channel, said) = line(&message("hi"), now).unwrap();
+        assert_eq!(channel, "#meow");
+        assert_eq!(said.render(Format::Text), "[13:37:00] <tiger> hi");
+        assert_eq!(
+            said.render(Format::Jsonl),
+            r#"{"nick":"tiger","text":"hi","time":"2026-10-16T13:37:00.000Z","type":"message"}"#
+        );
+        let (_, action) = line(&message("\x01ACTION purrs\x01"), now).unwrap();
+        assert_eq!(action.render(Format::Text), "[13:37:00] * tiger purrs");
+        let join = Event::UserJoined {
+            nick: "cat".to_string(),
+            channel: "#meow".to_string(),
+        };
+        let (_, join) = line(&join, now).unwrap();
+        assert_eq!(join.render(Format::Text), "[13:37:00] *** Joins: cat");
+        assert!(!join.render(Format::Jsonl).contains("text"));
+
+        let ops_only = Event::ChannelMessage {
+            source: "tiger".to_string(),
+            channel: "@#meow".to_string(),
+            text: "secret".to_string(),
+        };
+        assert_eq!(line(&ops_only, now), None);
+    }
+
+    #[test]
+    fn paths_and_formats() {
+        let config: ChannelLogConfig = toml::from_str(
+            r##"
+            dir = "/logs"
+            channels = ["#Rust"]
+            [formats]
+            "#help" = "jsonl"
+            "##,
+        )
+        .unwrap();
+        assert!(config.listed("#rust") && !config.listed("#help"));
+        assert!(!config.served("#rust"));
+        let public = ChannelLogConfig {
+            public: true,
+            ..config.clone()
+        };
+        assert!(public.served("#RUST") && !public.served("#help"));
+        assert!(public.validate().is_ok());
+        let everything = ChannelLogConfig {
+            channels: Vec::new(),
+            ..public
+        };
+        assert!(everything.validate().is_err());
+        assert_eq!(config.format("#HELP"), Format::Jsonl);
+        assert_eq!(config.format("#rust"), Format::Text);
+
+        let day = NaiveDate::from_ymd_opt(2026, 10, 16).unwrap();
+        assert_eq!(
+            path(&config.dir, "#Rust", day, Format::Text),
+            Path::new("/logs/#rust/2026-10-16.log")
+        );
+        assert_eq!(
+            path(&config.dir, "#../../etc", day, Format::Jsonl),
+            Path::new("/logs/#%2E%2E%2F%2E%2E%2Fetc/2026-10-16.jsonl")
+        );
+    }
+
+    #[tokio::test]
+    async fn reading_back() {
+        let dir = std::env::temp_dir().join(format!("rust_irc_chanlog_{}", std::process::id()));
+        let config = ChannelLogConfig {
+            dir: dir.clone(),
+            format: Format::Jsonl,
+            channels: vec!["#meow".to_string()],
+            formats: HashMap::new(),
+            public: true,
+        };
+        let now = Utc.with_ymd_and_hms(2026, 10, 16, 13, 37, 0).unwrap();
+        let said = Event::ChannelMessage {
+            source: "tiger".to_string(),
+            channel: "#meow".to_string(),
+            text: "<b>hi</b>".to_string(),
+        };
+        for day in [now, now - chrono::Duration::days(1)] {
+            let (_, line) = line(&said, day).unwrap();
+            let path = path(&dir, "#meow", day.date_naive(), Format::Jsonl);
+            append(&path, &line.render(Format::Jsonl)).await.unwrap();
+        }
+
+        let days = days(&config, "#MEOW").await;
+        assert_eq!(
+            days,
+            [now.date_naive(), now.date_naive().pred_opt().unwrap()]
+        );
+        let lines = read(&config, "#meow", days[0]).await.unwrap();
+        assert_eq!(lines, ["[13:37:00] <tiger> <b>hi</b>"]);
+        assert!(read(&config, "#elsewhere", days[0]).await.is_none());
+
+        let page = day_page("#meow", days[0], &lines);
+        assert!(page.contains(
+            r##"<a id="L1" href="#L1">   1</a> [13:37:00] &lt;tiger&gt; &lt;b&gt;hi&lt;/b&gt;"##
+        ));
+        assert!(index_page("#meow", &days).contains(r#"<a href="/logs/%23meow/2026-10-15">"#));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,809 @@
+//! Who is in which channel, and with what status. Whoever creates a channel by joining it first gets op.
+//!
+//! Messages can be sent to just the members of a channel with at least some status by putting its prefix in front
+//! of the channel, like `PRIVMSG @#chan :ops only`.
+//!
+//! Halfops and up can ban people with `MODE #chan +b <mask>`, or quiet them with `MODE #chan +q <mask>` so they can
+//! stay but can't talk unless they're voiced. Owners are `+y`, since `q` was taken by quiets first.
+//!
+//! Anyone in a channel can set its topic, and we remember who did and when for RPL_TOPICWHOTIME. LIST can pick
+//! channels by how long ago their topic changed (ELIST=T), like `LIST T<60` for the last hour. The last few topics
+//! are kept too, for TOPICHISTORY, so ops can put one back after it's been wiped.
+
+use crate::{ban::glob_match, mode, registry::Uid, server::lock};
+use chrono::Utc;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+/// Membership status, lowest first so they compare the way you'd expect
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    Member,
+    Voice,
+    Halfop,
+    Op,
+    Admin,
+    Owner,
+}
+
+impl Status {
+    /// Every status with a prefix, highest first like PREFIX wants them
+    const PREFIXED: [Status; 5] = [
+        Status::Owner,
+        Status::Admin,
+        Status::Op,
+        Status::Halfop,
+        Status::Voice,
+    ];
+
+    pub fn prefix(&self) -> Option<char> {
+        match self {
+            Status::Member => None,
+            Status::Voice => Some('+'),
+            Status::Halfop => Some('%'),
+            Status::Op => Some('@'),
+            Status::Admin => Some('&'),
+            Status::Owner => Some('~'),
+        }
+    }
+
+    pub fn mode(&self) -> Option<char> {
+        match self {
+            Status::Member => None,
+            Status::Voice => Some('v'),
+            Status::Halfop => Some('h'),
+            Status::Op => Some('o'),
+            Status::Admin => Some('a'),
+            Status::Owner => Some('y'),
+        }
+    }
+
+    pub fn from_prefix(prefix: char) -> Option<Status> {
+        Self::PREFIXED
+            .into_iter()
+            .find(|x| x.prefix() == Some(prefix))
+    }
+
+    pub fn from_mode(mode: char) -> Option<Status> {
+        Self::PREFIXED.into_iter().find(|x| x.mode() == Some(mode))
+    }
+
+    /// Returns `true` if someone with this status can give or take `status`. Halfops can only voice people,
+    /// everyone else can hand out anything up to their own status.
+    pub fn can_set(&self, status: Status) -> bool {
+        match status {
+            Status::Member => false,
+            Status::Voice => *self >= Status::Halfop,
+            Status::Halfop => *self >= Status::Op,
+            status => *self >= status,
+        }
+    }
+}
+
+/// Splits a message target like `@#chan` into the status it's limited to and the rest.
+pub fn split_status(target: &str) -> (Option<Status>, &str) {
+    let mut chars = target.chars();
+    match chars.next().and_then(Status::from_prefix) {
+        // `&` is a channel type as well as admin, so `&chan` is a channel and `&#chan` means its admins
+        Some(status) if is_channel(chars.as_str()) => (Some(status), chars.as_str()),
+        _ => (None, target),
+    }
+}
+
+/// Returns `true` if `target` names a channel rather than a nick.
+pub fn is_channel(target: &str) -> bool {
+    target.starts_with('#') || target.starts_with('&')
+}
+
+/// Why a name can't be a channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BadName {
+    /// Doesn't start with one of CHANTYPES, so it isn't a channel at all. ERR_NOSUCHCHANNEL
+    NotAChannel,
+    /// Too long, or has something in it channel names can't. ERR_BADCHANMASK
+    Mask,
+}
+
+/// Checks that `name` could be a channel here: it starts with one of `chantypes`, is at most `max` bytes, and has no
+/// spaces, commas or control characters (BEL included) in it.
+pub fn check_name(name: &str, chantypes: &str, max: usize) -> Result<(), BadName> {
+    if !name.starts_with(|x| chantypes.contains(x)) {
+        return Err(BadName::NotAChannel);
+    }
+    if name.len() > max || name.chars().any(|x| x == ' ' || x == ',' || x.is_control()) {
+        return Err(BadName::Mask);
+    }
+    Ok(())
+}
+
+/// Fills in whatever's missing from a `nick!user@host` mask, so `tiger` becomes `tiger!*@*`.
+pub fn normalize_mask(mask: &str) -> String {
+    match (mask.contains('!'), mask.contains('@')) {
+        (true, true) => mask.to_string(),
+        (true, false) => format!("{}@*", mask),
+        (false, true) => format!("*!{}", mask),
+        (false, false) => format!("{}!*@*", mask),
+    }
+}
+
+/// The ISUPPORT tokens describing channel membership and modes
+pub fn isupport() -> Vec<String> {
+    let modes: String = Status::PREFIXED.iter().filter_map(|x| x.mode()).collect();
+    let prefixes: String = Status::PREFIXED.iter().filter_map(|x| x.prefix()).collect();
+    vec![
+        format!("PREFIX=({}){}", modes, prefixes),
+        format!("STATUSMSG={}", prefixes),
+        mode::CHANNEL.chanmodes(),
+        "ELIST=T".to_string(),
+    ]
+}
+
+/// A mask on one of a channel's lists, like +b
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListEntry {
+    pub mask: String,
+    pub set_by: String,
+    /// Unix timestamp
+    pub set_at: i64,
+}
+
+/// A channel's topic, and who set it when
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topic {
+    pub text: String,
+    /// Hostmask of whoever set it
+    pub set_by: String,
+    /// Unix timestamp
+    pub set_at: i64,
+}
+
+/// One line of LIST
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listing {
+    pub name: String,
+    pub members: usize,
+    pub topic: Option<Topic>,
+    /// +s, so only members and opers with `spy` see it
+    pub secret: bool,
+}
+
+impl Listing {
+    /// Checks an ELIST condition, `T<n` for a topic set less than `n` minutes before `now` or `T>n` for more.
+    /// Channels without a topic never pass. Returns `None` if `condition` isn't one of those.
+    pub fn topic_condition(&self, condition: &str, now: i64) -> Option<bool> {
+        let rest = condition.strip_prefix(['T', 't'])?;
+        let (newer, minutes) = match rest.split_at_checked(1)? {
+            ("<", minutes) => (true, minutes),
+            (">", minutes) => (false, minutes),
+            _ => return None,
+        };
+        let minutes: i64 = minutes.parse().ok()?;
+        Some(self.topic.as_ref().is_some_and(|topic| {
+            let age = (now - topic.set_at) / 60;
+            if newer {
+                age < minutes
+            } else {
+                age > minutes
+            }
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct Channel {
+    /// As whoever made it spelled it
+    name: String,
+    members: HashMap<Uid, Status>,
+    /// +b, matching users can't join
+    bans: Vec<ListEntry>,
+    /// +q, matching members can't talk
+    quiets: Vec<ListEntry>,
+    topic: Option<Topic>,
+    /// Topics it had before `topic`, newest first
+    old_topics: VecDeque<Topic>,
+    /// +s
+    secret: bool,
+    /// +N, nothing said in it goes into `history`
+    no_history: bool,
+    /// Unix timestamp, for RPL_CREATIONTIME
+    created: i64,
+}
+
+impl Channel {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            members: HashMap::new(),
+            bans: Vec::new(),
+            quiets: Vec::new(),
+            topic: None,
+            old_topics: VecDeque::new(),
+            secret: false,
+            no_history: false,
+            created: Utc::now().timestamp(),
+        }
+    }
+}
+
+/// Adds `mask` to `list`. Returns `false` if it was already there.
+fn add_entry(list: &mut Vec<ListEntry>, mask: &str, set_by: &str) -> bool {
+    if list.iter().any(|x| x.mask.eq_ignore_ascii_case(mask)) {
+        return false;
+    }
+    list.push(ListEntry {
+        mask: mask.to_string(),
+        set_by: set_by.to_string(),
+        set_at: Utc::now().timestamp(),
+    });
+    true
+}
+
+/// Takes `mask` off `list`. Returns `false` if it wasn't on it.
+fn remove_entry(list: &mut Vec<ListEntry>, mask: &str) -> bool {
+    let before = list.len();
+    list.retain(|x| !x.mask.eq_ignore_ascii_case(mask));
+    list.len() != before
+}
+
+/// Channels are the same whatever case they're written in, with CASEMAPPING=ascii
+fn key(name: &str) -> String {
+    name.to_ascii_lowercase()
+}
+
+/// Shared between every connection.
+#[derive(Debug, Clone, Default)]
+pub struct Channels {
+    /// By `key`
+    channels: Arc<Mutex<HashMap<String, Channel>>>,
+}
+
+impl Channels {
+    /// Puts `uid` in `channel`, returning the status they got. Rejoining keeps whatever status they had.
+    pub fn join(&self, channel: &str, uid: &Uid) -> Status {
+        let mut channels = lock(&self.channels);
+        let members = &mut channels
+            .entry(key(channel))
+            .or_insert_with(|| Channel::new(channel))
+            .members;
+        let status = if members.is_empty() {
+            Status::Op
+        } else {
+            Status::Member
+        };
+        *members.entry(uid.clone()).or_insert(status)
+    }
+
+    /// Takes `uid` out of `channel`, dropping it along with its topic and bans if they were the last one in it.
+    /// Returns `false` if they weren't in it.
+    pub fn part(&self, channel: &str, uid: &Uid) -> bool {
+        let mut channels = lock(&self.channels);
+        let members = match channels.get_mut(&key(channel)) {
+            Some(channel) => &mut channel.members,
+            None => return false,
+        };
+        if members.remove(uid).is_none() {
+            return false;
+        }
+        if members.is_empty() {
+            channels.remove(&key(channel));
+        }
+        true
+    }
+
+    /// Takes `uid` out of every channel, dropping channels nobody is left in.
+    pub fn quit(&self, uid: &Uid) {
+        let mut channels = lock(&self.channels);
+        for channel in channels.values_mut() {
+            channel.members.remove(uid);
+        }
+        channels.retain(|_, channel| !channel.members.is_empty());
+    }
+
+    /// How many members each channel has.
+    pub fn member_counts(&self) -> Vec<(String, usize)> {
+        let channels = lock(&self.channels);
+        channels
+            .values()
+            .map(|channel| (channel.name.clone(), channel.members.len()))
+            .collect()
+    }
+
+    /// What `target` is called, spelled the way whoever made the channel did, keeping any status prefix like
+    /// `@#chan`. It's left as it is if there's no such channel.
+    pub fn name(&self, target: &str) -> String {
+        let (status, name) = split_status(target);
+        let channels = lock(&self.channels);
+        match (channels.get(&key(name)), status.and_then(|x| x.prefix())) {
+            (Some(channel), Some(prefix)) => format!("{}{}", prefix, channel.name),
+            (Some(channel), None) => channel.name.clone(),
+            (None, _) => target.to_string(),
+        }
+    }
+
+    /// `name` for each of `targets`, without any that turn out to be the same.
+    pub fn names(&self, targets: &[String]) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for target in targets {
+            let name = self.name(target);
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        names
+    }
+
+    /// When `channel` was created, `None` if there's no such channel.
+    pub fn created(&self, channel: &str) -> Option<i64> {
+        let channels = lock(&self.channels);
+        channels.get(&key(channel)).map(|x| x.created)
+    }
+
+    /// The topic of `channel`, if it has one.
+    pub fn topic(&self, channel: &str) -> Option<Topic> {
+        let channels = lock(&self.channels);
+        channels.get(&key(channel))?.topic.clone()
+    }
+
+    /// Sets the topic of `channel`, or clears it if `text` is empty, keeping up to `keep` of the ones before it.
+    /// Returns `false` if there's no such channel.
+    pub fn set_topic(&self, channel: &str, text: &str, set_by: &str, keep: usize) -> bool {
+        let mut channels = lock(&self.channels);
+        let channel = match channels.get_mut(&key(channel)) {
+            Some(channel) => channel,
+            None => return false,
+        };
+        let new = (!text.is_empty()).then(|| Topic {
+            text: text.to_string(),
+            set_by: set_by.to_string(),
+            set_at: Utc::now().timestamp(),
+        });
+        if let Some(old) = std::mem::replace(&mut channel.topic, new) {
+            channel.old_topics.push_front(old);
+        }
+        channel.old_topics.truncate(keep);
+        true
+    }
+
+    /// The topics `channel` had before this one, newest first.
+    pub fn old_topics(&self, channel: &str) -> Vec<Topic> {
+        let channels = lock(&self.channels);
+        channels
+            .get(&key(channel))
+            .map_or(Vec::new(), |x| x.old_topics.iter().cloned().collect())
+    }
+
+    /// Returns `true` if `channel` is +s.
+    pub fn is_secret(&self, channel: &str) -> bool {
+        let channels = lock(&self.channels);
+        channels.get(&key(channel)).is_some_and(|x| x.secret)
+    }
+
+    /// Sets or unsets +s on `channel`. Returns `false` if nothing changed.
+    pub fn set_secret(&self, channel: &str, secret: bool) -> bool {
+        let mut channels = lock(&self.channels);
+        match channels.get_mut(&key(channel)) {
+            Some(channel) if channel.secret != secret => {
+                channel.secret = secret;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns `false` if `channel` is +N.
+    pub fn keeps_history(&self, channel: &str) -> bool {
+        let channels = lock(&self.channels);
+        !channels.get(&key(channel)).is_some_and(|x| x.no_history)
+    }
+
+    /// Sets or unsets +N on `channel`. Returns `false` if nothing changed.
+    pub fn set_no_history(&self, channel: &str, no_history: bool) -> bool {
+        let mut channels = lock(&self.channels);
+        match channels.get_mut(&key(channel)) {
+            Some(channel) if channel.no_history != no_history => {
+                channel.no_history = no_history;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The modes `channel` has set without a parameter, like `+Ns`.
+    pub fn modes(&self, channel: &str) -> String {
+        let channels = lock(&self.channels);
+        let mut modes = "+".to_string();
+        if let Some(channel) = channels.get(&key(channel)) {
+            if channel.no_history {
+                modes.push('N');
+            }
+            if channel.secret {
+                modes.push('s');
+            }
+        }
+        modes
+    }
+
+    /// Every channel for LIST, sorted by name.
+    pub fn list(&self) -> Vec<Listing> {
+        let channels = lock(&self.channels);
+        let mut list: Vec<Listing> = channels
+            .values()
+            .map(|channel| Listing {
+                name: channel.name.clone(),
+                members: channel.members.len(),
+                topic: channel.topic.clone(),
+                secret: channel.secret,
+            })
+            .collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+
+    /// Everyone in `channel` and their status.
+    pub fn members(&self, channel: &str) -> Vec<(Uid, Status)> {
+        let channels = lock(&self.channels);
+        channels.get(&key(channel)).map_or(Vec::new(), |x| {
+            x.members
+                .iter()
+                .map(|(uid, status)| (uid.clone(), *status))
+                .collect()
+        })
+    }
+
+    /// Everyone a message to `targets` reaches. A target can have a status prefix like `@#chan` to only reach members
+    /// with at least that status.
+    pub fn resolve(&self, targets: &[String]) -> HashSet<Uid> {
+        let channels = lock(&self.channels);
+        let mut uids = HashSet::new();
+        for target in targets {
+            let (status, name) = split_status(target);
+            if let Some(channel) = channels.get(&key(name)) {
+                uids.extend(
+                    channel
+                        .members
+                        .iter()
+                        .filter(|(_, x)| status.is_none_or(|status| **x >= status))
+                        .map(|(uid, _)| uid.clone()),
+                );
+            }
+        }
+        uids
+    }
+
+    /// What `uid` is in `channel`, `None` if they aren't in it.
+    pub fn status(&self, channel: &str, uid: &Uid) -> Option<Status> {
+        let channels = lock(&self.channels);
+        channels.get(&key(channel))?.members.get(uid).copied()
+    }
+
+    /// Gives `uid` `status` in `channel`, or takes it away if `adding` isn't set. Members only have their highest
+    /// status, so giving someone less than they have or taking away something they don't have does nothing.
+    /// Returns `false` if nothing changed.
+    pub fn set_status(&self, channel: &str, uid: &Uid, status: Status, adding: bool) -> bool {
+        let mut channels = lock(&self.channels);
+        let current = match channels
+            .get_mut(&key(channel))
+            .and_then(|x| x.members.get_mut(uid))
+        {
+            Some(current) => current,
+            None => return false,
+        };
+        match adding {
+            true if *current < status => *current = status,
+            false if *current == status => *current = Status::Member,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Adds `mask` to the ban list of `channel`. Returns `false` if it was already there, or there's no such
+    /// channel.
+    pub fn add_ban(&self, channel: &str, mask: &str, set_by: &str) -> bool {
+        let mut channels = lock(&self.channels);
+        channels
+            .get_mut(&key(channel))
+            .is_some_and(|x| add_entry(&mut x.bans, mask, set_by))
+    }
+
+    /// Takes `mask` off the ban list of `channel`. Returns `false` if it wasn't on it.
+    pub fn remove_ban(&self, channel: &str, mask: &str) -> bool {
+        let mut channels = lock(&self.channels);
+        channels
+            .get_mut(&key(channel))
+            .is_some_and(|x| remove_entry(&mut x.bans, mask))
+    }
+
+    /// Adds `mask` to the quiet list of `channel`. Returns `false` if it was already there, or there's no such
+    /// channel.
+    pub fn add_quiet(&self, channel: &str, mask: &str, set_by: &str) -> bool {
+        let mut channels = lock(&self.channels);
+        channels
+            .get_mut(&key(channel))
+            .is_some_and(|x| add_entry(&mut x.quiets, mask, set_by))
+    }
+
+    /// Takes `mask` off the quiet list of `channel`. Returns `false` if it wasn't on it.
+    pub fn remove_quiet(&self, channel: &str, mask: &str) -> bool {
+        let mut channels = lock(&self.channels);
+        channels
+            .get_mut(&key(channel))
+            .is_some_and(|x| remove_entry(&mut x.quiets, mask))
+    }
+
+    /// The quiet list of `channel`, oldest first.
+    pub fn quiets(&self, channel: &str) -> Vec<ListEntry> {
+        let channels = lock(&self.channels);
+        channels
+            .get(&key(channel))
+            .map(|x| x.quiets.clone())
+            .unwrap_or_default()
+    }
+
+    /// The ban list of `channel`, oldest first.
+    pub fn bans(&self, channel: &str) -> Vec<ListEntry> {
+        let channels = lock(&self.channels);
+        channels
+            .get(&key(channel))
+            .map(|x| x.bans.clone())
+            .unwrap_or_default()
+    }
+
+    /// Returns `true` if someone with `hostmask` is banned from joining `channel`.
+    pub fn is_banned(&self, channel: &str, hostmask: &str) -> bool {
+        let channels = lock(&self.channels);
+        channels
+            .get(&key(channel))
+            .is_some_and(|x| x.bans.iter().any(|x| glob_match(&x.mask, hostmask)))
+    }
+
+    /// Returns `true` if `uid`, going by `hostmask`, isn't allowed to talk in `channel`. Voice gets you out of it.
+    pub fn is_quieted(&self, channel: &str, uid: &Uid, hostmask: &str) -> bool {
+        let channels = lock(&self.channels);
+        let channel = match channels.get(&key(channel)) {
+            Some(channel) => channel,
+            None => return false,
+        };
+        channel.members.get(uid).is_some_and(|x| *x < Status::Voice)
+            && channel.quiets.iter().any(|x| glob_match(&x.mask, hostmask))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::isolate;
+
+    #[tokio::test]
+    async fn panicking_handlers_leave_channels_usable() {
+        let channels = Channels::default();
+        let (tiger, cat) = (Uid::new("001", 1), Uid::new("001", 2));
+        channels.join("#meow", &tiger);
+        channels.join("#meow", &cat);
+        // A handler dying partway through, with the channels still locked
+        let caught = isolate::catching(async {
+            let _channels = lock(&channels.channels);
+            panic!("meow");
+        })
+        .await;
+        assert!(caught.is_err() && channels.channels.is_poisoned());
+
+        // Cleaning up after it like any other disconnect
+        channels.quit(&tiger);
+        assert_eq!(channels.members("#meow"), [(cat.clone(), Status::Member)]);
+        assert!(channels.part("#meow", &cat));
+        assert!(channels.member_counts().is_empty());
+    }
+
+    #[test]
+    fn statuses() {
+        assert_eq!(split_status("@#chan"), (Some(Status::Op), "#chan"));
+        assert_eq!(split_status("+#chan"), (Some(Status::Voice), "#chan"));
+        assert_eq!(split_status("#chan"), (None, "#chan"));
+        assert_eq!(split_status("&#chan"), (Some(Status::Admin), "#chan"));
+        assert_eq!(split_status("&chan"), (None, "&chan"));
+        assert_eq!(split_status("~&chan"), (Some(Status::Owner), "&chan"));
+        assert!(Status::Op > Status::Voice && Status::Voice > Status::Member);
+        assert!(Status::Owner > Status::Admin && Status::Op > Status::Halfop);
+        assert_eq!(
+            isupport(),
+            [
+                "PREFIX=(yaohv)~&@%+",
+                "STATUSMSG=~&@%+",
+                "CHANMODES=bq,,,Ns",
+                "ELIST=T"
+            ]
+        );
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(check_name("#meow", "#&", 50), Ok(()));
+        assert_eq!(check_name("&meow", "#&", 50), Ok(()));
+        assert_eq!(check_name("&meow", "#", 50), Err(BadName::NotAChannel));
+        assert_eq!(check_name("0", "#&", 50), Err(BadName::NotAChannel));
+        assert_eq!(check_name("", "#&", 50), Err(BadName::NotAChannel));
+        assert_eq!(check_name("#me\x07ow", "#&", 50), Err(BadName::Mask));
+        assert_eq!(check_name("#me\u{85}ow", "#&", 50), Err(BadName::Mask));
+        assert_eq!(check_name("#meow", "#&", 4), Err(BadName::Mask));
+    }
+
+    #[test]
+    fn who_can_set_what() {
+        assert!(Status::Owner.can_set(Status::Owner));
+        assert!(Status::Admin.can_set(Status::Op));
+        assert!(!Status::Admin.can_set(Status::Owner));
+        assert!(Status::Op.can_set(Status::Halfop));
+        assert!(Status::Halfop.can_set(Status::Voice));
+        assert!(!Status::Halfop.can_set(Status::Halfop));
+        assert!(!Status::Voice.can_set(Status::Voice));
+        assert_eq!(Status::from_mode('y'), Some(Status::Owner));
+        assert_eq!(Status::from_mode('q'), None);
+    }
+
+    #[test]
+    fn topics() {
+        let channels = Channels::default();
+        assert!(!channels.set_topic("#chan", "meow", "tiger!tiger@host", 2));
+        channels.join("#chan", &Uid::new("001", 1));
+        assert!(channels.set_topic("#chan", "meow", "tiger!tiger@host", 2));
+        let topic = channels.topic("#chan").unwrap();
+        assert_eq!(
+            (topic.text.as_str(), topic.set_by.as_str()),
+            ("meow", "tiger!tiger@host")
+        );
+
+        let listing = &channels.list()[0];
+        let now = topic.set_at + 10 * 60;
+        assert_eq!(listing.topic_condition("T<60", now), Some(true));
+        assert_eq!(listing.topic_condition("T>5", now), Some(true));
+        assert_eq!(listing.topic_condition("T>60", now), Some(false));
+        assert_eq!(listing.topic_condition("T=1", now), None);
+        assert_eq!(listing.topic_condition("#chan", now), None);
+
+        channels.set_topic("#chan", "", "tiger!tiger@host", 2);
+        assert_eq!(channels.topic("#chan"), None);
+        assert_eq!(channels.list()[0].topic_condition("T>5", now), Some(false));
+
+        // The wiped topic is kept, and only the last two are
+        assert_eq!(channels.old_topics("#CHAN"), [topic]);
+        channels.set_topic("#chan", "purr", "tiger!tiger@host", 2);
+        channels.set_topic("#chan", "hiss", "tiger!tiger@host", 2);
+        let old: Vec<_> = channels
+            .old_topics("#chan")
+            .into_iter()
+            .map(|x| x.text)
+            .collect();
+        assert_eq!(old, ["purr", "meow"]);
+        channels.set_topic("#chan", "mrrp", "tiger!tiger@host", 0);
+        assert!(channels.old_topics("#chan").is_empty());
+    }
+
+    #[test]
+    fn first_joiner_gets_op() {
+        let channels = Channels::default();
+        let (tiger, cat) = (Uid::new("001", 1), Uid::new("001", 2));
+        assert_eq!(channels.join("#chan", &tiger), Status::Op);
+        assert_eq!(channels.join("#chan", &cat), Status::Member);
+        assert_eq!(channels.join("#chan", &tiger), Status::Op);
+        assert_eq!(channels.status("#chan", &cat), Some(Status::Member));
+        assert_eq!(channels.status("#elsewhere", &cat), None);
+        assert!(channels.created("#chan").is_some());
+        assert_eq!(channels.created("#elsewhere"), None);
+
+        assert!(channels.set_secret("#chan", true));
+        assert!(!channels.set_secret("#chan", true));
+        assert!(channels.is_secret("#chan") && channels.list()[0].secret);
+        assert!(!channels.set_secret("#elsewhere", true));
+        assert!(channels.keeps_history("#chan"));
+        assert!(channels.set_no_history("#CHAN", true));
+        assert!(!channels.keeps_history("#chan"));
+        assert_eq!(channels.modes("#chan"), "+Ns");
+
+        assert!(channels.set_status("#chan", &cat, Status::Halfop, true));
+        assert!(!channels.set_status("#chan", &cat, Status::Voice, true));
+        assert!(!channels.set_status("#chan", &cat, Status::Voice, false));
+        assert!(channels.set_status("#chan", &cat, Status::Halfop, false));
+        assert_eq!(channels.status("#chan", &cat), Some(Status::Member));
+
+        channels.quit(&tiger);
+        channels.quit(&cat);
+        assert_eq!(channels.join("#chan", &cat), Status::Op);
+    }
+
+    #[test]
+    fn empty_channels_go() {
+        let channels = Channels::default();
+        let (tiger, cat) = (Uid::new("001", 1), Uid::new("001", 2));
+        channels.join("#chan", &tiger);
+        channels.join("#chan", &cat);
+        channels.join("#other", &cat);
+        channels.set_topic("#chan", "meow", "tiger!tiger@host", 0);
+        channels.add_ban("#chan", "*!*@spam", "tiger");
+
+        assert!(channels.part("#chan", &tiger));
+        assert!(!channels.part("#chan", &tiger));
+        assert!(channels.topic("#chan").is_some());
+        // Dropping without parting takes them out of everything
+        channels.quit(&cat);
+        assert!(channels.member_counts().is_empty());
+        assert!(channels.resolve(&["#chan".to_string()]).is_empty());
+
+        // Coming back makes a fresh one
+        assert_eq!(channels.join("#chan", &cat), Status::Op);
+        assert_eq!(channels.topic("#chan"), None);
+        assert!(channels.bans("#chan").is_empty());
+        assert!(channels.part("#chan", &cat));
+        assert_eq!(channels.created("#chan"), None);
+        assert!(!channels.part("#nowhere", &cat));
+    }
+
+    #[test]
+    fn casefolding() {
+        let channels = Channels::default();
+        let (tiger, cat) = (Uid::new("001", 1), Uid::new("001", 2));
+        assert_eq!(channels.join("#Meow", &tiger), Status::Op);
+        assert_eq!(channels.join("#mEOW", &cat), Status::Member);
+        assert_eq!(channels.member_counts(), [("#Meow".to_string(), 2)]);
+        assert_eq!(channels.status("#MEOW", &cat), Some(Status::Member));
+        assert_eq!(channels.name("#meow"), "#Meow");
+        assert_eq!(channels.name("@#meow"), "@#Meow");
+        assert_eq!(channels.name("#nowhere"), "#nowhere");
+        let targets = [
+            "#meow".to_string(),
+            "#MEOW".to_string(),
+            "@#meow".to_string(),
+        ];
+        assert_eq!(channels.names(&targets), ["#Meow", "@#Meow"]);
+        assert_eq!(channels.resolve(&targets).len(), 2);
+
+        assert!(channels.part("#MEOW", &tiger));
+        assert!(channels.part("#meow", &cat));
+        assert!(channels.list().is_empty());
+    }
+
+    #[test]
+    fn resolving() {
+        let channels = Channels::default();
+        let (tiger, cat, kitty) = (Uid::new("001", 1), Uid::new("001", 2), Uid::new("001", 3));
+        channels.join("#chan", &tiger);
+        channels.join("#chan", &cat);
+        channels.join("#other", &kitty);
+        let targets = ["#chan".to_string(), "#other".to_string()];
+        assert_eq!(channels.resolve(&targets).len(), 3);
+        assert_eq!(
+            channels.resolve(&["@#chan".to_string()]),
+            HashSet::from([tiger])
+        );
+        assert!(channels.resolve(&["#nowhere".to_string()]).is_empty());
+    }
+
+    #[test]
+    fn bans_and_quiets() {
+        let channels = Channels::default();
+        let (tiger, cat) = (Uid::new("001", 1), Uid::new("001", 2));
+        channels.join("#chan", &tiger);
+        channels.join("#chan", &cat);
+        assert_eq!(normalize_mask("cat"), "cat!*@*");
+        assert_eq!(normalize_mask("*@10.*"), "*!*@10.*");
+
+        assert!(!channels.add_quiet("#elsewhere", "cat!*@*", "tiger"));
+        assert!(channels.add_quiet("#chan", "cat!*@*", "tiger"));
+        assert!(!channels.add_quiet("#chan", "CAT!*@*", "tiger"));
+        assert!(channels.is_quieted("#chan", &cat, "cat!cat@127.0.0.1"));
+        assert!(!channels.is_banned("#chan", "cat!cat@127.0.0.1"));
+        // Ops can talk through it
+        assert!(!channels.is_quieted("#chan", &tiger, "cat!tiger@127.0.0.1"));
+        assert_eq!(channels.quiets("#chan")[0].set_by, "tiger");
+        assert!(channels.bans("#chan").is_empty());
+
+        assert!(channels.remove_quiet("#chan", "cat!*@*"));
+        assert!(!channels.remove_quiet("#chan", "cat!*@*"));
+        assert!(!channels.is_quieted("#chan", &cat, "cat!cat@127.0.0.1"));
+
+        assert!(channels.add_ban("#chan", "*!*@10.*", "tiger"));
+        assert!(channels.is_banned("#chan", "cat!cat@10.0.0.1"));
+        assert!(!channels.is_quieted("#chan", &cat, "cat!cat@10.0.0.1"));
+    }
+}
@@ -0,0 +1,1104 @@
+//! Running several rust_irc processes as one logical server, with Redis pub/sub in between. Each process (node)
+//! publishes the channel messages (tags included), JOINs, PARTs and KILLs its clients send, and passes on whatever the other nodes publish
+//! to its own clients. Who is online on which node is kept in Redis too so a message to a nick on another node can
+//! be sent straight there.
+//!
+//! ```toml
+//! [cluster]
+//! redis = "redis://127.0.0.1/"
+//! # Keeps separate networks on the same Redis apart
+//! prefix = "rust_irc"
+//! ```
+//!
+//! Opers can send a command to particular nodes with `ENCAP <node mask> <subcommand> [params]`, which goes to every
+//! node but is only acted on by the ones whose id matches the mask, by publishing an `Event::Encap` for whatever
+//! understands the subcommand. Nodes that don't understand it just ignore it. Standalone, only masks that match
+//! anything (like `*`) reach us.
+//!
+//! Bursts of lines (like a netsplit's worth of QUITs, or a busy channel) can go out several to a publish by setting
+//! `batch` to the most lines to put in one. Older nodes only read single lines, so each node adds itself to a set in
+//! Redis saying it understands batches, and only batches while every node still subscribed has. That's checked every
+//! `NEGOTIATE_INTERVAL`, so an older node that's only just joined can miss a batch or two in the meantime.
+//!
+//! `WHOIS <nick> <nick>` and `MOTD <nick>` are answered by the node that nick is on, and `MOTD <node mask>` by every
+//! node matching the mask, with the replies sent back to whoever asked. If none have answered a mask within
+//! `ANSWER_TIMEOUT`, it matched nothing and they get ERR_NOSUCHSERVER.
+//!
+//! A nick is claimed in Redis before a node hands it out, and whichever node claims it first has it until its user
+//! is gone, so two nodes can't hand out the same nick at once. Only a node's own claims are let go by it.
+//!
+//! `SQUIT <node id>` splits a node off: it lets go of its claims and carries on standalone, same as if it lost Redis.
+//! This all needs the `redis` feature, without it `[cluster]` is ignored and the server runs standalone.
+
+use crate::{
+    ban::glob_match,
+    catalog::Catalog,
+    channel::Channels,
+    event::{Event, EventBus},
+    identity::LiveIdentity,
+    log,
+    message_parse::{Command, Message},
+    privacy::PrivacyConfig,
+    registry::Users,
+    server::ServerToClientPacket,
+    Result, Shutdown,
+};
+use serde::Deserialize;
+use std::{net::SocketAddr, time::Duration};
+use tokio::sync::{broadcast, mpsc};
+
+#[cfg(feature = "redis")]
+use crate::{
+    message_parse::{source_nick, Side},
+    server::{ClientInfo, Route},
+    IrcConnection,
+};
+#[cfg(feature = "redis")]
+use futures_util::StreamExt;
+#[cfg(feature = "redis")]
+use redis::{aio::MultiplexedConnection, AsyncCommands};
+#[cfg(feature = "redis")]
+use serde::Serialize;
+#[cfg(feature = "redis")]
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+#[cfg(feature = "redis")]
+use tokio::io::AsyncReadExt;
+
+/// How long to wait for any node to answer a query sent to a node mask
+pub const ANSWER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often to check whether every node understands batches
+#[cfg(feature = "redis")]
+const NEGOTIATE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Who a query went to, see `Cluster::query`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(feature = "redis"), allow(dead_code))]
+pub enum Asked {
+    /// Standalone, or Redis couldn't be reached
+    Nobody,
+    /// The node the nick it named is on
+    Node,
+    /// Every node, for whichever match the mask it named to answer
+    Everyone,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClusterConfig {
+    /// Redis URL, like `redis://127.0.0.1/`
+    pub redis: String,
+    /// Start of every key and pub/sub channel we use
+    #[serde(default = "default_prefix")]
+    #[cfg_attr(not(feature = "redis"), allow(dead_code))]
+    pub prefix: String,
+    /// Most lines to publish at once, 1 sends every line by itself. Only while every node understands batches
+    #[serde(default = "default_batch")]
+    #[cfg_attr(not(feature = "redis"), allow(dead_code))]
+    pub batch: usize,
+}
+
+fn default_prefix() -> String {
+    "rust_irc".to_string()
+}
+
+fn default_batch() -> usize {
+    1
+}
+
+/// What goes over pub/sub: lines as they would be sent to clients, and the node they came from. Where they go is
+/// worked out from each line itself. A single line goes in `line` so nodes that don't know about batches can read it.
+#[cfg(feature = "redis")]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Envelope {
+    node: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    line: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    lines: Vec<String>,
+    /// Set on replies to a remote WHOIS or MOTD, the nick they go straight to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    to: Option<String>,
+}
+
+#[cfg(feature = "redis")]
+impl Envelope {
+    fn new(node: String, mut lines: Vec<String>) -> Self {
+        match lines.len() {
+            1 => Self {
+                node,
+                line: lines.remove(0),
+                lines: Vec::new(),
+                to: None,
+            },
+            _ => Self {
+                node,
+                line: String::new(),
+                lines,
+                to: None,
+            },
+        }
+    }
+
+    /// Every line in it, in order
+    fn lines(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.line.as_str())
+            .filter(|x| !x.is_empty())
+            .chain(self.lines.iter().map(String::as_str))
+    }
+}
+
+/// Groups lines queued for pub/sub channels into one batch per run of lines for the same channel, keeping their
+/// order.
+#[cfg(feature = "redis")]
+fn batches(queued: Vec<(String, String)>) -> Vec<(String, Vec<String>)> {
+    let mut batches: Vec<(String, Vec<String>)> = Vec::new();
+    for (channel, line) in queued {
+        match batches.last_mut() {
+            Some((last, lines)) if *last == channel => lines.push(line),
+            _ => batches.push((channel, vec![line])),
+        }
+    }
+    batches
+}
+
+/// What it takes to answer a remote WHOIS or MOTD the way our own clients would see it
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "redis"), allow(dead_code))]
+pub struct Answers {
+    pub identity: LiveIdentity,
+    pub catalog: Catalog,
+    pub motd: String,
+    pub privacy: PrivacyConfig,
+    /// Where our listener is, our name if we don't have one configured
+    pub server_addr: SocketAddr,
+}
+
+#[cfg(feature = "redis")]
+impl Answers {
+    /// The lines answering `query` from another node, written out the same way they would be for one of our own
+    /// clients. Secret channels are left out of WHOIS, since we can't tell if whoever asked is in them, and so is
+    /// being an oper for opers hiding it. They're a stranger as far as `privacy` goes, for the same reason.
+    async fn lines(
+        &self,
+        query: &Message,
+        users: &Users,
+        channels: &Channels,
+    ) -> Result<Vec<String>> {
+        let (ours, mut theirs) = tokio::io::duplex(1 << 16);
+        // Read as it's written, or anything bigger than the buffer (like a long MOTD) would never finish
+        let read = async move {
+            let mut written = String::new();
+            theirs.read_to_string(&mut written).await?;
+            Result::Ok(written)
+        };
+        let mut connection = IrcConnection::new_virtual(ours, self.server_addr);
+        connection.identity = self.identity.clone();
+        connection.catalog = self.catalog.clone();
+        let source = query.source.clone().unwrap_or_default();
+        let client = ClientInfo {
+            nickname: source_nick(&source).to_string(),
+            ..Default::default()
+        };
+        let write = async move {
+            match &query.command {
+                Command::WHOIS(_, nick) => match users.info(nick) {
+                    Some(mut target) => {
+                        if target.hide_oper {
+                            target.oper = None;
+                        }
+                        let (target, privacy) = self.privacy.shown(&client, target);
+                        let idle = (!privacy.hides_idle()).then(|| target.idle());
+                        let shown: Vec<String> = target
+                            .channels
+                            .iter()
+                            .filter(|x| !channels.is_secret(x))
+                            .map(|x| {
+                                match channels.status(x, &target.uid).and_then(|x| x.prefix()) {
+                                    Some(prefix) => format!("{}{}", prefix, x),
+                                    None => x.clone(),
+                                }
+                            })
+                            .collect();
+                        connection
+                            .write_whois(&client, &target, &shown, None, false, idle)
+                            .await?
+                    }
+                    None => connection.write_no_such_nick(&client, nick).await?,
+                },
+                Command::MOTD(_) => connection.write_motd(&client, &self.motd).await?,
+                _ => {}
+            }
+            // Dropping the connection at the end is what lets the read finish
+            connection.flush().await?;
+            Result::Ok(())
+        };
+        let ((), written) = tokio::try_join!(write, read)?;
+        Ok(written.lines().map(String::from).collect())
+    }
+}
+
+/// Shared handle to the rest of the cluster, cheap to clone into each connection. Does nothing when standalone.
+#[derive(Debug, Clone, Default)]
+pub struct Cluster {
+    #[cfg(feature = "redis")]
+    node: Option<Arc<Node>>,
+}
+
+#[cfg(feature = "redis")]
+struct Node {
+    /// Tells us apart from the other nodes, unique per process
+    id: String,
+    prefix: String,
+    /// Most lines to a publish
+    batch: usize,
+    redis: MultiplexedConnection,
+    /// (channel, line) to publish. Everything goes through one task so it comes out in the order it went in.
+    outbox: mpsc::UnboundedSender<(String, String)>,
+    /// Set once we've left the cluster, like after a SQUIT, from when on we're standalone
+    left: AtomicBool,
+    /// Set while every node has said it understands batches, see `negotiate`
+    batching: AtomicBool,
+}
+
+#[cfg(feature = "redis")]
+impl std::fmt::Debug for Node {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Node")
+            .field("id", &self.id)
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+impl Cluster {
+    /// Connects to Redis and spawns the task that talks to the other nodes until shutdown. Messages from them are
+    /// sent on `local.client_tx` to whoever in `local.channels` they're for, or straight to a user in `local.users`
+    /// if they're private.
+    pub(crate) async fn start(
+        config: &ClusterConfig,
+        local: Local,
+        shutdown: Shutdown,
+        shutdown_complete: mpsc::Sender<()>,
+    ) -> Result<Self> {
+        #[cfg(feature = "redis")]
+        {
+            let client = redis::Client::open(config.redis.as_str())?;
+            let redis = client.get_multiplexed_async_connection().await?;
+            let mut pubsub = client.get_async_pubsub().await?;
+            let (outbox, outbox_rx) = mpsc::unbounded_channel();
+            let node = Arc::new(Node {
+                id: format!(
+                    "{}-{}",
+                    std::process::id(),
+                    chrono::Utc::now().timestamp_millis()
+                ),
+                prefix: config.prefix.clone(),
+                batch: config.batch.max(1),
+                redis,
+                outbox,
+                left: AtomicBool::new(false),
+                batching: AtomicBool::new(false),
+            });
+            pubsub.subscribe(node.broadcast_channel()).await?;
+            pubsub.subscribe(node.direct_channel(&node.id)).await?;
+            let _: i64 = node.redis.clone().sadd(node.batch_key(), &node.id).await?;
+            log::info!("Joined cluster {} as node {}", node.prefix, node.id);
+            let events = local.events.clone();
+            events.publish(Event::LinkChanged {
+                name: node.prefix.clone(),
+                up: true,
+            });
+
+            let task = node.clone();
+            let rx = events.subscribe();
+            tokio::spawn(async move {
+                let ran = task.run(pubsub, outbox_rx, local, rx, shutdown).await;
+                // However it ended, nothing's keeping our claims up to date anymore
+                task.left.store(true, Ordering::Relaxed);
+                if let Err(e) = ran {
+                    log::error!("Lost the cluster: {}", e);
+                    events.publish(Event::LinkChanged {
+                        name: task.prefix.clone(),
+                        up: false,
+                    });
+                }
+                drop(shutdown_complete);
+            });
+            Ok(Self { node: Some(node) })
+        }
+        #[cfg(not(feature = "redis"))]
+        {
+            let _ = (local, shutdown, shutdown_complete);
+            log::error!(
+                "Built without the redis feature, running standalone instead of joining {}",
+                config.redis
+            );
+            Ok(Self::default())
+        }
+    }
+
+    /// Our node, unless we're standalone or have left the cluster.
+    #[cfg(feature = "redis")]
+    fn node(&self) -> Option<&Node> {
+        self.node
+            .as_deref()
+            .filter(|x| !x.left.load(Ordering::Relaxed))
+    }
+
+    /// Our node id, if we're part of a cluster.
+    pub fn id(&self) -> Option<&str> {
+        #[cfg(feature = "redis")]
+        if let Some(node) = self.node() {
+            return Some(&node.id);
+        }
+        None
+    }
+
+    /// Asks another node to answer a WHOIS or MOTD for whoever is its source. `server` is a nick to ask the node
+    /// they're on, or failing that a mask of node ids for every node matching it to answer.
+    pub async fn query(&self, server: &str, message: &Message) -> Asked {
+        #[cfg(feature = "redis")]
+        if let Some(node) = self.node() {
+            let mut redis = node.redis.clone();
+            let found: redis::RedisResult<Option<String>> = redis
+                .hget(node.users_key(), server.to_ascii_lowercase())
+                .await;
+            return match found {
+                Ok(Some(id)) => {
+                    node.send(node.direct_channel(&id), message);
+                    Asked::Node
+                }
+                Ok(None) => {
+                    node.send(node.broadcast_channel(), message);
+                    Asked::Everyone
+                }
+                Err(e) => {
+                    log::error!("Couldn't look {} up in the cluster: {}", server, e);
+                    Asked::Nobody
+                }
+            };
+        }
+        let _ = (server, message);
+        Asked::Nobody
+    }
+
+    /// Claims `nick` for this node, unless another node already has it. Returns `false` if one does. If Redis
+    /// can't be reached it's let through, so the network keeps working without it.
+    pub async fn claim(&self, nick: &str) -> bool {
+        #[cfg(feature = "redis")]
+        if let Some(node) = self.node() {
+            let nick = nick.to_ascii_lowercase();
+            let mut redis = node.redis.clone();
+            let claimed: redis::RedisResult<bool> =
+                redis.hset_nx(node.users_key(), &nick, &node.id).await;
+            let holder: redis::RedisResult<Option<String>> = match claimed {
+                Ok(true) => return true,
+                Ok(false) => redis.hget(node.users_key(), &nick).await,
+                Err(e) => Err(e),
+            };
+            return match holder {
+                // Gone since, or another of our own connections has it
+                Ok(holder) => holder.is_none_or(|x| x == node.id),
+                Err(e) => {
+                    log::error!("Couldn't claim {} in the cluster: {}", nick, e);
+                    true
+                }
+            };
+        }
+        let _ = nick;
+        true
+    }
+
+    /// Returns `true` if another node has `nick`. If Redis can't be reached it's assumed one might.
+    pub async fn has(&self, nick: &str) -> bool {
+        #[cfg(feature = "redis")]
+        if let Some(node) = self.node() {
+            let mut redis = node.redis.clone();
+            let found: redis::RedisResult<Option<String>> = redis
+                .hget(node.users_key(), nick.to_ascii_lowercase())
+                .await;
+            return match found {
+                Ok(holder) => holder.is_some_and(|x| x != node.id),
+                Err(e) => {
+                    log::error!("Couldn't look {} up in the cluster: {}", nick, e);
+                    true
+                }
+            };
+        }
+        let _ = nick;
+        false
+    }
+
+    /// Tells the node `id` to leave the cluster, for SQUIT. Returns `false` if there's no node by that id to tell.
+    pub async fn squit(&self, id: &str, message: &Message) -> bool {
+        #[cfg(feature = "redis")]
+        if let Some(node) = self.node() {
+            if id == node.id {
+                return false;
+            }
+            // Straight out rather than through the outbox, to find out if anyone heard it
+            let envelope = Envelope::new(node.id.clone(), vec![message.to_string()]);
+            let payload = serde_json::to_string(&envelope).expect("envelopes always serialize");
+            let mut redis = node.redis.clone();
+            let heard: redis::RedisResult<i64> =
+                redis.publish(node.direct_channel(id), payload).await;
+            return match heard {
+                Ok(heard) => heard > 0,
+                Err(e) => {
+                    log::error!("Couldn't send SQUIT to {}: {}", id, e);
+                    false
+                }
+            };
+        }
+        let _ = (id, message);
+        false
+    }
+
+    /// Passes something that was just sent to our own clients on to every other node.
+    pub fn publish(&self, message: &Message) {
+        #[cfg(feature = "redis")]
+        if let Some(node) = self.node() {
+            node.send(node.broadcast_channel(), message);
+        }
+        #[cfg(not(feature = "redis"))]
+        let _ = message;
+    }
+
+    /// Passes an ENCAP on to every other node, and publishes it for ourselves if it's addressed to us too. Does
+    /// nothing with anything but an ENCAP.
+    pub fn encap(&self, message: &Message, events: &EventBus) {
+        let Command::ENCAP(target, _, _) = &message.command else {
+            return;
+        };
+        #[cfg(feature = "redis")]
+        if let Some(node) = self.node() {
+            node.send(node.broadcast_channel(), message);
+            encap_for(message, &node.id, events);
+            return;
+        }
+        // No node id standalone, so it's only for us if it's for anyone
+        if glob_match(target, "") {
+            encap_for(message, "", events);
+        }
+    }
+
+    /// Sends a private message to `nick` on whichever node they're on. Returns `false` if nobody has the nick.
+    pub async fn send_direct(&self, nick: &str, message: &Message) -> bool {
+        #[cfg(feature = "redis")]
+        if let Some(node) = self.node() {
+            let mut redis = node.redis.clone();
+            let found: redis::RedisResult<Option<String>> = redis
+                .hget(node.users_key(), nick.to_ascii_lowercase())
+                .await;
+            return match found {
+                Ok(Some(id)) => {
+                    node.send(node.direct_channel(&id), message);
+                    true
+                }
+                Ok(None) => false,
+                Err(e) => {
+                    log::error!("Couldn't look {} up in the cluster: {}", nick, e);
+                    false
+                }
+            };
+        }
+        let _ = (nick, message);
+        false
+    }
+}
+
+#[cfg(feature = "redis")]
+impl Node {
+    fn broadcast_channel(&self) -> String {
+        format!("{}:broadcast", self.prefix)
+    }
+
+    fn direct_channel(&self, id: &str) -> String {
+        format!("{}:node:{}", self.prefix, id)
+    }
+
+    /// Hash of lowercased nick to the node they're on
+    fn users_key(&self) -> String {
+        format!("{}:users", self.prefix)
+    }
+
+    /// Set of the ids of nodes that understand batches
+    fn batch_key(&self) -> String {
+        format!("{}:batching", self.prefix)
+    }
+
+    fn send(&self, channel: String, message: &Message) {
+        // Only closed once we've left the cluster, at which point nobody else is listening anyway
+        let _ = self.outbox.send((channel, message.to_string()));
+    }
+
+    async fn run(
+        &self,
+        pubsub: redis::aio::PubSub,
+        mut outbox: mpsc::UnboundedReceiver<(String, String)>,
+        local: Local,
+        mut events: broadcast::Receiver<Event>,
+        mut shutdown: Shutdown,
+    ) -> Result<()> {
+        let mut redis = self.redis.clone();
+        let mut messages = pubsub.into_on_message();
+        // Our users' nicks, so they can be let go when they go
+        let mut presence: HashSet<String> = HashSet::new();
+        let direct = self.direct_channel(&self.id);
+        // Set if an oper elsewhere SQUIT us
+        let mut split = None;
+        let mut negotiate = tokio::time::interval(NEGOTIATE_INTERVAL);
+
+        'cluster: loop {
+            tokio::select! {
+                Some(first) = outbox.recv() => {
+                    // Whatever else has piled up goes along with it
+                    let mut queued = vec![first];
+                    let most = match self.batching.load(Ordering::Relaxed) {
+                        true => self.batch,
+                        false => 1,
+                    };
+                    while queued.len() < most {
+                        match outbox.try_recv() {
+                            Ok(next) => queued.push(next),
+                            Err(_) => break,
+                        }
+                    }
+                    for (channel, lines) in batches(queued) {
+                        let envelope = Envelope::new(self.id.clone(), lines);
+                        let payload = serde_json::to_string(&envelope).expect("envelopes always serialize");
+                        let _: () = redis.publish(channel, payload).await?;
+                    }
+                }
+                message = messages.next() => {
+                    let message = message.ok_or("Redis closed the subscription")?;
+                    let payload: String = message.get_payload()?;
+                    match serde_json::from_str::<Envelope>(&payload) {
+                        Ok(envelope) if envelope.node != self.id => {
+                            let to_us = message.get_channel_name() == direct;
+                            for line in envelope.lines() {
+                                if let Some(to) = &envelope.to {
+                                    answered(&envelope.node, to, line, &local.users);
+                                } else if let Some(query) = deliver(&envelope.node, line, to_us, &self.id, &local) {
+                                    if let Command::SQUIT(_, reason) = &query.command {
+                                        split = Some(format!(
+                                            "{} split us off ({})",
+                                            query.source.as_deref().unwrap_or(&envelope.node),
+                                            reason.as_deref().unwrap_or("no reason")
+                                        ));
+                                        break 'cluster;
+                                    }
+                                    tokio::spawn(answer(
+                                        self.redis.clone(),
+                                        self.id.clone(),
+                                        self.direct_channel(&envelope.node),
+                                        query,
+                                        local.clone(),
+                                    ));
+                                }
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(e) => log::error!("Bad message from the cluster: {}", e),
+                    }
+                }
+                event = events.recv() => match event {
+                    Ok(event) => self.track(&mut redis, &mut presence, &local.users, event).await?,
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = negotiate.tick(), if self.batch > 1 => {
+                    let batching = self.negotiate(&mut redis).await?;
+                    self.batching.store(batching, Ordering::Relaxed);
+                }
+                _ = shutdown.recv() => break,
+            }
+        }
+
+        // Standalone from here on, so no more claims go in behind the ones being let go
+        self.left.store(true, Ordering::Relaxed);
+        // Leave nothing behind for the other nodes to route to
+        let _: i64 = redis.srem(self.batch_key(), &self.id).await?;
+        for nick in presence {
+            self.forget(&mut redis, &nick).await?;
+        }
+        match split {
+            Some(split) => Err(split.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns `true` if every node subscribed to the cluster is in `batch_key`, going by the ones still subscribed to
+    /// their own channel. Anyone in it that isn't anymore is gone, and taken out.
+    async fn negotiate(&self, redis: &mut MultiplexedConnection) -> Result<bool> {
+        let batching: Vec<String> = redis.smembers(self.batch_key()).await?;
+        let mut numsub = redis::cmd("PUBSUB");
+        numsub.arg("NUMSUB").arg(self.broadcast_channel());
+        for id in &batching {
+            numsub.arg(self.direct_channel(id));
+        }
+        let subscribers: Vec<(String, usize)> = numsub.query_async(redis).await?;
+        let Some(((_, nodes), theirs)) = subscribers.split_first() else {
+            return Ok(false);
+        };
+        let mut understood = 0;
+        for (id, (_, subscribers)) in batching.iter().zip(theirs) {
+            match subscribers {
+                0 => {
+                    let _: i64 = redis.srem(self.batch_key(), id).await?;
+                }
+                _ => understood += 1,
+            }
+        }
+        Ok(understood >= *nodes)
+    }
+
+    /// Keeps presence in Redis up to date with what happens to our users. Nicks they picked were claimed before
+    /// they got them, this only catches the ones we handed out ourselves, like guest nicks.
+    async fn track(
+        &self,
+        redis: &mut MultiplexedConnection,
+        presence: &mut HashSet<String>,
+        users: &Users,
+        event: Event,
+    ) -> Result<()> {
+        match event {
+            Event::UserRegistered { nick, .. } => {
+                let nick = nick.to_ascii_lowercase();
+                let _: bool = redis.hset_nx(self.users_key(), &nick, &self.id).await?;
+                presence.insert(nick);
+            }
+            Event::NickChanged { old, new } => {
+                let (old, new) = (old.to_ascii_lowercase(), new.to_ascii_lowercase());
+                if presence.remove(&old) {
+                    self.forget(redis, &old).await?;
+                }
+                let _: bool = redis.hset_nx(self.users_key(), &new, &self.id).await?;
+                presence.insert(new);
+            }
+            // Only gone once their last connection is
+            Event::UserQuit { nick, .. } if !users.contains(&nick) => {
+                let nick = nick.to_ascii_lowercase();
+                if presence.remove(&nick) {
+                    self.forget(redis, &nick).await?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Lets go of `nick`, if it's still ours and not another node's.
+    async fn forget(&self, redis: &mut MultiplexedConnection, nick: &str) -> Result<()> {
+        let _: i64 = redis::cmd("EVAL")
+            .arg(RELEASE)
+            .arg(1)
+            .arg(self.users_key())
+            .arg(nick)
+            .arg(&self.id)
+            .query_async(redis)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Deletes the field `ARGV[1]` of the hash `KEYS[1]`, but only if it's still `ARGV[2]`
+#[cfg(feature = "redis")]
+const RELEASE: &str = "if redis.call('HGET', KEYS[1], ARGV[1]) == ARGV[2] then \
+                       return redis.call('HDEL', KEYS[1], ARGV[1]) end return 0";
+
+/// Where things from other nodes go on this one, and what answers their questions
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "redis"), allow(dead_code))]
+pub struct Local {
+    pub users: Users,
+    pub channels: Channels,
+    pub client_tx: broadcast::Sender<ServerToClientPacket>,
+    pub events: EventBus,
+    pub answers: Answers,
+}
+
+/// Publishes `message` as an `Event::Encap` if it's an ENCAP addressed to the node `id`.
+fn encap_for(message: &Message, id: &str, events: &EventBus) {
+    if let Command::ENCAP(target, subcommand, params) = &message.command {
+        if glob_match(target, id) {
+            events.publish(Event::Encap {
+                source: message.source.clone().unwrap_or_default(),
+                subcommand: subcommand.clone(),
+                params: params.clone(),
+            });
+        }
+    }
+}
+
+/// Answers a WHOIS or MOTD from the node `from`, publishing the replies to `reply_channel`.
+#[cfg(feature = "redis")]
+async fn answer(
+    mut redis: MultiplexedConnection,
+    id: String,
+    reply_channel: String,
+    query: Message,
+    local: Local,
+) {
+    let lines = match local
+        .answers
+        .lines(&query, &local.users, &local.channels)
+        .await
+    {
+        Ok(lines) => lines,
+        Err(e) => {
+            log::error!("Couldn't answer {}: {}", query.command.name(), e);
+            return;
+        }
+    };
+    let source = query.source.unwrap_or_default();
+    let envelope = Envelope {
+        to: Some(source_nick(&source).to_string()),
+        ..Envelope::new(id, lines)
+    };
+    let payload = serde_json::to_string(&envelope).expect("envelopes always serialize");
+    let published: redis::RedisResult<()> = redis.publish(reply_channel, payload).await;
+    if let Err(e) = published {
+        log::error!("Couldn't answer {}: {}", query.command.name(), e);
+    }
+}
+
+/// Passes a line answering a remote query on to `to`, who asked it.
+#[cfg(feature = "redis")]
+fn answered(from: &str, to: &str, line: &str, users: &Users) {
+    match line.parse::<Message>() {
+        Ok(mut message) => {
+            message.side = Side::Server;
+            users.send(to, message);
+        }
+        Err(e) => log::error!("Bad line from node {}: {}", from, e),
+    }
+}
+
+/// Hands a line the node `from` published to our clients, or to whatever's listening for it if it's an ENCAP.
+/// `direct` is set if it was sent to just this node, `id`. Returns a WHOIS or MOTD for us to answer, or a SQUIT
+/// telling us to leave.
+#[cfg(feature = "redis")]
+fn deliver(from: &str, line: &str, direct: bool, id: &str, local: &Local) -> Option<Message> {
+    let Local {
+        users,
+        channels,
+        client_tx,
+        events,
+        ..
+    } = local;
+    let mut message: Message = match line.parse() {
+        Ok(message) => message,
+        Err(e) => {
+            log::error!("Bad line from node {}: {}", from, e);
+            return None;
+        }
+    };
+    message.side = Side::Server;
+    if matches!(message.command, Command::ENCAP(..)) {
+        encap_for(&message, id, events);
+        return None;
+    }
+    // Nobody here sent it, so no connection is left out
+    let route = match message.command.clone() {
+        Command::WHOIS(Some(server), _) | Command::MOTD(Some(server)) => {
+            return (direct || glob_match(&server, id)).then_some(message);
+        }
+        Command::SQUIT(..) => return direct.then_some(message),
+        Command::PRIVMSG(targets, _) | Command::TAGMSG(targets) if direct => {
+            for target in targets {
+                users.send(&target, message.clone());
+            }
+            return None;
+        }
+        Command::PRIVMSG(targets, _) => Route::new(0, channels.resolve(&targets), message),
+        Command::TAGMSG(targets) => Route::new(0, channels.resolve(&targets), message)
+            .needing(crate::capability::MESSAGE_TAGS),
+        Command::JOIN(targets, _) | Command::PART(targets, _) => {
+            Route::new(0, channels.resolve(&targets), message)
+        }
+        Command::KILL(nick, _) => {
+            Route::new(0, users.uid(&nick).into_iter().collect(), message).applied()
+        }
+        _ => return None,
+    };
+    // Nobody hearing it is fine, same as for our own clients
+    let _ = client_tx.send(ServerToClientPacket::Route(route));
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn config() {
+        let config: ClusterConfig = toml::from_str("redis = \"redis://127.0.0.1/\"").unwrap();
+        assert_eq!(config.prefix, "rust_irc");
+        assert_eq!(config.batch, 1);
+    }
+
+    #[cfg(feature = "redis")]
+    #[test]
+    fn envelopes() {
+        let hi = ":tiger!tiger@localhost PRIVMSG #chan :hi".to_string();
+        let envelope = Envelope::new("1234-5678".to_string(), vec![hi.clone()]);
+        let json = serde_json::to_string(&envelope).unwrap();
+        // Readable by nodes from before batches
+        assert_eq!(json, format!(r#"{{"node":"1234-5678","line":"{}"}}"#, hi));
+        assert_eq!(serde_json::from_str::<Envelope>(&json).unwrap(), envelope);
+
+        let bye = ":tiger!tiger@localhost PART #chan".to_string();
+        let envelope = Envelope::new("1234-5678".to_string(), vec![hi.clone(), bye.clone()]);
+        let json = serde_json::to_string(&envelope).unwrap();
+        let envelope = serde_json::from_str::<Envelope>(&json).unwrap();
+        assert_eq!(envelope.lines().collect::<Vec<_>>(), [&hi, &bye]);
+    }
+
+    #[cfg(feature = "redis")]
+    #[test]
+    fn batching() {
+        let queued = [("a", "1"), ("a", "2"), ("b", "3"), ("a", "4")]
+            .map(|(x, y)| (x.to_string(), y.to_string()))
+            .to_vec();
+        assert_eq!(
+            batches(queued),
+            [
+                ("a".to_string(), vec!["1".to_string(), "2".to_string()]),
+                ("b".to_string(), vec!["3".to_string()]),
+                ("a".to_string(), vec!["4".to_string()]),
+            ]
+        );
+    }
+
+    #[cfg(feature = "redis")]
+    fn local() -> Local {
+        Local {
+            users: Users::default(),
+            channels: Channels::default(),
+            client_tx: broadcast::channel(4).0,
+            events: EventBus::default(),
+            answers: Answers {
+                identity: LiveIdentity::default(),
+                catalog: Catalog::default(),
+                motd: "Meow".to_string(),
+                privacy: PrivacyConfig::default(),
+                server_addr: SocketAddr::from(([127, 0, 0, 1], 6667)),
+            },
+        }
+    }
+
+    /// Just enough of a Redis server to test against, answering each command with whatever `answer` says (in RESP).
+    /// Returns the URL to connect to, and every command it's been sent.
+    #[cfg(feature = "redis")]
+    async fn fake_redis(
+        answer: impl Fn(&[String]) -> String + Send + Sync + 'static,
+    ) -> (String, Arc<std::sync::Mutex<Vec<Vec<String>>>>) {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}/", listener.local_addr().unwrap());
+        let sent = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (answer, log) = (Arc::new(answer), sent.clone());
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let (answer, log) = (answer.clone(), log.clone());
+                tokio::spawn(async move {
+                    let (read, mut write) = socket.into_split();
+                    let mut lines = BufReader::new(read).lines();
+                    // Arrays of bulk strings, *<count> then $<length> and the string for each
+                    while let Ok(Some(count)) = lines.next_line().await {
+                        let count: usize = count[1..].parse().unwrap();
+                        let mut command = Vec::new();
+                        for _ in 0..count {
+                            lines.next_line().await.unwrap();
+                            command.push(lines.next_line().await.unwrap().unwrap());
+                        }
+                        let reply = match command[0].as_str() {
+                            "CLIENT" => "+OK\r\n".to_string(),
+                            _ => answer(&command),
+                        };
+                        log.lock().unwrap().push(command);
+                        write.write_all(reply.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        (url, sent)
+    }
+
+    #[cfg(feature = "redis")]
+    async fn node(url: &str) -> Arc<Node> {
+        let client = redis::Client::open(url).unwrap();
+        Arc::new(Node {
+            id: "1234-5678".to_string(),
+            prefix: "rust_irc".to_string(),
+            batch: 1,
+            redis: client.get_multiplexed_async_connection().await.unwrap(),
+            outbox: mpsc::unbounded_channel().0,
+            left: AtomicBool::new(false),
+            batching: AtomicBool::new(false),
+        })
+    }
+
+    /// RESP for an array of `items`, which are bulk strings unless they're numbers
+    #[cfg(feature = "redis")]
+    fn array(items: &[&str]) -> String {
+        let mut resp = format!("*{}\r\n", items.len());
+        for x in items {
+            match x.parse::<i64>() {
+                Ok(x) => resp.push_str(&format!(":{}\r\n", x)),
+                Err(_) => resp.push_str(&format!("${}\r\n{}\r\n", x.len(), x)),
+            }
+        }
+        resp
+    }
+
+    #[cfg(feature = "redis")]
+    #[tokio::test]
+    async fn negotiating_batches() {
+        // Two nodes that have said they understand batches, one of them long gone, and two nodes around
+        let (url, sent) = fake_redis(|command| match command[0].as_str() {
+            "SMEMBERS" => array(&["1234-5678", "4321-1234"]),
+            "PUBSUB" => array(&[
+                "rust_irc:broadcast",
+                "2",
+                "rust_irc:node:1234-5678",
+                "1",
+                "rust_irc:node:4321-1234",
+                "0",
+            ]),
+            _ => ":1\r\n".to_string(),
+        })
+        .await;
+        let ours = node(&url).await;
+        let mut redis = ours.redis.clone();
+        // So the other one around doesn't
+        assert!(!ours.negotiate(&mut redis).await.unwrap());
+        assert!(sent.lock().unwrap().contains(&vec![
+            "SREM".to_string(),
+            "rust_irc:batching".to_string(),
+            "4321-1234".to_string()
+        ]));
+
+        let (url, _) = fake_redis(|command| match command[0].as_str() {
+            "SMEMBERS" => array(&["1234-5678", "8765-4321"]),
+            "PUBSUB" => array(&[
+                "rust_irc:broadcast",
+                "2",
+                "rust_irc:node:1234-5678",
+                "1",
+                "rust_irc:node:8765-4321",
+                "1",
+            ]),
+            _ => ":1\r\n".to_string(),
+        })
+        .await;
+        let ours = node(&url).await;
+        let mut redis = ours.redis.clone();
+        assert!(ours.negotiate(&mut redis).await.unwrap());
+    }
+
+    #[cfg(feature = "redis")]
+    #[tokio::test]
+    async fn split_nodes_are_standalone() {
+        // Every nick is free, and there's always someone to hear it
+        let (url, sent) = fake_redis(|command| match command[0].as_str() {
+            "HGET" => "$-1\r\n".to_string(),
+            _ => ":1\r\n".to_string(),
+        })
+        .await;
+        let node = node(&url).await;
+        let cluster = Cluster {
+            node: Some(node.clone()),
+        };
+        let whois = Message {
+            tags: None,
+            source: Some("tiger!meow@localhost".to_string()),
+            command: Command::WHOIS(Some("lion".to_string()), "lion".to_string()),
+            side: Side::Server,
+        };
+        assert!(cluster.claim("tiger").await);
+        assert_eq!(cluster.id(), Some("1234-5678"));
+        assert_eq!(cluster.query("lion", &whois).await, Asked::Everyone);
+
+        // As the run loop does when it's SQUIT
+        node.left.store(true, Ordering::Relaxed);
+        let before = sent.lock().unwrap().len();
+        assert!(cluster.claim("lion").await);
+        assert!(!cluster.has("lion").await);
+        assert_eq!(cluster.id(), None);
+        assert_eq!(cluster.query("lion", &whois).await, Asked::Nobody);
+        assert!(!cluster.send_direct("lion", &whois).await);
+        assert!(!cluster.squit("8765-4321", &whois).await);
+        // Without a word to Redis
+        assert_eq!(sent.lock().unwrap().len(), before);
+    }
+
+    #[cfg(feature = "redis")]
+    #[tokio::test]
+    async fn queries() {
+        let local = local();
+        let motd = ":tiger!meow@localhost MOTD 8765-*";
+        assert!(deliver("1234-5678", motd, false, "4321-1234", &local).is_none());
+        let query = deliver("1234-5678", motd, false, "8765-4321", &local).unwrap();
+        let lines = local
+            .answers
+            .lines(&query, &local.users, &local.channels)
+            .await
+            .unwrap();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].ends_with(" 372 tiger :- Meow"), "{}", lines[1]);
+        // Sent straight to us because the nick is here, so answered whatever the mask
+        let whois = ":tiger!meow@localhost WHOIS cat cat";
+        let query = deliver("1234-5678", whois, true, "8765-4321", &local).unwrap();
+        let lines = local
+            .answers
+            .lines(&query, &local.users, &local.channels)
+            .await
+            .unwrap();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains(" 401 tiger cat "), "{}", lines[0]);
+
+        // More than the buffer holds
+        let mut big = local.answers.clone();
+        big.motd = "Meow meow meow meow\n".repeat(5000);
+        let query = deliver("1234-5678", motd, false, "8765-4321", &local).unwrap();
+        let lines = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            big.lines(&query, &local.users, &local.channels),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(lines.len(), 5002);
+    }
+
+    #[cfg(feature = "redis")]
+    #[test]
+    fn encap() {
+        let local = local();
+        let mut events = local.events.subscribe();
+        let line = ":tiger!tiger@localhost ENCAP 8765-* SVSLOGIN tiger :Tiger Cat";
+        deliver("1234-5678", line, false, "4321-1234", &local);
+        assert!(events.try_recv().is_err());
+        deliver("1234-5678", line, false, "8765-4321", &local);
+        match events.try_recv().unwrap() {
+            Event::Encap {
+                source,
+                subcommand,
+                params,
+            } => {
+                assert_eq!(source, "tiger!tiger@localhost");
+                assert_eq!(subcommand, "SVSLOGIN");
+                assert_eq!(params, ["tiger", "Tiger Cat"]);
+            }
+            event => panic!("Expected an ENCAP, got {:?}", event),
+        }
+    }
+}
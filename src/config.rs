@@ -0,0 +1,797 @@
+use crate::{
+    auth::AuthConfig,
+    catalog::{self, LanguageConfig},
+    chanlog::ChannelLogConfig,
+    channel,
+    cluster::ClusterConfig,
+    defcon::DefconConfig,
+    fakelag::FakelagConfig,
+    filter::FilterConfig,
+    geoip::GeoIpConfig,
+    history::HistoryConfig,
+    lockout::LockoutConfig,
+    log::LogConfig,
+    message_parse::Command,
+    nick::NickConfig,
+    pace::BigRepliesConfig,
+    password,
+    privacy::PrivacyConfig,
+    tls::{self, TlsConfig},
+    webhook::AccountWebhookConfig,
+    Result,
+};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+/// Server configuration, loaded from a TOML file passed as the first argument.
+/// Everything has a default so an empty (or missing) file gives you a working server.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Where this was loaded from, if anywhere
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+    /// Address the listener binds to
+    pub listen: String,
+    /// More addresses to take plaintext clients on, which unlike `listen` can change on REHASH, see `listeners`
+    pub listeners: Vec<String>,
+    /// Name the server goes by, the address clients connected to if this isn't set. Reread on REHASH, see `identity`
+    pub server_name: Option<String>,
+    /// Name of the network this server is part of, given to clients as NETWORK in ISUPPORT and in ADMIN
+    pub network: String,
+    /// One line about the server, shown in WHOIS, LINKS and ADMIN
+    pub description: String,
+    /// TLS listener, off unless this is set
+    pub tls: Option<TlsConfig>,
+    /// Server ID, the start of every UID handed out here. A digit followed by two digits or capital letters
+    pub sid: String,
+    /// Seconds a connection gets to finish registering before it's dropped
+    pub registration_timeout: u64,
+    /// Seconds between PINGs to each registered client, whose PONGs are how TRACE knows their lag
+    pub ping_interval: u64,
+    /// Most clients connected at once, anyone past that gets an ERROR and is dropped. No limit unless this is set
+    pub max_connections: Option<usize>,
+    /// Seconds a user can go without sending a message before they're marked away, off unless this is set
+    pub auto_away: Option<u64>,
+    /// Seconds away-notify waits between telling anyone about the same user, see `away`
+    pub away_notify_interval: u64,
+    /// Message of the day, can be several lines
+    pub motd: String,
+    /// Only tell clients how to get the MOTD when they connect, rather than sending all of it
+    pub short_motd: bool,
+    /// File RULES shows, read every time someone asks so it can be edited without a REHASH
+    pub rules: Option<PathBuf>,
+    /// Channels every client is joined to as soon as it registers
+    pub auto_join: Vec<String>,
+    /// What channel names can start with, CHANTYPES. Some of `#` and `&`
+    pub chantypes: String,
+    /// Who can JOIN a channel that doesn't exist yet, making it
+    pub channel_creation: ChannelCreation,
+    /// How many old topics each channel keeps for TOPICHISTORY, 0 for none
+    pub topic_history: usize,
+    /// Extra command aliases, name to the nick it messages, see `alias`
+    pub aliases: HashMap<String, String>,
+    /// Unicode nick handling, see `nick`
+    pub nicks: NickConfig,
+    /// Keeps authenticated users online while they have no connections attached, soju-style. Each device (the part
+    /// after an `@` in USER, like `tiger@phone`) gets played back what it missed from `history` when it reattaches
+    pub bouncer: bool,
+    /// Only lets in clients that logged in with SASL before registering, for private servers. SASL here is EXTERNAL,
+    /// so this needs `tls`
+    pub require_sasl: bool,
+    /// Accounts that clients can log into with PASS
+    #[serde(rename = "account")]
+    pub accounts: Vec<AccountConfig>,
+    /// Where PASS logins are checked, see `auth`
+    pub auth: AuthConfig,
+    /// Redis to share this server with other processes through, see `cluster`
+    pub cluster: Option<ClusterConfig>,
+    /// Inbound HTTP API for posting messages, off unless this is set
+    pub http: Option<HttpConfig>,
+    /// External processes to run as bridges
+    #[serde(rename = "bridge")]
+    pub bridges: Vec<BridgeConfig>,
+    /// Built in reply bots
+    #[serde(rename = "bot")]
+    pub bots: Vec<BotConfig>,
+    /// Where account changes and repeated login failures are POSTed, see `webhook`
+    pub account_webhook: Option<AccountWebhookConfig>,
+    /// Append-only log of everything opers do, see `audit`
+    pub audit_log: Option<PathBuf>,
+    /// Per-channel daily logs on disk, see `chanlog`
+    pub channel_log: Option<ChannelLogConfig>,
+    /// Where users are connecting from, for opers, see `geoip`
+    pub geoip: GeoIpConfig,
+    /// Translated or rebranded numeric texts, see `catalog`
+    pub language: LanguageConfig,
+    /// Operator blocks, for OPER
+    #[serde(rename = "oper")]
+    pub opers: Vec<OperConfig>,
+    /// Oper classes, each granting a set of privileges to the opers in it
+    #[serde(rename = "class")]
+    pub classes: Vec<OperClassConfig>,
+    /// Lua scripts to load, see `script`
+    pub scripts: Vec<PathBuf>,
+    /// WASM plugins to load, see `plugin`
+    pub plugins: Vec<PathBuf>,
+    /// Where K-lines and D-lines are saved, they're forgotten on restart if this isn't set
+    pub bans: Option<PathBuf>,
+    /// Spam filters, see `filter`
+    #[serde(rename = "filter")]
+    pub filters: Vec<FilterConfig>,
+    pub limits: Limits,
+    /// Flood protection, see `fakelag`
+    pub fakelag: FakelagConfig,
+    /// How many wrong OPER passwords it takes to get locked out, see `lockout`
+    pub oper_lockout: LockoutConfig,
+    /// Same for passwords given to NickServ's GHOST and REGAIN, see `nickserv`
+    pub nickserv_lockout: LockoutConfig,
+    /// Emergency protection levels, see `defcon`
+    pub defcon: DefconConfig,
+    /// Keeping messages for users to export, see `history`
+    pub history: HistoryConfig,
+    /// Pacing and caps for LIST and WHO, see `pace`
+    pub big_replies: BigRepliesConfig,
+    /// What WHO and WHOIS show people who don't share a channel, see `privacy`
+    pub privacy: PrivacyConfig,
+    /// Log format and level, see `log`
+    pub log: LogConfig,
+    /// How the tokio runtime is set up, `--worker-threads` and `--current-thread` on the command line win over this
+    pub runtime: RuntimeConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            path: None,
+            listen: "0.0.0.0:6667".to_string(),
+            listeners: Vec::new(),
+            server_name: None,
+            network: "rust_irc".to_string(),
+            description: "rust_irc".to_string(),
+            tls: None,
+            sid: "001".to_string(),
+            registration_timeout: 60,
+            ping_interval: 120,
+            max_connections: None,
+            auto_away: None,
+            away_notify_interval: 5,
+            motd: "Hi from Rust-IRC!".to_string(),
+            short_motd: false,
+            rules: None,
+            auto_join: Vec::new(),
+            chantypes: "#&".to_string(),
+            channel_creation: ChannelCreation::default(),
+            topic_history: 10,
+            aliases: HashMap::new(),
+            nicks: NickConfig::default(),
+            bouncer: false,
+            require_sasl: false,
+            accounts: Vec::new(),
+            auth: AuthConfig::default(),
+            cluster: None,
+            http: None,
+            bridges: Vec::new(),
+            bots: Vec::new(),
+            account_webhook: None,
+            audit_log: None,
+            channel_log: None,
+            geoip: GeoIpConfig::default(),
+            language: LanguageConfig::default(),
+            opers: Vec::new(),
+            classes: Vec::new(),
+            scripts: Vec::new(),
+            plugins: Vec::new(),
+            bans: None,
+            filters: Vec::new(),
+            limits: Limits::default(),
+            fakelag: FakelagConfig::default(),
+            oper_lockout: LockoutConfig::default(),
+            nickserv_lockout: LockoutConfig::default(),
+            defcon: DefconConfig::default(),
+            history: HistoryConfig::default(),
+            big_replies: BigRepliesConfig::default(),
+            privacy: PrivacyConfig::default(),
+            log: LogConfig::default(),
+            runtime: RuntimeConfig::default(),
+        }
+    }
+}
+
+/// Longest things can be, in bytes, advertised in ISUPPORT so clients can check before sending
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    pub topic: usize,
+    /// Away messages
+    pub away: usize,
+    /// Channel names, including the prefix
+    pub channel: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            topic: 390,
+            away: 255,
+            channel: 50,
+        }
+    }
+}
+
+/// Who can make new channels, for servers that want to keep a curated set of them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelCreation {
+    #[default]
+    Anyone,
+    /// Users logged into an account, and opers
+    Identified,
+    Opers,
+}
+
+impl ChannelCreation {
+    pub fn allows(&self, oper: bool, identified: bool) -> bool {
+        match self {
+            ChannelCreation::Anyone => true,
+            ChannelCreation::Identified => oper || identified,
+            ChannelCreation::Opers => oper,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Flavor {
+    /// Connections are spread over a pool of worker threads
+    #[default]
+    MultiThread,
+    /// Everything runs on the main thread, plenty for a small server on a small box
+    CurrentThread,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RuntimeConfig {
+    pub flavor: Flavor,
+    /// Worker threads for `multi_thread`, one per core if not set
+    pub worker_threads: Option<usize>,
+}
+
+impl RuntimeConfig {
+    pub fn build(&self) -> Result<tokio::runtime::Runtime> {
+        let mut builder = match self.flavor {
+            Flavor::MultiThread => tokio::runtime::Builder::new_multi_thread(),
+            Flavor::CurrentThread => tokio::runtime::Builder::new_current_thread(),
+        };
+        if let Some(threads) = self.worker_threads {
+            // tokio panics on 0 rather than erroring
+            if threads == 0 {
+                return Err("worker_threads has to be at least 1".into());
+            }
+            builder.worker_threads(threads);
+        }
+        Ok(builder.enable_all().build()?)
+    }
+}
+
+impl Limits {
+    pub fn isupport(&self) -> Vec<String> {
+        vec![
+            format!("AWAYLEN={}", self.away),
+            format!("CHANNELLEN={}", self.channel),
+            format!("TOPICLEN={}", self.topic),
+        ]
+    }
+}
+
+/// Returns `true` if `sid` is a TS6 server ID: a digit followed by two digits or capital letters.
+fn valid_sid(sid: &str) -> bool {
+    let b = sid.as_bytes();
+    b.len() == 3
+        && b[0].is_ascii_digit()
+        && b[1..]
+            .iter()
+            .all(|x| x.is_ascii_digit() || x.is_ascii_uppercase())
+}
+
+/// Returns `true` if `name` could be a server name: a hostname with at least one dot, so it can't be mistaken for
+/// a nick.
+fn valid_server_name(name: &str) -> bool {
+    name.contains('.')
+        && name.len() <= 63
+        && name
+            .bytes()
+            .all(|x| x.is_ascii_alphanumeric() || x == b'.' || x == b'-')
+}
+
+/// Cuts `s` down to at most `len` bytes, without splitting a character.
+pub fn truncate(s: &str, len: usize) -> &str {
+    if s.len() <= len {
+        return s;
+    }
+    let mut end = len;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AccountConfig {
+    pub name: String,
+    /// argon2id hash, from `rust_irc hash-password`. Can be left out if `certfps` is set
+    #[serde(default)]
+    pub password: String,
+    /// Fingerprints of client certificates that can log in with SASL EXTERNAL
+    #[serde(default)]
+    pub certfps: Vec<String>,
+    /// Highlights are POSTed here as JSON while the account is away or detached
+    pub webhook: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HttpConfig {
+    pub listen: String,
+    /// Bearer token callers have to send
+    pub token: String,
+    /// Nick that messages posted through the API come from
+    #[serde(default = "default_bot")]
+    pub bot: String,
+}
+
+/// A bridge process, see `bridge::ProcessBridge`
+#[derive(Debug, Clone, Deserialize)]
+pub struct BridgeConfig {
+    /// Name of the virtual server the bridge's puppets live on
+    pub name: String,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+/// A `bot::ReplyBot`
+#[derive(Debug, Clone, Deserialize)]
+pub struct BotConfig {
+    pub nick: String,
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// Trigger to reply
+    #[serde(default)]
+    pub replies: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OperConfig {
+    pub name: String,
+    /// argon2id hash, from `rust_irc hash-password`. Can be left out if `certfps` is set
+    #[serde(default)]
+    pub password: String,
+    /// Fingerprints of client certificates that can OPER without the password
+    #[serde(default)]
+    pub certfps: Vec<String>,
+    /// Name of the oper's class, an oper without one can do everything
+    pub class: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OperClassConfig {
+    pub name: String,
+    pub privileges: Vec<Privilege>,
+}
+
+/// Something only some opers are allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Privilege {
+    Kill,
+    Kline,
+    Rehash,
+    Die,
+    Connect,
+    Spy,
+    Filter,
+    Defcon,
+    Chghost,
+}
+
+impl Privilege {
+    pub const ALL: &'static [Privilege] = &[
+        Privilege::Kill,
+        Privilege::Kline,
+        Privilege::Rehash,
+        Privilege::Die,
+        Privilege::Connect,
+        Privilege::Spy,
+        Privilege::Filter,
+        Privilege::Defcon,
+        Privilege::Chghost,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Privilege::Kill => "kill",
+            Privilege::Kline => "kline",
+            Privilege::Rehash => "rehash",
+            Privilege::Die => "die",
+            Privilege::Connect => "connect",
+            Privilege::Spy => "spy",
+            Privilege::Filter => "filter",
+            Privilege::Defcon => "defcon",
+            Privilege::Chghost => "chghost",
+        }
+    }
+}
+
+fn default_bot() -> String {
+    "webhook".to_string()
+}
+
+impl Config {
+    /// Reads and parses the config file at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let text = std::fs::read_to_string(&path)?;
+        let mut config: Config = toml::from_str(&text)?;
+        config.path = Some(path.as_ref().to_path_buf());
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks everything `load` checks, for configs that weren't loaded from a file.
+    pub fn validate(&self) -> Result<()> {
+        if !valid_sid(&self.sid) {
+            return Err(format!("Invalid sid {:?}", self.sid).into());
+        }
+        if let Some(name) = self
+            .server_name
+            .as_deref()
+            .filter(|x| !valid_server_name(x))
+        {
+            return Err(format!(
+                "Invalid server_name {:?}, it has to look like a hostname",
+                name
+            )
+            .into());
+        }
+        if self.network.is_empty()
+            || self
+                .network
+                .contains(|x: char| x.is_whitespace() || x.is_control())
+        {
+            return Err(format!(
+                "Invalid network {:?}, it can't be empty or have spaces",
+                self.network
+            )
+            .into());
+        }
+        if self.chantypes.is_empty() || !self.chantypes.chars().all(|x| "#&".contains(x)) {
+            return Err(
+                format!("chantypes can only be some of #&, not {:?}", self.chantypes).into(),
+            );
+        }
+        if let Some(x) = self
+            .auto_join
+            .iter()
+            .find(|x| channel::check_name(x, &self.chantypes, self.limits.channel).is_err())
+        {
+            return Err(format!("auto_join has {:?}, which isn't a channel", x).into());
+        }
+        self.log.validate()?;
+        self.oper_lockout.validate()?;
+        self.nickserv_lockout.validate()?;
+        // EXTERNAL is the only mechanism, and it takes a client certificate
+        if self.require_sasl && self.tls.is_none() {
+            return Err(
+                "require_sasl needs tls, SASL EXTERNAL is the only way to log in with SASL".into(),
+            );
+        }
+        if let Some(x) = &self.channel_log {
+            x.validate()?;
+        }
+        if self.language.default != catalog::DEFAULT_LANGUAGE
+            && !self.language.catalogs.contains_key(&self.language.default)
+        {
+            return Err(format!(
+                "The default language {} has no catalog",
+                self.language.default
+            )
+            .into());
+        }
+        if let Some(x) = self.aliases.keys().find(|x| {
+            !matches!(
+                format!("{} x", x).parse::<Command>(),
+                Ok(Command::UNKNOWN(_))
+            )
+        }) {
+            return Err(format!("The alias {} would hide the command with that name", x).into());
+        }
+        self.check_passwords()
+    }
+
+    /// Makes sure nobody left a plaintext password in the config.
+    fn check_passwords(&self) -> Result<()> {
+        let mut passwords: Vec<(&str, &String, &String, &[String])> = Vec::new();
+        for x in &self.accounts {
+            passwords.push(("account", &x.name, &x.password, &x.certfps));
+        }
+        for x in &self.opers {
+            passwords.push(("oper", &x.name, &x.password, &x.certfps));
+        }
+        if let AuthConfig::Static { users } = &self.auth {
+            for (name, hash) in users {
+                passwords.push(("user", name, hash, &[]));
+            }
+        }
+        for (kind, name, hash, certfps) in passwords {
+            // Certificate only
+            if hash.is_empty() && !certfps.is_empty() {
+                continue;
+            }
+            if !password::is_hash(hash) {
+                return Err(format!(
+                    "The password for {} {} isn't an argon2id hash, make one with `rust_irc hash-password`",
+                    kind, name
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the privileges of the oper block for `name`, if there is one with this `password` or trusting
+    /// `certfp`. An oper in a class that doesn't exist gets nothing.
+    pub fn check_oper(
+        &self,
+        name: &str,
+        password: &str,
+        certfp: Option<&str>,
+    ) -> Option<HashSet<Privilege>> {
+        let oper = self.opers.iter().find(|x| {
+            x.name == name
+                && (password::verify(&x.password, password)
+                    || certfp.is_some_and(|fp| {
+                        x.certfps
+                            .iter()
+                            .any(|x| tls::normalize_fingerprint(x) == fp)
+                    }))
+        })?;
+        let privileges = match &oper.class {
+            None => Privilege::ALL.iter().copied().collect(),
+            Some(class) => self
+                .classes
+                .iter()
+                .find(|x| &x.name == class)
+                .map(|x| x.privileges.iter().copied().collect())
+                .unwrap_or_default(),
+        };
+        Some(privileges)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_empty_config() {
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config.listen, "0.0.0.0:6667");
+        assert!(!config.bouncer);
+        assert!(config.accounts.is_empty());
+        assert!(config.auto_join.is_empty());
+        assert!(config.http.is_none());
+        assert_eq!(config.channel_creation, ChannelCreation::Anyone);
+    }
+
+    #[test]
+    fn channel_creation() {
+        let config: Config = toml::from_str("channel_creation = \"identified\"").unwrap();
+        assert!(!config.channel_creation.allows(false, false));
+        assert!(config.channel_creation.allows(false, true));
+        assert!(config.channel_creation.allows(true, false));
+        assert!(!ChannelCreation::Opers.allows(false, true));
+    }
+
+    #[test]
+    fn parse_limits() {
+        let config: Config = toml::from_str(
+            r#"
+            [limits]
+            away = 10
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.limits.away, 10);
+        assert_eq!(config.limits.topic, 390);
+        assert!(config.limits.isupport().contains(&"AWAYLEN=10".to_string()));
+    }
+
+    #[test]
+    fn truncation() {
+        assert_eq!(truncate("hello", 10), "hello");
+        assert_eq!(truncate("hello", 3), "hel");
+        // 'é' is two bytes
+        assert_eq!(truncate("héllo", 2), "h");
+    }
+
+    #[test]
+    fn runtimes() {
+        let config: Config =
+            toml::from_str("[runtime]\nflavor = \"current_thread\"\nworker_threads = 2").unwrap();
+        assert_eq!(config.runtime.flavor, Flavor::CurrentThread);
+        assert!(config.runtime.build().is_ok());
+        assert_eq!(Config::default().runtime.flavor, Flavor::MultiThread);
+
+        let zero = RuntimeConfig {
+            worker_threads: Some(0),
+            ..Default::default()
+        };
+        assert!(zero.build().is_err());
+    }
+
+    #[test]
+    fn sids() {
+        assert!(valid_sid(&Config::default().sid));
+        assert!(valid_sid("9ZZ"));
+        assert!(!valid_sid("ABC"));
+        assert!(!valid_sid("0a1"));
+        assert!(!valid_sid("0001"));
+    }
+
+    #[test]
+    fn server_names() {
+        assert!(valid_server_name("irc.example.net"));
+        assert!(!valid_server_name("tiger"));
+        assert!(!valid_server_name("irc example.net"));
+        assert!(!valid_server_name("irc.example.net:6667"));
+    }
+
+    #[test]
+    fn networks() {
+        let config: Config = toml::from_str("network = \"ExampleNet\"").unwrap();
+        assert!(config.validate().is_ok());
+        let config: Config = toml::from_str("network = \"Example Net\"").unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn parse_http() {
+        let config: Config = toml::from_str(
+            r#"
+            [http]
+            listen = "127.0.0.1:8080"
+            token = "sekrit"
+            "#,
+        )
+        .unwrap();
+        let http = config.http.unwrap();
+        assert_eq!(http.listen, "127.0.0.1:8080");
+        assert_eq!(http.token, "sekrit");
+        assert_eq!(http.bot, "webhook");
+    }
+
+    #[test]
+    fn parse_bridges() {
+        let config: Config = toml::from_str(
+            r#"
+            [[bridge]]
+            name = "matrix"
+            command = "/usr/bin/matrix-bridge"
+            args = ["--config", "bridge.yaml"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.bridges.len(), 1);
+        assert_eq!(config.bridges[0].name, "matrix");
+        assert_eq!(config.bridges[0].args, vec!["--config", "bridge.yaml"]);
+    }
+
+    #[test]
+    fn parse_bots() {
+        let config: Config = toml::from_str(
+            r##"
+            [[bot]]
+            nick = "HelpBot"
+            channels = ["#help"]
+            replies = { "!rules" = "Be nice." }
+            "##,
+        )
+        .unwrap();
+        assert_eq!(config.bots.len(), 1);
+        assert_eq!(config.bots[0].nick, "HelpBot");
+        assert_eq!(config.bots[0].channels, vec!["#help"]);
+        assert_eq!(config.bots[0].replies["!rules"], "Be nice.");
+    }
+
+    /// "hunter2"
+    const HUNTER2: &str =
+        "$argon2id$v=19$m=19456,t=2,p=1$mY4dMs3cyPYSyFIiUBbWpA$VaMLUcUCZ6Lh6rlJay6Z4sEedSh5A+Y2z80guA+n3KQ";
+
+    #[test]
+    fn parse_opers_and_scripts() {
+        let config: Config = toml::from_str(
+            &r#"
+            scripts = ["moderation.lua"]
+
+            [[oper]]
+            name = "tiger"
+            password = "HUNTER2"
+
+            [[oper]]
+            name = "certy"
+            certfps = ["AA:BB"]
+            "#
+            .replace("HUNTER2", HUNTER2),
+        )
+        .unwrap();
+        assert!(config.check_passwords().is_ok());
+        assert_eq!(config.scripts, vec![PathBuf::from("moderation.lua")]);
+        assert_eq!(
+            config.check_oper("tiger", "hunter2", None).map(|x| x.len()),
+            Some(Privilege::ALL.len())
+        );
+        assert!(config.check_oper("tiger", "hunter3", None).is_none());
+        assert!(config.check_oper("nobody", "hunter2", None).is_none());
+        assert!(config.check_oper("certy", "", Some("aabb")).is_some());
+        assert!(config.check_oper("certy", "", Some("ccdd")).is_none());
+        assert!(config.check_oper("certy", "", None).is_none());
+    }
+
+    #[test]
+    fn parse_oper_classes() {
+        let config: Config = toml::from_str(
+            &r#"
+            [[class]]
+            name = "helper"
+            privileges = ["kill", "rehash"]
+
+            [[oper]]
+            name = "helpy"
+            password = "HUNTER2"
+            class = "helper"
+
+            [[oper]]
+            name = "lost"
+            password = "HUNTER2"
+            class = "nonexistent"
+            "#
+            .replace("HUNTER2", HUNTER2),
+        )
+        .unwrap();
+        assert_eq!(
+            config.check_oper("helpy", "hunter2", None),
+            Some(HashSet::from([Privilege::Kill, Privilege::Rehash]))
+        );
+        assert_eq!(
+            config.check_oper("lost", "hunter2", None),
+            Some(HashSet::new())
+        );
+    }
+
+    #[test]
+    fn parse_accounts() {
+        let config: Config = toml::from_str(
+            r#"
+            bouncer = true
+
+            [[account]]
+            name = "tiger"
+            password = "hunter2"
+            webhook = "https://ntfy.sh/tiger"
+            "#,
+        )
+        .unwrap();
+        assert!(config.bouncer);
+        assert_eq!(config.accounts.len(), 1);
+        assert_eq!(config.accounts[0].name, "tiger");
+        assert_eq!(config.accounts[0].password, "hunter2");
+        assert_eq!(
+            config.accounts[0].webhook.as_deref(),
+            Some("https://ntfy.sh/tiger")
+        );
+        assert!(config.check_passwords().is_err());
+    }
+}
@@ -0,0 +1,143 @@
+//! Emergency protection levels for riding out an attack. Opers with `defcon` set one with `DEFCON <level>`, going
+//! from 5 where everything's normal down to 1, and each level keeps whatever the ones above it do:
+//!
+//! - 4: JOINs are throttled to one every `join_interval` seconds
+//! - 3: nobody can make new channels
+//! - 2: new connections have to log into an account, with SASL or PASS
+//! - 1: no new connections at all
+//!
+//! Opers get past the channel ones. It goes back up a level by itself every `decay` seconds, so a DEFCON nobody
+//! remembered to lift doesn't keep everyone out for good.
+//!
+//! ```toml
+//! [defcon]
+//! decay = 1800
+//! join_interval = 10
+//! ```
+
+use crate::server::lock;
+use serde::Deserialize;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Where it normally sits
+pub const NORMAL: u8 = 5;
+/// JOINs are throttled at this level and below
+pub const THROTTLE_JOINS: u8 = 4;
+/// No new channels
+pub const NO_NEW_CHANNELS: u8 = 3;
+/// Only clients logged into an account can connect
+pub const ACCOUNTS_ONLY: u8 = 2;
+/// Nobody new can connect
+pub const CLOSED: u8 = 1;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DefconConfig {
+    /// Seconds before it goes back up a level
+    pub decay: u64,
+    /// Seconds a user has to wait between JOINs while they're throttled
+    pub join_interval: u64,
+}
+
+impl Default for DefconConfig {
+    fn default() -> Self {
+        Self {
+            decay: 1800,
+            join_interval: 10,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    level: u8,
+    /// When it was set or last went up a level
+    at: Instant,
+}
+
+/// The current level. Shared between every connection.
+#[derive(Debug, Clone)]
+pub struct Defcon {
+    state: Arc<Mutex<State>>,
+}
+
+impl Default for Defcon {
+    fn default() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                level: NORMAL,
+                at: Instant::now(),
+            })),
+        }
+    }
+}
+
+impl Defcon {
+    /// The level as of `now`, having gone up one for every `decay` seconds since it was set. The `bool` is set if it
+    /// went up since anyone last asked, so it can be announced once.
+    pub fn level(&self, config: &DefconConfig, now: Instant) -> (u8, bool) {
+        let mut state = lock(&self.state);
+        if state.level >= NORMAL || config.decay == 0 {
+            return (state.level, false);
+        }
+        let steps = now.saturating_duration_since(state.at).as_secs() / config.decay;
+        if steps == 0 {
+            return (state.level, false);
+        }
+        state.level = (state.level as u64 + steps).min(NORMAL as u64) as u8;
+        state.at += Duration::from_secs(config.decay * steps);
+        (state.level, true)
+    }
+
+    /// Sets the level, which starts decaying from `now`.
+    pub fn set(&self, level: u8, now: Instant) {
+        let mut state = lock(&self.state);
+        state.level = level.clamp(CLOSED, NORMAL);
+        state.at = now;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decays() {
+        let config = DefconConfig {
+            decay: 60,
+            ..Default::default()
+        };
+        let defcon = Defcon::default();
+        let now = Instant::now();
+        assert_eq!(defcon.level(&config, now), (NORMAL, false));
+        defcon.set(CLOSED, now);
+        assert_eq!(
+            defcon.level(&config, now + Duration::from_secs(59)),
+            (1, false)
+        );
+        assert_eq!(
+            defcon.level(&config, now + Duration::from_secs(60)),
+            (2, true)
+        );
+        assert_eq!(
+            defcon.level(&config, now + Duration::from_secs(61)),
+            (2, false)
+        );
+        // Several levels at once if nobody asked in between
+        assert_eq!(
+            defcon.level(&config, now + Duration::from_secs(200)),
+            (4, true)
+        );
+        assert_eq!(
+            defcon.level(&config, now + Duration::from_secs(1000)),
+            (NORMAL, true)
+        );
+        assert_eq!(
+            defcon.level(&config, now + Duration::from_secs(2000)),
+            (NORMAL, false)
+        );
+    }
+}
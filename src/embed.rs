@@ -0,0 +1,809 @@
+//! Running the server inside another program, say as the chat backend of a game:
+//!
+//! ```ignore
+//! let server = ServerBuilder::new()
+//!     .bind("127.0.0.1:6667")
+//!     .motd("Welcome to the arena")
+//!     .with_channel("#lobby")
+//!     .start()
+//!     .await?;
+//! let mut events = server.events();
+//! server
+//!     .send(BridgeEvent::Message {
+//!         puppet: "referee".to_string(),
+//!         channel: "#lobby".to_string(),
+//!         text: "Round 1, fight!".to_string(),
+//!     })
+//!     .await?;
+//! server.shutdown().await;
+//! ```
+//! Messages sent through the handle come from puppets on a virtual server (see `bridge`), named `embedded` unless
+//! `name` says otherwise.
+//!
+//! For something that behaves exactly like a real user, say in a test, `connect` makes a [`VirtualClient`]. It goes
+//! through all the same code a TCP client would, it just doesn't need a socket:
+//! ```ignore
+//! let mut alice = server.connect("alice").await?;
+//! alice.send("JOIN #lobby").await?;
+//! while let Some(line) = alice.recv().await? { ... }
+//! ```
+
+use crate::{
+    bridge::{Bridge, BridgeEvent, BridgeLink},
+    config::Config,
+    event::{Event, EventBus},
+    log, server, IrcConnection, Result,
+};
+use async_trait::async_trait;
+use std::net::SocketAddr;
+use tokio::{
+    io::{
+        self, AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream, Lines, ReadHalf, WriteHalf,
+    },
+    net::TcpListener,
+    sync::{broadcast, mpsc, oneshot},
+    task::JoinHandle,
+};
+
+pub struct ServerBuilder {
+    config: Config,
+    name: String,
+    bridges: Vec<Box<dyn Bridge>>,
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Self {
+            config: Config::default(),
+            name: "embedded".to_string(),
+            bridges: Vec::new(),
+        }
+    }
+
+    /// Starts from `config` instead of the defaults, so call this before anything else.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Address to listen on, port 0 picks any free one.
+    pub fn bind(mut self, addr: impl Into<String>) -> Self {
+        self.config.listen = addr.into();
+        self
+    }
+
+    pub fn motd(mut self, motd: impl Into<String>) -> Self {
+        self.config.motd = motd.into();
+        self
+    }
+
+    /// Joins every client to `channel` as soon as it registers.
+    pub fn with_channel(mut self, channel: impl Into<String>) -> Self {
+        self.config.auto_join.push(channel.into());
+        self
+    }
+
+    /// Name of the virtual server the handle's puppets live on.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    pub fn bridge(mut self, bridge: Box<dyn Bridge>) -> Self {
+        self.bridges.push(bridge);
+        self
+    }
+
+    /// Binds the listener and starts serving in the background.
+    pub async fn start(self) -> Result<ServerHandle> {
+        let Self {
+            config,
+            name,
+            mut bridges,
+        } = self;
+        config.validate()?;
+        log::init(&config.log)?;
+        let listener = TcpListener::bind(&config.listen).await?;
+        let local_addr = listener.local_addr()?;
+        log::info!("Listening on {}", local_addr);
+        let (tx, rx) = mpsc::channel(64);
+        bridges.push(Box::new(HandleBridge { name, rx }));
+        let events = EventBus::new();
+        let (virtual_tx, virtual_rx) = mpsc::channel(20);
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let task = tokio::spawn(server::run(
+            listener,
+            config,
+            bridges,
+            events.clone(),
+            virtual_rx,
+            shutdown_rx,
+        ));
+        Ok(ServerHandle {
+            local_addr,
+            events,
+            tx,
+            virtual_tx,
+            shutdown_tx,
+            task,
+        })
+    }
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A running server. Dropping it shuts the server down too, without waiting for it.
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    events: EventBus,
+    tx: mpsc::Sender<BridgeEvent>,
+    virtual_tx: mpsc::Sender<IrcConnection>,
+    shutdown_tx: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl ServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Everything that happens from now on, see `event`.
+    pub fn events(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Makes one of the handle's puppets join or talk.
+    pub async fn send(&self, event: BridgeEvent) -> Result<()> {
+        self.tx.send(event).await?;
+        Ok(())
+    }
+
+    /// Connects a virtual client and registers it as `nick`. Fails if the server won't have that nick.
+    pub async fn connect(&self, nick: &str) -> Result<VirtualClient> {
+        let (ours, theirs) = io::duplex(VIRTUAL_BUFFER);
+        self.virtual_tx
+            .send(IrcConnection::new_virtual(theirs, self.local_addr))
+            .await
+            .map_err(|_| "The server has shut down")?;
+        let (read, write) = io::split(ours);
+        let mut client = VirtualClient {
+            lines: BufReader::new(read).lines(),
+            write,
+        };
+        client.send(&format!("NICK {}", nick)).await?;
+        client.send(&format!("USER {} 0 * :{}", nick, nick)).await?;
+        loop {
+            let line = client
+                .recv()
+                .await?
+                .ok_or("The server hung up before we registered")?;
+            match line.split(' ').nth(1) {
+                Some("001") => return Ok(client),
+                Some("431" | "432" | "433" | "436") => return Err(line.into()),
+                _ if line.starts_with("ERROR") => return Err(line.into()),
+                _ => {}
+            }
+        }
+    }
+
+    /// Waits for the server to stop by itself, like after a DIE.
+    pub async fn stopped(&mut self) {
+        let _ = (&mut self.task).await;
+    }
+
+    /// Stops accepting, and waits for every connection to finish up.
+    pub async fn shutdown(self) {
+        let _ = self.shutdown_tx.send(());
+        if !self.task.is_finished() {
+            let _ = self.task.await;
+        }
+        // Everything it said on the way out
+        let _ = tokio::task::spawn_blocking(log::flush).await;
+    }
+}
+
+/// How much a virtual client can have waiting to be read before the server stops writing to it, same as a full socket
+const VIRTUAL_BUFFER: usize = 64 * 1024;
+
+/// A user without a socket, see `ServerHandle::connect`. Everything the server sends it has to be read, or its
+/// connection stalls like one with a full socket would.
+#[derive(Debug)]
+pub struct VirtualClient {
+    lines: Lines<BufReader<ReadHalf<DuplexStream>>>,
+    write: WriteHalf<DuplexStream>,
+}
+
+impl VirtualClient {
+    /// Sends a raw IRC line, without the line ending.
+    pub async fn send(&mut self, line: &str) -> Result<()> {
+        self.write
+            .write_all(format!("{}\r\n", line).as_bytes())
+            .await?;
+        Ok(())
+    }
+
+    /// The next line from the server, `None` once it's hung up.
+    pub async fn recv(&mut self) -> Result<Option<String>> {
+        Ok(self.lines.next_line().await?)
+    }
+}
+
+/// Feeds what's sent through the handle into the server.
+struct HandleBridge {
+    name: String,
+    rx: mpsc::Receiver<BridgeEvent>,
+}
+
+#[async_trait]
+impl Bridge for HandleBridge {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn run(mut self: Box<Self>, mut link: BridgeLink) -> Result<()> {
+        loop {
+            tokio::select! {
+                event = self.rx.recv() => match event {
+                    Some(event) => link.tx.send(event).await?,
+                    None => return Ok(()),
+                },
+                // Whoever's embedding us gets all of this from `events` anyway
+                Some(_) = link.rx.recv() => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpStream,
+    };
+
+    #[tokio::test]
+    async fn embedded_server() {
+        let server = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .motd("Welcome to the arena")
+            .with_channel("#lobby")
+            .start()
+            .await
+            .unwrap();
+        let mut events = server.events();
+
+        let (read, mut write) = TcpStream::connect(server.local_addr())
+            .await
+            .unwrap()
+            .into_split();
+        let mut lines = BufReader::new(read).lines();
+        write
+            .write_all(b"NICK tiger\r\nUSER tiger 0 * :tiger\r\nMOTD\r\n")
+            .await
+            .unwrap();
+        loop {
+            if let Event::UserJoined { nick, channel } = events.recv().await.unwrap() {
+                assert_eq!((nick.as_str(), channel.as_str()), ("tiger", "#lobby"));
+                break;
+            }
+        }
+
+        server
+            .send(BridgeEvent::Message {
+                puppet: "referee".to_string(),
+                channel: "#lobby".to_string(),
+                text: "Round 1, fight!".to_string(),
+            })
+            .await
+            .unwrap();
+        let (mut motd, mut message) = (false, false);
+        while !(motd && message) {
+            let line = lines.next_line().await.unwrap().unwrap();
+            motd |= line.ends_with(" 372 tiger :- Welcome to the arena");
+            message |= line == ":referee!referee@embedded PRIVMSG #lobby :Round 1, fight!";
+        }
+
+        drop(write);
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn virtual_clients() {
+        let server = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+        let mut alice = server.connect("alice").await.unwrap();
+        let mut bob = server.connect("bob").await.unwrap();
+        assert!(server.connect("Alice").await.is_err());
+
+        bob.send("JOIN #meow").await.unwrap();
+        alice.send("JOIN #meow").await.unwrap();
+        // Once bob has seen alice join, she's in
+        while !bob.recv().await.unwrap().unwrap().contains("JOIN #meow") {}
+        while !bob.recv().await.unwrap().unwrap().contains("JOIN #meow") {}
+        alice.send("PRIVMSG #meow :hi bob").await.unwrap();
+        loop {
+            let line = bob.recv().await.unwrap().unwrap();
+            if line.contains("PRIVMSG") {
+                assert_eq!(line, ":alice!alice@127.0.0.1 PRIVMSG #meow :hi bob");
+                break;
+            }
+        }
+
+        drop((alice, bob));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn membership_echoes() {
+        let server = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+        let mut alice = server.connect("alice").await.unwrap();
+        alice.send("JOIN #meow,#meow secret").await.unwrap();
+        alice.send("JOIN #meow").await.unwrap();
+        alice.send("PART #meow :bye").await.unwrap();
+        alice.send("PING done").await.unwrap();
+        let mut echoes = Vec::new();
+        loop {
+            let line = alice.recv().await.unwrap().unwrap();
+            if line.contains("PONG") {
+                break;
+            }
+            if line.contains(" JOIN ") || line.contains(" PART ") {
+                echoes.push(line);
+            }
+        }
+        assert_eq!(
+            echoes,
+            [
+                ":alice!alice@127.0.0.1 JOIN #meow",
+                ":alice!alice@127.0.0.1 PART #meow :bye"
+            ]
+        );
+
+        drop(alice);
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn guest_nicks() {
+        let mut config = Config::default();
+        config.nicks.guest_after = Some(0);
+        let server = ServerBuilder::new()
+            .config(config)
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+        let _alice = server.connect("alice").await.unwrap();
+        let (read, mut write) = TcpStream::connect(server.local_addr())
+            .await
+            .unwrap()
+            .into_split();
+        let mut lines = BufReader::new(read).lines();
+        write
+            .write_all(b"NICK alice\r\nUSER alice 0 * :alice\r\n")
+            .await
+            .unwrap();
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert!(line.contains(" 433 "), "{}", line);
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert!(
+            line.contains(" 001 ") && line.contains(" :Welcome"),
+            "{}",
+            line
+        );
+        assert!(line.contains("Guest"), "{}", line);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn nickserv_lockout() {
+        let config = Config {
+            accounts: vec![crate::config::AccountConfig {
+                name: "tiger".to_string(),
+                // "p"
+                password: "$argon2id$v=19$m=19456,t=2,p=1$Vo5jgtsLLVW7gPeNvigBzA$kNeUdeowP2vUJcYGxlGk/ZM0Rmn30Eg9ySiYlONauGk".to_string(),
+                certfps: Vec::new(),
+                webhook: None,
+            }],
+            nickserv_lockout: crate::lockout::LockoutConfig {
+                per_connection: 2,
+                per_ip: 3,
+                window: 600,
+            },
+            ..Default::default()
+        };
+        let server = ServerBuilder::new()
+            .config(config)
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+        let mut alice = server.connect("alice").await.unwrap();
+        alice
+            .send("PRIVMSG NickServ :GHOST tiger wrong")
+            .await
+            .unwrap();
+        while !alice
+            .recv()
+            .await
+            .unwrap()
+            .unwrap()
+            .contains("You don't own tiger")
+        {}
+        alice
+            .send("PRIVMSG NickServ :GHOST tiger wrong")
+            .await
+            .unwrap();
+        while !alice.recv().await.unwrap().unwrap().starts_with("ERROR ") {}
+
+        // Reconnecting doesn't get them any more tries than the IP has left
+        let mut bob = server.connect("bob").await.unwrap();
+        bob.send("PRIVMSG NickServ :GHOST tiger wrong")
+            .await
+            .unwrap();
+        while !bob
+            .recv()
+            .await
+            .unwrap()
+            .unwrap()
+            .contains("You don't own tiger")
+        {}
+        bob.send("PRIVMSG NickServ :GHOST tiger p").await.unwrap();
+        let line = bob.recv().await.unwrap().unwrap();
+        assert!(
+            line.ends_with(":Too many wrong passwords, try again later"),
+            "{}",
+            line
+        );
+
+        drop((alice, bob));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn nick_changes_reach_channels() {
+        let config = Config {
+            accounts: vec![crate::config::AccountConfig {
+                name: "tiger".to_string(),
+                // "p"
+                password: "$argon2id$v=19$m=19456,t=2,p=1$Vo5jgtsLLVW7gPeNvigBzA$kNeUdeowP2vUJcYGxlGk/ZM0Rmn30Eg9ySiYlONauGk".to_string(),
+                certfps: Vec::new(),
+                webhook: None,
+            }],
+            ..Default::default()
+        };
+        let server = ServerBuilder::new()
+            .config(config)
+            .with_channel("#meow")
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+        let mut alice = server.connect("alice").await.unwrap();
+        let mut squatter = server.connect("tiger").await.unwrap();
+        let mut carol = server.connect("carol").await.unwrap();
+        for x in [&mut alice, &mut squatter, &mut carol] {
+            while !x.recv().await.unwrap().unwrap().contains(" 366 ") {}
+        }
+        async fn nick(client: &mut VirtualClient) -> String {
+            loop {
+                let line = client.recv().await.unwrap().unwrap();
+                if line.contains(" NICK ") {
+                    return line;
+                }
+            }
+        }
+
+        carol.send("NICK cat").await.unwrap();
+        assert_eq!(nick(&mut carol).await, ":carol!carol@127.0.0.1 NICK cat");
+        assert_eq!(nick(&mut alice).await, ":carol!carol@127.0.0.1 NICK cat");
+        assert_eq!(nick(&mut squatter).await, ":carol!carol@127.0.0.1 NICK cat");
+
+        carol
+            .send("PRIVMSG NickServ :REGAIN tiger p")
+            .await
+            .unwrap();
+        let line = nick(&mut alice).await;
+        assert!(
+            line.starts_with(":tiger!tiger@127.0.0.1 NICK Guest"),
+            "{}",
+            line
+        );
+        assert_eq!(nick(&mut alice).await, ":cat!carol@127.0.0.1 NICK tiger");
+        let line = nick(&mut squatter).await;
+        assert!(
+            line.starts_with(":tiger!tiger@127.0.0.1 NICK Guest"),
+            "{}",
+            line
+        );
+        assert_eq!(nick(&mut carol).await, ":cat!carol@127.0.0.1 NICK tiger");
+
+        drop((alice, squatter, carol));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn require_sasl() {
+        let config = || Config {
+            require_sasl: true,
+            ..Default::default()
+        };
+        // No way to log in with SASL without TLS
+        assert!(ServerBuilder::new().config(config()).start().await.is_err());
+        let tls = format!(
+            "listen = \"127.0.0.1:0\"\ncert = \"{0}/testdata/cert.pem\"\nkey = \"{0}/testdata/key.pem\"",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let config = Config {
+            tls: Some(toml::from_str(&tls).unwrap()),
+            ..config()
+        };
+        let server = ServerBuilder::new()
+            .config(config)
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+        let mut events = server.events();
+        let (read, mut write) = TcpStream::connect(server.local_addr())
+            .await
+            .unwrap()
+            .into_split();
+        let mut lines = BufReader::new(read).lines();
+        write
+            .write_all(b"NICK alice\r\nUSER alice 0 * :alice\r\n")
+            .await
+            .unwrap();
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert!(line.contains(" 904 "), "{}", line);
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert!(line.starts_with("ERROR "), "{}", line);
+        assert!(lines.next_line().await.unwrap().is_none());
+
+        server.shutdown().await;
+        // Turned away before registering, so they were never here to quit
+        while let Ok(event) = events.try_recv() {
+            assert!(!matches!(event, Event::UserQuit { .. }), "{:?}", event);
+        }
+    }
+
+    #[tokio::test]
+    async fn chghost() {
+        let mut config = Config::default();
+        config.opers.push(crate::config::OperConfig {
+            name: "root".to_string(),
+            // "p"
+            password: "$argon2id$v=19$m=19456,t=2,p=1$Vo5jgtsLLVW7gPeNvigBzA$kNeUdeowP2vUJcYGxlGk/ZM0Rmn30Eg9ySiYlONauGk".to_string(),
+            certfps: Vec::new(),
+            class: None,
+        });
+        let server = ServerBuilder::new()
+            .config(config)
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+        let mut alice = server.connect("alice").await.unwrap();
+        let mut bob = server.connect("bob").await.unwrap();
+        let mut carol = server.connect("carol").await.unwrap();
+        bob.send("CAP REQ chghost").await.unwrap();
+        while !bob.recv().await.unwrap().unwrap().contains("ACK") {}
+        carol.send("JOIN #meow").await.unwrap();
+        while !carol.recv().await.unwrap().unwrap().contains(" 366 ") {}
+        for x in [&mut alice, &mut bob] {
+            x.send("JOIN #meow").await.unwrap();
+            while !x.recv().await.unwrap().unwrap().contains(" 366 ") {}
+        }
+
+        alice.send("OPER root p").await.unwrap();
+        alice.send("CHGHOST carol cat.example").await.unwrap();
+        // Without chghost it looks like they left and came back
+        while !alice.recv().await.unwrap().unwrap().contains("QUIT") {}
+        assert_eq!(
+            alice.recv().await.unwrap().unwrap(),
+            ":carol!carol@cat.example JOIN #meow"
+        );
+        let line = alice.recv().await.unwrap().unwrap();
+        assert!(line.ends_with(" MODE #meow +o carol"), "{}", line);
+        loop {
+            let line = bob.recv().await.unwrap().unwrap();
+            if line.contains("carol") {
+                assert_eq!(line, ":carol!carol@127.0.0.1 CHGHOST carol cat.example");
+                break;
+            }
+        }
+        loop {
+            let line = carol.recv().await.unwrap().unwrap();
+            if line.contains(" 396 ") {
+                assert!(line.contains(" 396 carol cat.example :"), "{}", line);
+                break;
+            }
+        }
+
+        drop((alice, bob, carol));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn join_zero() {
+        let server = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+        let mut alice = server.connect("alice").await.unwrap();
+        alice.send("JOIN #meow,#purr").await.unwrap();
+        alice.send("JOIN 0").await.unwrap();
+        alice.send("PING done").await.unwrap();
+        let mut parts = Vec::new();
+        loop {
+            let line = alice.recv().await.unwrap().unwrap();
+            if line.contains("PONG") {
+                break;
+            }
+            if line.contains(" PART ") {
+                parts.push(line);
+            }
+        }
+        assert_eq!(
+            parts,
+            [
+                ":alice!alice@127.0.0.1 PART #meow",
+                ":alice!alice@127.0.0.1 PART #purr"
+            ]
+        );
+
+        drop(alice);
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn names() {
+        let server = ServerBuilder::new()
+            .with_channel("#welcome")
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+        let mut alice = server.connect("alice").await.unwrap();
+        // Auto-joined, with the names like any other JOIN
+        while !alice
+            .recv()
+            .await
+            .unwrap()
+            .unwrap()
+            .contains("JOIN #welcome")
+        {}
+        assert_eq!(
+            alice.recv().await.unwrap().unwrap(),
+            ":127.0.0.1 353 alice = #welcome :@alice"
+        );
+        let line = alice.recv().await.unwrap().unwrap();
+        assert!(
+            line.starts_with(":127.0.0.1 366 alice #welcome :"),
+            "{}",
+            line
+        );
+
+        let mut bob = server.connect("bob").await.unwrap();
+        while !bob.recv().await.unwrap().unwrap().contains(" 366 ") {}
+        bob.send("NAMES #welcome,#nowhere").await.unwrap();
+        let line = bob.recv().await.unwrap().unwrap();
+        let (start, names) = line.split_once(" :").unwrap();
+        assert_eq!(start, ":127.0.0.1 353 bob = #welcome");
+        let mut names: Vec<&str> = names.split(' ').collect();
+        names.sort_unstable();
+        assert_eq!(names, ["@alice", "bob"]);
+        let line = bob.recv().await.unwrap().unwrap();
+        assert!(
+            line.starts_with(":127.0.0.1 366 bob #welcome :"),
+            "{}",
+            line
+        );
+        // Nobody there, so just the end
+        let line = bob.recv().await.unwrap().unwrap();
+        assert!(
+            line.starts_with(":127.0.0.1 366 bob #nowhere :"),
+            "{}",
+            line
+        );
+
+        drop((alice, bob));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn quits_reach_shared_channels_once() {
+        let server = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+        let mut alice = server.connect("alice").await.unwrap();
+        let mut bob = server.connect("bob").await.unwrap();
+        bob.send("JOIN #meow,#purr").await.unwrap();
+        while !bob.recv().await.unwrap().unwrap().contains("JOIN #meow") {}
+        alice.send("JOIN #meow,#purr").await.unwrap();
+        while !bob.recv().await.unwrap().unwrap().contains("alice") {}
+
+        alice.send("QUIT :bye").await.unwrap();
+        loop {
+            let line = bob.recv().await.unwrap().unwrap();
+            if line.contains("QUIT") {
+                assert_eq!(line, ":alice!alice@127.0.0.1 QUIT :bye");
+                break;
+            }
+        }
+        // Anything after it came after a second QUIT would have
+        bob.send("PING done").await.unwrap();
+        loop {
+            let line = bob.recv().await.unwrap().unwrap();
+            assert!(!line.contains("QUIT"));
+            if line.contains("PONG") {
+                break;
+            }
+        }
+
+        drop((alice, bob));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn channels_go_when_everyone_drops() {
+        let server = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+        let mut alice = server.connect("alice").await.unwrap();
+        alice.send("JOIN #meow").await.unwrap();
+        alice.send("TOPIC #meow :stale").await.unwrap();
+        while !alice.recv().await.unwrap().unwrap().contains("TOPIC #meow") {}
+        // Gone without a PART
+        drop(alice);
+
+        let mut bob = server.connect("bob").await.unwrap();
+        loop {
+            // Just the one channel, listing everything has a cooldown
+            bob.send("LIST #meow").await.unwrap();
+            let mut listed = false;
+            loop {
+                let line = bob.recv().await.unwrap().unwrap();
+                listed |= line.contains(" 322 ");
+                if line.contains(" 323 ") {
+                    break;
+                }
+            }
+            if !listed {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        bob.send("JOIN #meow").await.unwrap();
+        bob.send("TOPIC #meow").await.unwrap();
+        loop {
+            let line = bob.recv().await.unwrap().unwrap();
+            assert!(!line.contains("stale"));
+            if line.contains(" 331 ") {
+                break;
+            }
+        }
+
+        drop(bob);
+        server.shutdown().await;
+    }
+}
@@ -0,0 +1,250 @@
+//! Typed events that core handlers publish as things happen, so subsystems (webhooks, and later metrics, history,
+//! services...) can subscribe to what they care about instead of all being spliced into `Message::apply`.
+//!
+//! ```ignore
+//! let mut events = server.events.subscribe();
+//! while let Ok(event) = events.recv().await {
+//!     if let Event::UserJoined { nick, channel } = event { ... }
+//! }
+//! ```
+//! Subscribers only see events published after they subscribed, and one that falls too far behind misses some.
+
+use crate::ban::{Ban, BanKind};
+use std::{fmt::Display, time::Duration};
+use tokio::sync::broadcast;
+
+/// How many events a slow subscriber can fall behind before it starts missing them
+const CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+// Not every event has a subscriber yet
+#[allow(dead_code)]
+pub enum Event {
+    /// A connection finished registering, or attached to its session
+    UserRegistered {
+        nick: String,
+        account: Option<String>,
+        host: String,
+    },
+    NickChanged {
+        old: String,
+        new: String,
+    },
+    UserJoined {
+        nick: String,
+        channel: String,
+    },
+    UserParted {
+        nick: String,
+        channel: String,
+    },
+    /// Someone said something in a channel, after scripts and plugins have had their say
+    ChannelMessage {
+        source: String,
+        channel: String,
+        text: String,
+    },
+    /// An account showed up in the config on REHASH
+    AccountRegistered {
+        account: String,
+    },
+    /// An account was taken out of the config on REHASH
+    AccountDropped {
+        account: String,
+    },
+    /// Someone got the password or certificate wrong logging in, to `account` if they named one
+    AuthFailed {
+        account: Option<String>,
+        ip: String,
+    },
+    /// A connection went away
+    UserQuit {
+        nick: String,
+        reason: Option<String>,
+    },
+    /// A connection closed, registered or not. `nick` is unset if it never got one.
+    ConnectionClosed {
+        nick: Option<String>,
+        host: String,
+        reason: String,
+        summary: ConnectionSummary,
+    },
+    /// An oper used KILL
+    UserKilled {
+        by: String,
+        nick: String,
+        reason: String,
+    },
+    /// An oper was shown `nick`'s real address, by `command`
+    RealIpShown {
+        by: String,
+        nick: String,
+        command: &'static str,
+    },
+    /// An oper with `spy` looked into secret channels they aren't in, `target` being the channel or nick they
+    /// asked about
+    OperSpied {
+        by: String,
+        command: &'static str,
+        target: String,
+    },
+    /// Someone used OPER, `by` is their hostmask
+    OperAttempt {
+        by: String,
+        name: String,
+        success: bool,
+    },
+    /// Too many wrong OPER passwords, from `by`'s connection if they were `dropped` for it and from their IP
+    /// otherwise
+    OperLockout {
+        by: String,
+        ip: String,
+        dropped: bool,
+    },
+    BanAdded {
+        ban: Ban,
+    },
+    BanRemoved {
+        by: String,
+        kind: BanKind,
+        mask: String,
+    },
+    Rehashed {
+        by: String,
+    },
+    /// An oper changed `nick`'s host to `host` with CHGHOST
+    HostChanged {
+        by: String,
+        nick: String,
+        host: String,
+    },
+    /// The DEFCON level changed, set by the oper `by` or going back up by itself without one
+    DefconChanged {
+        by: Option<String>,
+        level: u8,
+    },
+    /// An oper used DIE, the server is on its way down
+    Died {
+        by: String,
+    },
+    /// An oper split the cluster node `node` off with SQUIT
+    Squit {
+        by: String,
+        node: String,
+        reason: Option<String>,
+    },
+    /// An ENCAP addressed to this node, for whatever understands `subcommand`
+    Encap {
+        source: String,
+        subcommand: String,
+        params: Vec<String>,
+    },
+    /// One of the extra `listeners` started or stopped accepting
+    ListenerChanged {
+        address: String,
+        up: bool,
+    },
+    ListenFailed {
+        address: String,
+        error: String,
+    },
+    /// We joined or lost the cluster
+    LinkChanged {
+        name: String,
+        up: bool,
+    },
+    /// An oper added a spam filter with FILTER ADD
+    FilterAdded {
+        by: String,
+        action: &'static str,
+        pattern: String,
+    },
+    /// An oper removed a spam filter with FILTER DEL
+    FilterRemoved {
+        by: String,
+        pattern: String,
+    },
+    /// Something someone sent tripped a spam filter
+    FilterHit {
+        nick: String,
+        action: &'static str,
+        text: String,
+    },
+}
+
+/// What a connection got up to before it closed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionSummary {
+    /// How long it was connected
+    pub connected: Duration,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Lines the client sent
+    pub messages: u64,
+    pub channels_joined: usize,
+}
+
+impl Display for ConnectionSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "connected {}s, {} bytes in, {} bytes out, {} messages, {} channels joined",
+            self.connected.as_secs(),
+            self.bytes_in,
+            self.bytes_out,
+            self.messages,
+            self.channels_joined
+        )
+    }
+}
+
+/// Cheap to clone, every clone publishes to the same subscribers.
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<Event>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(CAPACITY);
+        Self { tx }
+    }
+
+    /// Tells every subscriber about `event`. Nobody listening is fine.
+    pub fn publish(&self, event: Event) {
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn subscribers_hear_later_events() {
+        let bus = EventBus::new();
+        bus.publish(Event::NickChanged {
+            old: "tiger".to_string(),
+            new: "tigercat".to_string(),
+        });
+
+        let mut events = bus.subscribe();
+        let event = Event::UserQuit {
+            nick: "tigercat".to_string(),
+            reason: None,
+        };
+        bus.clone().publish(event.clone());
+        assert_eq!(events.try_recv(), Ok(event));
+        assert!(events.try_recv().is_err());
+    }
+}
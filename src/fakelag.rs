@@ -0,0 +1,144 @@
+//! Fakelag, hybrid-style flood protection. Every command a client sends costs it some time on its own clock, and
+//! once that clock is more than `burst` seconds ahead of the real one, its commands are held back until it isn't.
+//! Nothing else is read from a client while it waits, so a flood just backs up in its own socket, but it still gets
+//! messages and PINGs like normal. Opers are exempt. It's off unless turned on.
+//!
+//! ```toml
+//! [fakelag]
+//! enabled = true
+//! burst = 10
+//! # Seconds, for anything not in `costs`
+//! default_cost = 1
+//!
+//! [fakelag.costs]
+//! LIST = 5
+//! PONG = 0
+//! ```
+//! `costs` is added on top of the built in ones, see `COSTS`.
+
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Costs for commands that are cheaper or dearer than the default, in seconds
+pub const COSTS: &[(&str, f64)] = &[
+    ("CAP", 0.0),
+    ("AUTHENTICATE", 0.0),
+    ("PONG", 0.0),
+    ("PING", 0.5),
+    ("TAGMSG", 0.5),
+    ("JOIN", 2.0),
+    ("NICK", 2.0),
+    ("WHO", 2.0),
+    ("WHOIS", 2.0),
+    ("LIST", 5.0),
+];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FakelagConfig {
+    pub enabled: bool,
+    /// Seconds a client can get ahead before its commands start waiting
+    pub burst: f64,
+    pub default_cost: f64,
+    /// Command to its cost in seconds, on top of `COSTS`
+    pub costs: HashMap<String, f64>,
+}
+
+impl Default for FakelagConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            burst: 10.0,
+            default_cost: 1.0,
+            costs: HashMap::new(),
+        }
+    }
+}
+
+impl FakelagConfig {
+    /// What the command on the raw `line` costs.
+    pub fn cost(&self, line: &str) -> Duration {
+        let command = command_name(line);
+        let configured = self
+            .costs
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(command))
+            .map(|(_, cost)| *cost);
+        let builtin = || {
+            COSTS
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(command))
+                .map(|(_, cost)| *cost)
+        };
+        let cost = configured.or_else(builtin).unwrap_or(self.default_cost);
+        Duration::from_secs_f64(cost.max(0.0))
+    }
+}
+
+/// The command on a raw line, skipping any tags and source.
+fn command_name(line: &str) -> &str {
+    line.split(' ')
+        .find(|x| !x.is_empty() && !x.starts_with('@') && !x.starts_with(':'))
+        .unwrap_or("")
+}
+
+/// One client's lag clock.
+#[derive(Debug)]
+pub struct Fakelag {
+    clock: Instant,
+}
+
+impl Fakelag {
+    pub fn new(now: Instant) -> Self {
+        Self { clock: now }
+    }
+
+    /// Charges the client for `line`. Returns how long to hold it back.
+    pub fn charge(&mut self, config: &FakelagConfig, line: &str, now: Instant) -> Duration {
+        self.clock = self.clock.max(now) + config.cost(line);
+        (self.clock - now).saturating_sub(Duration::from_secs_f64(config.burst))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn costs() {
+        let mut config: FakelagConfig = toml::from_str("[costs]\nlist = 10").unwrap();
+        assert_eq!(config.cost("LIST"), Duration::from_secs(10));
+        assert_eq!(config.cost("@a=b :x JOIN #c"), Duration::from_secs(2));
+        assert_eq!(config.cost("PRIVMSG #c :hi"), Duration::from_secs(1));
+        config.default_cost = 0.5;
+        assert_eq!(config.cost("PRIVMSG #c :hi"), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn lag_builds_up_and_drains() {
+        let config = FakelagConfig {
+            enabled: true,
+            burst: 2.0,
+            ..Default::default()
+        };
+        let now = Instant::now();
+        let mut lag = Fakelag::new(now);
+        assert_eq!(lag.charge(&config, "PRIVMSG a b", now), Duration::ZERO);
+        assert_eq!(lag.charge(&config, "PRIVMSG a b", now), Duration::ZERO);
+        assert_eq!(
+            lag.charge(&config, "PRIVMSG a b", now),
+            Duration::from_secs(1)
+        );
+        // Waiting it out
+        let later = now + Duration::from_secs(10);
+        assert_eq!(lag.charge(&config, "JOIN #c", later), Duration::ZERO);
+        assert_eq!(
+            lag.charge(&config, "JOIN #c", later),
+            Duration::from_secs(2)
+        );
+        assert_eq!(lag.charge(&config, "PONG x", later), Duration::from_secs(2));
+    }
+}
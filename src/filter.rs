@@ -0,0 +1,249 @@
+//! Spam filters: rules matching the text of messages, part and quit reasons, each with something to do about it.
+//! Rules come from `[[filter]]` blocks in the config and can be changed at runtime with FILTER.
+//!
+//! ```toml
+//! [[filter]]
+//! pattern = "free crypto"
+//! action = "kline"
+//!
+//! [[filter]]
+//! pattern = "(?i)discord\\.gg/\\w+"
+//! regex = true
+//! action = "warn"
+//! commands = ["privmsg", "part"]
+//! ```
+//! Substring patterns ignore case, regexes only if they ask to.
+
+use crate::server::lock;
+use crate::Result;
+use regex::Regex;
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+
+/// Commands whose text gets checked, and the only ones a filter can be limited to
+pub const FILTERED: &[&str] = &["privmsg", "part", "quit"];
+
+/// What happens to whoever sent something a filter matched
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    /// Drop it
+    Block,
+    /// Let it through, but tell them (and the logs) about it
+    Warn,
+    /// Drop it and disconnect them
+    Kill,
+    /// Drop it, disconnect them and K-line their IP
+    Kline,
+}
+
+impl FilterAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FilterAction::Block => "block",
+            FilterAction::Warn => "warn",
+            FilterAction::Kill => "kill",
+            FilterAction::Kline => "kline",
+        }
+    }
+}
+
+impl std::str::FromStr for FilterAction {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "block" => Ok(FilterAction::Block),
+            "warn" => Ok(FilterAction::Warn),
+            "kill" => Ok(FilterAction::Kill),
+            "kline" => Ok(FilterAction::Kline),
+            _ => Err(format!("Unknown filter action {}", s)),
+        }
+    }
+}
+
+/// A `[[filter]]` block, also what FILTER ADD builds
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct FilterConfig {
+    pub pattern: String,
+    /// Treat `pattern` as a regex rather than a substring
+    #[serde(default)]
+    pub regex: bool,
+    pub action: FilterAction,
+    /// Lowercase command names this applies to, every filterable command if empty
+    #[serde(default)]
+    pub commands: Vec<String>,
+    /// Told to the user instead of the default
+    pub reason: Option<String>,
+}
+
+/// A filter that matched
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hit {
+    pub action: FilterAction,
+    pub reason: String,
+}
+
+#[derive(Debug)]
+enum Pattern {
+    /// Lowercased
+    Substring(String),
+    Regex(Regex),
+}
+
+#[derive(Debug)]
+struct Rule {
+    config: FilterConfig,
+    pattern: Pattern,
+}
+
+impl Rule {
+    fn new(config: FilterConfig) -> Result<Self> {
+        if let Some(command) = config
+            .commands
+            .iter()
+            .find(|x| !FILTERED.contains(&x.as_str()))
+        {
+            return Err(format!(
+                "Filters only apply to {}, not {}",
+                FILTERED.join(", "),
+                command
+            )
+            .into());
+        }
+        let pattern = if config.regex {
+            Pattern::Regex(Regex::new(&config.pattern)?)
+        } else {
+            Pattern::Substring(config.pattern.to_lowercase())
+        };
+        Ok(Self { config, pattern })
+    }
+
+    fn matches(&self, command: &str, text: &str) -> bool {
+        if !self.config.commands.is_empty() && !self.config.commands.iter().any(|x| x == command) {
+            return false;
+        }
+        match &self.pattern {
+            Pattern::Substring(pattern) => text.to_lowercase().contains(pattern),
+            Pattern::Regex(regex) => regex.is_match(text),
+        }
+    }
+}
+
+/// Shared handle to every filter, cheap to clone into each connection.
+#[derive(Debug, Clone, Default)]
+pub struct Filters {
+    rules: Arc<Mutex<Vec<Rule>>>,
+}
+
+impl Filters {
+    pub fn from_config(filters: &[FilterConfig]) -> Result<Self> {
+        let rules = filters
+            .iter()
+            .map(|x| Rule::new(x.clone()))
+            .collect::<Result<Vec<Rule>>>()?;
+        Ok(Self {
+            rules: Arc::new(Mutex::new(rules)),
+        })
+    }
+
+    /// Adds a filter, replacing any other filter with the same pattern.
+    pub fn add(&self, config: FilterConfig) -> Result<()> {
+        let rule = Rule::new(config)?;
+        let mut rules = lock(&self.rules);
+        rules.retain(|x| x.config.pattern != rule.config.pattern);
+        rules.push(rule);
+        Ok(())
+    }
+
+    /// Removes the filter with `pattern`, returning `false` if there wasn't one.
+    pub fn remove(&self, pattern: &str) -> bool {
+        let mut rules = lock(&self.rules);
+        let before = rules.len();
+        rules.retain(|x| x.config.pattern != pattern);
+        rules.len() != before
+    }
+
+    pub fn list(&self) -> Vec<FilterConfig> {
+        let rules = lock(&self.rules);
+        rules.iter().map(|x| x.config.clone()).collect()
+    }
+
+    /// Checks `text` sent with `command` (lowercase) against every filter, returning the first that matched.
+    pub fn check(&self, command: &str, text: &str) -> Option<Hit> {
+        let rules = lock(&self.rules);
+        rules
+            .iter()
+            .find(|x| x.matches(command, text))
+            .map(|x| Hit {
+                action: x.config.action,
+                reason: x
+                    .config
+                    .reason
+                    .clone()
+                    .unwrap_or_else(|| "Message matched a spam filter".to_string()),
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn filter(pattern: &str, regex: bool, action: FilterAction, commands: &[&str]) -> FilterConfig {
+        FilterConfig {
+            pattern: pattern.to_string(),
+            regex,
+            action,
+            commands: commands.iter().map(|x| x.to_string()).collect(),
+            reason: None,
+        }
+    }
+
+    #[test]
+    fn filters_match_by_command() {
+        let filters = Filters::from_config(&[
+            filter("free crypto", false, FilterAction::Kline, &[]),
+            filter(r"discord\.gg/\w+", true, FilterAction::Warn, &["privmsg"]),
+        ])
+        .unwrap();
+        assert_eq!(
+            filters.check("quit", "FREE CRYPTO here").map(|x| x.action),
+            Some(FilterAction::Kline)
+        );
+        assert_eq!(
+            filters
+                .check("privmsg", "join discord.gg/abc")
+                .map(|x| x.action),
+            Some(FilterAction::Warn)
+        );
+        assert_eq!(filters.check("quit", "join discord.gg/abc"), None);
+        assert!(filters.check("part", "free crypto").is_some());
+        assert_eq!(filters.check("privmsg", "hello"), None);
+    }
+
+    #[test]
+    fn filters_can_change() {
+        let filters = Filters::default();
+        assert!(filters
+            .add(filter("(unclosed", true, FilterAction::Block, &[]))
+            .is_err());
+        // Nothing checks NOTICE, so a filter for it would never fire
+        assert!(filters
+            .add(filter("spam", false, FilterAction::Block, &["notice"]))
+            .is_err());
+        filters
+            .add(filter("spam", false, FilterAction::Block, &[]))
+            .unwrap();
+        filters
+            .add(filter("spam", false, FilterAction::Kill, &[]))
+            .unwrap();
+        assert_eq!(filters.list().len(), 1);
+        assert_eq!(
+            filters.check("privmsg", "spam").map(|x| x.action),
+            Some(FilterAction::Kill)
+        );
+        assert!(filters.remove("spam"));
+        assert!(!filters.remove("spam"));
+        assert_eq!(filters.check("privmsg", "spam"), None);
+    }
+}
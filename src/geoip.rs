@@ -0,0 +1,160 @@
+//! Country and ASN lookups from MaxMind's GeoLite2 databases, for working out where abuse is coming from. Opers see
+//! where a user is connecting from in WHOIS, and it's tacked onto the connect server notices (snomask `c`).
+//!
+//! ```toml
+//! [geoip]
+//! # GeoLite2-City works here too
+//! country = "/usr/share/GeoIP/GeoLite2-Country.mmdb"
+//! asn = "/usr/share/GeoIP/GeoLite2-ASN.mmdb"
+//! ```
+//! The databases are read once at startup. This all needs the `geoip` feature, without it the config is ignored.
+
+use crate::Result;
+use serde::Deserialize;
+use std::{fmt, net::IpAddr, path::PathBuf};
+
+#[cfg(feature = "geoip")]
+use maxminddb::{geoip2, Reader};
+#[cfg(feature = "geoip")]
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct GeoIpConfig {
+    /// GeoLite2 Country or City database
+    pub country: Option<PathBuf>,
+    /// GeoLite2 ASN database
+    pub asn: Option<PathBuf>,
+}
+
+/// What we know about where an address is.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Location {
+    /// ISO code, like `DE`
+    pub country_code: Option<String>,
+    /// English name, like `Germany`
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+    /// Whoever owns the ASN
+    pub organization: Option<String>,
+}
+
+impl fmt::Display for Location {
+    /// Like `Germany (DE), AS3320 Deutsche Telekom AG`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        match (&self.country, &self.country_code) {
+            (Some(name), Some(code)) => parts.push(format!("{} ({})", name, code)),
+            (Some(x), None) | (None, Some(x)) => parts.push(x.clone()),
+            (None, None) => {}
+        }
+        match (self.asn, &self.organization) {
+            (Some(asn), Some(org)) => parts.push(format!("AS{} {}", asn, org)),
+            (Some(asn), None) => parts.push(format!("AS{}", asn)),
+            (None, Some(org)) => parts.push(org.clone()),
+            (None, None) => {}
+        }
+        if parts.is_empty() {
+            write!(f, "unknown")
+        } else {
+            write!(f, "{}", parts.join(", "))
+        }
+    }
+}
+
+/// Shared handle to the databases, cheap to clone into each connection. Finds nothing when none are loaded.
+#[derive(Debug, Clone, Default)]
+pub struct GeoIp {
+    #[cfg(feature = "geoip")]
+    country: Option<Arc<Reader<Vec<u8>>>>,
+    #[cfg(feature = "geoip")]
+    asn: Option<Arc<Reader<Vec<u8>>>>,
+}
+
+impl GeoIp {
+    /// Reads whichever databases `config` has.
+    pub fn load(config: &GeoIpConfig) -> Result<Self> {
+        #[cfg(feature = "geoip")]
+        {
+            let open = |path: &Option<PathBuf>| -> Result<Option<Arc<Reader<Vec<u8>>>>> {
+                let Some(path) = path else {
+                    return Ok(None);
+                };
+                let reader = Reader::open_readfile(path)
+                    .map_err(|e| format!("Failed to load {}: {}", path.display(), e))?;
+                Ok(Some(Arc::new(reader)))
+            };
+            Ok(Self {
+                country: open(&config.country)?,
+                asn: open(&config.asn)?,
+            })
+        }
+        #[cfg(not(feature = "geoip"))]
+        {
+            if config.country.is_some() || config.asn.is_some() {
+                crate::log::error!("Built without the geoip feature, ignoring the GeoIP databases");
+            }
+            Ok(Self::default())
+        }
+    }
+
+    /// Where `ip` is, if any of the databases know. Private addresses never are.
+    pub fn lookup(&self, ip: IpAddr) -> Option<Location> {
+        #[cfg(feature = "geoip")]
+        {
+            let mut location = Location::default();
+            if let Some(Ok(found)) = self
+                .country
+                .as_ref()
+                .map(|x| x.lookup::<geoip2::Country>(ip))
+            {
+                if let Some(country) = found.country {
+                    location.country_code = country.iso_code.map(str::to_string);
+                    location.country = country
+                        .names
+                        .and_then(|x| x.get("en").map(|x| x.to_string()));
+                }
+            }
+            if let Some(Ok(found)) = self.asn.as_ref().map(|x| x.lookup::<geoip2::Asn>(ip)) {
+                location.asn = found.autonomous_system_number;
+                location.organization = found.autonomous_system_organization.map(str::to_string);
+            }
+            Some(location).filter(|x| *x != Location::default())
+        }
+        #[cfg(not(feature = "geoip"))]
+        {
+            let _ = ip;
+            None
+        }
+    }
+
+    /// `lookup` for a host we've got as a string, which is only an address until there's rDNS.
+    pub fn lookup_host(&self, host: &str) -> Option<Location> {
+        self.lookup(host.parse().ok()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let location = Location {
+            country_code: Some("DE".to_string()),
+            country: Some("Germany".to_string()),
+            asn: Some(3320),
+            organization: Some("Deutsche Telekom AG".to_string()),
+        };
+        assert_eq!(
+            location.to_string(),
+            "Germany (DE), AS3320 Deutsche Telekom AG"
+        );
+        let location = Location {
+            asn: Some(3320),
+            ..Default::default()
+        };
+        assert_eq!(location.to_string(), "AS3320");
+        assert_eq!(GeoIp::default().lookup_host("127.0.0.1"), None);
+    }
+}
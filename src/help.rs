@@ -0,0 +1,370 @@
+//! What HELP knows about. Every command we handle gets an entry in `COMMANDS`, and both the index and the per-command
+//! help come from there, so a new command shows up in HELP as soon as it has one.
+
+/// Help for one command
+#[derive(Debug)]
+pub struct Topic {
+    pub name: &'static str,
+    pub usage: &'static str,
+    /// One line per line of help
+    pub text: &'static [&'static str],
+}
+
+pub const COMMANDS: &[Topic] = &[
+    Topic {
+        name: "ADMIN",
+        usage: "ADMIN [server|nick]",
+        text: &["Shows the server's description and the network it's part of."],
+    },
+    Topic {
+        name: "AUTHENTICATE",
+        usage: "AUTHENTICATE <mechanism|data>",
+        text: &[
+            "SASL login, only EXTERNAL (your TLS certificate) is supported.",
+            "You can log in after connecting too, if you didn't while registering.",
+        ],
+    },
+    Topic {
+        name: "AWAY",
+        usage: "AWAY [:message]",
+        text: &["Marks you away with a message, or back without one."],
+    },
+    Topic {
+        name: "CAP",
+        usage: "CAP <LS|LIST|REQ|END> [capabilities]",
+        text: &["IRCv3 capability negotiation."],
+    },
+    Topic {
+        name: "CHGHOST",
+        usage: "CHGHOST <nick> <host>",
+        text: &[
+            "Changes the host everyone sees someone with, like for a vanity host. Needs the chghost privilege.",
+            "Clients without the chghost cap see them quit and join back.",
+        ],
+    },
+    Topic {
+        name: "DEFCON",
+        usage: "DEFCON [level]",
+        text: &[
+            "Shows or sets the emergency protection level, from 5 for normal down to 1. Needs the defcon privilege.",
+            "4 throttles JOINs, 3 stops new channels, 2 only lets in clients logged into an account, 1 nobody new.",
+            "It goes back up a level by itself after a while.",
+        ],
+    },
+    Topic {
+        name: "DIE",
+        usage: "DIE",
+        text: &["Shuts the server down. Needs the die privilege."],
+    },
+    Topic {
+        name: "DLINE",
+        usage: "DLINE [duration] <ip mask> :<reason>",
+        text: &[
+            "Bans an IP mask from connecting, for a while (like 30m or 2d) or for good.",
+            "Needs the kline privilege.",
+        ],
+    },
+    Topic {
+        name: "ENCAP",
+        usage: "ENCAP <node mask> <subcommand> [params]",
+        text: &[
+            "Sends a command to every node whose id matches the mask, for whatever there understands it.",
+            "Needs the connect privilege.",
+        ],
+    },
+    Topic {
+        name: "FILTER",
+        usage: "FILTER <LIST|ADD|DEL> ...",
+        text: &[
+            "FILTER LIST shows the spam filters.",
+            "FILTER ADD <block|warn|kill> <commands|*> <substring|regex> :<pattern> adds one, for privmsg, part or quit.",
+            "FILTER DEL :<pattern> removes one. Needs the filter privilege.",
+        ],
+    },
+    Topic {
+        name: "GLOBOPS",
+        usage: "GLOBOPS :<message>",
+        text: &["Same as OPERWALL."],
+    },
+    Topic {
+        name: "HELP",
+        usage: "HELP [command]",
+        text: &["Lists the commands, or tells you about one of them."],
+    },
+    Topic {
+        name: "JOIN",
+        usage: "JOIN <channel>[,<channel>...] [keys]",
+        text: &[
+            "Joins one or more channels. JOIN 0 leaves every channel you're in.",
+            "The server might only let opers, or users logged into an account, make new channels.",
+        ],
+    },
+    Topic {
+        name: "KILL",
+        usage: "KILL <nick> :<reason>",
+        text: &["Disconnects someone. Needs the kill privilege."],
+    },
+    Topic {
+        name: "KLINE",
+        usage: "KLINE [duration] <user@host mask> :<reason>",
+        text: &[
+            "Bans a user@host mask, or an extban like $a:account, for a while or for good.",
+            "Needs the kline privilege.",
+        ],
+    },
+    Topic {
+        name: "LANGUAGE",
+        usage: "LANGUAGE [code]",
+        text: &[
+            "Switches the text of server replies to another language, or lists the ones there are.",
+            "Works before registering too, so the welcome can be in it.",
+        ],
+    },
+    Topic {
+        name: "LINKS",
+        usage: "LINKS [[server] mask]",
+        text: &["Lists the servers matching a mask, which is only ever this one."],
+    },
+    Topic {
+        name: "LIST",
+        usage: "LIST [<channel|condition>[,...]]",
+        text: &[
+            "Lists channels with their member counts and topics, or just the ones given.",
+            "T<n and T>n only list channels whose topic changed less or more than n minutes ago.",
+            "Secret channels only show up for their members, and for opers with spy, flagged [+s].",
+            "Listing every channel can only be done every so often, and long lists get cut short, except for opers.",
+        ],
+    },
+    Topic {
+        name: "LUSERS",
+        usage: "LUSERS",
+        text: &["Shows how many users, opers and channels there are."],
+    },
+    Topic {
+        name: "MARKREAD",
+        usage: "MARKREAD <target> [timestamp=<time>]",
+        text: &["Gets or sets your read marker for a channel or nick."],
+    },
+    Topic {
+        name: "MODE",
+        usage: "MODE <target> [modes] [arguments]",
+        text: &[
+            "On a channel: b for bans, q for quiets, s for secret, y a o h v for owner, admin, op, halfop and voice.",
+            "N keeps the channel out of message history, and throws away what was already kept.",
+            "Opers with spy can see the modes and bans of secret channels they aren't in.",
+            "On yourself: B marks you as a bot, s is server notices, which opers can set with letters like +s +ckx.",
+            "Opers can set H to keep their oper status out of WHOIS and STATS p for anyone but other opers.",
+        ],
+    },
+    Topic {
+        name: "MOTD",
+        usage: "MOTD [nick|server]",
+        text: &[
+            "Shows the message of the day, or the one on the node someone is on (or the nodes matching a mask).",
+        ],
+    },
+    Topic {
+        name: "NAMES",
+        usage: "NAMES <channel>[,<channel>...]",
+        text: &["Lists who's in channels, with their status like @ for ops. Secret ones only for their members."],
+    },
+    Topic {
+        name: "NICK",
+        usage: "NICK <nickname>",
+        text: &["Sets or changes your nick."],
+    },
+    Topic {
+        name: "OPER",
+        usage: "OPER <name> <password>",
+        text: &["Logs in as an IRC operator."],
+    },
+    Topic {
+        name: "OPERWALL",
+        usage: "OPERWALL :<message>",
+        text: &["Sends a message to every oper. Opers only."],
+    },
+    Topic {
+        name: "PART",
+        usage: "PART <channel>[,<channel>...] [:<reason>]",
+        text: &["Leaves one or more channels."],
+    },
+    Topic {
+        name: "PASS",
+        usage: "PASS <password>",
+        text: &["Logs into the account named by your username as you register."],
+    },
+    Topic {
+        name: "PING",
+        usage: "PING <token>",
+        text: &["Asks for a PONG back."],
+    },
+    Topic {
+        name: "PONG",
+        usage: "PONG [server] <token>",
+        text: &["Answers the server's PING, which is how it knows your lag."],
+    },
+    Topic {
+        name: "PRIVMSG",
+        usage: "PRIVMSG <target>[,<target>...] :<message>",
+        text: &["Sends a message to channels or nicks."],
+    },
+    Topic {
+        name: "QUIT",
+        usage: "QUIT [:reason]",
+        text: &["Disconnects you."],
+    },
+    Topic {
+        name: "REHASH",
+        usage: "REHASH",
+        text: &["Reloads scripts and plugins. Needs the rehash privilege."],
+    },
+    Topic {
+        name: "RULES",
+        usage: "RULES",
+        text: &["Shows the server's rules."],
+    },
+    Topic {
+        name: "SQUIT",
+        usage: "SQUIT <node id> [:reason]",
+        text: &[
+            "Splits a node off the cluster, leaving it running on its own. Needs the connect privilege.",
+        ],
+    },
+    Topic {
+        name: "STATS",
+        usage: "STATS <query>",
+        text: &[
+            "k and d list K-lines and D-lines (needs the kline privilege), u shows the uptime.",
+            "p lists the opers around and how long they've been idle, for when you need a hand.",
+        ],
+    },
+    Topic {
+        name: "TAGMSG",
+        usage: "TAGMSG <target>[,<target>...]",
+        text: &["Sends nothing but message tags, like typing notifications."],
+    },
+    Topic {
+        name: "TOPIC",
+        usage: "TOPIC <channel> [:topic]",
+        text: &[
+            "Shows a channel's topic, or sets it if you're in the channel. An empty topic clears it.",
+            "TOPICHISTORY shows the ones it had before.",
+        ],
+    },
+    Topic {
+        name: "TOPICHISTORY",
+        usage: "TOPICHISTORY <channel> [RESTORE <n>]",
+        text: &[
+            "Lists a channel's old topics, newest first, with who set them and when.",
+            "Channel ops can put one back with RESTORE and its number, like after an accidental wipe.",
+        ],
+    },
+    Topic {
+        name: "TRACE",
+        usage: "TRACE [nick]",
+        text: &[
+            "Shows connection ids, queued messages and lag for yourself.",
+            "Anyone else, or everyone without a nick, needs the spy privilege.",
+        ],
+    },
+    Topic {
+        name: "UNDLINE",
+        usage: "UNDLINE <ip mask>",
+        text: &["Removes a D-line. Needs the kline privilege."],
+    },
+    Topic {
+        name: "UNKLINE",
+        usage: "UNKLINE <user@host mask>",
+        text: &["Removes a K-line. Needs the kline privilege."],
+    },
+    Topic {
+        name: "USER",
+        usage: "USER <username> 0 * :<realname>",
+        text: &["Sets your username and realname as you register."],
+    },
+    Topic {
+        name: "USERIP",
+        usage: "USERIP <nick>",
+        text: &["Shows the address someone is really connecting from. Needs the spy privilege."],
+    },
+    Topic {
+        name: "WHO",
+        usage: "WHO <channel|nick>",
+        text: &[
+            "Lists a channel's members, or one user. Flags are H here or G away, * oper, B bot, then channel status.",
+            "Big channels get cut short, except for opers.",
+        ],
+    },
+    Topic {
+        name: "WHOIS",
+        usage: "WHOIS [nick|server] <nick>",
+        text: &[
+            "Shows who someone is, where they are and what they're up to.",
+            "WHOIS nick nick asks the node they're on, in a cluster.",
+            "Unless you share a channel, the server might keep their channels, idle time or host to itself.",
+        ],
+    },
+];
+
+/// How many command names go on each line of the index
+const PER_LINE: usize = 8;
+
+/// Help for `command`, ignoring case.
+pub fn topic(command: &str) -> Option<&'static Topic> {
+    COMMANDS
+        .iter()
+        .find(|x| x.name.eq_ignore_ascii_case(command))
+}
+
+/// The list of every command, a few to a line.
+pub fn index() -> Vec<String> {
+    let mut names: Vec<&str> = COMMANDS.iter().map(|x| x.name).collect();
+    names.sort_unstable();
+    names.chunks(PER_LINE).map(|x| x.join(" ")).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message_parse::Command;
+
+    #[test]
+    fn every_topic_is_a_command() {
+        for topic in COMMANDS {
+            let command: std::result::Result<Command, _> = topic.usage.parse();
+            // Usage has placeholders, so only the name has to be recognized
+            if let Ok(Command::UNKNOWN(_) | Command::UNIMPLEMENTED(_)) = command {
+                panic!("{} isn't a command we handle", topic.name);
+            }
+            assert!(topic.usage.starts_with(topic.name));
+        }
+    }
+
+    #[test]
+    fn every_command_has_a_topic() {
+        // Straight from the parser's match, so a command can't be added without HELP knowing
+        let source = include_str!("message_parse.rs");
+        let parser = &source[source.find("fn from_str").unwrap()..];
+        let parser = &parser[..parser.find("\n    }").unwrap()];
+        let names: Vec<&str> = parser
+            .lines()
+            .filter_map(|x| x.strip_prefix("            \""))
+            .filter_map(|x| x.split_once(" =>"))
+            .flat_map(|(names, _)| names.split(" | "))
+            .map(|x| x.trim_matches('"'))
+            // Blank lines
+            .filter(|x| !x.is_empty())
+            .collect();
+        assert!(names.contains(&"PRIVMSG") && names.contains(&"GLOBOPS"));
+        for name in names {
+            assert!(topic(name).is_some(), "{} has no HELP topic", name);
+        }
+    }
+
+    #[test]
+    fn lookup() {
+        assert_eq!(topic("join").unwrap().name, "JOIN");
+        assert!(topic("nope").is_none());
+        assert!(index().iter().any(|x| x.contains("PRIVMSG")));
+    }
+}
@@ -0,0 +1,343 @@
+//! Messages kept after they've been sent, so users can take a copy of theirs with `GET /history` on the HTTP API,
+//! logging in with their account over basic auth. Off unless it's turned on, and only ever kept in memory.
+//!
+//! ```toml
+//! [history]
+//! enabled = true
+//! limit = 1000
+//! # A week, 0 keeps them until `limit` pushes them out
+//! max_age = 604800
+//!
+//! # Channels can keep less, or nothing at all with a limit of 0
+//! [history.channels."#help"]
+//! limit = 100
+//! max_age = 86400
+//! ```
+//!
+//! Channel ops can opt a channel out with `+N`, which throws away what it had too. Messages past their `max_age` are
+//! pruned once a minute.
+//!
+//! Every message remembers which accounts could see it, whoever sent it and whoever was logged in and in the
+//! channel (or being messaged) at the time, so an export only ever has what that account saw.
+
+use crate::{log, server::lock, Shutdown};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// How often messages past their `max_age` are pruned
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    pub enabled: bool,
+    /// Most messages kept per channel or nick, the oldest go first
+    pub limit: usize,
+    /// Seconds messages are kept for, 0 for no limit
+    pub max_age: u64,
+    /// Channels that keep less than everything else
+    pub channels: HashMap<String, Retention>,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            limit: 1000,
+            max_age: 0,
+            channels: HashMap::new(),
+        }
+    }
+}
+
+/// `[history.channels."#chan"]`, anything left out is the same as for every other channel
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Retention {
+    pub limit: Option<usize>,
+    pub max_age: Option<u64>,
+}
+
+impl HistoryConfig {
+    /// The limit and max age for messages to `target`.
+    fn retention(&self, target: &str) -> (usize, u64) {
+        let channel = self
+            .channels
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(target))
+            .map(|(_, x)| x);
+        (
+            channel.and_then(|x| x.limit).unwrap_or(self.limit),
+            channel.and_then(|x| x.max_age).unwrap_or(self.max_age),
+        )
+    }
+}
+
+/// A PRIVMSG to a channel or a nick
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Entry {
+    #[serde(serialize_with = "rfc3339")]
+    pub time: DateTime<Utc>,
+    /// `nick!user@host` of whoever sent it
+    pub source: String,
+    /// The channel or nick it went to
+    pub target: String,
+    pub text: String,
+    /// Accounts that saw it
+    #[serde(skip)]
+    accounts: HashSet<String>,
+    /// Order it was kept in, since more than one can share a `time`
+    #[serde(skip)]
+    seq: u64,
+}
+
+impl Entry {
+    pub fn new(
+        source: &str,
+        target: &str,
+        text: &str,
+        accounts: impl IntoIterator<Item = String>,
+    ) -> Self {
+        Self {
+            time: Utc::now(),
+            source: source.to_string(),
+            target: target.to_string(),
+            text: text.to_string(),
+            accounts: accounts.into_iter().collect(),
+            seq: 0,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Kept {
+    targets: HashMap<String, VecDeque<Entry>>,
+    next_seq: u64,
+}
+
+/// Kept messages by channel or nick. Shared between every connection.
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    kept: Arc<Mutex<Kept>>,
+}
+
+impl History {
+    /// Keeps `entry` if history is on, dropping the oldest message to its target if it's full.
+    pub fn record(&self, config: &HistoryConfig, mut entry: Entry) {
+        let (limit, _) = config.retention(&entry.target);
+        if !config.enabled || limit == 0 {
+            return;
+        }
+        let mut kept = lock(&self.kept);
+        entry.seq = kept.next_seq;
+        kept.next_seq += 1;
+        let target = kept
+            .targets
+            .entry(entry.target.to_ascii_lowercase())
+            .or_default();
+        while target.len() >= limit {
+            target.pop_front();
+        }
+        target.push_back(entry);
+    }
+
+    /// Where history is up to, everything kept after this comes back from `since`.
+    pub fn mark(&self) -> u64 {
+        lock(&self.kept).next_seq
+    }
+
+    /// Everything `account` saw from `mark` on, oldest first.
+    pub fn since(&self, account: &str, mark: u64) -> Vec<Entry> {
+        let mut entries = self.export(account);
+        entries.retain(|x| x.seq >= mark);
+        entries
+    }
+
+    /// Throws away everything kept for `target`.
+    pub fn forget(&self, target: &str) {
+        let mut kept = lock(&self.kept);
+        kept.targets.remove(&target.to_ascii_lowercase());
+    }
+
+    /// Drops messages older than their target's max age as of `now`, returning how many went.
+    pub fn prune(&self, config: &HistoryConfig, now: DateTime<Utc>) -> usize {
+        let mut kept = lock(&self.kept);
+        let mut pruned = 0;
+        kept.targets.retain(|_, entries| {
+            let Some(target) = entries.front().map(|x| x.target.clone()) else {
+                return false;
+            };
+            let (_, max_age) = config.retention(&target);
+            if max_age > 0 {
+                let cutoff = now - chrono::Duration::seconds(max_age as i64);
+                while entries.front().is_some_and(|x| x.time < cutoff) {
+                    entries.pop_front();
+                    pruned += 1;
+                }
+            }
+            !entries.is_empty()
+        });
+        pruned
+    }
+
+    /// Everything `account` saw, oldest first.
+    pub fn export(&self, account: &str) -> Vec<Entry> {
+        let kept = lock(&self.kept);
+        let mut entries: Vec<Entry> = kept
+            .targets
+            .values()
+            .flatten()
+            .filter(|x| x.accounts.contains(account))
+            .cloned()
+            .collect();
+        entries.sort_by_key(|x| x.seq);
+        entries
+    }
+}
+
+fn rfc3339<S: serde::Serializer>(time: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&time.to_rfc3339_opts(SecondsFormat::Millis, true))
+}
+
+/// Prunes `history` every minute until shutdown.
+pub async fn prune(history: History, config: HistoryConfig, mut shutdown: Shutdown) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.recv() => return,
+        }
+        let pruned = history.prune(&config, Utc::now());
+        if pruned > 0 {
+            log::info!("Pruned {} messages from history", pruned);
+        }
+    }
+}
+
+/// `entries` as JSON Lines, one object per message.
+pub fn to_jsonl(entries: &[Entry]) -> String {
+    entries
+        .iter()
+        .map(|x| serde_json::to_string(x).expect("entries always serialize") + "\n")
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exports_what_was_seen() {
+        let config = HistoryConfig {
+            enabled: true,
+            limit: 2,
+            ..Default::default()
+        };
+        let history = History::default();
+        let accounts = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        history.record(
+            &config,
+            Entry::new("a!a@host", "#meow", "first", accounts(&["a"])),
+        );
+        history.record(
+            &config,
+            Entry::new("a!a@host", "#MEOW", "second", accounts(&["a", "tiger"])),
+        );
+        history.record(
+            &config,
+            Entry::new("tiger!t@host", "a", "hi", accounts(&["a", "tiger"])),
+        );
+        // Pushes "first" out
+        history.record(
+            &config,
+            Entry::new("a!a@host", "#meow", "third", accounts(&["a"])),
+        );
+
+        let texts = |account| {
+            history
+                .export(account)
+                .into_iter()
+                .map(|x| x.text)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(texts("tiger"), ["second", "hi"]);
+        assert_eq!(texts("a"), ["second", "hi", "third"]);
+        assert!(texts("nobody").is_empty());
+
+        let jsonl = to_jsonl(&history.export("tiger"));
+        assert_eq!(jsonl.lines().count(), 2);
+        assert!(jsonl.contains(r##""target":"#MEOW","text":"second""##));
+        assert!(!jsonl.contains("accounts"));
+
+        let mark = history.mark();
+        assert!(history.since("tiger", mark).is_empty());
+        history.record(
+            &config,
+            Entry::new("a!a@host", "#meow", "fourth", accounts(&["tiger"])),
+        );
+        let missed: Vec<_> = history.since("tiger", mark);
+        assert_eq!(missed.len(), 1);
+        assert_eq!(missed[0].text, "fourth");
+
+        // Off keeps nothing
+        let history = History::default();
+        history.record(
+            &HistoryConfig::default(),
+            Entry::new("a!a@host", "#meow", "first", accounts(&["a"])),
+        );
+        assert!(history.export("a").is_empty());
+    }
+
+    #[test]
+    fn retention() {
+        let config: HistoryConfig = toml::from_str(
+            r##"
+            enabled = true
+            max_age = 60
+            [channels."#Quick"]
+            max_age = 10
+            [channels."#off"]
+            limit = 0
+            "##,
+        )
+        .unwrap();
+        assert_eq!(config.retention("#quick"), (1000, 10));
+        assert_eq!(config.retention("#off"), (0, 60));
+        assert_eq!(config.retention("tiger"), (1000, 60));
+
+        let history = History::default();
+        for target in ["#quick", "#off", "#slow", "tiger"] {
+            history.record(
+                &config,
+                Entry::new("a!a@host", target, "meow", ["a".to_string()]),
+            );
+        }
+        let targets = || {
+            history
+                .export("a")
+                .into_iter()
+                .map(|x| x.target)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(targets(), ["#quick", "#slow", "tiger"]);
+        let now = Utc::now();
+        assert_eq!(history.prune(&config, now), 0);
+        assert_eq!(
+            history.prune(&config, now + chrono::Duration::seconds(30)),
+            1
+        );
+        assert_eq!(targets(), ["#slow", "tiger"]);
+        history.forget("#SLOW");
+        assert_eq!(targets(), ["tiger"]);
+        assert_eq!(
+            history.prune(&config, now + chrono::Duration::seconds(90)),
+            1
+        );
+        assert!(targets().is_empty());
+    }
+}
@@ -0,0 +1,252 @@
+use crate::{
+    auth::AuthProvider,
+    chanlog::{self, ChannelLogConfig},
+    channel::{self, Channels},
+    event::{Event, EventBus},
+    history::{self, History},
+    log,
+    message_parse::{Command, Message, Side},
+    metrics::Metrics,
+    server::ClientToServerPacket,
+    Result, Shutdown,
+};
+use axum::{
+    extract::{ConnectInfo, Path, State},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderMap, StatusCode,
+    },
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use base64::prelude::*;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Deserialize;
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{net::TcpListener, sync::mpsc};
+
+/// Everything the HTTP handlers need to get messages into the server
+#[derive(Debug, Clone)]
+pub struct ApiState {
+    /// Callers have to send this as a bearer token
+    pub token: String,
+    /// Nick that injected messages come from
+    pub bot: String,
+    /// Connection id reserved for the API so broadcasts treat it like any other sender
+    pub origin: usize,
+    pub server_tx: mpsc::Sender<ClientToServerPacket>,
+    /// When the server started, for the uptime in `/metrics`
+    pub started: DateTime<Utc>,
+    pub metrics: Metrics,
+    /// For member counts in `/metrics`
+    pub channels: Channels,
+    /// Checks the logins for `/history`
+    pub auth: Arc<dyn AuthProvider>,
+    pub history: History,
+    /// For failed logins
+    pub events: EventBus,
+    /// Channel logs to serve, if they're public
+    pub channel_log: Option<ChannelLogConfig>,
+    /// What a channel name can look like, so messages can be posted to the same channels JOIN lets you into
+    pub chantypes: String,
+    pub channel_len: usize,
+}
+
+/// Body of `POST /message`
+#[derive(Debug, Deserialize)]
+struct PostMessage {
+    channel: String,
+    text: String,
+    /// Shown in front of the text, so you can tell which script said what
+    from: Option<String>,
+}
+
+/// Serves the HTTP API on `listener` until the server shuts down.
+pub async fn serve(listener: TcpListener, state: ApiState, mut shutdown: Shutdown) -> Result<()> {
+    let mut app = Router::new()
+        .route("/message", post(post_message))
+        .route("/metrics", get(metrics))
+        .route("/history", get(export_history));
+    if state.channel_log.as_ref().is_some_and(|x| x.public) {
+        app = app
+            .route("/logs/{channel}", get(log_days))
+            .route("/logs/{channel}/{day}", get(log_day));
+    }
+    let app = app.with_state(state);
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(async move { shutdown.recv().await })
+    .await?;
+    Ok(())
+}
+
+/// Prometheus metrics. Secret channels are only counted all together, so no token is needed and any scraper can have
+/// it.
+async fn metrics(State(state): State<ApiState>) -> String {
+    let uptime = (Utc::now() - state.started).num_seconds();
+    format!(
+        "# HELP rust_irc_uptime_seconds How long the server has been running\n\
+         # TYPE rust_irc_uptime_seconds gauge\n\
+         rust_irc_uptime_seconds {}\n{}",
+        uptime,
+        state.metrics.render(&state.channels)
+    )
+}
+
+/// The channel log config if `channel` is one whose logs are public, see `chanlog`.
+fn public_log<'a>(state: &'a ApiState, channel: &str) -> Option<&'a ChannelLogConfig> {
+    state.channel_log.as_ref().filter(|x| x.served(channel))
+}
+
+/// Links to every day `channel` has a log for.
+async fn log_days(State(state): State<ApiState>, Path(channel): Path<String>) -> Response {
+    let Some(config) = public_log(&state, &channel) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let days = chanlog::days(config, &channel).await;
+    if days.is_empty() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    Html(chanlog::index_page(&channel, &days)).into_response()
+}
+
+/// `channel`'s log for one day, `day` being like `2026-10-16`.
+async fn log_day(
+    State(state): State<ApiState>,
+    Path((channel, day)): Path<(String, String)>,
+) -> Response {
+    let Some(config) = public_log(&state, &channel) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let Ok(day) = NaiveDate::parse_from_str(&day, "%Y-%m-%d") else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match chanlog::read(config, &channel, day).await {
+        Some(lines) => Html(chanlog::day_page(&channel, day, &lines)).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Everything the account logging in with basic auth has seen, as JSON Lines, see `history`.
+async fn export_history(
+    State(state): State<ApiState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    let Some((account, password)) = basic_auth(&headers) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    match state.auth.authenticate(&account, &password).await {
+        Ok(true) => {}
+        Ok(false) => {
+            state.events.publish(Event::AuthFailed {
+                account: Some(account),
+                ip: addr.ip().to_string(),
+            });
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        Err(e) => {
+            log::error!("Couldn't check the password for {}: {}", account, e);
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
+    }
+    let body = history::to_jsonl(&state.history.export(&account));
+    ([(CONTENT_TYPE, "application/jsonl")], body).into_response()
+}
+
+/// The account and password from an `Authorization: Basic` header.
+fn basic_auth(headers: &HeaderMap) -> Option<(String, String)> {
+    let encoded = headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(BASE64_STANDARD.decode(encoded).ok()?).ok()?;
+    let (account, password) = decoded.split_once(':')?;
+    Some((account.to_string(), password.to_string()))
+}
+
+/// Compares without stopping at the first difference, so how long it takes doesn't tell anyone how much of their
+/// guess was right.
+fn same_token(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+/// Returns `false` if `text` has a NUL or a CR that isn't part of a CRLF, which `lines()` wouldn't split on.
+fn clean_text(text: &str) -> bool {
+    !text.replace("\r\n", "\n").contains(['\r', '\0'])
+}
+
+/// Injects a PRIVMSG into `channel` from the bot, one message per line of `text`.
+async fn post_message(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(body): Json<PostMessage>,
+) -> StatusCode {
+    let authorized = headers
+        .get(AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .is_some_and(|x| same_token(x, &state.token));
+    if !authorized {
+        return StatusCode::UNAUTHORIZED;
+    }
+    if channel::check_name(&body.channel, &state.chantypes, state.channel_len).is_err() {
+        return StatusCode::BAD_REQUEST;
+    }
+    if body
+        .from
+        .as_ref()
+        .is_some_and(|x| x.contains(['\r', '\n', '\0']))
+        || !clean_text(&body.text)
+    {
+        return StatusCode::BAD_REQUEST;
+    }
+
+    // Anything that looks like a line break becomes its own message, otherwise we'd be letting
+    // HTTP callers write raw IRC.
+    for line in body.text.lines().filter(|x| !x.trim().is_empty()) {
+        let text = match &body.from {
+            Some(from) => format!("<{}> {}", from, line),
+            None => line.to_string(),
+        };
+        let message = Message {
+            tags: None,
+            source: Some(state.bot.clone()),
+            command: Command::PRIVMSG(vec![body.channel.clone()], text),
+            side: Side::Server,
+        };
+        let packet = ClientToServerPacket::BlindBroadcast {
+            origin: state.origin,
+            message,
+        };
+        if state.server_tx.send(packet).await.is_err() {
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+    }
+    StatusCode::NO_CONTENT
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn no_raw_lines() {
+        assert!(same_token("hunter2", "hunter2"));
+        assert!(!same_token("hunter3", "hunter2"));
+        assert!(!same_token("hunter", "hunter2"));
+
+        assert!(clean_text("one\r\ntwo\nthree"));
+        assert!(!clean_text("one\rPRIVMSG #meow :two"));
+        assert!(!clean_text("one\0two"));
+    }
+}
@@ -0,0 +1,92 @@
+//! Who the server says it is: its name, the network it's part of, and a line describing it.
+//!
+//! ```toml
+//! server_name = "irc.example.net"
+//! network = "ExampleNet"
+//! description = "Example's IRC server"
+//! ```
+//! Most of the config is read once at startup, but these are reread on REHASH. They live in an `ArcSwap` that every
+//! reply looks at as it's built, so a rehash shows up in the next message without anything having to restart, and
+//! anything being built while it happens just finishes with the old values.
+
+use crate::config::Config;
+use arc_swap::ArcSwap;
+use std::{net::SocketAddr, sync::Arc};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    /// `None` goes by the address the client connected to
+    pub server_name: Option<String>,
+    pub network: String,
+    pub description: String,
+}
+
+impl Identity {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            server_name: config.server_name.clone(),
+            network: config.network.clone(),
+            description: config.description.clone(),
+        }
+    }
+
+    /// The server's name, as seen by someone who connected to `addr`.
+    pub fn name(&self, addr: SocketAddr) -> String {
+        match &self.server_name {
+            Some(name) => name.clone(),
+            None => addr.ip().to_string(),
+        }
+    }
+}
+
+impl Default for Identity {
+    fn default() -> Self {
+        Self::from_config(&Config::default())
+    }
+}
+
+/// Shared handle to the current `Identity`, cheap to clone into each connection.
+#[derive(Debug, Clone, Default)]
+pub struct LiveIdentity {
+    current: Arc<ArcSwap<Identity>>,
+}
+
+impl LiveIdentity {
+    pub fn new(identity: Identity) -> Self {
+        Self {
+            current: Arc::new(ArcSwap::from_pointee(identity)),
+        }
+    }
+
+    /// What it is right now. Hold on to this while building a message so it's all from the same config.
+    pub fn load(&self) -> Arc<Identity> {
+        self.current.load_full()
+    }
+
+    /// Swaps in `identity` for every connection at once.
+    pub fn store(&self, identity: Identity) {
+        self.current.store(Arc::new(identity));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn swapping() {
+        let live = LiveIdentity::default();
+        let copy = live.clone();
+        let before = live.load();
+        let addr = SocketAddr::from(([10, 0, 0, 1], 6667));
+        assert_eq!(before.name(addr), "10.0.0.1");
+
+        copy.store(Identity {
+            server_name: Some("irc.example.net".to_string()),
+            ..Identity::default()
+        });
+        assert_eq!(live.load().name(addr), "irc.example.net");
+        // Whoever already had the old one keeps it
+        assert_eq!(before.server_name, None);
+    }
+}
@@ -1,16 +1,103 @@
+/// Buffers a line, it goes out with the next `flush`
 macro_rules! format_write {
     ($dst:expr, $($arg:tt)*) => {
-        $dst.write_all(format!($($arg)*).as_bytes()).await?;
-        $dst.flush().await?;
+        $dst.write_bytes(format!($($arg)*).as_bytes()).await?;
     };
 }
 
-use crate::{ClientInfo, Result};
-use std::net::SocketAddr;
+use crate::{
+    ban::{self, Ban, BanKind},
+    catalog::Catalog,
+    channel::{ListEntry, Listing, Status, Topic},
+    geoip::Location,
+    identity::LiveIdentity,
+    message_parse::Message,
+    mode,
+    registry::{Counts, Traced},
+    tags::Wanted,
+    tls, ClientInfo, Result,
+};
+use chrono::{DateTime, Duration, Utc};
+use std::{fmt::Display, io, net::SocketAddr, sync::Arc};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
+    io::{
+        AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter, DuplexStream,
+    },
     net::TcpStream,
 };
+use tokio_rustls::server::TlsStream;
+
+/// The client went away under us, reading from or writing to its socket failed. Handlers pass it up like any other
+/// error, and the connection's task cleans up after it like the client quit, with this as the reason.
+#[derive(Debug)]
+pub struct Disconnected(pub String);
+
+impl Disconnected {
+    fn read(e: io::Error) -> Self {
+        Self(format!("Read error: {}", e))
+    }
+
+    fn write(e: io::Error) -> Self {
+        Self(format!("Write error: {}", e))
+    }
+}
+
+impl Display for Disconnected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Disconnected {}
+
+/// A line serialized once and shared by every connection it goes to, with and without its message tags. Channel
+/// messages are written from here instead of being formatted again for each member.
+#[derive(Debug, Clone)]
+pub struct Line {
+    tagged: Arc<[u8]>,
+    /// With only the `account` tag, for account-tag without message-tags
+    account: Arc<[u8]>,
+    plain: Arc<[u8]>,
+}
+
+impl Line {
+    pub fn new(message: &Message) -> Self {
+        let with = |tags: Option<Vec<String>>| -> Arc<[u8]> {
+            let message = Message {
+                tags,
+                ..message.clone()
+            };
+            format!("{}\r\n", message).into_bytes().into()
+        };
+        let tagged = with(message.tags.clone());
+        let account = match Wanted::Account.keep(message.tags.clone()) {
+            tags if tags == message.tags => tagged.clone(),
+            tags => with(tags),
+        };
+        let plain = match message.tags {
+            Some(_) => with(None),
+            None => tagged.clone(),
+        };
+        Self {
+            tagged,
+            account,
+            plain,
+        }
+    }
+
+    /// The bytes to send to a connection that wants `tags`
+    pub fn bytes(&self, tags: Wanted) -> &[u8] {
+        match tags {
+            Wanted::All => &self.tagged,
+            Wanted::Account => &self.account,
+            Wanted::Nothing => &self.plain,
+        }
+    }
+}
+
+/// Anything we can speak IRC over, plain TCP or TLS
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + Sync + std::fmt::Debug {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync + std::fmt::Debug> Stream for T {}
 
 #[repr(usize)]
 #[derive(Clone, Copy, Debug)]
@@ -21,15 +108,103 @@ enum NumericReply {
     RPL_CREATED = 3,
     RPL_MYINFO = 4,
     RPL_ISUPPORT = 5,
+    RPL_SNOMASK = 8,
+    RPL_TRACEOPERATOR = 204,
+    RPL_TRACEUSER = 205,
+    RPL_STATSKLINE = 216,
+    RPL_ENDOFSTATS = 219,
+    RPL_STATSUPTIME = 242,
+    RPL_STATSDEBUG = 249,
+    RPL_LUSERCLIENT = 251,
+    RPL_LUSEROP = 252,
+    RPL_LUSERCHANNELS = 254,
+    RPL_LUSERME = 255,
+    RPL_ADMINME = 256,
+    RPL_ADMINLOC1 = 257,
+    RPL_ADMINLOC2 = 258,
+    RPL_STATSDLINE = 225,
+    RPL_RULES = 232,
+    RPL_TRACEEND = 262,
+    RPL_TRYAGAIN = 263,
+    RPL_AWAY = 301,
+    RPL_WHOISUSER = 311,
+    RPL_WHOISSERVER = 312,
+    RPL_WHOISOPERATOR = 313,
+    RPL_ENDOFWHO = 315,
+    RPL_WHOISIDLE = 317,
+    RPL_ENDOFWHOIS = 318,
+    RPL_WHOISCHANNELS = 319,
+    RPL_WHOISACCOUNT = 330,
+    RPL_WHOISBOT = 335,
+    RPL_WHOISACTUALLY = 338,
+    RPL_USERIP = 340,
+    RPL_LIST = 322,
+    RPL_LISTEND = 323,
+    RPL_CHANNELMODEIS = 324,
+    RPL_CREATIONTIME = 329,
+    RPL_NOTOPIC = 331,
+    RPL_TOPIC = 332,
+    RPL_TOPICWHOTIME = 333,
+    RPL_WHOISCOUNTRY = 344,
+    RPL_WHOREPLY = 352,
+    RPL_NAMREPLY = 353,
+    RPL_LINKS = 364,
+    RPL_ENDOFLINKS = 365,
+    RPL_ENDOFNAMES = 366,
+    RPL_BANLIST = 367,
+    RPL_ENDOFBANLIST = 368,
+    RPL_UNAWAY = 305,
+    RPL_NOWAWAY = 306,
+    RPL_RULESTART = 308,
+    RPL_ENDOFRULES = 309,
     RPL_MOTDSTART = 375,
     RPL_MOTD = 372,
     RPL_ENDOFMOTD = 376,
+    RPL_YOUREOPER = 381,
+    RPL_REHASHING = 382,
+    RPL_HOSTHIDDEN = 396,
+    ERR_NOSUCHNICK = 401,
+    ERR_NOSUCHSERVER = 402,
+    ERR_NOSUCHCHANNEL = 403,
+    ERR_CANNOTSENDTOCHAN = 404,
+    ERR_INVALIDCAPCMD = 410,
+    ERR_TOOMANYMATCHES = 416,
     ERR_UNKNOWN_COMMAND = 421,
+    ERR_ERRONEUSNICKNAME = 432,
+    ERR_NICKNAMEINUSE = 433,
+    ERR_NORULES = 434,
+    ERR_UNAVAILRESOURCE = 437,
+    ERR_USERNOTINCHANNEL = 441,
+    ERR_NOTONCHANNEL = 442,
+    ERR_PASSWDMISMATCH = 464,
+    ERR_UNKNOWNMODE = 472,
+    ERR_BANNEDFROMCHAN = 474,
+    ERR_BADCHANMASK = 476,
+    ERR_NEEDREGGEDNICK = 477,
+    ERR_NOPRIVILEGES = 481,
+    ERR_CHANOPRIVSNEEDED = 482,
+    ERR_UMODEUNKNOWNFLAG = 501,
+    ERR_USERSDONTMATCH = 502,
+    ERR_HELPNOTFOUND = 524,
+    RPL_YOURLANGUAGE = 687,
+    RPL_HELPSTART = 704,
+    RPL_HELPTXT = 705,
+    RPL_ENDOFHELP = 706,
+    ERR_NOPRIVS = 723,
+    RPL_QUIETLIST = 728,
+    RPL_ENDOFQUIETLIST = 729,
+    RPL_LOGGEDIN = 900,
+    RPL_SASLSUCCESS = 903,
+    ERR_SASLFAIL = 904,
+    ERR_SASLABORTED = 906,
+    ERR_SASLALREADY = 907,
+    RPL_SASLMECHS = 908,
+    ERR_NOLANGUAGE = 982,
 }
 
-impl ToString for NumericReply {
-    fn to_string(&self) -> String {
-        format!("{:0>3}", *self as usize)
+impl std::fmt::Display for NumericReply {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:0>3}", *self as usize)
     }
 }
 
@@ -38,7 +213,30 @@ impl ToString for NumericReply {
 pub struct IrcConnection {
     pub client_addr: SocketAddr,
     pub server_addr: SocketAddr,
-    stream: BufWriter<BufReader<TcpStream>>,
+    /// Set if the client connected over TLS
+    pub tls: bool,
+    /// Set for clients living in this process, see `embed`
+    pub in_process: bool,
+    /// SHA-256 fingerprint of the client's TLS certificate, if it sent one
+    pub certfp: Option<String>,
+    /// Who we say we are, shared with every other connection
+    pub identity: LiveIdentity,
+    /// Where numeric texts come from, see `catalog`
+    pub catalog: Catalog,
+    /// Picked with LANGUAGE, the catalog's default if not
+    pub language: Option<String>,
+    /// What's gone through so far
+    pub traffic: Traffic,
+    stream: BufWriter<BufReader<Box<dyn Stream>>>,
+}
+
+/// How much a connection has sent and been sent
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Traffic {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Lines the client sent
+    pub lines_in: u64,
 }
 
 // Wrapper stuff.
@@ -48,39 +246,122 @@ impl IrcConnection {
         Self {
             client_addr: socket.peer_addr().expect("Client didn't have an address."),
             server_addr: socket.local_addr().expect("Server didn't have an address."),
-            stream: BufWriter::new(BufReader::new(socket)),
+            tls: false,
+            in_process: false,
+            certfp: None,
+            identity: LiveIdentity::default(),
+            catalog: Catalog::default(),
+            language: None,
+            traffic: Traffic::default(),
+            stream: BufWriter::new(BufReader::new(Box::new(socket))),
+        }
+    }
+
+    /// Same as `new`, over a TLS stream that has finished its handshake.
+    pub fn new_tls(stream: TlsStream<TcpStream>) -> Self {
+        let (socket, session) = stream.get_ref();
+        Self {
+            client_addr: socket.peer_addr().expect("Client didn't have an address."),
+            server_addr: socket.local_addr().expect("Server didn't have an address."),
+            tls: true,
+            in_process: false,
+            certfp: session
+                .peer_certificates()
+                .and_then(|x| x.first())
+                .map(|x| tls::fingerprint(x)),
+            identity: LiveIdentity::default(),
+            catalog: Catalog::default(),
+            language: None,
+            traffic: Traffic::default(),
+            stream: BufWriter::new(BufReader::new(Box::new(stream))),
+        }
+    }
+
+    /// A client living in this process, talking over one end of `stream`. It shows up as connecting from
+    /// localhost.
+    pub fn new_virtual(stream: DuplexStream, server_addr: SocketAddr) -> Self {
+        Self {
+            client_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            server_addr,
+            tls: false,
+            in_process: true,
+            certfp: None,
+            identity: LiveIdentity::default(),
+            catalog: Catalog::default(),
+            language: None,
+            traffic: Traffic::default(),
+            stream: BufWriter::new(BufReader::new(Box::new(stream))),
         }
     }
 
+    /// Our name, as this client sees it.
+    pub fn server_name(&self) -> String {
+        self.identity.load().name(self.server_addr)
+    }
+
+    /// The text for `number` in this client's language.
+    fn text(&self, number: NumericReply, args: &[&(dyn Display + Sync)]) -> String {
+        self.catalog
+            .render(self.language.as_deref(), number as u16, args)
+    }
+
+    /// Sends everything written since the last flush. Writes are buffered so a handler's replies go out together,
+    /// this has to happen before waiting on anything.
+    pub async fn flush(&mut self) -> Result<()> {
+        self.stream.flush().await.map_err(Disconnected::write)?;
+        Ok(())
+    }
+
     /// Reads a line if possible, or exits if the stream has closed.
+    /// The line ending is stripped off, writers put it back.
     pub async fn read_line(&mut self) -> Result<Option<String>> {
         let mut buf = String::new();
-        if 0 == self.stream.read_line(&mut buf).await? {
+        let read = self
+            .stream
+            .read_line(&mut buf)
+            .await
+            .map_err(Disconnected::read)?;
+        if read == 0 {
             return Ok(None);
         }
+        self.traffic.bytes_in += read as u64;
+        self.traffic.lines_in += 1;
+        let len = buf.trim_end_matches(&['\r', '\n'][..]).len();
+        buf.truncate(len);
         Ok(Some(buf))
     }
 }
 
 // Private helpers for writing IRC commands to the stream.
 impl IrcConnection {
+    /// Buffers `bytes`, counting them as sent.
+    async fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        self.stream
+            .write_all(bytes)
+            .await
+            .map_err(Disconnected::write)?;
+        self.traffic.bytes_out += bytes.len() as u64;
+        Ok(())
+    }
+
     async fn write_numeric<S: AsRef<str>>(
         &mut self,
         client: &ClientInfo,
         number: NumericReply,
         message: S,
     ) -> Result<()> {
-        let username = if client.username.is_empty() {
+        // Until they've given a nick there's nothing else to call them
+        let nickname = if client.nickname.is_empty() {
             "*"
         } else {
-            client.username.as_str()
+            client.nickname.as_str()
         };
         format_write!(
-            self.stream,
+            self,
             ":{} {} {} {}\r\n",
-            self.server_addr.ip(),
+            self.server_name(),
             number.to_string(),
-            username,
+            nickname,
             message.as_ref()
         );
         Ok(())
@@ -106,42 +387,144 @@ impl IrcConnection {
         reason: S,
     ) -> Result<()> {
         format_write!(
-            self.stream,
+            self,
             ":{} QUIT :{}\r\n",
-            client.username,
+            client.to_canonical(),
             reason.as_ref()
         );
         Ok(())
     }
 
+    /// ERROR is the last thing a connection gets, so this one goes out right away.
     pub async fn write_error<S: AsRef<str>>(&mut self, error: S) -> Result<()> {
-        format_write!(self.stream, "ERROR :{}", error.as_ref());
+        format_write!(self, "ERROR :{}\r\n", error.as_ref());
+        self.flush().await
+    }
+
+    pub async fn write_nick<S: AsRef<str>, T: AsRef<str>>(&mut self, old: S, new: T) -> Result<()> {
+        format_write!(self, ":{} NICK {}\r\n", old.as_ref(), new.as_ref());
         Ok(())
     }
 
-    pub async fn write_registration(&mut self, client: &ClientInfo) -> Result<()> {
-        self.write_numeric_trailer(
+    pub async fn write_join<S: AsRef<str>>(
+        &mut self,
+        client: &ClientInfo,
+        channel: S,
+    ) -> Result<()> {
+        format_write!(
+            self,
+            ":{} JOIN {}\r\n",
+            client.to_canonical(),
+            channel.as_ref()
+        );
+        Ok(())
+    }
+
+    pub async fn write_cap<S: AsRef<str>, T: AsRef<str>>(
+        &mut self,
+        client: &ClientInfo,
+        subcommand: S,
+        caps: T,
+    ) -> Result<()> {
+        let nickname = if client.nickname.is_empty() {
+            "*"
+        } else {
+            client.nickname.as_str()
+        };
+        format_write!(
+            self,
+            ":{} CAP {} {} :{}\r\n",
+            self.server_name(),
+            nickname,
+            subcommand.as_ref(),
+            caps.as_ref()
+        );
+        Ok(())
+    }
+
+    pub async fn write_invalid_cap<S: AsRef<str>>(
+        &mut self,
+        client: &ClientInfo,
+        subcommand: S,
+    ) -> Result<()> {
+        self.write_numeric(
             client,
-            NumericReply::RPL_WELCOME,
+            NumericReply::ERR_INVALIDCAPCMD,
             format!(
-                "Welcome to the Internet Relay Network {}",
-                client.to_canonical(self.server_addr.ip().to_string())
+                "{} :{}",
+                subcommand.as_ref(),
+                self.text(NumericReply::ERR_INVALIDCAPCMD, &[])
             ),
         )
         .await?;
+        Ok(())
+    }
+
+    /// IRCv3 standard reply, see https://ircv3.net/specs/extensions/standard-replies
+    pub async fn write_fail<S: AsRef<str>, T: AsRef<str>, U: AsRef<str>, V: AsRef<str>>(
+        &mut self,
+        command: S,
+        code: T,
+        context: U,
+        description: V,
+    ) -> Result<()> {
+        format_write!(
+            self,
+            ":{} FAIL {} {} {} :{}\r\n",
+            self.server_name(),
+            command.as_ref(),
+            code.as_ref(),
+            context.as_ref(),
+            description.as_ref()
+        );
+        Ok(())
+    }
+
+    /// `timestamp` is `None` when there's no marker set for `target`.
+    pub async fn write_markread<S: AsRef<str>>(
+        &mut self,
+        target: S,
+        timestamp: Option<&str>,
+    ) -> Result<()> {
+        format_write!(
+            self,
+            ":{} MARKREAD {} timestamp={}\r\n",
+            self.server_name(),
+            target.as_ref(),
+            timestamp.unwrap_or("*")
+        );
+        Ok(())
+    }
+
+    /// Welcome burst, `isupport` is any ISUPPORT tokens on top of the ones we always send.
+    pub async fn write_registration(
+        &mut self,
+        client: &ClientInfo,
+        isupport: &[String],
+        started: DateTime<Utc>,
+    ) -> Result<()> {
+        self.write_numeric_trailer(
+            client,
+            NumericReply::RPL_WELCOME,
+            self.text(NumericReply::RPL_WELCOME, &[&client.to_canonical()]),
+        )
+        .await?;
         self.write_numeric_trailer(
             client,
             NumericReply::RPL_YOURHOST,
-            format!(
-                "Your host is {}, running version rust_irc-0.0.0",
-                self.server_addr.ip()
+            self.text(
+                NumericReply::RPL_YOURHOST,
+                &[&self.server_name(), &"rust_irc-0.0.0"],
             ),
         )
         .await?;
         self.write_numeric_trailer(
             client,
             NumericReply::RPL_CREATED,
-            "This server was created... probably 10 seconds ago who cares",
+            self.text(
+                NumericReply::RPL_CREATED,
+                &[&started.format("%a %b %d %Y at %H:%M:%S UTC")],
+            ),
         )
         .await?;
         self.write_numeric(
@@ -149,63 +532,1461 @@ impl IrcConnection {
             NumericReply::RPL_MYINFO,
             format!(
                 "{} {} {} {}",
-                self.server_addr.ip(),
+                self.server_name(),
                 "rust_irc-0.0.0",
-                " ",
-                " "
+                mode::USER.all(),
+                mode::CHANNEL.all()
             ),
         )
         .await?;
         self.write_numeric(
             client,
             NumericReply::RPL_ISUPPORT,
-            "CASEMAPPING=ascii :are available on this server",
+            format!(
+                "CASEMAPPING=ascii {} :{}",
+                isupport.join(" "),
+                self.text(NumericReply::RPL_ISUPPORT, &[])
+            ),
         )
         .await?;
         Ok(())
     }
 
-    pub async fn write_unknown<S: AsRef<str>>(
+    /// How many users, opers and channels there are. This is the only server as far as anyone can tell.
+    pub async fn write_lusers(
         &mut self,
         client: &ClientInfo,
-        command: S,
+        counts: &Counts,
+        channels: usize,
     ) -> Result<()> {
+        self.write_numeric_trailer(
+            client,
+            NumericReply::RPL_LUSERCLIENT,
+            self.text(NumericReply::RPL_LUSERCLIENT, &[&counts.users, &0, &1]),
+        )
+        .await?;
         self.write_numeric(
             client,
-            NumericReply::ERR_UNKNOWN_COMMAND,
-            format!("* {}: Unknown command", command.as_ref()),
+            NumericReply::RPL_LUSEROP,
+            format!(
+                "{} :{}",
+                counts.opers,
+                self.text(NumericReply::RPL_LUSEROP, &[])
+            ),
+        )
+        .await?;
+        self.write_numeric(
+            client,
+            NumericReply::RPL_LUSERCHANNELS,
+            format!(
+                "{} :{}",
+                channels,
+                self.text(NumericReply::RPL_LUSERCHANNELS, &[])
+            ),
+        )
+        .await?;
+        self.write_numeric_trailer(
+            client,
+            NumericReply::RPL_LUSERME,
+            self.text(NumericReply::RPL_LUSERME, &[&counts.connections, &0]),
         )
         .await?;
         Ok(())
     }
 
-    pub async fn write_pong<S: AsRef<str>>(&mut self, discrimator: S) -> Result<()> {
-        format_write!(
-            self.stream,
-            "PONG {} {}",
-            self.server_addr.ip(),
-            discrimator.as_ref()
-        );
+    /// ADMIN, which is just who we are: the description and the network.
+    pub async fn write_admin(&mut self, client: &ClientInfo) -> Result<()> {
+        let identity = self.identity.load();
+        let name = identity.name(self.server_addr);
+        self.write_numeric(
+            client,
+            NumericReply::RPL_ADMINME,
+            format!("{} :{}", name, self.text(NumericReply::RPL_ADMINME, &[])),
+        )
+        .await?;
+        self.write_numeric_trailer(client, NumericReply::RPL_ADMINLOC1, &identity.description)
+            .await?;
+        self.write_numeric_trailer(client, NumericReply::RPL_ADMINLOC2, &identity.network)
+            .await?;
         Ok(())
     }
 
-    pub async fn write_motd(&mut self, client: &ClientInfo) -> Result<()> {
+    /// LINKS, listing just us if we match `mask`.
+    pub async fn write_links(&mut self, client: &ClientInfo, mask: &str) -> Result<()> {
+        let identity = self.identity.load();
+        let name = identity.name(self.server_addr);
+        if ban::glob_match(mask, &name) {
+            self.write_numeric(
+                client,
+                NumericReply::RPL_LINKS,
+                format!("{} {} :0 {}", name, name, identity.description),
+            )
+            .await?;
+        }
         self.write_numeric(
             client,
-            NumericReply::RPL_MOTDSTART,
-            format!("- {} Message of the day - ", self.server_addr.ip()),
+            NumericReply::RPL_ENDOFLINKS,
+            format!("{} :{}", mask, self.text(NumericReply::RPL_ENDOFLINKS, &[])),
         )
         .await?;
-        self.write_numeric(client, NumericReply::RPL_MOTD, "- Hi from Rust-IRC!")
+        Ok(())
+    }
+
+    pub async fn write_away_status(&mut self, client: &ClientInfo) -> Result<()> {
+        let number = match client.away {
+            Some(_) => NumericReply::RPL_NOWAWAY,
+            None => NumericReply::RPL_UNAWAY,
+        };
+        self.write_numeric_trailer(client, number, self.text(number, &[]))
             .await?;
-        self.write_numeric(client, NumericReply::RPL_MOTD, "End of /MOTD command")
+        Ok(())
+    }
+
+    pub async fn write_notice<S: AsRef<str>>(
+        &mut self,
+        client: &ClientInfo,
+        text: S,
+    ) -> Result<()> {
+        // Notices can go out before the client has a nick
+        let nickname = match client.nickname.is_empty() {
+            true => "*",
+            false => client.nickname.as_str(),
+        };
+        format_write!(
+            self,
+            ":{} NOTICE {} :{}\r\n",
+            self.server_name(),
+            nickname,
+            text.as_ref()
+        );
+        Ok(())
+    }
+
+    /// A notice from one of the services built into the server, like `nickserv`
+    pub async fn write_service_notice(
+        &mut self,
+        client: &ClientInfo,
+        service: &str,
+        text: &str,
+    ) -> Result<()> {
+        format_write!(
+            self,
+            ":{}!{}@{} NOTICE {} :{}\r\n",
+            service,
+            service,
+            self.server_name(),
+            client.nickname,
+            text
+        );
+        Ok(())
+    }
+
+    /// A server notice for opers, see `snomask`
+    pub async fn write_server_notice(&mut self, client: &ClientInfo, text: &str) -> Result<()> {
+        self.write_notice(client, format!("*** Notice -- {}", text))
             .await?;
         Ok(())
     }
 
-    /// SAFETY: You have to end `message` with a \r\n or bad shit will happen.
-    pub async unsafe fn write_raw<S: AsRef<str>>(&mut self, message: S) -> Result<()> {
-        format_write!(self.stream, "{}", message.as_ref());
+    pub async fn write_snomask(&mut self, client: &ClientInfo, masks: &str) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::RPL_SNOMASK,
+            format!("{} :{}", masks, self.text(NumericReply::RPL_SNOMASK, &[])),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_unknown_umode(&mut self, client: &ClientInfo) -> Result<()> {
+        self.write_numeric_trailer(
+            client,
+            NumericReply::ERR_UMODEUNKNOWNFLAG,
+            self.text(NumericReply::ERR_UMODEUNKNOWNFLAG, &[]),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_users_dont_match(&mut self, client: &ClientInfo) -> Result<()> {
+        self.write_numeric_trailer(
+            client,
+            NumericReply::ERR_USERSDONTMATCH,
+            self.text(NumericReply::ERR_USERSDONTMATCH, &[]),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_youreoper(&mut self, client: &ClientInfo) -> Result<()> {
+        self.write_numeric_trailer(
+            client,
+            NumericReply::RPL_YOUREOPER,
+            self.text(NumericReply::RPL_YOUREOPER, &[]),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_rehashing<S: AsRef<str>>(
+        &mut self,
+        client: &ClientInfo,
+        file: S,
+    ) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::RPL_REHASHING,
+            format!(
+                "{} :{}",
+                file.as_ref(),
+                self.text(NumericReply::RPL_REHASHING, &[])
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_password_mismatch(&mut self, client: &ClientInfo) -> Result<()> {
+        self.write_numeric_trailer(
+            client,
+            NumericReply::ERR_PASSWDMISMATCH,
+            self.text(NumericReply::ERR_PASSWDMISMATCH, &[]),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_no_privileges(&mut self, client: &ClientInfo) -> Result<()> {
+        self.write_numeric_trailer(
+            client,
+            NumericReply::ERR_NOPRIVILEGES,
+            self.text(NumericReply::ERR_NOPRIVILEGES, &[]),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_no_privs<S: AsRef<str>>(
+        &mut self,
+        client: &ClientInfo,
+        privilege: S,
+    ) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::ERR_NOPRIVS,
+            format!(
+                "{} :{}",
+                privilege.as_ref(),
+                self.text(NumericReply::ERR_NOPRIVS, &[])
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_nick_in_use(&mut self, client: &ClientInfo, nick: &str) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::ERR_NICKNAMEINUSE,
+            format!(
+                "{} :{}",
+                nick,
+                self.text(NumericReply::ERR_NICKNAMEINUSE, &[])
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Continues a SASL exchange, `+` for an empty challenge
+    pub async fn write_authenticate(&mut self, payload: &str) -> Result<()> {
+        format_write!(self, "AUTHENTICATE {}\r\n", payload);
+        Ok(())
+    }
+
+    /// RPL_LOGGEDIN followed by RPL_SASLSUCCESS
+    pub async fn write_sasl_success(&mut self, client: &ClientInfo, account: &str) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::RPL_LOGGEDIN,
+            format!(
+                "{} {} :{}",
+                client.to_canonical(),
+                account,
+                self.text(NumericReply::RPL_LOGGEDIN, &[&account])
+            ),
+        )
+        .await?;
+        self.write_numeric_trailer(
+            client,
+            NumericReply::RPL_SASLSUCCESS,
+            self.text(NumericReply::RPL_SASLSUCCESS, &[]),
+        )
+        .await?;
         Ok(())
     }
+
+    pub async fn write_sasl_fail(&mut self, client: &ClientInfo) -> Result<()> {
+        self.write_numeric_trailer(
+            client,
+            NumericReply::ERR_SASLFAIL,
+            self.text(NumericReply::ERR_SASLFAIL, &[]),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_sasl_aborted(&mut self, client: &ClientInfo) -> Result<()> {
+        self.write_numeric_trailer(
+            client,
+            NumericReply::ERR_SASLABORTED,
+            self.text(NumericReply::ERR_SASLABORTED, &[]),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_sasl_already(&mut self, client: &ClientInfo) -> Result<()> {
+        self.write_numeric_trailer(
+            client,
+            NumericReply::ERR_SASLALREADY,
+            self.text(NumericReply::ERR_SASLALREADY, &[]),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_sasl_mechs(&mut self, client: &ClientInfo, mechanisms: &str) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::RPL_SASLMECHS,
+            format!(
+                "{} :{}",
+                mechanisms,
+                self.text(NumericReply::RPL_SASLMECHS, &[])
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Tells the client that `nick` is away, after messaging them.
+    pub async fn write_away(
+        &mut self,
+        client: &ClientInfo,
+        nick: &str,
+        message: &str,
+    ) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::RPL_AWAY,
+            format!("{} :{}", nick, message),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// WHOIS for `target`, `channels` already have their status prefixes. `location` is only for opers.
+    /// WHOIS, with RPL_WHOISACTUALLY if `real` says whoever asked gets to see the real address
+    pub async fn write_whois(
+        &mut self,
+        client: &ClientInfo,
+        target: &ClientInfo,
+        channels: &[String],
+        location: Option<&Location>,
+        real: bool,
+        idle: Option<std::time::Duration>,
+    ) -> Result<()> {
+        let nick = &target.nickname;
+        self.write_numeric(
+            client,
+            NumericReply::RPL_WHOISUSER,
+            format!(
+                "{} {} {} * :{}",
+                nick, target.username, target.host, target.realname
+            ),
+        )
+        .await?;
+        if !channels.is_empty() {
+            self.write_numeric(
+                client,
+                NumericReply::RPL_WHOISCHANNELS,
+                format!("{} :{}", nick, channels.join(" ")),
+            )
+            .await?;
+        }
+        self.write_numeric(
+            client,
+            NumericReply::RPL_WHOISSERVER,
+            format!(
+                "{} {} :{}",
+                nick,
+                self.server_name(),
+                self.identity.load().description
+            ),
+        )
+        .await?;
+        if let Some(away) = &target.away {
+            self.write_away(client, nick, away).await?;
+        }
+        if target.oper.is_some() {
+            self.write_numeric(
+                client,
+                NumericReply::RPL_WHOISOPERATOR,
+                format!(
+                    "{} :{}",
+                    nick,
+                    self.text(NumericReply::RPL_WHOISOPERATOR, &[])
+                ),
+            )
+            .await?;
+        }
+        if let Some(account) = &target.account {
+            self.write_numeric(
+                client,
+                NumericReply::RPL_WHOISACCOUNT,
+                format!(
+                    "{} {} :{}",
+                    nick,
+                    account,
+                    self.text(NumericReply::RPL_WHOISACCOUNT, &[])
+                ),
+            )
+            .await?;
+        }
+        if target.bot {
+            self.write_numeric(
+                client,
+                NumericReply::RPL_WHOISBOT,
+                format!("{} :{}", nick, self.text(NumericReply::RPL_WHOISBOT, &[])),
+            )
+            .await?;
+        }
+        if let Some(idle) = idle {
+            self.write_numeric(
+                client,
+                NumericReply::RPL_WHOISIDLE,
+                format!(
+                    "{} {} {} :{}",
+                    nick,
+                    idle.as_secs(),
+                    target.signon,
+                    self.text(NumericReply::RPL_WHOISIDLE, &[])
+                ),
+            )
+            .await?;
+        }
+        if real {
+            self.write_numeric(
+                client,
+                NumericReply::RPL_WHOISACTUALLY,
+                format!(
+                    "{} {}@{} {} :{}",
+                    nick,
+                    target.username,
+                    target.host,
+                    target.ip,
+                    self.text(NumericReply::RPL_WHOISACTUALLY, &[])
+                ),
+            )
+            .await?;
+        }
+        if let Some(location) = location {
+            self.write_numeric(
+                client,
+                NumericReply::RPL_WHOISCOUNTRY,
+                format!(
+                    "{} {} :{}",
+                    nick,
+                    location.country_code.as_deref().unwrap_or("*"),
+                    self.text(NumericReply::RPL_WHOISCOUNTRY, &[location])
+                ),
+            )
+            .await?;
+        }
+        self.write_numeric(
+            client,
+            NumericReply::RPL_ENDOFWHOIS,
+            format!("{} :{}", nick, self.text(NumericReply::RPL_ENDOFWHOIS, &[])),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// One line of WHO, `status` is what `target` is in `channel` if it's a channel.
+    pub async fn write_who(
+        &mut self,
+        client: &ClientInfo,
+        channel: &str,
+        target: &ClientInfo,
+        status: Option<Status>,
+    ) -> Result<()> {
+        let mut flags = String::from(if target.away.is_some() { "G" } else { "H" });
+        if target.oper.is_some() {
+            flags.push('*');
+        }
+        if target.bot {
+            flags.push('B');
+        }
+        flags.extend(status.and_then(|x| x.prefix()));
+        self.write_numeric(
+            client,
+            NumericReply::RPL_WHOREPLY,
+            format!(
+                "{} {} {} {} {} {} :0 {}",
+                channel,
+                target.username,
+                target.host,
+                self.server_name(),
+                target.nickname,
+                flags,
+                target.realname
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_who_end(&mut self, client: &ClientInfo, mask: &str) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::RPL_ENDOFWHO,
+            format!("{} :{}", mask, self.text(NumericReply::RPL_ENDOFWHO, &[])),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Tells the client its own user modes changed, `change` being like `+B`.
+    pub async fn write_user_mode(&mut self, client: &ClientInfo, change: &str) -> Result<()> {
+        format_write!(
+            self,
+            ":{} MODE {} :{}\r\n",
+            client.nickname,
+            client.nickname,
+            change
+        );
+        Ok(())
+    }
+
+    /// USERIP for one user, `nick*=-user@ip` with the `*` for opers and `-` for away
+    pub async fn write_userip(&mut self, client: &ClientInfo, target: &ClientInfo) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::RPL_USERIP,
+            format!(
+                ":{}{}={}{}@{}",
+                target.nickname,
+                if target.oper.is_some() { "*" } else { "" },
+                if target.away.is_some() { "-" } else { "+" },
+                target.username,
+                target.ip
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_no_such_nick(&mut self, client: &ClientInfo, nick: &str) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::ERR_NOSUCHNICK,
+            format!("{} :{}", nick, self.text(NumericReply::ERR_NOSUCHNICK, &[])),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Our host is `host` now, for CHGHOST.
+    pub async fn write_host_hidden(&mut self, client: &ClientInfo, host: &str) -> Result<()> {
+        let text = self.text(NumericReply::RPL_HOSTHIDDEN, &[]);
+        self.write_numeric(
+            client,
+            NumericReply::RPL_HOSTHIDDEN,
+            format!("{} :{}", host, text),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// `command` was refused for now, like a LIST of everything too soon after the last one.
+    pub async fn write_try_again(&mut self, client: &ClientInfo, command: &str) -> Result<()> {
+        let text = self.text(NumericReply::RPL_TRYAGAIN, &[]);
+        self.write_numeric(
+            client,
+            NumericReply::RPL_TRYAGAIN,
+            format!("{} :{}", command, text),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// The reply to `command` was cut short.
+    pub async fn write_too_many_matches(
+        &mut self,
+        client: &ClientInfo,
+        command: &str,
+    ) -> Result<()> {
+        let text = self.text(NumericReply::ERR_TOOMANYMATCHES, &[]);
+        self.write_numeric(
+            client,
+            NumericReply::ERR_TOOMANYMATCHES,
+            format!("{} :{}", command, text),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_no_such_server(&mut self, client: &ClientInfo, server: &str) -> Result<()> {
+        let text = self.text(NumericReply::ERR_NOSUCHSERVER, &[]);
+        self.write_numeric(
+            client,
+            NumericReply::ERR_NOSUCHSERVER,
+            format!("{} :{}", server, text),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_no_such_channel(
+        &mut self,
+        client: &ClientInfo,
+        channel: &str,
+    ) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::ERR_NOSUCHCHANNEL,
+            format!(
+                "{} :{}",
+                channel,
+                self.text(NumericReply::ERR_NOSUCHCHANNEL, &[])
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// The topic of `channel` with who set it and when, or RPL_NOTOPIC if it hasn't got one.
+    pub async fn write_topic(
+        &mut self,
+        client: &ClientInfo,
+        channel: &str,
+        topic: Option<&Topic>,
+    ) -> Result<()> {
+        let topic = match topic {
+            Some(topic) => topic,
+            None => {
+                self.write_numeric(
+                    client,
+                    NumericReply::RPL_NOTOPIC,
+                    format!("{} :{}", channel, self.text(NumericReply::RPL_NOTOPIC, &[])),
+                )
+                .await?;
+                return Ok(());
+            }
+        };
+        self.write_numeric(
+            client,
+            NumericReply::RPL_TOPIC,
+            format!("{} :{}", channel, topic.text),
+        )
+        .await?;
+        self.write_numeric(
+            client,
+            NumericReply::RPL_TOPICWHOTIME,
+            format!("{} {} {}", channel, topic.set_by, topic.set_at),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Who's in `channel`, `names` already having their status prefixes, over as many lines as it takes.
+    pub async fn write_names(
+        &mut self,
+        client: &ClientInfo,
+        channel: &str,
+        secret: bool,
+        names: &[String],
+    ) -> Result<()> {
+        let symbol = if secret { '@' } else { '=' };
+        let mut lines: Vec<String> = Vec::new();
+        for name in names {
+            match lines.last_mut() {
+                Some(line) if line.len() + 1 + name.len() <= 400 => {
+                    line.push(' ');
+                    line.push_str(name);
+                }
+                _ => lines.push(name.clone()),
+            }
+        }
+        for line in lines {
+            self.write_numeric(
+                client,
+                NumericReply::RPL_NAMREPLY,
+                format!("{} {} :{}", symbol, channel, line),
+            )
+            .await?;
+        }
+        self.write_numeric(
+            client,
+            NumericReply::RPL_ENDOFNAMES,
+            format!(
+                "{} :{}",
+                channel,
+                self.text(NumericReply::RPL_ENDOFNAMES, &[])
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_not_on_channel(&mut self, client: &ClientInfo, channel: &str) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::ERR_NOTONCHANNEL,
+            format!(
+                "{} :{}",
+                channel,
+                self.text(NumericReply::ERR_NOTONCHANNEL, &[])
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// One RPL_LIST, LIST writes these then `write_list_end`
+    pub async fn write_list(&mut self, client: &ClientInfo, channel: &Listing) -> Result<()> {
+        let topic = channel.topic.as_ref().map_or("", |x| x.text.as_str());
+        // Flagged the way other servers do, for the members and opers who get to see it
+        let flag = if channel.secret { "[+s] " } else { "" };
+        self.write_numeric(
+            client,
+            NumericReply::RPL_LIST,
+            format!("{} {} :{}{}", channel.name, channel.members, flag, topic),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_list_end(&mut self, client: &ClientInfo) -> Result<()> {
+        let text = self.text(NumericReply::RPL_LISTEND, &[]);
+        self.write_numeric_trailer(client, NumericReply::RPL_LISTEND, text)
+            .await?;
+        Ok(())
+    }
+
+    /// Answers a bare `MODE #chan`, with RPL_CHANNELMODEIS then RPL_CREATIONTIME.
+    pub async fn write_channel_modes(
+        &mut self,
+        client: &ClientInfo,
+        channel: &str,
+        modes: &str,
+        created: i64,
+    ) -> Result<()> {
+        // Lists and statuses aren't shown here
+        self.write_numeric(
+            client,
+            NumericReply::RPL_CHANNELMODEIS,
+            format!("{} {}", channel, modes),
+        )
+        .await?;
+        self.write_numeric(
+            client,
+            NumericReply::RPL_CREATIONTIME,
+            format!("{} {}", channel, created),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_cannot_send(&mut self, client: &ClientInfo, channel: &str) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::ERR_CANNOTSENDTOCHAN,
+            format!(
+                "{} :{}",
+                channel,
+                self.text(NumericReply::ERR_CANNOTSENDTOCHAN, &[])
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_unknown_mode(&mut self, client: &ClientInfo, mode: char) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::ERR_UNKNOWNMODE,
+            format!(
+                "{} :{}",
+                mode,
+                self.text(NumericReply::ERR_UNKNOWNMODE, &[])
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_not_chanop(&mut self, client: &ClientInfo, channel: &str) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::ERR_CHANOPRIVSNEEDED,
+            format!(
+                "{} :{}",
+                channel,
+                self.text(NumericReply::ERR_CHANOPRIVSNEEDED, &[])
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_not_in_channel(
+        &mut self,
+        client: &ClientInfo,
+        nick: &str,
+        channel: &str,
+    ) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::ERR_USERNOTINCHANNEL,
+            format!(
+                "{} {} :{}",
+                nick,
+                channel,
+                self.text(NumericReply::ERR_USERNOTINCHANNEL, &[])
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// The ban list of `channel`, one RPL_BANLIST per entry then RPL_ENDOFBANLIST
+    pub async fn write_ban_list(
+        &mut self,
+        client: &ClientInfo,
+        channel: &str,
+        bans: &[ListEntry],
+    ) -> Result<()> {
+        for ban in bans {
+            self.write_numeric(
+                client,
+                NumericReply::RPL_BANLIST,
+                format!("{} {} {} {}", channel, ban.mask, ban.set_by, ban.set_at),
+            )
+            .await?;
+        }
+        self.write_numeric(
+            client,
+            NumericReply::RPL_ENDOFBANLIST,
+            format!(
+                "{} :{}",
+                channel,
+                self.text(NumericReply::RPL_ENDOFBANLIST, &[])
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// The quiet list of `channel`, one RPL_QUIETLIST per entry then RPL_ENDOFQUIETLIST
+    pub async fn write_quiet_list(
+        &mut self,
+        client: &ClientInfo,
+        channel: &str,
+        quiets: &[ListEntry],
+    ) -> Result<()> {
+        for quiet in quiets {
+            self.write_numeric(
+                client,
+                NumericReply::RPL_QUIETLIST,
+                format!(
+                    "{} q {} {} {}",
+                    channel, quiet.mask, quiet.set_by, quiet.set_at
+                ),
+            )
+            .await?;
+        }
+        self.write_numeric(
+            client,
+            NumericReply::RPL_ENDOFQUIETLIST,
+            format!(
+                "{} q :{}",
+                channel,
+                self.text(NumericReply::RPL_ENDOFQUIETLIST, &[])
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// One line of STATS k or d, `<K|D> <mask> <expires> <set by> :<reason>`. Permanent bans expire at 0.
+    pub async fn write_stats_ban(&mut self, client: &ClientInfo, ban: &Ban) -> Result<()> {
+        let (number, letter) = match ban.kind {
+            BanKind::Kline => (NumericReply::RPL_STATSKLINE, "K"),
+            BanKind::Dline => (NumericReply::RPL_STATSDLINE, "D"),
+        };
+        self.write_numeric(
+            client,
+            number,
+            format!(
+                "{} {} {} {} :{}",
+                letter,
+                ban.mask,
+                ban.expires.unwrap_or(0),
+                ban.set_by,
+                ban.reason
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// One line of STATS p, an oper and how long since they last said anything
+    pub async fn write_stats_oper(
+        &mut self,
+        client: &ClientInfo,
+        oper: &ClientInfo,
+        idle: Option<std::time::Duration>,
+    ) -> Result<()> {
+        let idle = idle.map_or("-".to_string(), |x| x.as_secs().to_string());
+        self.write_numeric(
+            client,
+            NumericReply::RPL_STATSDEBUG,
+            format!(
+                "p :{} ({}@{}) Idle: {}",
+                oper.nickname, oper.username, oper.host, idle
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// STATS u, how long we've been up
+    pub async fn write_stats_uptime(
+        &mut self,
+        client: &ClientInfo,
+        uptime: Duration,
+    ) -> Result<()> {
+        let seconds = uptime.num_seconds();
+        self.write_numeric_trailer(
+            client,
+            NumericReply::RPL_STATSUPTIME,
+            self.text(
+                NumericReply::RPL_STATSUPTIME,
+                &[
+                    &(seconds / 86400),
+                    &(seconds / 3600 % 24),
+                    &(seconds / 60 % 60),
+                    &(seconds % 60),
+                ],
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// One line of TRACE, for a connection of a registered user
+    pub async fn write_trace(&mut self, client: &ClientInfo, traced: &Traced) -> Result<()> {
+        let (number, class) = if traced.info.oper.is_some() {
+            (NumericReply::RPL_TRACEOPERATOR, "Oper")
+        } else {
+            (NumericReply::RPL_TRACEUSER, "User")
+        };
+        self.write_numeric(
+            client,
+            number,
+            format!(
+                "{} users {}[{}] :id {} sendq {} lag {}",
+                class,
+                traced.info.nickname,
+                traced.info.ip,
+                traced.id,
+                traced.sendq,
+                traced
+                    .lag
+                    .map_or("?".to_string(), |x| format!("{}ms", x.as_millis()))
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_trace_end(&mut self, client: &ClientInfo) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::RPL_TRACEEND,
+            format!(
+                "{} rust_irc-0.0.0 :{}",
+                self.server_name(),
+                self.text(NumericReply::RPL_TRACEEND, &[])
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// HELP on `subject`, starting with `first` and then a line for each of `lines`
+    pub async fn write_help(
+        &mut self,
+        client: &ClientInfo,
+        subject: &str,
+        first: &str,
+        lines: &[String],
+    ) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::RPL_HELPSTART,
+            format!("{} :{}", subject, first),
+        )
+        .await?;
+        for line in lines {
+            self.write_numeric(
+                client,
+                NumericReply::RPL_HELPTXT,
+                format!("{} :{}", subject, line),
+            )
+            .await?;
+        }
+        self.write_numeric(
+            client,
+            NumericReply::RPL_ENDOFHELP,
+            format!(
+                "{} :{}",
+                subject,
+                self.text(NumericReply::RPL_ENDOFHELP, &[])
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_help_not_found(&mut self, client: &ClientInfo, subject: &str) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::ERR_HELPNOTFOUND,
+            format!(
+                "{} :{}",
+                subject,
+                self.text(NumericReply::ERR_HELPNOTFOUND, &[])
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_end_of_stats<S: AsRef<str>>(
+        &mut self,
+        client: &ClientInfo,
+        query: S,
+    ) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::RPL_ENDOFSTATS,
+            format!(
+                "{} :{}",
+                query.as_ref(),
+                self.text(NumericReply::RPL_ENDOFSTATS, &[])
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_erroneous_nick<S: AsRef<str>>(
+        &mut self,
+        client: &ClientInfo,
+        nickname: S,
+    ) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::ERR_ERRONEUSNICKNAME,
+            format!(
+                "{} :{}",
+                nickname.as_ref(),
+                self.text(NumericReply::ERR_ERRONEUSNICKNAME, &[])
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_cannot_join<S: AsRef<str>>(
+        &mut self,
+        client: &ClientInfo,
+        channel: S,
+    ) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::ERR_BANNEDFROMCHAN,
+            format!(
+                "{} :{}",
+                channel.as_ref(),
+                self.text(NumericReply::ERR_BANNEDFROMCHAN, &[])
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// A channel DEFCON is keeping us out of for now
+    pub async fn write_unavailable(&mut self, client: &ClientInfo, channel: &str) -> Result<()> {
+        let text = self.text(NumericReply::ERR_UNAVAILRESOURCE, &[]);
+        self.write_numeric(
+            client,
+            NumericReply::ERR_UNAVAILRESOURCE,
+            format!("{} :{}", channel, text),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_need_account(&mut self, client: &ClientInfo, channel: &str) -> Result<()> {
+        let text = self.text(NumericReply::ERR_NEEDREGGEDNICK, &[]);
+        self.write_numeric(
+            client,
+            NumericReply::ERR_NEEDREGGEDNICK,
+            format!("{} :{}", channel, text),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_bad_chan_mask<S: AsRef<str>>(
+        &mut self,
+        client: &ClientInfo,
+        channel: S,
+    ) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::ERR_BADCHANMASK,
+            format!(
+                "{} :{}",
+                channel.as_ref(),
+                self.text(NumericReply::ERR_BADCHANMASK, &[])
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_unknown<S: AsRef<str>>(
+        &mut self,
+        client: &ClientInfo,
+        command: S,
+    ) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::ERR_UNKNOWN_COMMAND,
+            format!(
+                "* {}: {}",
+                command.as_ref(),
+                self.text(NumericReply::ERR_UNKNOWN_COMMAND, &[])
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_ping(&mut self, token: &str) -> Result<()> {
+        format_write!(self, ":{} PING :{}\r\n", self.server_name(), token);
+        Ok(())
+    }
+
+    pub async fn write_pong<S: AsRef<str>>(&mut self, discrimator: S) -> Result<()> {
+        let name = self.server_name();
+        format_write!(self, ":{} PONG {} {}\r\n", name, name, discrimator.as_ref());
+        Ok(())
+    }
+
+    pub async fn write_motd(&mut self, client: &ClientInfo, motd: &str) -> Result<()> {
+        self.write_numeric_trailer(
+            client,
+            NumericReply::RPL_MOTDSTART,
+            self.text(NumericReply::RPL_MOTDSTART, &[&self.server_name()]),
+        )
+        .await?;
+        for line in motd.lines() {
+            self.write_numeric_trailer(client, NumericReply::RPL_MOTD, format!("- {}", line))
+                .await?;
+        }
+        let text = self.text(NumericReply::RPL_ENDOFMOTD, &[]);
+        self.write_numeric_trailer(client, NumericReply::RPL_ENDOFMOTD, text)
+            .await?;
+        Ok(())
+    }
+
+    /// RULES, or ERR_NORULES if there aren't any
+    pub async fn write_rules(&mut self, client: &ClientInfo, rules: Option<&str>) -> Result<()> {
+        let Some(rules) = rules else {
+            let text = self.text(NumericReply::ERR_NORULES, &[]);
+            return self
+                .write_numeric_trailer(client, NumericReply::ERR_NORULES, text)
+                .await;
+        };
+        self.write_numeric_trailer(
+            client,
+            NumericReply::RPL_RULESTART,
+            self.text(NumericReply::RPL_RULESTART, &[&self.server_name()]),
+        )
+        .await?;
+        for line in rules.lines() {
+            self.write_numeric_trailer(client, NumericReply::RPL_RULES, format!("- {}", line))
+                .await?;
+        }
+        let text = self.text(NumericReply::RPL_ENDOFRULES, &[]);
+        self.write_numeric_trailer(client, NumericReply::RPL_ENDOFRULES, text)
+            .await?;
+        Ok(())
+    }
+
+    /// LANGUAGE worked, `language` being what numerics will be in from now on
+    pub async fn write_your_language(&mut self, client: &ClientInfo, language: &str) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::RPL_YOURLANGUAGE,
+            format!(
+                "{} :{}",
+                language,
+                self.text(NumericReply::RPL_YOURLANGUAGE, &[])
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write_no_language(&mut self, client: &ClientInfo, language: &str) -> Result<()> {
+        self.write_numeric(
+            client,
+            NumericReply::ERR_NOLANGUAGE,
+            format!(
+                "{} :{}",
+                language,
+                self.text(NumericReply::ERR_NOLANGUAGE, &[])
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Writes a shared line as is, see `Line`
+    pub async fn write_line(&mut self, line: &Line, tags: Wanted) -> Result<()> {
+        self.write_bytes(line.bytes(tags)).await
+    }
+
+    /// SAFETY: You have to end `message` with a \r\n or bad shit will happen.
+    pub async unsafe fn write_raw<S: AsRef<str>>(&mut self, message: S) -> Result<()> {
+        format_write!(self, "{}", message.as_ref());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    /// Our end of a connection, and the client's
+    async fn connected() -> (IrcConnection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        (IrcConnection::new(socket), client)
+    }
+
+    fn disconnected(e: crate::Error) -> String {
+        e.downcast::<Disconnected>().unwrap().0
+    }
+
+    #[tokio::test]
+    async fn counts_traffic() {
+        use tokio::io::AsyncWriteExt;
+        let (mut connection, mut client) = connected().await;
+        client.write_all(b"NICK tiger\r\nPING x\r\n").await.unwrap();
+        connection.read_line().await.unwrap();
+        connection.read_line().await.unwrap();
+        unsafe { connection.write_raw("PONG x\r\n").await.unwrap() };
+        assert_eq!(
+            connection.traffic,
+            Traffic {
+                bytes_in: 20,
+                bytes_out: 8,
+                lines_in: 2,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn client_stopped_reading() {
+        let (mut connection, client) = connected().await;
+        drop(client);
+        let client = ClientInfo::default();
+        // The first few can make it into buffers before the reset comes back
+        for _ in 0..100 {
+            let res = async {
+                connection.write_notice(&client, "meow").await?;
+                connection.flush().await
+            }
+            .await;
+            if let Err(e) = res {
+                assert!(disconnected(e).starts_with("Write error: "));
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("Writes never failed");
+    }
+
+    #[tokio::test]
+    async fn client_reset() {
+        let (mut connection, client) = connected().await;
+        // Closing with what we sent still unread resets the connection
+        connection
+            .write_notice(&ClientInfo::default(), "meow")
+            .await
+            .unwrap();
+        connection.flush().await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        drop(client);
+        let e = connection.read_line().await.unwrap_err();
+        assert!(disconnected(e).starts_with("Read error: "));
+    }
+
+    #[test]
+    fn shared_lines() {
+        let message: Message = "@+typing=active;account=tiger :tiger!t@host PRIVMSG #meow :hi"
+            .parse()
+            .unwrap();
+        let line = Line::new(&message);
+        assert_eq!(
+            line.bytes(Wanted::All),
+            b"@+typing=active;account=tiger :tiger!t@host PRIVMSG #meow :hi\r\n"
+        );
+        assert_eq!(
+            line.bytes(Wanted::Account),
+            b"@account=tiger :tiger!t@host PRIVMSG #meow :hi\r\n"
+        );
+        assert_eq!(
+            line.bytes(Wanted::Nothing),
+            b":tiger!t@host PRIVMSG #meow :hi\r\n"
+        );
+    }
+
+    /// Everything `write` wrote, with `server_name` set if it's given
+    async fn written<F>(server_name: Option<&str>, write: F) -> String
+    where
+        F: AsyncFnOnce(&mut IrcConnection) -> Result<()>,
+    {
+        let (stream, mut other) = tokio::io::duplex(65536);
+        let mut connection =
+            IrcConnection::new_virtual(stream, SocketAddr::from(([10, 0, 0, 1], 6667)));
+        connection.identity.store(crate::identity::Identity {
+            server_name: server_name.map(str::to_string),
+            ..Default::default()
+        });
+        write(&mut connection).await.unwrap();
+        connection.flush().await.unwrap();
+        drop(connection);
+        let mut out = String::new();
+        other.read_to_string(&mut out).await.unwrap();
+        out
+    }
+
+    #[tokio::test]
+    async fn prefixes() {
+        let client = ClientInfo {
+            nickname: "tiger".to_string(),
+            username: "tiger".to_string(),
+            ..Default::default()
+        };
+        let out = written(Some("irc.example.net"), async |x: &mut IrcConnection| {
+            x.write_no_privileges(&client).await?;
+            x.write_notice(&client, "meow").await?;
+            x.write_pong(":123").await
+        })
+        .await;
+        assert_eq!(
+            out,
+            ":irc.example.net 481 tiger :Permission Denied- You're not an IRC operator\r\n\
+             :irc.example.net NOTICE tiger :meow\r\n\
+             :irc.example.net PONG irc.example.net :123\r\n"
+        );
+        // Without one it's whatever address they connected to
+        let out = written(None, async |x: &mut IrcConnection| {
+            x.write_notice(&client, "meow").await
+        })
+        .await;
+        assert_eq!(out, ":10.0.0.1 NOTICE tiger :meow\r\n");
+    }
+
+    #[tokio::test]
+    async fn numeric_targets() {
+        // Numerics go to the nick, not the username
+        let client = ClientInfo {
+            nickname: "tiger".to_string(),
+            username: "cat".to_string(),
+            host: "localhost".to_string(),
+            ..Default::default()
+        };
+        let out = written(Some("irc.example.net"), async |x: &mut IrcConnection| {
+            x.write_registration(&client, &["BOT=B".to_string()], DateTime::UNIX_EPOCH)
+                .await?;
+            x.write_motd(&client, "Meow").await?;
+            x.write_nick_in_use(&client, "kitty").await?;
+            x.write_no_such_nick(&client, "kitty").await
+        })
+        .await;
+        assert_eq!(
+            out.lines().collect::<Vec<_>>(),
+            [
+                ":irc.example.net 001 tiger :Welcome to the Internet Relay Network tiger!cat@localhost",
+                ":irc.example.net 002 tiger :Your host is irc.example.net, running version rust_irc-0.0.0",
+                ":irc.example.net 003 tiger :This server was created Thu Jan 01 1970 at 00:00:00 UTC",
+                ":irc.example.net 004 tiger irc.example.net rust_irc-0.0.0 BHs Nabhoqsvy",
+                ":irc.example.net 005 tiger CASEMAPPING=ascii BOT=B :are available on this server",
+                ":irc.example.net 375 tiger :- irc.example.net Message of the day - ",
+                ":irc.example.net 372 tiger :- Meow",
+                ":irc.example.net 376 tiger :End of /MOTD command",
+                ":irc.example.net 433 tiger kitty :Nickname is already in use",
+                ":irc.example.net 401 tiger kitty :No such nick/channel",
+            ]
+        );
+        // Before they've picked a nick
+        let out = written(Some("irc.example.net"), async |x: &mut IrcConnection| {
+            x.write_nick_in_use(&ClientInfo::default(), "kitty").await
+        })
+        .await;
+        assert_eq!(
+            out,
+            ":irc.example.net 433 * kitty :Nickname is already in use\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn names() {
+        let (stream, mut other) = tokio::io::duplex(65536);
+        let mut connection =
+            IrcConnection::new_virtual(stream, SocketAddr::from(([127, 0, 0, 1], 6667)));
+        let client = ClientInfo {
+            nickname: "tiger".to_string(),
+            ..Default::default()
+        };
+        let names: Vec<String> = (0..100).map(|x| format!("@nick{:02}", x)).collect();
+        connection
+            .write_names(&client, "#meow", true, &names)
+            .await
+            .unwrap();
+        connection.flush().await.unwrap();
+        drop(connection);
+        let mut out = String::new();
+        other.read_to_string(&mut out).await.unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        // 100 names of 7 and a space don't fit in one line
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with(":127.0.0.1 353 tiger @ #meow :@nick00 @nick01"));
+        assert!(lines[1].ends_with("@nick99"));
+        assert_eq!(lines[2], ":127.0.0.1 366 tiger #meow :End of /NAMES list");
+    }
 }
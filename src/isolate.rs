@@ -0,0 +1,52 @@
+//! Keeping a panic in one connection's handler from going unnoticed or leaving things half done. The panic is caught
+//! where the connection's task starts, so it can be logged with who it was and cleaned up after like any other
+//! disconnect.
+
+use std::{
+    any::Any,
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::pin,
+    task::Poll,
+};
+
+/// Runs `future`, turning a panic in it into an `Err` with what it panicked with.
+pub async fn catching<F: Future>(future: F) -> Result<F::Output, Box<dyn Any + Send>> {
+    let mut future = pin!(future);
+    std::future::poll_fn(|cx| {
+        match panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    })
+    .await
+}
+
+/// The message a panic was given, if it was given one.
+pub fn message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "no message"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn caught() {
+        assert_eq!(catching(async { 1 }).await.unwrap(), 1);
+        let payload = catching(async {
+            tokio::task::yield_now().await;
+            panic!("meow {}", 2)
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(message(&*payload), "meow 2");
+    }
+}
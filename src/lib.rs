@@ -0,0 +1,66 @@
+//! A tokio based IRC server. The `rust_irc` binary runs one from a config file, other programs can run one
+//! in-process with [`ServerBuilder`].
+
+mod accept;
+mod account;
+mod alias;
+mod audit;
+mod auth;
+mod away;
+mod ban;
+mod bot;
+mod bridge;
+mod burst;
+mod capability;
+mod catalog;
+mod chanlog;
+mod channel;
+mod cluster;
+mod config;
+mod defcon;
+mod embed;
+mod event;
+mod fakelag;
+mod filter;
+mod geoip;
+mod help;
+mod history;
+mod http;
+mod identity;
+mod irc_connection;
+mod isolate;
+mod listeners;
+mod lockout;
+mod log;
+mod message_impl;
+mod message_parse;
+mod metrics;
+mod mode;
+mod nick;
+mod nickserv;
+mod pace;
+mod password;
+mod plugin;
+mod privacy;
+mod registry;
+use irc_connection::IrcConnection;
+mod script;
+mod server;
+use server::{ClientConnection, ClientInfo};
+mod session;
+mod shutdown;
+mod snomask;
+mod tags;
+mod tls;
+mod webhook;
+use shutdown::Shutdown;
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub use bot::{Bot, BotContext, Bots};
+pub use bridge::{Bridge, BridgeEvent, BridgeLink, ServerEvent};
+pub use config::{Config, Flavor};
+pub use embed::{ServerBuilder, ServerHandle, VirtualClient};
+pub use event::{Event, EventBus};
+pub use password::hash_password_command;
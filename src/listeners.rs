@@ -0,0 +1,150 @@
+//! Plaintext listeners besides `listen`, from `listeners` in the config. REHASH binds any that are new and stops
+//! accepting on any that are gone. Clients that came in through one that's stopped stay connected.
+//!
+//! ```toml
+//! listeners = ["0.0.0.0:6665", "[::]:6667"]
+//! ```
+
+use crate::{
+    accept,
+    event::{Event, EventBus},
+    log,
+    metrics::Metrics,
+    IrcConnection, Shutdown,
+};
+use std::{collections::HashMap, net::SocketAddr};
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, mpsc, oneshot},
+};
+
+/// Every extra listener that's running, by the address it was configured with
+#[derive(Debug)]
+pub struct Listeners {
+    /// Dropping one of these stops its listener
+    running: HashMap<String, oneshot::Sender<()>>,
+    /// Where accepted clients go
+    tx: mpsc::Sender<IrcConnection>,
+    metrics: Metrics,
+    events: EventBus,
+}
+
+impl Listeners {
+    pub fn new(tx: mpsc::Sender<IrcConnection>, metrics: Metrics, events: EventBus) -> Self {
+        Self {
+            running: HashMap::new(),
+            tx,
+            metrics,
+            events,
+        }
+    }
+
+    /// Makes what's running match `addresses`, returning where each new one ended up bound. Ones that fail to bind
+    /// are logged and left out, and tried again next time.
+    pub async fn update(
+        &mut self,
+        addresses: &[String],
+        notify_shutdown: &broadcast::Sender<()>,
+    ) -> Vec<SocketAddr> {
+        self.running.retain(|address, _| {
+            let keep = addresses.contains(address);
+            if !keep {
+                log::info!("Stopped listening on {}", address);
+                self.events.publish(Event::ListenerChanged {
+                    address: address.clone(),
+                    up: false,
+                });
+            }
+            keep
+        });
+        let mut bound = Vec::new();
+        for address in addresses {
+            if self.running.contains_key(address) {
+                continue;
+            }
+            let listener = match TcpListener::bind(address).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    log::error!("Failed to listen on {}: {}", address, e);
+                    self.events.publish(Event::ListenFailed {
+                        address: address.clone(),
+                        error: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+            if let Ok(local) = listener.local_addr() {
+                log::info!("Listening on {}", local);
+                bound.push(local);
+            }
+            self.events.publish(Event::ListenerChanged {
+                address: address.clone(),
+                up: true,
+            });
+            let (stop_tx, stop_rx) = oneshot::channel();
+            self.running.insert(address.clone(), stop_tx);
+            tokio::spawn(serve(
+                listener,
+                stop_rx,
+                self.tx.clone(),
+                self.metrics.clone(),
+                Shutdown::new(notify_shutdown.subscribe()),
+            ));
+        }
+        bound
+    }
+}
+
+/// Accepts clients on `listener` until it's stopped or the server shuts down.
+async fn serve(
+    listener: TcpListener,
+    mut stop: oneshot::Receiver<()>,
+    tx: mpsc::Sender<IrcConnection>,
+    metrics: Metrics,
+    mut shutdown: Shutdown,
+) {
+    let mut backoff = accept::Backoff::default();
+    loop {
+        let socket = tokio::select! {
+            socket = accept::next(&listener, &metrics, &mut backoff) => socket,
+            _ = &mut stop => return,
+            _ = shutdown.recv() => return,
+        };
+        if tx.send(IrcConnection::new(socket)).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn binds_and_stops() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let mut listeners = Listeners::new(tx, Metrics::default(), EventBus::new());
+        let addresses = ["127.0.0.1:0".to_string()];
+        let bound = listeners.update(&addresses, &notify_shutdown).await;
+        assert_eq!(bound.len(), 1);
+        // Already running, so left alone
+        assert!(listeners
+            .update(&addresses, &notify_shutdown)
+            .await
+            .is_empty());
+        let _client = TcpStream::connect(bound[0]).await.unwrap();
+        assert!(rx.recv().await.is_some());
+
+        listeners.update(&[], &notify_shutdown).await;
+        // Gone once its task sees it's been stopped
+        for _ in 0..100 {
+            if TcpStream::connect(bound[0]).await.is_err() {
+                return;
+            }
+            tokio::task::yield_now().await;
+        }
+        panic!("Still listening");
+    }
+}
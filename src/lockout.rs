@@ -0,0 +1,135 @@
+//! Keeping OPER passwords from being guessed. Failed attempts are counted per connection and per IP: a connection
+//! that fails `per_connection` times is dropped, and an IP that fails `per_ip` times within `window` seconds can't
+//! try again until the oldest of those have aged out, however many times it reconnects. Either one tells opers
+//! with snomask `o` and goes in the audit log.
+//!
+//! ```toml
+//! [oper_lockout]
+//! per_connection = 3
+//! per_ip = 5
+//! window = 600
+//! ```
+//!
+//! Passwords given to NickServ are counted the same way, separately, under `[nickserv_lockout]`.
+
+use crate::{server::lock, Result};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LockoutConfig {
+    pub per_connection: usize,
+    pub per_ip: usize,
+    /// Seconds a failure counts against its IP for
+    pub window: u64,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        Self {
+            per_connection: 3,
+            per_ip: 5,
+            window: 600,
+        }
+    }
+}
+
+impl LockoutConfig {
+    pub fn validate(&self) -> Result<()> {
+        // 0 would lock everyone out before they'd tried, or drop them for no reason
+        if self.per_connection == 0 || self.per_ip == 0 {
+            return Err("oper_lockout.per_connection and per_ip have to be at least 1".into());
+        }
+        Ok(())
+    }
+}
+
+/// Recent failures from each IP. Shared between every connection.
+#[derive(Debug, Clone, Default)]
+pub struct Lockouts {
+    failures: Arc<Mutex<HashMap<IpAddr, VecDeque<Instant>>>>,
+}
+
+impl Lockouts {
+    /// Returns `true` if `ip` has used up its attempts for now.
+    pub fn locked(&self, config: &LockoutConfig, ip: IpAddr, now: Instant) -> bool {
+        let mut failures = lock(&self.failures);
+        Self::expire(&mut failures, config, now);
+        failures.get(&ip).is_some_and(|x| x.len() >= config.per_ip)
+    }
+
+    /// Counts a failure against `ip`, returning how many it has in the window now.
+    pub fn fail(&self, config: &LockoutConfig, ip: IpAddr, now: Instant) -> usize {
+        let mut failures = lock(&self.failures);
+        Self::expire(&mut failures, config, now);
+        let times = failures.entry(ip).or_default();
+        times.push_back(now);
+        times.len()
+    }
+
+    /// Forgets failures older than the window, and IPs left with none.
+    fn expire(
+        failures: &mut HashMap<IpAddr, VecDeque<Instant>>,
+        config: &LockoutConfig,
+        now: Instant,
+    ) {
+        let window = Duration::from_secs(config.window);
+        failures.retain(|_, times| {
+            while times
+                .front()
+                .is_some_and(|x| now.duration_since(*x) >= window)
+            {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn locks_and_unlocks() {
+        let config = LockoutConfig {
+            per_ip: 2,
+            window: 60,
+            ..Default::default()
+        };
+        let lockouts = Lockouts::default();
+        let (ip, other): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "::1".parse().unwrap());
+        let now = Instant::now();
+        assert_eq!(lockouts.fail(&config, ip, now), 1);
+        assert!(!lockouts.locked(&config, ip, now));
+        assert_eq!(lockouts.fail(&config, ip, now + Duration::from_secs(30)), 2);
+        assert!(lockouts.locked(&config, ip, now + Duration::from_secs(30)));
+        assert!(!lockouts.locked(&config, other, now));
+        // Until the first one ages out
+        assert!(lockouts.locked(&config, ip, now + Duration::from_secs(59)));
+        assert!(!lockouts.locked(&config, ip, now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn zero_attempts_is_invalid() {
+        assert!(LockoutConfig::default().validate().is_ok());
+        for config in [
+            LockoutConfig {
+                per_ip: 0,
+                ..Default::default()
+            },
+            LockoutConfig {
+                per_connection: 0,
+                ..Default::default()
+            },
+        ] {
+            assert!(config.validate().is_err());
+        }
+    }
+}
@@ -0,0 +1,518 @@
+//! Logging. Plain text by default, same as it's always been, or one JSON object per line for shipping straight
+//! into Loki or Elasticsearch:
+//!
+//! ```toml
+//! [log]
+//! format = "json"
+//! # trace, debug, info or error. debug logs every command with how long it took
+//! level = "debug"
+//! ```
+//! Errors go to stderr and everything else to stdout, unless logs go to a file or syslog instead:
+//!
+//! ```toml
+//! [log.file]
+//! path = "/var/log/rust_irc/server.log"
+//! # Start a new file once this one is this many bytes, and/or every day at midnight UTC
+//! max_size = 10485760
+//! daily = true
+//! # How many old files to keep around, as server.log.1, server.log.2...
+//! keep = 5
+//!
+//! # or, on unix
+//! [log.syslog]
+//! facility = "local0"
+//! ```
+//! Lines are written out by a thread of their own, so a slow disk or syslog never holds up a connection.
+
+use crate::Result;
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::{
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::Write as _,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        mpsc, OnceLock,
+    },
+    time::Duration,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[repr(u8)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[repr(u8)]
+pub enum Level {
+    /// Every message we parse
+    Trace,
+    /// Every command, with how long it took
+    Debug,
+    #[default]
+    Info,
+    Error,
+}
+
+impl Level {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Level::Trace => "trace",
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Error => "error",
+        }
+    }
+
+    /// Syslog severity
+    #[cfg(unix)]
+    fn severity(&self) -> u8 {
+        match self {
+            Level::Trace | Level::Debug => 7,
+            Level::Info => 6,
+            Level::Error => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    /// Anything less important than this isn't logged
+    pub level: Level,
+    /// Log to a rotating file instead of stdout
+    pub file: Option<FileConfig>,
+    /// Log to syslog instead of stdout, unix only
+    pub syslog: Option<SyslogConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FileConfig {
+    pub path: PathBuf,
+    /// Bytes the file can grow to before it's rotated
+    pub max_size: Option<u64>,
+    /// Rotate when the day changes, UTC
+    #[serde(default)]
+    pub daily: bool,
+    /// Rotated files to keep
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+}
+
+fn default_keep() -> usize {
+    5
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SyslogConfig {
+    /// The syslog socket
+    pub path: PathBuf,
+    pub facility: String,
+    /// What our lines are tagged with
+    pub ident: String,
+}
+
+impl Default for SyslogConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("/dev/log"),
+            facility: "daemon".to_string(),
+            ident: "rust_irc".to_string(),
+        }
+    }
+}
+
+/// Syslog facility names, in code order
+const FACILITIES: &[&str] = &[
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv",
+    "ftp",
+];
+
+/// The code for the syslog facility called `name`.
+fn facility(name: &str) -> Option<u8> {
+    if let Some(n) = name.strip_prefix("local") {
+        return n.parse::<u8>().ok().filter(|x| *x < 8).map(|x| 16 + x);
+    }
+    FACILITIES.iter().position(|x| *x == name).map(|x| x as u8)
+}
+
+impl LogConfig {
+    /// Checks the config makes sense, without opening anything.
+    pub fn validate(&self) -> Result<()> {
+        if self.file.is_some() && self.syslog.is_some() {
+            return Err("Logs can go to log.file or log.syslog, not both".into());
+        }
+        if let Some(syslog) = &self.syslog {
+            if !cfg!(unix) {
+                return Err("log.syslog only works on unix".into());
+            }
+            if facility(&syslog.facility).is_none() {
+                return Err(format!("Unknown syslog facility {:?}", syslog.facility).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A log file that moves itself out of the way once it's too big or too old.
+#[derive(Debug)]
+struct RotatingFile {
+    config: FileConfig,
+    file: File,
+    size: u64,
+    /// Day the file was started on, for `daily`
+    day: NaiveDate,
+}
+
+impl RotatingFile {
+    fn open(config: FileConfig) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let metadata = file.metadata()?;
+        let day = metadata
+            .modified()
+            .map(DateTime::<Utc>::from)
+            .unwrap_or_else(|_| Utc::now())
+            .date_naive();
+        Ok(Self {
+            config,
+            file,
+            size: metadata.len(),
+            day,
+        })
+    }
+
+    fn write_line(&mut self, line: &str, now: DateTime<Utc>) -> std::io::Result<()> {
+        let len = line.len() as u64 + 1;
+        let too_big = self
+            .config
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + len > max);
+        let too_old = self.config.daily && now.date_naive() != self.day;
+        if too_big || too_old {
+            self.rotate(now)?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// `path` becomes `path.1`, `path.1` becomes `path.2` and so on, dropping whatever's past `keep`.
+    fn rotate(&mut self, now: DateTime<Utc>) -> std::io::Result<()> {
+        let path = &self.config.path;
+        let numbered = |n: usize| {
+            let mut name = path.as_os_str().to_owned();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        if self.config.keep == 0 {
+            fs::remove_file(path)?;
+        } else {
+            for n in (1..self.config.keep).rev() {
+                if numbered(n).exists() {
+                    fs::rename(numbered(n), numbered(n + 1))?;
+                }
+            }
+            fs::rename(path, numbered(1))?;
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.size = 0;
+        self.day = now.date_naive();
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+#[derive(Debug)]
+struct Syslog {
+    socket: std::os::unix::net::UnixDatagram,
+    facility: u8,
+    ident: String,
+}
+
+#[cfg(unix)]
+impl Syslog {
+    fn connect(config: &SyslogConfig) -> Result<Self> {
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        socket.connect(&config.path)?;
+        Ok(Self {
+            socket,
+            facility: facility(&config.facility).ok_or("Unknown syslog facility")?,
+            ident: config.ident.clone(),
+        })
+    }
+
+    fn write_line(&self, level: Level, line: &str) -> std::io::Result<()> {
+        let priority = self.facility * 8 + level.severity();
+        let message = format!(
+            "<{}>{}[{}]: {}",
+            priority,
+            self.ident,
+            std::process::id(),
+            line
+        );
+        self.socket.send(message.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum Sink {
+    Stdout,
+    File(RotatingFile),
+    #[cfg(unix)]
+    Syslog(Syslog),
+}
+
+impl Sink {
+    fn write_line(&mut self, level: Level, line: &str) -> std::io::Result<()> {
+        match self {
+            Sink::Stdout if level >= Level::Error => {
+                eprintln!("{}", line);
+                Ok(())
+            }
+            Sink::Stdout => {
+                println!("{}", line);
+                Ok(())
+            }
+            Sink::File(file) => file.write_line(line, Utc::now()),
+            #[cfg(unix)]
+            Sink::Syslog(syslog) => syslog.write_line(level, line),
+        }
+    }
+}
+
+/// Something for the writer thread to do
+enum Job {
+    Line(Level, String),
+    /// Write everything after this somewhere else
+    Switch(Sink),
+    /// Says so once everything before it is written
+    Flush(mpsc::Sender<()>),
+}
+
+/// The least important `Level` that gets logged, checked before anything is sent to the writer
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+/// The `LogFormat`, as lines are formatted before they go to the writer
+static FORMAT: AtomicU8 = AtomicU8::new(LogFormat::Text as u8);
+/// Hands lines to the writer thread, which is started by whatever logs first
+static WRITER: OnceLock<mpsc::Sender<Job>> = OnceLock::new();
+
+fn writer() -> &'static mpsc::Sender<Job> {
+    WRITER.get_or_init(|| {
+        let (tx, rx) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("log".to_string())
+            .spawn(move || run(rx))
+            .expect("the log thread should be able to start");
+        tx
+    })
+}
+
+/// The writer thread, writing out lines in the order they were logged. Runs for as long as the process does.
+fn run(jobs: mpsc::Receiver<Job>) {
+    let mut sink = Sink::Stdout;
+    for job in jobs {
+        match job {
+            Job::Line(level, line) => {
+                // Nowhere better to put it
+                if let Err(e) = sink.write_line(level, &line) {
+                    eprintln!("Failed to log: {}\n{}", e, line);
+                }
+            }
+            Job::Switch(new) => sink = new,
+            Job::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+/// Waits for everything logged so far to be written out, like before exiting.
+pub fn flush() {
+    let (tx, rx) = mpsc::channel();
+    if writer().send(Job::Flush(tx)).is_ok() {
+        let _ = rx.recv();
+    }
+}
+
+/// Switches logging over to `config`, for the whole process.
+pub fn init(config: &LogConfig) -> Result<()> {
+    config.validate()?;
+    let sink = match (&config.file, &config.syslog) {
+        (Some(file), _) => Sink::File(
+            RotatingFile::open(file.clone())
+                .map_err(|e| format!("Failed to open log file {}: {}", file.path.display(), e))?,
+        ),
+        #[cfg(unix)]
+        (None, Some(syslog)) => Sink::Syslog(Syslog::connect(syslog)?),
+        _ => Sink::Stdout,
+    };
+    // Whatever was logged before this still goes where it was going to
+    let _ = writer().send(Job::Switch(sink));
+    FORMAT.store(config.format as u8, Ordering::Relaxed);
+    LEVEL.store(config.level as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Logs `message` along with some extra `fields`, which plain text tacks on the end as `key=value`.
+pub fn write(level: Level, message: std::fmt::Arguments, fields: &[(&str, Value)]) {
+    if (level as u8) < LEVEL.load(Ordering::Relaxed) {
+        return;
+    }
+    let format = match FORMAT.load(Ordering::Relaxed) {
+        x if x == LogFormat::Json as u8 => LogFormat::Json,
+        _ => LogFormat::Text,
+    };
+    let line = format_line(format, level, message, fields);
+    let _ = writer().send(Job::Line(level, line));
+}
+
+fn format_line(
+    format: LogFormat,
+    level: Level,
+    message: std::fmt::Arguments,
+    fields: &[(&str, Value)],
+) -> String {
+    match format {
+        LogFormat::Text => {
+            let mut line = message.to_string();
+            for (key, value) in fields {
+                let _ = match value {
+                    Value::String(value) => write!(line, " {}={}", key, value),
+                    value => write!(line, " {}={}", key, value),
+                };
+            }
+            line
+        }
+        LogFormat::Json => {
+            let mut object = Map::new();
+            object.insert(
+                "timestamp".to_string(),
+                Utc::now()
+                    .to_rfc3339_opts(SecondsFormat::Millis, true)
+                    .into(),
+            );
+            object.insert("level".to_string(), level.as_str().into());
+            object.insert("message".to_string(), message.to_string().into());
+            for (key, value) in fields {
+                object.insert(key.to_string(), value.clone());
+            }
+            Value::Object(object).to_string()
+        }
+    }
+}
+
+/// A command from connection `id` finished, after `latency`.
+pub fn command(id: usize, nick: &str, command: &str, latency: Duration) {
+    write(
+        Level::Debug,
+        format_args!("{} from {}", command, nick),
+        &[
+            ("conn", id.into()),
+            ("nick", nick.into()),
+            ("command", command.into()),
+            ("latency_us", (latency.as_micros() as u64).into()),
+        ],
+    );
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Trace, format_args!($($arg)*), &[])
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Info, format_args!($($arg)*), &[])
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Error, format_args!($($arg)*), &[])
+    };
+}
+
+pub(crate) use {error, info, trace};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn formats() {
+        let fields = [("conn", 3.into()), ("nick", "tiger".into())];
+        assert_eq!(
+            format_line(
+                LogFormat::Text,
+                Level::Debug,
+                format_args!("JOIN from {}", "tiger"),
+                &fields
+            ),
+            "JOIN from tiger conn=3 nick=tiger"
+        );
+        let json: Value = serde_json::from_str(&format_line(
+            LogFormat::Json,
+            Level::Debug,
+            format_args!("JOIN from {}", "tiger"),
+            &fields,
+        ))
+        .unwrap();
+        assert_eq!(json["level"], "debug");
+        assert_eq!(json["message"], "JOIN from tiger");
+        assert_eq!(json["conn"], 3);
+        assert_eq!(json["nick"], "tiger");
+    }
+
+    #[test]
+    fn rotation() {
+        let path = std::env::temp_dir().join(format!("rust_irc_log_{}.log", std::process::id()));
+        let numbered = |n: usize| PathBuf::from(format!("{}.{}", path.display(), n));
+        let config = FileConfig {
+            path: path.clone(),
+            max_size: Some(10),
+            daily: true,
+            keep: 2,
+        };
+        let now = Utc::now();
+        let mut file = RotatingFile::open(config).unwrap();
+        file.write_line("first", now).unwrap();
+        // Would go over 10 bytes
+        file.write_line("second", now).unwrap();
+        // New day
+        file.write_line("third", now + chrono::Duration::days(1))
+            .unwrap();
+        file.write_line("fourth", now + chrono::Duration::days(1))
+            .unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(numbered(1)).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(numbered(2)).unwrap(), "second\n");
+        assert!(!numbered(3).exists());
+        for x in [path.clone(), numbered(1), numbered(2)] {
+            let _ = fs::remove_file(x);
+        }
+    }
+
+    #[test]
+    fn facilities() {
+        assert_eq!(facility("daemon"), Some(3));
+        assert_eq!(facility("local7"), Some(23));
+        assert_eq!(facility("local8"), None);
+        assert_eq!(facility("nope"), None);
+    }
+}
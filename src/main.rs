@@ -1,20 +1,41 @@
-mod irc_connection;
-mod message_impl;
-mod message_parse;
-use irc_connection::IrcConnection;
-mod server;
-use server::{ClientConnection, ClientInfo};
-mod shutdown;
-use shutdown::Shutdown;
-use tokio::{net::TcpListener, signal};
+use rust_irc::{Config, Flavor, Result, ServerBuilder};
+use tokio::signal;
 
-pub type Error = Box<dyn std::error::Error + Send + Sync>;
-pub type Result<T> = std::result::Result<T, Error>;
+/// `rust_irc [config.toml] [--worker-threads N] [--current-thread]`, or `rust_irc hash-password`
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let (mut path, mut worker_threads, mut current_thread) = (None, None, false);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "hash-password" => return rust_irc::hash_password_command(),
+            "--worker-threads" => {
+                let threads = args.next().ok_or("--worker-threads needs a number")?;
+                worker_threads = Some(threads.parse()?);
+            }
+            "--current-thread" => current_thread = true,
+            _ => path = Some(arg),
+        }
+    }
+    let mut config = match path {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if worker_threads.is_some() {
+        config.runtime.worker_threads = worker_threads;
+    }
+    if current_thread {
+        config.runtime.flavor = Flavor::CurrentThread;
+    }
+    config.runtime.build()?.block_on(serve(config))
+}
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let listener = TcpListener::bind("0.0.0.0:6667").await?;
-    println!("Listening on {}", listener.local_addr().unwrap());
-    server::run(listener, signal::ctrl_c()).await;
+async fn serve(config: Config) -> Result<()> {
+    let mut server = ServerBuilder::new().config(config).start().await?;
+    tokio::select! {
+        res = signal::ctrl_c() => res?,
+        // DIE
+        _ = server.stopped() => {}
+    }
+    server.shutdown().await;
     Ok(())
 }
@@ -1,6 +1,28 @@
-use crate::message_parse::{Command, Message, Side};
+use crate::ban::{self, Ban, BanKind};
+use crate::capability;
+use crate::channel::{self, is_channel, BadName};
+use crate::cluster::{self, Asked};
+use crate::config::{truncate, ChannelCreation, Config, Privilege};
+use crate::defcon;
+use crate::event::Event;
+use crate::filter::{FilterAction, FilterConfig};
+use crate::help;
+use crate::identity::Identity;
+use crate::log;
+use crate::message_parse::{is_timestamp, Command, Message, Side};
+use crate::mode;
+use crate::nick;
+use crate::nickserv;
+use crate::pace::Pacer;
+use crate::script::Verdict;
+use crate::snomask;
+use crate::tags;
 use crate::ClientConnection;
+use crate::ClientInfo;
 use crate::Result;
+use base64::prelude::*;
+use chrono::{DateTime, Utc};
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub enum Code {
@@ -12,58 +34,1735 @@ pub enum Code {
 impl Message {
     pub async fn apply(&self, cc: &mut ClientConnection) -> Result<Code> {
         match &self.command {
+            Command::PASS(password) => {
+                cc.password = Some(password.clone());
+            }
+            // We were renamed, by nick protection or someone's REGAIN
+            Command::NICK(_) if self.side == Side::Server => {
+                cc.nick_deadline = None;
+                // Safety: we terminate the line ourselves.
+                unsafe {
+                    cc.connection.write_raw(format!("{}\r\n", self)).await?;
+                }
+            }
             Command::NICK(nickname) => {
-                cc.info.nickname = nickname.clone();
+                let nickname = &if cc.config.nicks.normalize {
+                    nick::normalize(nickname)
+                } else {
+                    nickname.clone()
+                };
+                let info = cc.info().clone();
+                let old = info.nickname.clone();
+                if cc.scripts.on_nick(&old, nickname) == Verdict::Block
+                    || (cc.config.nicks.strict_confusables
+                        && cc.users.lookalike(nickname, &info.uid))
+                {
+                    cc.connection.write_erroneous_nick(&info, nickname).await?;
+                    cc.nick_rejected();
+                } else if cc.registered
+                    && !(cc.cluster.claim(nickname).await && cc.users.rename(&info.uid, nickname))
+                {
+                    cc.connection.write_nick_in_use(&info, nickname).await?;
+                } else {
+                    cc.info().nickname = nickname.clone();
+                    if cc.registered {
+                        cc.announce_nick(info.to_canonical(), nickname).await?;
+                        cc.events.publish(Event::NickChanged {
+                            old,
+                            new: nickname.clone(),
+                        });
+                        cc.check_nick_owner().await?;
+                    } else if cc.can_register() && !cc.register().await? {
+                        return Ok(Code::Exit);
+                    }
+                }
+            }
+            Command::OPER(name, password) => {
+                let (config, ip) = (cc.config.clone(), cc.connection.client_addr.ip());
+                let lockout = &config.oper_lockout;
+                if cc.lockouts.locked(lockout, ip, Instant::now()) {
+                    let info = cc.info().clone();
+                    cc.connection
+                        .write_notice(&info, "Too many failed OPER attempts, try again later")
+                        .await?;
+                    return Ok(Code::Fine);
+                }
+                let certfp = cc.connection.certfp.clone();
+                let privileges = cc.config.check_oper(name, password, certfp.as_deref());
+                cc.events.publish(Event::OperAttempt {
+                    by: cc.info().to_canonical(),
+                    name: name.clone(),
+                    success: privileges.is_some(),
+                });
+                let info = if let Some(privileges) = privileges {
+                    let mut info = cc.info();
+                    info.oper = Some(privileges);
+                    info.clone()
+                } else {
+                    let info = cc.info().clone();
+                    cc.connection.write_password_mismatch(&info).await?;
+                    cc.oper_failures += 1;
+                    let from_ip = cc.lockouts.fail(lockout, ip, Instant::now());
+                    let dropped = cc.oper_failures >= lockout.per_connection;
+                    if dropped || from_ip == lockout.per_ip {
+                        cc.events.publish(Event::OperLockout {
+                            by: info.to_canonical(),
+                            ip: ip.to_string(),
+                            dropped,
+                        });
+                    }
+                    if dropped {
+                        let reason = "Too many failed OPER attempts";
+                        cc.connection.write_error(reason).await?;
+                        cc.quit_reason = Some(reason.to_string());
+                        return Ok(Code::Exit);
+                    }
+                    return Ok(Code::Fine);
+                };
+                cc.connection.write_youreoper(&info).await?;
+            }
+            Command::OPERWALL(text) => match self.side {
+                Side::Client => {
+                    let info = cc.info().clone();
+                    if info.oper.is_none() {
+                        cc.connection.write_no_privileges(&info).await?;
+                        return Ok(Code::Fine);
+                    }
+                    cc.users.send_opers(Message {
+                        tags: None,
+                        source: Some(info.to_canonical()),
+                        command: Command::OPERWALL(text.clone()),
+                        side: Side::Server,
+                    });
+                }
+                // Safety: we terminate the line ourselves.
+                Side::Server => unsafe {
+                    cc.connection.write_raw(format!("{}\r\n", self)).await?;
+                },
+                _ => {}
+            },
+            Command::REHASH => {
+                if !cc.check_privilege(Privilege::Rehash).await? {
+                    return Ok(Code::Fine);
+                }
+                let info = cc.info().clone();
+                cc.events.publish(Event::Rehashed {
+                    by: info.to_canonical(),
+                });
+                let file = match &cc.config.path {
+                    Some(path) => path.display().to_string(),
+                    None => "*".to_string(),
+                };
+                cc.connection.write_rehashing(&info, file).await?;
+                if let Some(path) = &cc.config.path {
+                    match Config::load(path) {
+                        Ok(config) => {
+                            cc.connection.identity.store(Identity::from_config(&config));
+                            let (added, dropped) = cc.accounts.reload(&config);
+                            for account in added {
+                                cc.events.publish(Event::AccountRegistered { account });
+                            }
+                            for account in dropped {
+                                cc.events.publish(Event::AccountDropped { account });
+                            }
+                            cc.listen(config.listeners).await?;
+                        }
+                        Err(e) => {
+                            cc.connection
+                                .write_notice(&info, format!("Failed to reload the config: {}", e))
+                                .await?
+                        }
+                    }
+                }
+                if let Err(e) = cc.scripts.reload() {
+                    cc.connection
+                        .write_notice(&info, format!("Failed to reload scripts: {}", e))
+                        .await?;
+                }
+                if let Err(e) = cc.plugins.reload() {
+                    cc.connection
+                        .write_notice(&info, format!("Failed to reload plugins: {}", e))
+                        .await?;
+                }
             }
             Command::USER(username, _, _, realname) => {
-                cc.info.username = username.clone();
-                cc.info.realname = realname.clone();
-                cc.connection.write_registration(&cc.info).await?;
+                {
+                    let mut info = cc.info();
+                    info.username = username.clone();
+                    info.realname = realname.clone();
+                }
+                // Clients negotiating capabilities register on CAP END instead.
+                if cc.can_register() && !cc.register().await? {
+                    return Ok(Code::Exit);
+                }
+            }
+            Command::CAP(subcommand, params) => {
+                let info = cc.info().clone();
+                match subcommand.as_str() {
+                    "LS" => {
+                        cc.cap_negotiating = !cc.registered;
+                        if let Some(version) = params.first().and_then(|x| x.parse().ok()) {
+                            cc.cap_version = cc.cap_version.max(version);
+                        }
+                        let caps = capability::ls(cc.cap_version, cc.connection.tls, &cc.config);
+                        cc.write_cap_list("LS", &caps).await?;
+                    }
+                    "LIST" => {
+                        let caps = cc.caps.iter().cloned().collect::<Vec<String>>();
+                        cc.write_cap_list("LIST", &caps).await?;
+                    }
+                    "REQ" => {
+                        cc.cap_negotiating = !cc.registered;
+                        // It's all or nothing, one bad cap NAKs the whole request.
+                        if params
+                            .iter()
+                            .all(|x| capability::is_supported(x.trim_start_matches('-')))
+                        {
+                            for cap in params {
+                                match cap.strip_prefix('-') {
+                                    Some(cap) => cc.caps.remove(cap),
+                                    None => cc.caps.insert(cap.clone()),
+                                };
+                            }
+                            cc.connection
+                                .write_cap(&info, "ACK", params.join(" "))
+                                .await?;
+                        } else {
+                            cc.connection
+                                .write_cap(&info, "NAK", params.join(" "))
+                                .await?;
+                        }
+                    }
+                    "END" => {
+                        cc.cap_negotiating = false;
+                        if cc.can_register() && !cc.register().await? {
+                            return Ok(Code::Exit);
+                        }
+                    }
+                    _ => {
+                        cc.connection.write_invalid_cap(&info, subcommand).await?;
+                    }
+                }
+            }
+            Command::MARKREAD(target, timestamp) => match self.side {
+                Side::Client => {
+                    let account = cc.info().account.clone();
+                    match (account, timestamp) {
+                        (_, Some(timestamp)) if !is_timestamp(timestamp) => {
+                            cc.connection
+                                .write_fail(
+                                    "MARKREAD",
+                                    "INVALID_PARAMS",
+                                    target,
+                                    "Invalid timestamp",
+                                )
+                                .await?;
+                        }
+                        (Some(account), Some(timestamp))
+                            if cc.accounts.set_read_marker(&account, target, timestamp) =>
+                        {
+                            // Everyone logged into the account hears about it, us included.
+                            cc.sync_read_marker(account, target.clone(), timestamp.clone())
+                                .await?;
+                        }
+                        _ => cc.send_read_marker(target).await?,
+                    }
+                }
+                Side::Server => {
+                    if let Command::MARKREAD(target, timestamp) = &self.command {
+                        cc.connection
+                            .write_markread(target, timestamp.as_deref())
+                            .await?;
+                    }
+                }
+                _ => {}
+            },
+            Command::AWAY(message) => match self.side {
+                Side::Client => {
+                    let away = message
+                        .as_deref()
+                        .map(|x| truncate(x, cc.config.limits.away).to_string());
+                    let info = {
+                        let mut info = cc.info();
+                        info.away = away;
+                        info.auto_away = false;
+                        info.clone()
+                    };
+                    cc.connection.write_away_status(&info).await?;
+                    cc.notify_away().await?;
+                }
+                // Only routed to connections with away-notify
+                Side::Server => {
+                    // Safety: we terminate the line ourselves.
+                    unsafe {
+                        cc.connection.write_raw(format!("{}\r\n", self)).await?;
+                    }
+                }
+                _ => {}
+            },
+            Command::CHGHOST(nick, host) => match self.side {
+                Side::Client => chghost(cc, nick, host).await?,
+                // Our own host changed, see `ClientConnection::announce_host`
+                Side::Server => {
+                    let info = cc.info().clone();
+                    cc.connection.write_host_hidden(&info, host).await?;
+                }
+                _ => {}
+            },
+            Command::KILL(_, comment) => match self.side {
+                Side::Client if cc.check_privilege(Privilege::Kill).await? => {
+                    if let Command::KILL(nick, _) = &self.command {
+                        if cc.users.uid(nick).is_none() && !cc.cluster.has(nick).await {
+                            let info = cc.info().clone();
+                            cc.connection.write_no_such_nick(&info, nick).await?;
+                            return Ok(Code::Fine);
+                        }
+                        cc.events.publish(Event::UserKilled {
+                            by: cc.info().to_canonical(),
+                            nick: nick.clone(),
+                            reason: comment.clone(),
+                        });
+                    }
+                    return Ok(Code::Broadcast);
+                }
+                Side::Server => {
+                    let killer = self.source.clone().unwrap_or_default();
+                    let reason = format!("Killed ({} ({}))", killer, comment);
+                    cc.connection.write_error(&reason).await?;
+                    cc.quit_reason = Some(reason);
+                    return Ok(Code::Exit);
+                }
+                _ => {}
+            },
+            Command::KLINE(duration, mask, reason) => {
+                // A bare host means any user on it
+                let mask = if mask.contains('@') || mask.starts_with('$') {
+                    mask.clone()
+                } else {
+                    format!("*@{}", mask)
+                };
+                add_ban(cc, BanKind::Kline, duration, mask, reason).await?;
             }
+            Command::DLINE(duration, mask, reason) => {
+                add_ban(cc, BanKind::Dline, duration, mask.clone(), reason).await?;
+            }
+            Command::UNKLINE(mask) => remove_ban(cc, BanKind::Kline, mask).await?,
+            Command::UNDLINE(mask) => remove_ban(cc, BanKind::Dline, mask).await?,
+            Command::TRACE(target) => trace(cc, target.as_deref()).await?,
+            Command::WHO(mask) => who(cc, mask).await?,
+            Command::NAMES(channels) => names(cc, channels.as_deref()).await?,
+            Command::WHOIS(server, nick)
+                if ask_elsewhere(cc, server.as_deref(), &self.command).await? =>
+            {
+                whois(cc, nick).await?
+            }
+            Command::USERIP(nick) => userip(cc, nick).await?,
+            Command::HELP(subject) => {
+                let info = cc.info().clone();
+                match subject.as_deref() {
+                    None => {
+                        let index = help::index();
+                        cc.connection
+                            .write_help(
+                                &info,
+                                "*",
+                                "HELP <command> for more on one of these:",
+                                &index,
+                            )
+                            .await?;
+                    }
+                    Some(subject) => match help::topic(subject) {
+                        Some(topic) => {
+                            let text: Vec<String> =
+                                topic.text.iter().map(|x| x.to_string()).collect();
+                            cc.connection
+                                .write_help(&info, topic.name, topic.usage, &text)
+                                .await?;
+                        }
+                        None => cc.connection.write_help_not_found(&info, subject).await?,
+                    },
+                }
+            }
+            Command::LANGUAGE(language) => {
+                let info = cc.info().clone();
+                match language {
+                    Some(language) if cc.connection.catalog.has(language) => {
+                        cc.connection.language = Some(language.clone());
+                        cc.connection.write_your_language(&info, language).await?;
+                    }
+                    Some(language) => cc.connection.write_no_language(&info, language).await?,
+                    None => {
+                        let languages = cc.connection.catalog.languages().join(" ");
+                        cc.connection
+                            .write_notice(&info, format!("Languages: {}", languages))
+                            .await?;
+                    }
+                }
+            }
+            Command::STATS(query, _) => {
+                let kind = match query.to_lowercase().as_str() {
+                    "k" => Some(BanKind::Kline),
+                    "d" => Some(BanKind::Dline),
+                    _ => None,
+                };
+                if let Some(kind) = kind {
+                    if !cc.check_privilege(Privilege::Kline).await? {
+                        return Ok(Code::Fine);
+                    }
+                    let info = cc.info().clone();
+                    for ban in cc.bans.list(kind) {
+                        cc.connection.write_stats_ban(&info, &ban).await?;
+                    }
+                } else if query.eq_ignore_ascii_case("p") {
+                    stats_opers(cc).await?;
+                } else if query.eq_ignore_ascii_case("u") {
+                    let info = cc.info().clone();
+                    let uptime = Utc::now() - cc.started;
+                    cc.connection.write_stats_uptime(&info, uptime).await?;
+                }
+                let info = cc.info().clone();
+                cc.connection.write_end_of_stats(&info, query).await?;
+            }
+            Command::ENCAP(..) => {
+                if !cc.check_privilege(Privilege::Connect).await? {
+                    return Ok(Code::Fine);
+                }
+                let message = Message {
+                    tags: None,
+                    source: Some(cc.info().to_canonical()),
+                    command: self.command.clone(),
+                    side: Side::Server,
+                };
+                cc.cluster.encap(&message, &cc.events);
+            }
+            Command::SQUIT(node, reason) => {
+                if !cc.check_privilege(Privilege::Connect).await? {
+                    return Ok(Code::Fine);
+                }
+                let info = cc.info().clone();
+                let message = Message {
+                    tags: None,
+                    source: Some(info.to_canonical()),
+                    command: self.command.clone(),
+                    side: Side::Server,
+                };
+                if cc.cluster.squit(node, &message).await {
+                    cc.events.publish(Event::Squit {
+                        by: info.to_canonical(),
+                        node: node.clone(),
+                        reason: reason.clone(),
+                    });
+                } else {
+                    cc.connection.write_no_such_server(&info, node).await?;
+                }
+            }
+            Command::FILTER(subcommand, params) => {
+                if !cc.check_privilege(Privilege::Filter).await? {
+                    return Ok(Code::Fine);
+                }
+                let info = cc.info().clone();
+                match (subcommand.as_str(), params.as_slice()) {
+                    ("LIST", _) => {
+                        for filter in cc.filters.list() {
+                            let commands = match filter.commands.is_empty() {
+                                true => "*".to_string(),
+                                false => filter.commands.join(","),
+                            };
+                            let kind = if filter.regex { "regex" } else { "substring" };
+                            cc.connection
+                                .write_notice(
+                                    &info,
+                                    format!(
+                                        "{} {} {} :{}",
+                                        filter.action.as_str(),
+                                        commands,
+                                        kind,
+                                        filter.pattern
+                                    ),
+                                )
+                                .await?;
+                        }
+                        cc.connection
+                            .write_notice(&info, "End of filter list")
+                            .await?;
+                    }
+                    ("ADD", [action, commands, kind, pattern]) => {
+                        let res = action
+                            .parse::<FilterAction>()
+                            .map_err(|e| e.into())
+                            .and_then(|action| {
+                                cc.filters
+                                    .add(FilterConfig {
+                                        pattern: pattern.clone(),
+                                        regex: kind.eq_ignore_ascii_case("regex"),
+                                        action,
+                                        commands: match commands.as_str() {
+                                            "*" => Vec::new(),
+                                            _ => commands
+                                                .to_lowercase()
+                                                .split(',')
+                                                .map(|x| x.to_string())
+                                                .collect(),
+                                        },
+                                        reason: None,
+                                    })
+                                    .map(|()| action)
+                            });
+                        let notice = match res {
+                            Ok(action) => {
+                                cc.events.publish(Event::FilterAdded {
+                                    by: info.to_canonical(),
+                                    action: action.as_str(),
+                                    pattern: pattern.clone(),
+                                });
+                                format!("Added filter {}", pattern)
+                            }
+                            Err(e) => format!("Couldn't add filter: {}", e),
+                        };
+                        cc.connection.write_notice(&info, notice).await?;
+                    }
+                    ("DEL", [pattern]) => {
+                        let notice = if cc.filters.remove(pattern) {
+                            cc.events.publish(Event::FilterRemoved {
+                                by: info.to_canonical(),
+                                pattern: pattern.clone(),
+                            });
+                            format!("Removed filter {}", pattern)
+                        } else {
+                            format!("No filter {}", pattern)
+                        };
+                        cc.connection.write_notice(&info, notice).await?;
+                    }
+                    _ => {
+                        cc.connection
+                            .write_notice(
+                                &info,
+                                "Usage: FILTER LIST | FILTER ADD <action> <commands|*> <substring|regex> :<pattern> | FILTER DEL :<pattern>",
+                            )
+                            .await?;
+                    }
+                }
+            }
+            Command::DEFCON(level) => {
+                if !cc.check_privilege(Privilege::Defcon).await? {
+                    return Ok(Code::Fine);
+                }
+                let info = cc.info().clone();
+                let text = match level.as_deref().map(str::parse::<u8>) {
+                    None => format!("DEFCON is at {}", cc.defcon_level()),
+                    Some(Ok(level)) if (defcon::CLOSED..=defcon::NORMAL).contains(&level) => {
+                        cc.defcon.set(level, Instant::now());
+                        cc.events.publish(Event::DefconChanged {
+                            by: Some(info.to_canonical()),
+                            level,
+                        });
+                        format!("DEFCON is now {}", level)
+                    }
+                    Some(_) => format!(
+                        "DEFCON levels go from {} for normal down to {}",
+                        defcon::NORMAL,
+                        defcon::CLOSED
+                    ),
+                };
+                cc.connection.write_notice(&info, text).await?;
+            }
+            Command::DIE if cc.check_privilege(Privilege::Die).await? => {
+                cc.events.publish(Event::Died {
+                    by: cc.info().to_canonical(),
+                });
+                cc.die().await?;
+            }
+            Command::AUTHENTICATE(param) => authenticate(cc, param).await?,
+            Command::MODE(target, modestring, args) => match self.side {
+                Side::Client => {
+                    let args = args.as_deref().unwrap_or_default();
+                    return channel_mode(cc, target, modestring.as_deref(), args).await;
+                }
+                // Safety: we terminate the line ourselves.
+                Side::Server => unsafe {
+                    cc.connection.write_raw(format!("{}\r\n", self)).await?;
+                },
+                _ => {}
+            },
             Command::PING(token) => {
                 cc.connection.write_pong(token).await?;
             }
-            Command::MOTD(_) => {
-                cc.connection.write_motd(&cc.info).await?;
+            Command::PONG(_, token) => cc.ponged(token),
+            Command::MOTD(server)
+                if ask_elsewhere(cc, server.as_deref(), &self.command).await? =>
+            {
+                let info = cc.info().clone();
+                cc.connection.write_motd(&info, &cc.config.motd).await?;
+            }
+            Command::LUSERS(..) => cc.write_lusers().await?,
+            Command::ADMIN(target) if ours(cc, target.as_deref()).await? => {
+                let info = cc.info().clone();
+                cc.connection.write_admin(&info).await?;
+            }
+            Command::LINKS(server, mask) if ours(cc, server.as_deref()).await? => {
+                let info = cc.info().clone();
+                let mask = mask.as_deref().unwrap_or("*");
+                cc.connection.write_links(&info, mask).await?;
             }
-            Command::QUIT(_reason) => {
+            Command::RULES => {
+                let info = cc.info().clone();
+                let rules = match &cc.config.rules {
+                    Some(path) => tokio::fs::read_to_string(path)
+                        .await
+                        .inspect_err(|e| log::error!("Failed to read {}: {}", path.display(), e))
+                        .ok(),
+                    None => None,
+                };
+                cc.connection.write_rules(&info, rules.as_deref()).await?;
+            }
+            Command::QUIT(reason) => {
+                cc.quit_reason = reason.clone();
+                if let Some(reason) = reason {
+                    if let Some(hit) = cc.filters.check("quit", reason) {
+                        match cc.filtered(hit, reason).await? {
+                            Code::Exit => return Ok(Code::Exit),
+                            Code::Broadcast => {}
+                            Code::Fine => cc.quit_reason = None,
+                        }
+                    }
+                }
                 cc.connection.write_error("Goodbye!").await?;
                 return Ok(Code::Exit);
             }
-            Command::PRIVMSG(_targets, _message) => match self.side {
-                Side::Client => return Ok(Code::Broadcast),
-                // Safety: self.to_string() always ends with \r\n.
-                Side::Server => unsafe {
-                    let str = self.to_string();
-                    cc.connection.write_raw(str).await?;
-                },
+            Command::PRIVMSG(targets, message) => match self.side {
+                Side::Client => {
+                    if let Some(hit) = cc.filters.check("privmsg", message) {
+                        match cc.filtered(hit, message).await? {
+                            Code::Broadcast => {}
+                            code => return Ok(code),
+                        }
+                    }
+                    let (mut channels, nicks): (Vec<String>, Vec<String>) = targets
+                        .iter()
+                        .cloned()
+                        .partition(|x| is_channel(channel::split_status(x).1));
+                    let info = cc.info().clone();
+                    let hostmask = info.to_canonical();
+                    let mut quieted = Vec::new();
+                    channels.retain(|x| {
+                        let channel = channel::split_status(x).1;
+                        let allowed = !cc.channels.is_quieted(channel, &info.uid, &hostmask);
+                        if !allowed {
+                            quieted.push(channel.to_string());
+                        }
+                        allowed
+                    });
+                    for channel in &quieted {
+                        cc.connection.write_cannot_send(&info, channel).await?;
+                    }
+                    let mut tags = cc.tags.relay(self.tags.as_deref(), Instant::now());
+                    if info.bot {
+                        tags = Some(tags::with_bot(tags));
+                    }
+                    if let Some(account) = &info.account {
+                        tags = Some(tags::with_account(tags, account));
+                    }
+                    for nick in &nicks {
+                        if nick.eq_ignore_ascii_case(nickserv::NICK) && cc.users.uid(nick).is_none()
+                        {
+                            if let Code::Exit = nickserv::handle(cc, message).await? {
+                                return Ok(Code::Exit);
+                            }
+                            continue;
+                        }
+                        cc.message_user(nick, message, tags.clone()).await?;
+                    }
+                    if !channels.is_empty() {
+                        cc.broadcast(Message {
+                            tags,
+                            command: Command::PRIVMSG(channels, message.clone()),
+                            ..self.clone()
+                        })
+                        .await?;
+                    }
+                }
+                Side::Server => {
+                    let mut message = self.clone();
+                    message.tags = cc.tags_wanted().keep(message.tags.take());
+                    // Safety: we terminate the line ourselves.
+                    unsafe {
+                        cc.connection.write_raw(format!("{}\r\n", message)).await?;
+                    }
+                }
                 _ => {}
             },
-            Command::JOIN(targets, _keys) => match self.side {
+            Command::TAGMSG(targets) => match self.side {
                 Side::Client => {
-                    for chan in targets {
-                        cc.info.channels.push(chan.clone());
+                    let mut tags = match cc.tags.relay(self.tags.as_deref(), Instant::now()) {
+                        Some(tags) => tags,
+                        None => return Ok(Code::Fine),
+                    };
+                    let info = cc.info().clone();
+                    if info.bot {
+                        tags = tags::with_bot(Some(tags));
+                    }
+                    if let Some(account) = &info.account {
+                        tags = tags::with_account(Some(tags), account);
+                    }
+                    let hostmask = info.to_canonical();
+                    let (channels, nicks): (Vec<String>, Vec<String>) = targets
+                        .iter()
+                        .cloned()
+                        .partition(|x| is_channel(channel::split_status(x).1));
+                    for nick in &nicks {
+                        cc.tag_user(nick, tags.clone()).await?;
+                    }
+                    // Nobody cares that someone quieted is typing
+                    let channels: Vec<String> = channels
+                        .into_iter()
+                        .filter(|x| {
+                            !cc.channels.is_quieted(
+                                channel::split_status(x).1,
+                                &info.uid,
+                                &hostmask,
+                            )
+                        })
+                        .collect();
+                    if !channels.is_empty() {
+                        cc.broadcast(Message {
+                            tags: Some(tags),
+                            command: Command::TAGMSG(channels),
+                            ..self.clone()
+                        })
+                        .await?;
                     }
-                    // We have to parrot the client's JOIN back to them.
-                    // Safety: self.to_string() always ends with \r\n
+                }
+                // Clients that don't speak message-tags would only see an empty message
+                Side::Server if cc.caps.contains(capability::MESSAGE_TAGS) => {
+                    // Safety: we terminate the line ourselves.
                     unsafe {
-                        cc.connection.write_raw(self.to_string()).await?;
+                        cc.connection.write_raw(format!("{}\r\n", self)).await?;
+                    }
+                }
+                _ => {}
+            },
+            Command::JOIN(targets, _) => match self.side {
+                // Leaves every channel instead
+                Side::Client if targets == &["0"] => {
+                    let channels = cc.info().channels.clone();
+                    return part(cc, &channels, None).await;
+                }
+                Side::Client => {
+                    let info = cc.info().clone();
+                    let mut allowed = Vec::new();
+                    for chan in targets {
+                        // Spelled the way whoever made it did
+                        let chan = &cc.channels.name(chan);
+                        // Already in it, nothing to tell anyone
+                        if cc.channels.status(chan, &info.uid).is_some()
+                            || allowed
+                                .iter()
+                                .any(|x: &String| x.eq_ignore_ascii_case(chan))
+                        {
+                            continue;
+                        }
+                        if !valid_channel(cc, chan).await? || !may_create(cc, chan).await? {
+                            continue;
+                        }
+                        if cc.scripts.on_join(&info.nickname, chan) == Verdict::Block
+                            || cc.channels.is_banned(chan, &info.to_canonical())
+                        {
+                            cc.connection.write_cannot_join(&info, chan).await?;
+                        } else if !defcon_allows_join(cc, chan) {
+                            cc.connection.write_unavailable(&info, chan).await?;
+                        } else {
+                            allowed.push(chan.clone());
+                        }
+                    }
+                    if allowed.is_empty() {
+                        return Ok(Code::Fine);
+                    }
+                    cc.info().channels.extend(allowed.iter().cloned());
+                    cc.channels_joined += allowed.len();
+                    // One at a time, so nobody hears about channels they aren't in and keys go no further
+                    for chan in allowed {
+                        cc.channels.join(&chan, &info.uid);
+                        cc.events.publish(Event::UserJoined {
+                            nick: info.nickname.clone(),
+                            channel: chan.clone(),
+                        });
+                        cc.echo(Message {
+                            tags: None,
+                            source: None,
+                            command: Command::JOIN(vec![chan.clone()], None),
+                            side: Side::Server,
+                        })
+                        .await?;
+                        if let Some(topic) = cc.channels.topic(&chan) {
+                            cc.connection
+                                .write_topic(&info, &chan, Some(&topic))
+                                .await?;
+                        }
+                        cc.names(&info, &chan).await?;
+                        cc.send_read_marker(&chan).await?;
                     }
-                    return Ok(Code::Broadcast);
                 }
                 Side::Server => {
-                    // Safety: self.to_string() always ends with \r\n.
+                    // Safety: we terminate the line ourselves.
                     unsafe {
-                        cc.connection.write_raw(self.to_string()).await?;
+                        cc.connection.write_raw(format!("{}\r\n", self)).await?;
                     }
                 }
                 _ => {}
             },
-            Command::UNKNOWN(attempt) | Command::UNIMPLEMENTED(attempt) => {
-                cc.connection.write_unknown(&cc.info, attempt).await?;
+            Command::PART(targets, reason) => match self.side {
+                Side::Client => return part(cc, targets, reason.as_deref()).await,
+                // Safety: we terminate the line ourselves.
+                Side::Server => unsafe {
+                    cc.connection.write_raw(format!("{}\r\n", self)).await?;
+                },
+                _ => {}
+            },
+            Command::TOPIC(channel, text) => match self.side {
+                Side::Client => return topic(cc, channel, text.as_deref()).await,
+                // Safety: we terminate the line ourselves.
+                Side::Server => unsafe {
+                    cc.connection.write_raw(format!("{}\r\n", self)).await?;
+                },
+                _ => {}
+            },
+            Command::TOPICHISTORY(channel, restore) => {
+                if let Side::Client = self.side {
+                    return topic_history(cc, channel, restore.as_deref()).await;
+                }
+            }
+            Command::LIST(params, _) => {
+                let info = cc.info().clone();
+                let now = Utc::now().timestamp();
+                let (mut names, mut conditions) = (Vec::new(), Vec::new());
+                for param in params.iter().flatten() {
+                    match param.starts_with(['T', 't']) && !is_channel(param) {
+                        true => conditions.push(param.as_str()),
+                        false => names.push(param.as_str()),
+                    }
+                }
+                // Listing everything is the expensive one, so non-opers can only do it every so often
+                if names.is_empty() && info.oper.is_none() {
+                    let now = Instant::now();
+                    if !cc.config.big_replies.list_allowed(cc.last_list, now) {
+                        cc.connection.write_try_again(&info, "LIST").await?;
+                        return Ok(Code::Fine);
+                    }
+                    cc.last_list = Some(now);
+                }
+                let spy = spy(cc);
+                let list: Vec<_> = cc
+                    .channels
+                    .list()
+                    .into_iter()
+                    .filter(|x| {
+                        names.is_empty() || names.iter().any(|y| y.eq_ignore_ascii_case(&x.name))
+                    })
+                    .filter(|x| {
+                        conditions
+                            .iter()
+                            .all(|y| x.topic_condition(y, now).unwrap_or(true))
+                    })
+                    .filter(|x| spy || !hidden(cc, &x.name))
+                    .collect();
+                // Opers only see other secret channels by spying on them
+                let spied_on: Vec<_> = list
+                    .iter()
+                    .filter(|x| hidden(cc, &x.name))
+                    .map(|x| x.name.as_str())
+                    .collect();
+                if !spied_on.is_empty() {
+                    spied(cc, "LIST", &spied_on.join(","));
+                }
+                let mut pacer = Pacer::new(&cc.config.big_replies, info.oper.is_some());
+                for channel in &list {
+                    if !pacer.line(&mut cc.connection).await? {
+                        break;
+                    }
+                    cc.connection.write_list(&info, channel).await?;
+                }
+                if pacer.truncated {
+                    cc.connection.write_too_many_matches(&info, "LIST").await?;
+                }
+                cc.connection.write_list_end(&info).await?;
+            }
+            Command::UNKNOWN(attempt) => {
+                let info = cc.info().clone();
+                match cc.plugins.handle_command(&info.nickname, attempt) {
+                    Some(reply) if reply.is_empty() => {}
+                    Some(reply) => cc.connection.write_notice(&info, reply).await?,
+                    None => cc.connection.write_unknown(&info, attempt).await?,
+                }
+            }
+            // Another node answering a WHOIS or MOTD we sent it
+            Command::NUMERIC(..) if self.side == Side::Server => {
+                cc.awaiting = None;
+                // Safety: we terminate the line ourselves.
+                unsafe {
+                    cc.connection.write_raw(format!("{}\r\n", self)).await?;
+                }
+            }
+            Command::UNIMPLEMENTED(attempt) => {
+                let info = cc.info().clone();
+                cc.connection.write_unknown(&info, attempt).await?;
             }
             _ => {}
         }
         Ok(Code::Fine)
     }
 }
+
+/// Makes sure `name` could be a channel, telling the user why not if it can't.
+async fn valid_channel(cc: &mut ClientConnection, name: &str) -> Result<bool> {
+    let info = cc.info().clone();
+    match channel::check_name(name, &cc.config.chantypes, cc.config.limits.channel) {
+        Ok(()) => return Ok(true),
+        Err(BadName::NotAChannel) => cc.connection.write_no_such_channel(&info, name).await?,
+        Err(BadName::Mask) => cc.connection.write_bad_chan_mask(&info, name).await?,
+    }
+    Ok(false)
+}
+
+/// Whether we're allowed to make `channel` if it doesn't exist yet, telling the user why not if they aren't.
+async fn may_create(cc: &mut ClientConnection, channel: &str) -> Result<bool> {
+    let info = cc.info().clone();
+    let creation = cc.config.channel_creation;
+    if cc.channels.created(channel).is_some()
+        || creation.allows(info.oper.is_some(), info.account.is_some())
+    {
+        return Ok(true);
+    }
+    match creation {
+        ChannelCreation::Opers => cc.connection.write_no_privileges(&info).await?,
+        _ => cc.connection.write_need_account(&info, channel).await?,
+    }
+    Ok(false)
+}
+
+/// Whether DEFCON lets us join `channel` right now, counting it against the JOIN throttle if it does. Opers
+/// always get in.
+fn defcon_allows_join(cc: &mut ClientConnection, channel: &str) -> bool {
+    if cc.info().oper.is_some() {
+        return true;
+    }
+    let level = cc.defcon_level();
+    if level <= defcon::NO_NEW_CHANNELS && cc.channels.created(channel).is_none() {
+        return false;
+    }
+    if level <= defcon::THROTTLE_JOINS {
+        let now = Instant::now();
+        let interval = Duration::from_secs(cc.config.defcon.join_interval);
+        if cc.last_join.is_some_and(|x| now < x + interval) {
+            return false;
+        }
+        cc.last_join = Some(now);
+    }
+    true
+}
+
+/// Returns `true` if `channel` is secret and we aren't in it.
+fn hidden(cc: &ClientConnection, channel: &str) -> bool {
+    let uid = cc.info().uid.clone();
+    cc.channels.is_secret(channel) && cc.channels.status(channel, &uid).is_none()
+}
+
+/// Returns `true` if we're an oper who can see secret channels.
+fn spy(cc: &ClientConnection) -> bool {
+    cc.info()
+        .oper
+        .as_ref()
+        .is_some_and(|x| x.contains(&Privilege::Spy))
+}
+
+/// Puts an oper looking into secret channels they aren't in in the audit log.
+fn spied(cc: &ClientConnection, command: &'static str, target: &str) {
+    let by = cc.info().to_canonical();
+    cc.events.publish(Event::OperSpied {
+        by,
+        command,
+        target: target.to_string(),
+    });
+}
+
+/// Whether we can see into `channel` with `command`. Anyone can unless it's secret and they aren't in it, when only
+/// opers with `spy` can, and that's audited.
+fn look_into(cc: &ClientConnection, channel: &str, command: &'static str) -> bool {
+    if !hidden(cc, channel) {
+        return true;
+    }
+    let spy = spy(cc);
+    if spy {
+        spied(cc, command, channel);
+    }
+    spy
+}
+
+/// PART, leaving each of `targets` and telling whoever is still in them. The last one out of a channel takes it with
+/// them.
+async fn part(cc: &mut ClientConnection, targets: &[String], reason: Option<&str>) -> Result<Code> {
+    let info = cc.info().clone();
+    let mut reason = reason;
+    if let Some(text) = reason {
+        if let Some(hit) = cc.filters.check("part", text) {
+            match cc.filtered(hit, text).await? {
+                Code::Exit => return Ok(Code::Exit),
+                Code::Broadcast => {}
+                // Still leaving, just without saying that
+                Code::Fine => reason = None,
+            }
+        }
+    }
+    for chan in targets {
+        if !valid_channel(cc, chan).await? {
+            continue;
+        }
+        let chan = &cc.channels.name(chan);
+        if cc.channels.part(chan, &info.uid) {
+            cc.info().channels.retain(|x| !x.eq_ignore_ascii_case(chan));
+            cc.events.publish(Event::UserParted {
+                nick: info.nickname.clone(),
+                channel: chan.clone(),
+            });
+            // One at a time, same as JOIN
+            cc.echo(Message {
+                tags: None,
+                source: None,
+                command: Command::PART(vec![chan.clone()], reason.map(str::to_string)),
+                side: Side::Server,
+            })
+            .await?;
+        } else if cc.channels.created(chan).is_none() {
+            cc.connection.write_no_such_channel(&info, chan).await?;
+        } else {
+            cc.connection.write_not_on_channel(&info, chan).await?;
+        }
+    }
+    Ok(Code::Fine)
+}
+
+/// TOPIC, showing the topic without `text` or setting it with it. Anyone in the channel can set it, and everyone in
+/// it hears about the change.
+async fn topic(cc: &mut ClientConnection, channel: &str, text: Option<&str>) -> Result<Code> {
+    let info = cc.info().clone();
+    if !valid_channel(cc, channel).await? {
+        return Ok(Code::Fine);
+    }
+    if cc.channels.created(channel).is_none() {
+        cc.connection.write_no_such_channel(&info, channel).await?;
+        return Ok(Code::Fine);
+    }
+    let text = match text {
+        Some(text) => truncate(text, cc.config.limits.topic),
+        None if !look_into(cc, channel, "TOPIC") => {
+            cc.connection.write_no_such_channel(&info, channel).await?;
+            return Ok(Code::Fine);
+        }
+        None => {
+            let topic = cc.channels.topic(channel);
+            cc.connection
+                .write_topic(&info, channel, topic.as_ref())
+                .await?;
+            return Ok(Code::Fine);
+        }
+    };
+    if cc.channels.status(channel, &info.uid).is_none() {
+        cc.connection.write_not_on_channel(&info, channel).await?;
+        return Ok(Code::Fine);
+    }
+    set_topic(cc, &info, channel, text).await
+}
+
+/// Sets the topic of `channel` as `info` and tells everyone in it.
+async fn set_topic(
+    cc: &mut ClientConnection,
+    info: &ClientInfo,
+    channel: &str,
+    text: &str,
+) -> Result<Code> {
+    let keep = cc.config.topic_history;
+    cc.channels
+        .set_topic(channel, text, &info.to_canonical(), keep);
+    let message = Message {
+        tags: None,
+        source: Some(info.to_canonical()),
+        command: Command::TOPIC(channel.to_string(), Some(text.to_string())),
+        side: Side::Server,
+    };
+    // Safety: we terminate the line ourselves.
+    unsafe {
+        cc.connection.write_raw(format!("{}\r\n", message)).await?;
+    }
+    cc.broadcast(message).await?;
+    Ok(Code::Fine)
+}
+
+/// TOPICHISTORY, the topics a channel had before this one, newest first. Ops can put one back with RESTORE, which
+/// goes out like any other TOPIC and keeps the one it replaces in the history too.
+async fn topic_history(
+    cc: &mut ClientConnection,
+    channel: &str,
+    restore: Option<&str>,
+) -> Result<Code> {
+    let info = cc.info().clone();
+    if !valid_channel(cc, channel).await? {
+        return Ok(Code::Fine);
+    }
+    if cc.channels.created(channel).is_none() || !look_into(cc, channel, "TOPICHISTORY") {
+        cc.connection.write_no_such_channel(&info, channel).await?;
+        return Ok(Code::Fine);
+    }
+    let old = cc.channels.old_topics(channel);
+    let Some(n) = restore else {
+        for (i, topic) in old.iter().enumerate() {
+            let set_at = DateTime::from_timestamp(topic.set_at, 0)
+                .map_or(String::new(), |x| x.format("%Y-%m-%d %H:%M:%S").to_string());
+            cc.connection
+                .write_notice(
+                    &info,
+                    format!(
+                        "{} {}. [{}] {}: {}",
+                        channel,
+                        i + 1,
+                        set_at,
+                        topic.set_by,
+                        topic.text
+                    ),
+                )
+                .await?;
+        }
+        cc.connection
+            .write_notice(&info, format!("End of topic history for {}", channel))
+            .await?;
+        return Ok(Code::Fine);
+    };
+    if cc
+        .channels
+        .status(channel, &info.uid)
+        .is_none_or(|x| x < channel::Status::Op)
+    {
+        cc.connection.write_not_chanop(&info, channel).await?;
+        return Ok(Code::Fine);
+    }
+    let topic = n
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| old.get(n.checked_sub(1)?));
+    let Some(topic) = topic else {
+        cc.connection
+            .write_notice(&info, format!("{} has no topic {} to restore", channel, n))
+            .await?;
+        return Ok(Code::Fine);
+    };
+    set_topic(cc, &info, channel, &topic.text).await
+}
+
+/// MODE on a channel. `b` and `q` take a mask to ban or quiet, or list the bans or quiets without one, and the
+/// statuses (`yaohv`) take a nick. Mode changes go to everyone in the channel.
+async fn channel_mode(
+    cc: &mut ClientConnection,
+    target: &str,
+    modestring: Option<&str>,
+    args: &[String],
+) -> Result<Code> {
+    if !is_channel(target) {
+        return user_mode(cc, target, modestring, args).await;
+    }
+    if !valid_channel(cc, target).await? {
+        return Ok(Code::Fine);
+    }
+    let info = cc.info().clone();
+    let modestring = match modestring {
+        Some(modestring) => modestring,
+        None => {
+            match cc.channels.created(target) {
+                Some(created) if look_into(cc, target, "MODE") => {
+                    let modes = cc.channels.modes(target);
+                    cc.connection
+                        .write_channel_modes(&info, target, &modes, created)
+                        .await?
+                }
+                _ => cc.connection.write_no_such_channel(&info, target).await?,
+            }
+            return Ok(Code::Fine);
+        }
+    };
+    let ours = cc
+        .channels
+        .status(target, &info.uid)
+        .unwrap_or(channel::Status::Member);
+    // What actually changed, to send out
+    let mut changed = Vec::new();
+    let mut told_off = false;
+    for parsed in mode::parse(&mode::CHANNEL, modestring, args) {
+        let (adding, mode, arg) = match parsed {
+            mode::Parsed::Change(mode::Change {
+                adding,
+                mode,
+                arg: Some(arg),
+            }) => match mode {
+                'b' | 'q' => (adding, mode, Some(channel::normalize_mask(&arg))),
+                _ => (adding, mode, Some(arg)),
+            },
+            mode::Parsed::Change(mode::Change { adding, mode, .. })
+                if mode::CHANNEL.kind(mode) == Some(mode::Kind::Never) =>
+            {
+                (adding, mode, None)
+            }
+            // Otherwise only lists can go without a parameter
+            mode::Parsed::Change(mode::Change { mode, .. }) => {
+                match (look_into(cc, target, "MODE"), mode) {
+                    (true, 'q') => {
+                        let quiets = cc.channels.quiets(target);
+                        cc.connection
+                            .write_quiet_list(&info, target, &quiets)
+                            .await?;
+                    }
+                    (true, _) => {
+                        let bans = cc.channels.bans(target);
+                        cc.connection.write_ban_list(&info, target, &bans).await?;
+                    }
+                    (false, _) => cc.connection.write_no_such_channel(&info, target).await?,
+                }
+                continue;
+            }
+            mode::Parsed::Unknown(mode) => {
+                cc.connection.write_unknown_mode(&info, mode).await?;
+                continue;
+            }
+        };
+
+        let allowed = match channel::Status::from_mode(mode) {
+            Some(status) => ours.can_set(status),
+            None => ours >= channel::Status::Halfop,
+        };
+        if !allowed {
+            if !told_off {
+                cc.connection.write_not_chanop(&info, target).await?;
+                told_off = true;
+            }
+            continue;
+        }
+        let arg = match arg {
+            Some(arg) => arg,
+            // +N and +s
+            None => {
+                let done = match mode {
+                    'N' => cc.channels.set_no_history(target, adding),
+                    _ => cc.channels.set_secret(target, adding),
+                };
+                // Opting out takes whatever was kept with it
+                if done && mode == 'N' && adding {
+                    cc.history.forget(target);
+                }
+                if done {
+                    changed.push(mode::Change {
+                        adding,
+                        mode,
+                        arg: None,
+                    });
+                }
+                continue;
+            }
+        };
+        let done = match channel::Status::from_mode(mode) {
+            Some(status) => {
+                let uid = match cc.users.uid(&arg) {
+                    Some(uid) => uid,
+                    None => {
+                        cc.connection.write_no_such_nick(&info, &arg).await?;
+                        continue;
+                    }
+                };
+                match cc.channels.status(target, &uid) {
+                    // Nobody gets to touch someone above them, though anyone can step down
+                    Some(theirs) if theirs > ours && uid != info.uid => {
+                        cc.connection.write_not_chanop(&info, target).await?;
+                        continue;
+                    }
+                    Some(_) => cc.channels.set_status(target, &uid, status, adding),
+                    None => {
+                        cc.connection
+                            .write_not_in_channel(&info, &arg, target)
+                            .await?;
+                        continue;
+                    }
+                }
+            }
+            None => match (mode, adding) {
+                ('q', true) => cc.channels.add_quiet(target, &arg, &info.nickname),
+                ('q', false) => cc.channels.remove_quiet(target, &arg),
+                (_, true) => cc.channels.add_ban(target, &arg, &info.nickname),
+                (_, false) => cc.channels.remove_ban(target, &arg),
+            },
+        };
+        if done {
+            changed.push(mode::Change {
+                adding,
+                mode,
+                arg: Some(arg),
+            });
+        }
+    }
+    if changed.is_empty() {
+        return Ok(Code::Fine);
+    }
+
+    let (changed, changed_args) = mode::join(&changed);
+    let mode = Message {
+        tags: None,
+        source: Some(info.to_canonical()),
+        command: Command::MODE(target.to_string(), Some(changed), Some(changed_args)),
+        side: Side::Server,
+    };
+    // Safety: we terminate the line ourselves.
+    unsafe {
+        cc.connection.write_raw(format!("{}\r\n", mode)).await?;
+    }
+    cc.broadcast(mode).await?;
+    Ok(Code::Fine)
+}
+
+/// STATS p, every oper around and how long they've been idle, so users can find someone to help. Opers hiding with
+/// +H are only listed for other opers.
+async fn stats_opers(cc: &mut ClientConnection) -> Result<()> {
+    let info = cc.info().clone();
+    let mut opers = cc.users.opers();
+    if info.oper.is_none() {
+        opers.retain(|x| !x.hide_oper);
+    }
+    opers.sort_by(|a, b| a.nickname.cmp(&b.nickname));
+    for oper in opers {
+        let (oper, privacy) = cc.config.privacy.shown(&info, oper);
+        let idle = (!privacy.hides_idle()).then(|| oper.idle());
+        cc.connection.write_stats_oper(&info, &oper, idle).await?;
+    }
+    Ok(())
+}
+
+/// MODE on a user, which can only be yourself: `B` for bots, `H` for opers hiding that they are, and `s`, server
+/// notices for opers, which takes the snomask letters to add or remove (all of them without any).
+async fn user_mode(
+    cc: &mut ClientConnection,
+    target: &str,
+    modestring: Option<&str>,
+    args: &[String],
+) -> Result<Code> {
+    let info = cc.info().clone();
+    if !target.eq_ignore_ascii_case(&info.nickname) {
+        cc.connection.write_users_dont_match(&info).await?;
+        return Ok(Code::Fine);
+    }
+    let modestring = match modestring {
+        Some(modestring) => modestring,
+        None => return Ok(Code::Fine),
+    };
+    let mut masks = info.snomasks.clone();
+    let mut bot = info.bot;
+    let mut hide_oper = info.hide_oper;
+    for parsed in mode::parse(&mode::USER, modestring, args) {
+        let change = match parsed {
+            mode::Parsed::Change(change) => change,
+            mode::Parsed::Unknown(_) => {
+                cc.connection.write_unknown_umode(&info).await?;
+                continue;
+            }
+        };
+        match change.mode {
+            'B' => bot = change.adding,
+            'H' if change.adding && info.oper.is_none() => {
+                cc.connection.write_no_privileges(&info).await?;
+                return Ok(Code::Fine);
+            }
+            'H' => hide_oper = change.adding,
+            's' if !change.adding => masks.clear(),
+            _ => {
+                if info.oper.is_none() {
+                    cc.connection.write_no_privileges(&info).await?;
+                    return Ok(Code::Fine);
+                }
+                let letters = change.arg.as_deref().unwrap_or("*");
+                if snomask::apply(&mut masks, letters).is_err() {
+                    cc.connection.write_unknown_umode(&info).await?;
+                }
+            }
+        }
+    }
+    if masks != info.snomasks {
+        cc.connection
+            .write_snomask(&info, &snomask::to_string(&masks))
+            .await?;
+        cc.info().snomasks = masks;
+    }
+    if bot != info.bot {
+        cc.info().bot = bot;
+        let change = if bot { "+B" } else { "-B" };
+        cc.connection.write_user_mode(&info, change).await?;
+    }
+    if hide_oper != info.hide_oper {
+        cc.info().hide_oper = hide_oper;
+        let change = if hide_oper { "+H" } else { "-H" };
+        cc.connection.write_user_mode(&info, change).await?;
+    }
+    Ok(Code::Fine)
+}
+
+/// Sends a WHOIS or MOTD aimed at `server` off to whichever other nodes should answer it, see `cluster`. Returns
+/// `true` if we should answer it too: there's no server, or it's our name, a nick here, or a node mask matching us.
+async fn ask_elsewhere(
+    cc: &mut ClientConnection,
+    server: Option<&str>,
+    command: &Command,
+) -> Result<bool> {
+    let Some(server) = server else {
+        return Ok(true);
+    };
+    let info = cc.info().clone();
+    if server.eq_ignore_ascii_case(&cc.connection.server_name()) || cc.users.uid(server).is_some() {
+        return Ok(true);
+    }
+    // Standalone we have no id, so only masks that match anything are for us
+    let ours = ban::glob_match(server, cc.cluster.id().unwrap_or_default());
+    let query = Message {
+        tags: None,
+        source: Some(info.to_canonical()),
+        command: command.clone(),
+        side: Side::Server,
+    };
+    match cc.cluster.query(server, &query).await {
+        Asked::Nobody if !ours => cc.connection.write_no_such_server(&info, server).await?,
+        // Nodes the mask doesn't match say nothing, so nobody answering is how we find out it matched none
+        Asked::Everyone if !ours => {
+            cc.awaiting = Some((server.to_string(), Instant::now() + cluster::ANSWER_TIMEOUT));
+        }
+        _ => {}
+    }
+    Ok(ours)
+}
+
+/// Whether a command aimed at `server` is for us, where it's our name or a nick here. Anything else gets
+/// ERR_NOSUCHSERVER, since only WHOIS and MOTD go to other nodes.
+async fn ours(cc: &mut ClientConnection, server: Option<&str>) -> Result<bool> {
+    let Some(server) = server else {
+        return Ok(true);
+    };
+    if server.eq_ignore_ascii_case(&cc.connection.server_name()) || cc.users.uid(server).is_some() {
+        return Ok(true);
+    }
+    let info = cc.info().clone();
+    cc.connection.write_no_such_server(&info, server).await?;
+    Ok(false)
+}
+
+/// WHOIS, for users on this server.
+async fn whois(cc: &mut ClientConnection, nick: &str) -> Result<()> {
+    let info = cc.info().clone();
+    let (target, privacy) = match cc.users.info(nick) {
+        Some(target) => cc.config.privacy.shown(&info, shown_oper(&info, target)),
+        None => return cc.connection.write_no_such_nick(&info, nick).await,
+    };
+    // Secret channels we aren't in are left out, unless we can spy on them
+    let spy = spy(cc);
+    let secret = target.channels.iter().any(|x| hidden(cc, x));
+    if spy && secret {
+        spied(cc, "WHOIS", &target.nickname);
+    }
+    let channels: Vec<String> = target
+        .channels
+        .iter()
+        .filter(|x| spy || !hidden(cc, x))
+        .map(
+            |x| match cc.channels.status(x, &target.uid).and_then(|x| x.prefix()) {
+                Some(prefix) => format!("{}{}", prefix, x),
+                None => x.clone(),
+            },
+        )
+        .collect();
+    let location = match info.oper {
+        Some(_) => cc.geoip.lookup_host(&target.ip),
+        None => None,
+    };
+    // Everyone can see their own, only opers who can spy see anyone else's
+    let own = target.uid == info.uid;
+    let real = own
+        || info
+            .oper
+            .as_ref()
+            .is_some_and(|x| x.contains(&Privilege::Spy));
+    if real && !own {
+        cc.events.publish(Event::RealIpShown {
+            by: info.to_canonical(),
+            nick: target.nickname.clone(),
+            command: "WHOIS",
+        });
+    }
+    let idle = (!privacy.hides_idle()).then(|| target.idle());
+    cc.connection
+        .write_whois(&info, &target, &channels, location.as_ref(), real, idle)
+        .await
+}
+
+/// CHGHOST, changes the host everyone sees someone with. Needs `chghost`, and goes in the audit log.
+async fn chghost(cc: &mut ClientConnection, nick: &str, host: &str) -> Result<()> {
+    if !cc.check_privilege(Privilege::Chghost).await? {
+        return Ok(());
+    }
+    let info = cc.info().clone();
+    if !valid_host(host) {
+        cc.connection
+            .write_notice(&info, format!("{} isn't a valid host", host))
+            .await?;
+        return Ok(());
+    }
+    let Some(old) = cc.users.set_host(nick, host) else {
+        return cc.connection.write_no_such_nick(&info, nick).await;
+    };
+    cc.events.publish(Event::HostChanged {
+        by: info.to_canonical(),
+        nick: old.nickname.clone(),
+        host: host.to_string(),
+    });
+    cc.announce_host(&old, host, &info.to_canonical()).await?;
+    cc.connection
+        .write_notice(
+            &info,
+            format!("Changed the host of {} to {}", old.nickname, host),
+        )
+        .await
+}
+
+/// Whether `host` is fine to show as someone's host, something like `user/tiger` or `cat.example` but nothing
+/// that would break a hostmask.
+fn valid_host(host: &str) -> bool {
+    !host.is_empty()
+        && host.len() <= 63
+        && !host.starts_with(['-', '.', '/', ':'])
+        && host
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || "-./:_".contains(x))
+}
+
+/// USERIP, someone's real address. Needs `spy`, and goes in the audit log.
+async fn userip(cc: &mut ClientConnection, nick: &str) -> Result<()> {
+    if !cc.check_privilege(Privilege::Spy).await? {
+        return Ok(());
+    }
+    let info = cc.info().clone();
+    let target = match cc.users.info(nick) {
+        Some(target) => target,
+        None => return cc.connection.write_no_such_nick(&info, nick).await,
+    };
+    cc.events.publish(Event::RealIpShown {
+        by: info.to_canonical(),
+        nick: target.nickname.clone(),
+        command: "USERIP",
+    });
+    cc.connection.write_userip(&info, &target).await
+}
+
+/// WHO, for everyone in a channel or just one nick.
+async fn who(cc: &mut ClientConnection, mask: &str) -> Result<()> {
+    let info = cc.info().clone();
+    if is_channel(mask) {
+        if !look_into(cc, mask, "WHO") {
+            return cc.connection.write_who_end(&info, mask).await;
+        }
+        let mut pacer = Pacer::new(&cc.config.big_replies, info.oper.is_some());
+        for (uid, status) in cc.channels.members(mask) {
+            if let Some(target) = cc.users.info_by_uid(&uid) {
+                if !pacer.line(&mut cc.connection).await? {
+                    break;
+                }
+                let (target, _) = cc.config.privacy.shown(&info, shown_oper(&info, target));
+                cc.connection
+                    .write_who(&info, mask, &target, Some(status))
+                    .await?;
+            }
+        }
+        if pacer.truncated {
+            cc.connection.write_too_many_matches(&info, "WHO").await?;
+        }
+    } else if let Some(target) = cc.users.info(mask) {
+        let (target, _) = cc.config.privacy.shown(&info, shown_oper(&info, target));
+        cc.connection.write_who(&info, "*", &target, None).await?;
+    }
+    cc.connection.write_who_end(&info, mask).await
+}
+
+/// NAMES, who's in each of `channels`. Without any there's only the end of the list, nobody needs every channel at
+/// once.
+async fn names(cc: &mut ClientConnection, channels: Option<&[String]>) -> Result<()> {
+    let info = cc.info().clone();
+    let Some(channels) = channels else {
+        return cc.connection.write_names(&info, "*", false, &[]).await;
+    };
+    for channel in channels {
+        if look_into(cc, channel, "NAMES") {
+            cc.names(&info, channel).await?;
+        } else {
+            cc.connection
+                .write_names(&info, channel, false, &[])
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// `target` as `viewer` gets to see them: opers hiding with +H only show up as opers to other opers.
+fn shown_oper(viewer: &ClientInfo, mut target: ClientInfo) -> ClientInfo {
+    if target.hide_oper && viewer.oper.is_none() {
+        target.oper = None;
+    }
+    target
+}
+
+/// TRACE. Anyone can trace themselves, anybody else (or everyone, without a target) needs `spy`.
+async fn trace(cc: &mut ClientConnection, target: Option<&str>) -> Result<()> {
+    let info = cc.info().clone();
+    let spy = info
+        .oper
+        .as_ref()
+        .is_some_and(|x| x.contains(&Privilege::Spy));
+    let nick = match target {
+        Some(nick) => Some(nick),
+        None if spy => None,
+        None => Some(info.nickname.as_str()),
+    };
+    let own = nick.is_some_and(|x| x.eq_ignore_ascii_case(&info.nickname));
+    if !own && !cc.check_privilege(Privilege::Spy).await? {
+        return Ok(());
+    }
+    let traced = cc.users.trace(nick);
+    if let (Some(nick), true) = (nick, traced.is_empty()) {
+        cc.connection.write_no_such_nick(&info, nick).await?;
+        return Ok(());
+    }
+    for x in &traced {
+        cc.connection.write_trace(&info, x).await?;
+    }
+    cc.connection.write_trace_end(&info).await?;
+    Ok(())
+}
+
+/// SASL. Only EXTERNAL for now, which logs the client into whichever account trusts their TLS certificate (or
+/// the one they ask for, if it does). Works after registering too, as long as they aren't logged in already.
+async fn authenticate(cc: &mut ClientConnection, param: &str) -> Result<()> {
+    let info = cc.info().clone();
+    if param == "*" {
+        if cc.sasl.take().is_some() {
+            cc.connection.write_sasl_aborted(&info).await?;
+        }
+        return Ok(());
+    }
+    if info.account.is_some() || cc.sasl_account.is_some() {
+        cc.connection.write_sasl_already(&info).await?;
+        return Ok(());
+    }
+    if cc.sasl.take().is_none() {
+        if param.eq_ignore_ascii_case("EXTERNAL") {
+            cc.sasl = Some("EXTERNAL".to_string());
+            cc.connection.write_authenticate("+").await?;
+        } else {
+            cc.connection.write_sasl_mechs(&info, "EXTERNAL").await?;
+            cc.connection.write_sasl_fail(&info).await?;
+        }
+        return Ok(());
+    }
+
+    // EXTERNAL's only message is who they want to log in as, if anyone in particular
+    let authzid = match param {
+        "+" => None,
+        _ => match BASE64_STANDARD.decode(param).map(String::from_utf8) {
+            Ok(Ok(authzid)) => Some(authzid),
+            _ => {
+                cc.connection.write_sasl_fail(&info).await?;
+                return Ok(());
+            }
+        },
+    };
+    let account = cc
+        .connection
+        .certfp
+        .clone()
+        .and_then(|fp| cc.accounts.authenticate_certfp(authzid.as_deref(), &fp));
+    match account {
+        Some(account) => {
+            cc.connection.write_sasl_success(&info, &account).await?;
+            match cc.registered {
+                true => cc.log_in(account).await?,
+                false => cc.sasl_account = Some(account),
+            }
+        }
+        None => {
+            cc.events.publish(Event::AuthFailed {
+                account: authzid,
+                ip: info.ip.clone(),
+            });
+            cc.connection.write_sasl_fail(&info).await?
+        }
+    }
+    Ok(())
+}
+
+/// KLINE and DLINE. Sets the ban and kicks off anyone already connected that it covers.
+async fn add_ban(
+    cc: &mut ClientConnection,
+    kind: BanKind,
+    duration: &Option<String>,
+    mask: String,
+    reason: &str,
+) -> Result<()> {
+    if !cc.check_privilege(Privilege::Kline).await? {
+        return Ok(());
+    }
+    let info = cc.info().clone();
+    if let Some(extban) = mask.strip_prefix('$') {
+        if kind != BanKind::Kline || ban::parse_extban(extban).is_none() {
+            cc.connection
+                .write_notice(&info, format!("Unknown extban {}", mask))
+                .await?;
+            return Ok(());
+        }
+    }
+    let ban = Ban::new(
+        kind,
+        mask,
+        reason.to_string(),
+        info.to_canonical(),
+        duration.as_deref().and_then(ban::parse_duration),
+    );
+    cc.bans.add(ban.clone());
+    cc.events.publish(Event::BanAdded { ban: ban.clone() });
+    let length = match duration {
+        Some(duration) => format!("temporary ({})", duration),
+        None => "permanent".to_string(),
+    };
+    cc.connection
+        .write_notice(
+            &info,
+            format!("Added {} {} for {}", length, kind.name(), ban.mask),
+        )
+        .await?;
+    cc.enforce_ban(ban).await?;
+    Ok(())
+}
+
+/// UNKLINE and UNDLINE.
+async fn remove_ban(cc: &mut ClientConnection, kind: BanKind, mask: &str) -> Result<()> {
+    if !cc.check_privilege(Privilege::Kline).await? {
+        return Ok(());
+    }
+    let info = cc.info().clone();
+    let notice = match cc.bans.remove(kind, mask) {
+        Some(ban) => {
+            cc.events.publish(Event::BanRemoved {
+                by: info.to_canonical(),
+                kind,
+                mask: ban.mask.clone(),
+            });
+            format!("Removed {} for {}", kind.name(), ban.mask)
+        }
+        None => format!("No {} for {}", kind.name(), mask),
+    };
+    cc.connection.write_notice(&info, notice).await?;
+    Ok(())
+}
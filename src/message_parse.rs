@@ -1,3 +1,5 @@
+use crate::ban::parse_duration;
+use crate::log;
 use std::str::FromStr;
 
 type Target = String;
@@ -18,6 +20,9 @@ type Unused = String;
 type NicknameMask = String;
 type Username = String;
 type Realname = String;
+type Timestamp = String;
+type Duration = String;
+type Mask = String;
 
 fn minlength_or_fail(x: &[&str], len: usize) -> std::result::Result<(), std::io::Error> {
     if x.len() < len {
@@ -30,6 +35,52 @@ fn minlength_or_fail(x: &[&str], len: usize) -> std::result::Result<(), std::io:
     }
 }
 
+/// Splits up plain parameters, where one starting with ':' takes up the rest of the line.
+fn split_params(parts: &[&str]) -> Vec<String> {
+    match parts.iter().position(|x| x.starts_with(':')) {
+        Some(trailing) => {
+            let mut params: Vec<String> = parts[..trailing].iter().map(|x| x.to_string()).collect();
+            params.push(parts[trailing..].join(" ")[1..].to_string());
+            params
+        }
+        None => parts.iter().map(|x| x.to_string()).collect(),
+    }
+}
+
+/// Splits up the parameters of KLINE and DLINE, `[duration] <mask> :<reason>`. The reason is optional.
+fn parse_ban(parts: &[&str]) -> std::result::Result<(Option<Duration>, Mask, Msg), std::io::Error> {
+    minlength_or_fail(parts, 2)?;
+    let (duration, rest) = match parse_duration(parts[1]) {
+        Some(_) => (Some(parts[1].to_string()), &parts[2..]),
+        None => (None, &parts[1..]),
+    };
+    minlength_or_fail(rest, 1)?;
+    let reason = match rest.get(1..) {
+        Some(reason) if !reason.is_empty() => strip_colon(reason.join(" "))?,
+        _ => "No reason".to_string(),
+    };
+    Ok((duration, rest[0].to_string(), reason))
+}
+
+/// Checks that `s` looks like an IRCv3 server-time timestamp, `YYYY-MM-DDThh:mm:ss.sssZ`.
+pub fn is_timestamp(s: &str) -> bool {
+    let b = s.as_bytes();
+    b.len() == 24
+        && b.iter().enumerate().all(|(i, c)| match i {
+            4 | 7 => *c == b'-',
+            10 => *c == b'T',
+            13 | 16 => *c == b':',
+            19 => *c == b'.',
+            23 => *c == b'Z',
+            _ => c.is_ascii_digit(),
+        })
+}
+
+/// The nick out of a `nick!user@host` source, or the whole thing if it's just a nick (or a server).
+pub fn source_nick(source: &str) -> &str {
+    source.split('!').next().unwrap_or(source)
+}
+
 fn strip_colon(mut a: String) -> std::result::Result<String, std::io::Error> {
     if a.is_empty() {
         Err(std::io::Error::new(
@@ -58,53 +109,82 @@ fn strip_colon(mut a: String) -> std::result::Result<String, std::io::Error> {
 // It's a protocol spec, we follow it
 #[allow(clippy::upper_case_acronyms, dead_code)]
 pub enum Command {
+    /// account-notify, the account someone just logged into or `*` if they logged out. Only ever sent by us
+    ACCOUNT(String),
     ADMIN(Option<Target>),
+    /// SASL, either a mechanism, a chunk of base64 payload, `+` for an empty one or `*` to abort
+    AUTHENTICATE(String),
     AWAY(Option<Msg>),
+    CAP(Subcommand, Vec<String>),
+    /// Oper only, `<nick> <host>`. What clients with chghost get is `<username> <host>`
+    CHGHOST(String, String),
     // CNOTICE(Nickname, Channel, Msg),
     // CPRIVMSG(Nickname, Channel, Msg),
     CONNECT(Server, Port, Server),
+    /// Oper only, the level to set, or without one asking what it is
+    DEFCON(Option<String>),
     DIE,
+    /// Oper only, `[duration] <ip mask> :<reason>`
+    DLINE(Option<Duration>, Mask, Msg),
     ENCAP(Server, Subcommand, Vec<String>),
     ERROR(Msg),
-    HELP,
+    /// Oper only, `LIST`, `ADD <action> <commands> <substring|regex> :<pattern>` or `DEL :<pattern>`
+    FILTER(Subcommand, Vec<String>),
+    HELP(Option<Subcommand>),
     INFO(Option<Target>),
     INVITE(Nickname, Channel),
     // ISON(Vec<Nickname>),
     JOIN(Vec<Channel>, Option<Vec<Key>>),
     KICK(Channel, Nickname, Option<Msg>),
     KILL(Nickname, Msg),
+    /// Oper only, `[duration] <user@host mask> :<reason>`
+    KLINE(Option<Duration>, Mask, Msg),
     KNOCK(Channel, Option<Msg>),
+    /// Which catalog numeric texts come from, `None` asks what's available
+    LANGUAGE(Option<String>),
     LINKS(Option<Server>, Option<ServerMask>),
     LIST(Option<Vec<Channel>>, Option<Server>),
     LUSERS(Option<ServerMask>, Option<Server>),
+    /// draft/read-marker, the timestamp is whatever came after `timestamp=`
+    MARKREAD(Target, Option<Timestamp>),
     MODE(Target, Option<ModeString>, Option<Vec<String>>),
     MOTD(Option<Server>),
     NAMES(Option<Vec<Channel>>),
     // NAMESX,
     NICK(Nickname),
     NOTICE(Vec<Target>, Msg),
+    /// A numeric reply, which only comes from another node answering a remote WHOIS or MOTD
+    NUMERIC(u16, Vec<String>),
     OPER(Nickname, Password),
-    PART(Vec<Channel>, Msg),
+    /// Oper to oper, GLOBOPS is the same thing
+    OPERWALL(Msg),
+    PART(Vec<Channel>, Option<Msg>),
     PASS(Password),
     PING(Token),
-    PONG(Server, Token),
+    PONG(Option<Server>, Token),
     PRIVMSG(Vec<Target>, Msg),
     QUIT(Option<Msg>),
     REHASH,
-    // RULES,
+    RULES,
     // SERVER(),
     // SERVICE,
     // SERVLIST,
     // SQUERY,
-    SQUIT(Option<Server>, Msg),
+    SQUIT(Server, Option<Msg>),
     // SETNAME,
     // SILENCE,
     STATS(Query, Option<Server>),
+    /// message-tags, a message that's nothing but its tags
+    TAGMSG(Vec<Target>),
     // SUMMON,
     TIME(Option<Server>),
     TOPIC(Channel, Option<Msg>),
+    /// `<channel>` lists its old topics, `<channel> RESTORE <n>` puts the nth one back
+    TOPICHISTORY(Channel, Option<String>),
     TRACE(Option<Target>),
     // UHNAMES,
+    UNDLINE(Mask),
+    UNKLINE(Mask),
     USER(Username, UserMode, Unused, Realname),
     USERHOST(Vec<Nickname>),
     USERIP(Nickname),
@@ -132,7 +212,43 @@ impl FromStr for Command {
         let parts: Vec<&str> = s.split(' ').collect();
 
         let message = match parts[0].to_uppercase().as_str() {
+            "ADMIN" => Self::ADMIN(parts.get(1).map(|x| x.to_string())),
+            "AUTHENTICATE" => {
+                minlength_or_fail(&parts, 2)?;
+                Self::AUTHENTICATE(parts[1].to_string())
+            }
+            "AWAY" => {
+                let mut message = None;
+                if let Some(pieces) = parts.get(1..) {
+                    if !pieces.is_empty() && !pieces[0].is_empty() {
+                        message = Some(strip_colon(pieces.join(" "))?);
+                    }
+                }
+                Self::AWAY(message)
+            }
+            "CHGHOST" => {
+                minlength_or_fail(&parts, 3)?;
+                Self::CHGHOST(parts[1].to_string(), strip_colon(parts[2].to_string())?)
+            }
+            "CAP" => {
+                minlength_or_fail(&parts, 2)?;
+                // Capability lists are usually the trailing parameter, but we flatten them in with
+                // anything before it since nobody cares which is which.
+                let params = parts[2..]
+                    .iter()
+                    .map(|x| x.trim_start_matches(':'))
+                    .filter(|x| !x.is_empty())
+                    .map(|x| x.to_string())
+                    .collect();
+                Self::CAP(parts[1].to_uppercase(), params)
+            }
+            "DEFCON" => Self::DEFCON(parts.get(1).map(|x| x.to_string())),
             "DIE" => Self::DIE,
+            "HELP" => Self::HELP(parts.get(1).map(|x| x.to_string())),
+            "DLINE" => {
+                let (duration, mask, reason) = parse_ban(&parts)?;
+                Self::DLINE(duration, mask, reason)
+            }
             "JOIN" => {
                 // Need at least one channel.
                 minlength_or_fail(&parts, 2)?;
@@ -151,25 +267,93 @@ impl FromStr for Command {
                 }
                 Self::JOIN(channels, keys)
             }
-            "MOTD" => {
-                if parts.len() != 1 {
-                    Self::UNIMPLEMENTED(s.trim().to_string())
-                } else {
-                    Self::MOTD(None)
-                }
+            "ENCAP" => {
+                minlength_or_fail(&parts, 3)?;
+                Self::ENCAP(
+                    parts[1].to_string(),
+                    parts[2].to_uppercase(),
+                    split_params(&parts[3..]),
+                )
+            }
+            "FILTER" => {
+                minlength_or_fail(&parts, 2)?;
+                Self::FILTER(parts[1].to_uppercase(), split_params(&parts[2..]))
+            }
+            "KILL" => {
+                minlength_or_fail(&parts, 3)?;
+                Self::KILL(parts[1].to_string(), strip_colon(parts[2..].join(" "))?)
+            }
+            "KLINE" => {
+                let (duration, mask, reason) = parse_ban(&parts)?;
+                Self::KLINE(duration, mask, reason)
             }
+            "LANGUAGE" => Self::LANGUAGE(parts.get(1).map(|x| x.to_string())),
+            // The mask is the last parameter, a server to ask comes before it
+            "LINKS" => match parts.len() {
+                1 => Self::LINKS(None, None),
+                2 => Self::LINKS(None, Some(parts[1].to_string())),
+                _ => Self::LINKS(Some(parts[1].to_string()), Some(parts[2].to_string())),
+            },
+            "LIST" => {
+                // Channels and ELIST conditions, all comma separated
+                let params = parts
+                    .get(1)
+                    .filter(|x| !x.is_empty())
+                    .map(|x| x.split(',').map(|x| x.to_string()).collect());
+                Self::LIST(params, parts.get(2).map(|x| x.to_string()))
+            }
+            "MARKREAD" => {
+                minlength_or_fail(&parts, 2)?;
+                let timestamp = parts
+                    .get(2)
+                    .map(|x| x.strip_prefix("timestamp=").unwrap_or(x).to_string());
+                Self::MARKREAD(parts[1].to_string(), timestamp)
+            }
+            "MODE" => {
+                minlength_or_fail(&parts, 2)?;
+                let mut params = split_params(&parts[1..]).into_iter();
+                let target = params.next().unwrap_or_default();
+                let modestring = params.next();
+                let args: Vec<String> = params.collect();
+                Self::MODE(target, modestring, (!args.is_empty()).then_some(args))
+            }
+            "LUSERS" => Self::LUSERS(
+                parts.get(1).map(|x| x.to_string()),
+                parts.get(2).map(|x| x.to_string()),
+            ),
+            "MOTD" => Self::MOTD(parts.get(1).map(|x| x.to_string())),
+            "NAMES" => Self::NAMES(
+                parts
+                    .get(1)
+                    .filter(|x| !x.is_empty())
+                    .map(|x| x.split(',').map(|x| x.to_string()).collect()),
+            ),
             "NICK" => {
                 minlength_or_fail(&parts, 2)?;
                 // Spaces aren't allowed.
                 Self::NICK(parts[1].to_string())
             }
+            "OPER" => {
+                minlength_or_fail(&parts, 3)?;
+                Self::OPER(parts[1].to_string(), strip_colon(parts[2..].join(" "))?)
+            }
+            "OPERWALL" | "GLOBOPS" => {
+                minlength_or_fail(&parts, 2)?;
+                Self::OPERWALL(strip_colon(parts[1..].join(" "))?)
+            }
+            "PASS" => {
+                minlength_or_fail(&parts, 2)?;
+                Self::PASS(strip_colon(parts[1..].join(" "))?)
+            }
             "PING" => {
                 minlength_or_fail(&parts, 2)?;
                 Self::PING(parts[1].to_string())
             }
+            // Clients usually just send the token back, as `PONG :token`
             "PONG" => {
-                minlength_or_fail(&parts, 3)?;
-                Self::PONG(parts[1].to_string(), parts[2].to_string())
+                minlength_or_fail(&parts, 2)?;
+                let token = strip_colon(parts[parts.len() - 1].to_string())?;
+                Self::PONG((parts.len() > 2).then(|| parts[1].to_string()), token)
             }
             "PRIVMSG" => {
                 minlength_or_fail(&parts, 3)?;
@@ -200,7 +384,78 @@ impl FromStr for Command {
                 }
                 Self::QUIT(message)
             }
+            "PART" => {
+                minlength_or_fail(&parts, 2)?;
+                let mut params = split_params(&parts[1..]).into_iter();
+                let channels = params.next().unwrap_or_default();
+                Self::PART(
+                    channels.split(',').map(|x| x.to_string()).collect(),
+                    params.next(),
+                )
+            }
             "REHASH" => Self::REHASH,
+            "RULES" => Self::RULES,
+            "SQUIT" => {
+                minlength_or_fail(&parts, 2)?;
+                let mut params = split_params(&parts[1..]).into_iter();
+                Self::SQUIT(params.next().unwrap_or_default(), params.next())
+            }
+            "STATS" => {
+                minlength_or_fail(&parts, 2)?;
+                Self::STATS(parts[1].to_string(), parts.get(2).map(|x| x.to_string()))
+            }
+            "TAGMSG" => {
+                minlength_or_fail(&parts, 2)?;
+                Self::TAGMSG(parts[1].split(',').map(|x| x.to_string()).collect())
+            }
+            "TOPIC" => {
+                minlength_or_fail(&parts, 2)?;
+                let mut params = split_params(&parts[1..]).into_iter();
+                let channel = params.next().unwrap_or_default();
+                Self::TOPIC(channel, params.next())
+            }
+            "TOPICHISTORY" => {
+                minlength_or_fail(&parts, 2)?;
+                let restore = match parts.get(2) {
+                    None => None,
+                    Some(x) if x.eq_ignore_ascii_case("RESTORE") => {
+                        minlength_or_fail(&parts, 4)?;
+                        Some(parts[3].to_string())
+                    }
+                    Some(_) => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "Expected RESTORE",
+                        ))
+                    }
+                };
+                Self::TOPICHISTORY(parts[1].to_string(), restore)
+            }
+            "TRACE" => Self::TRACE(parts.get(1).map(|x| x.to_string())),
+            "USERIP" => {
+                minlength_or_fail(&parts, 2)?;
+                Self::USERIP(parts[1].to_string())
+            }
+            "WHO" => {
+                minlength_or_fail(&parts, 2)?;
+                Self::WHO(parts[1].to_string())
+            }
+            "WHOIS" => {
+                minlength_or_fail(&parts, 2)?;
+                // WHOIS [server] <nick>
+                match parts.get(2) {
+                    Some(nick) => Self::WHOIS(Some(parts[1].to_string()), nick.to_string()),
+                    None => Self::WHOIS(None, parts[1].to_string()),
+                }
+            }
+            "UNDLINE" => {
+                minlength_or_fail(&parts, 2)?;
+                Self::UNDLINE(parts[1].to_string())
+            }
+            "UNKLINE" => {
+                minlength_or_fail(&parts, 2)?;
+                Self::UNKLINE(parts[1].to_string())
+            }
             "USER" => {
                 minlength_or_fail(&parts, 5)?;
                 let realname = strip_colon(parts[4..].join(" "))?;
@@ -218,23 +473,135 @@ impl FromStr for Command {
                     "Blank input",
                 ));
             }
+            numeric if numeric.len() == 3 && numeric.bytes().all(|x| x.is_ascii_digit()) => {
+                Self::NUMERIC(
+                    numeric.parse().unwrap_or_default(),
+                    split_params(&parts[1..]),
+                )
+            }
             _ => Self::UNKNOWN(s.trim().to_string()),
         };
-        eprintln!("Message parsed: {:?}", message);
+        log::trace!("Message parsed: {:?}", message);
         Ok(message)
     }
 }
 
-impl ToString for Command {
-    fn to_string(&self) -> String {
+impl Command {
+    /// The command's name, `UNKNOWN` for anything we didn't recognise.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::ACCOUNT(..) => "ACCOUNT",
+            Command::ADMIN(..) => "ADMIN",
+            Command::AUTHENTICATE(..) => "AUTHENTICATE",
+            Command::AWAY(..) => "AWAY",
+            Command::CAP(..) => "CAP",
+            Command::CHGHOST(..) => "CHGHOST",
+            Command::CONNECT(..) => "CONNECT",
+            Command::DEFCON(..) => "DEFCON",
+            Command::DIE => "DIE",
+            Command::DLINE(..) => "DLINE",
+            Command::ENCAP(..) => "ENCAP",
+            Command::ERROR(..) => "ERROR",
+            Command::FILTER(..) => "FILTER",
+            Command::HELP(..) => "HELP",
+            Command::INFO(..) => "INFO",
+            Command::INVITE(..) => "INVITE",
+            Command::JOIN(..) => "JOIN",
+            Command::KICK(..) => "KICK",
+            Command::KILL(..) => "KILL",
+            Command::KLINE(..) => "KLINE",
+            Command::KNOCK(..) => "KNOCK",
+            Command::LANGUAGE(..) => "LANGUAGE",
+            Command::LINKS(..) => "LINKS",
+            Command::LIST(..) => "LIST",
+            Command::LUSERS(..) => "LUSERS",
+            Command::MARKREAD(..) => "MARKREAD",
+            Command::MODE(..) => "MODE",
+            Command::MOTD(..) => "MOTD",
+            Command::NAMES(..) => "NAMES",
+            Command::NUMERIC(..) => "NUMERIC",
+            Command::NICK(..) => "NICK",
+            Command::NOTICE(..) => "NOTICE",
+            Command::OPER(..) => "OPER",
+            Command::OPERWALL(..) => "OPERWALL",
+            Command::PART(..) => "PART",
+            Command::PASS(..) => "PASS",
+            Command::PING(..) => "PING",
+            Command::PONG(..) => "PONG",
+            Command::PRIVMSG(..) => "PRIVMSG",
+            Command::QUIT(..) => "QUIT",
+            Command::REHASH => "REHASH",
+            Command::RULES => "RULES",
+            Command::SQUIT(..) => "SQUIT",
+            Command::STATS(..) => "STATS",
+            Command::TAGMSG(..) => "TAGMSG",
+            Command::TIME(..) => "TIME",
+            Command::TOPIC(..) => "TOPIC",
+            Command::TOPICHISTORY(..) => "TOPICHISTORY",
+            Command::TRACE(..) => "TRACE",
+            Command::UNDLINE(..) => "UNDLINE",
+            Command::UNKLINE(..) => "UNKLINE",
+            Command::USER(..) => "USER",
+            Command::USERHOST(..) => "USERHOST",
+            Command::USERIP(..) => "USERIP",
+            Command::USERS(..) => "USERS",
+            Command::VERSION(..) => "VERSION",
+            Command::WALLOPS(..) => "WALLOPS",
+            Command::WHO(..) => "WHO",
+            Command::WHOIS(..) => "WHOIS",
+            Command::UNKNOWN(..) => "UNKNOWN",
+            Command::UNIMPLEMENTED(..) => "UNIMPLEMENTED",
+        }
+    }
+}
+
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let str = match self {
-            Command::ADMIN(_) => todo!(),
-            Command::AWAY(_) => todo!(),
+            Command::ACCOUNT(account) => format!("ACCOUNT {}", account),
+            Command::ADMIN(Some(target)) => format!("ADMIN {}", target),
+            Command::ADMIN(None) => "ADMIN".to_string(),
+            Command::AUTHENTICATE(x) => format!("AUTHENTICATE {}", x),
+            Command::AWAY(Some(message)) => format!("AWAY :{}", message),
+            Command::AWAY(None) => "AWAY".to_string(),
+            Command::CAP(subcommand, params) => {
+                if params.is_empty() {
+                    format!("CAP {}", subcommand)
+                } else {
+                    format!("CAP {} :{}", subcommand, params.join(" "))
+                }
+            }
+            Command::CHGHOST(user, host) => format!("CHGHOST {} {}", user, host),
             Command::CONNECT(_, _, _) => todo!(),
+            Command::DEFCON(Some(level)) => format!("DEFCON {}", level),
+            Command::DEFCON(None) => "DEFCON".to_string(),
             Command::DIE => "DIE".to_string(),
-            Command::ENCAP(_, _, _) => todo!(),
+            Command::DLINE(duration, mask, reason) => match duration {
+                Some(duration) => format!("DLINE {} {} :{}", duration, mask, reason),
+                None => format!("DLINE {} :{}", mask, reason),
+            },
+            Command::ENCAP(target, subcommand, params) => match params.split_last() {
+                Some((trailing, params)) => format!(
+                    "ENCAP {} {} {}:{}",
+                    target,
+                    subcommand,
+                    params.iter().map(|x| format!("{} ", x)).collect::<String>(),
+                    trailing
+                ),
+                None => format!("ENCAP {} {}", target, subcommand),
+            },
             Command::ERROR(_) => todo!(),
-            Command::HELP => todo!(),
+            Command::FILTER(subcommand, params) => match params.split_last() {
+                Some((trailing, params)) => format!(
+                    "FILTER {} {}:{}",
+                    subcommand,
+                    params.iter().map(|x| format!("{} ", x)).collect::<String>(),
+                    trailing
+                ),
+                None => format!("FILTER {}", subcommand),
+            },
+            Command::HELP(Some(topic)) => format!("HELP {}", topic),
+            Command::HELP(None) => "HELP".to_string(),
             Command::INFO(_) => todo!(),
             Command::INVITE(_, _) => todo!(),
             Command::JOIN(chans, maybe_keys) => {
@@ -245,22 +612,62 @@ impl ToString for Command {
                 }
             }
             Command::KICK(_, _, _) => todo!(),
-            Command::KILL(_, _) => todo!(),
+            Command::KILL(nickname, comment) => format!("KILL {} :{}", nickname, comment),
+            Command::KLINE(duration, mask, reason) => match duration {
+                Some(duration) => format!("KLINE {} {} :{}", duration, mask, reason),
+                None => format!("KLINE {} :{}", mask, reason),
+            },
             Command::KNOCK(_, _) => todo!(),
-            Command::LINKS(_, _) => todo!(),
-            Command::LIST(_, _) => todo!(),
-            Command::LUSERS(_, _) => todo!(),
-            Command::MODE(_, _, _) => todo!(),
-            Command::MOTD(x) if x.is_some() => todo!(),
-            Command::MOTD(_) => "MOTD".to_string(),
-            Command::NAMES(_) => todo!(),
+            Command::LANGUAGE(Some(language)) => format!("LANGUAGE {}", language),
+            Command::LANGUAGE(None) => "LANGUAGE".to_string(),
+            Command::LINKS(server, mask) => {
+                let mut line = "LINKS".to_string();
+                for x in server.iter().chain(mask) {
+                    line.push(' ');
+                    line.push_str(x);
+                }
+                line
+            }
+            Command::LIST(None, _) => "LIST".to_string(),
+            Command::LIST(Some(params), _) => format!("LIST {}", params.join(",")),
+            Command::LUSERS(_, _) => "LUSERS".to_string(),
+            Command::MARKREAD(target, Some(timestamp)) => {
+                format!("MARKREAD {} timestamp={}", target, timestamp)
+            }
+            Command::MARKREAD(target, None) => format!("MARKREAD {}", target),
+            Command::MODE(target, modestring, args) => {
+                let mut line = format!("MODE {}", target);
+                for x in modestring.iter().chain(args.iter().flatten()) {
+                    line.push(' ');
+                    line.push_str(x);
+                }
+                line
+            }
+            Command::MOTD(Some(server)) => format!("MOTD {}", server),
+            Command::MOTD(None) => "MOTD".to_string(),
+            Command::NAMES(None) => "NAMES".to_string(),
+            Command::NAMES(Some(channels)) => format!("NAMES {}", channels.join(",")),
+            Command::NUMERIC(number, params) => match params.split_last() {
+                Some((trailing, params)) => format!(
+                    "{:03} {}:{}",
+                    number,
+                    params.iter().map(|x| format!("{} ", x)).collect::<String>(),
+                    trailing
+                ),
+                None => format!("{:03}", number),
+            },
             Command::NICK(nickname) => format!("NICK {}", nickname),
             Command::NOTICE(_, _) => todo!(),
-            Command::OPER(_, _) => todo!(),
-            Command::PART(_, _) => todo!(),
-            Command::PASS(_) => todo!(),
+            Command::OPER(name, password) => format!("OPER {} {}", name, password),
+            Command::OPERWALL(text) => format!("OPERWALL :{}", text),
+            Command::PART(channels, Some(reason)) => {
+                format!("PART {} :{}", channels.join(","), reason)
+            }
+            Command::PART(channels, None) => format!("PART {}", channels.join(",")),
+            Command::PASS(password) => format!("PASS {}", password),
             Command::PING(token) => format!("PING {}", token),
-            Command::PONG(server, token) => format!("PONG {} {}", server, token),
+            Command::PONG(Some(server), token) => format!("PONG {} {}", server, token),
+            Command::PONG(None, token) => format!("PONG :{}", token),
             Command::PRIVMSG(targets, message) => {
                 format!("PRIVMSG {} :{}", targets.join(","), message)
             }
@@ -272,11 +679,23 @@ impl ToString for Command {
                 }
             }
             Command::REHASH => "REHASH".to_string(),
-            Command::SQUIT(_, _) => todo!(),
-            Command::STATS(_, _) => todo!(),
+            Command::RULES => "RULES".to_string(),
+            Command::SQUIT(server, Some(reason)) => format!("SQUIT {} :{}", server, reason),
+            Command::SQUIT(server, None) => format!("SQUIT {}", server),
+            Command::STATS(query, Some(server)) => format!("STATS {} {}", query, server),
+            Command::STATS(query, None) => format!("STATS {}", query),
+            Command::TAGMSG(targets) => format!("TAGMSG {}", targets.join(",")),
             Command::TIME(_) => todo!(),
-            Command::TOPIC(_, _) => todo!(),
-            Command::TRACE(_) => todo!(),
+            Command::TOPIC(channel, Some(topic)) => format!("TOPIC {} :{}", channel, topic),
+            Command::TOPIC(channel, None) => format!("TOPIC {}", channel),
+            Command::TOPICHISTORY(channel, Some(n)) => {
+                format!("TOPICHISTORY {} RESTORE {}", channel, n)
+            }
+            Command::TOPICHISTORY(channel, None) => format!("TOPICHISTORY {}", channel),
+            Command::TRACE(Some(target)) => format!("TRACE {}", target),
+            Command::TRACE(None) => "TRACE".to_string(),
+            Command::UNDLINE(mask) => format!("UNDLINE {}", mask),
+            Command::UNKLINE(mask) => format!("UNKLINE {}", mask),
             Command::USER(username, mode, un, real) => {
                 if real.contains(' ') {
                     format!("USER {} {} {} :{}", username, mode, un, real)
@@ -285,17 +704,17 @@ impl ToString for Command {
                 }
             }
             Command::USERHOST(_) => todo!(),
-            Command::USERIP(_) => todo!(),
+            Command::USERIP(nick) => format!("USERIP {}", nick),
             Command::USERS(_) => todo!(),
             Command::VERSION(_) => todo!(),
             Command::WALLOPS(_) => todo!(),
-            Command::WHO(_) => todo!(),
-            Command::WHOIS(_, _) => todo!(),
+            Command::WHO(mask) => format!("WHO {}", mask),
+            Command::WHOIS(Some(target), nick) => format!("WHOIS {} {}", target, nick),
+            Command::WHOIS(None, nick) => format!("WHOIS {}", nick),
             Command::UNKNOWN(s) => s.clone(),
             Command::UNIMPLEMENTED(s) => s.clone(),
         };
-        eprintln!("Message stringified: {:?}", str);
-        str
+        write!(f, "{}", str)
     }
 }
 
@@ -325,7 +744,7 @@ impl FromStr for Message {
             side: Side::Unknown,
         };
 
-        let mut rest = "".to_string();
+        let rest;
 
         let parts = s.split(' ').collect::<Vec<&str>>();
 
@@ -333,7 +752,7 @@ impl FromStr for Message {
         match (
             parts[0].starts_with('@'),
             parts[0].starts_with(':'),
-            parts[1].starts_with(':'),
+            parts.get(1).is_some_and(|x| x.starts_with(':')),
         ) {
             // Not possible
             (true, true, true) => unreachable!(),
@@ -353,11 +772,12 @@ impl FromStr for Message {
             // Tags, but no source
             (true, false, false) => {
                 new_self.tags = Some(
-                    parts[0]
+                    parts[0][1..]
                         .split(';')
                         .map(|x| x.to_string())
                         .collect::<Vec<String>>(),
                 );
+                rest = parts[1..].join(" ");
             }
             // Reachable but invalid
             (false, true, true) => {
@@ -386,18 +806,15 @@ impl FromStr for Message {
     }
 }
 
-impl ToString for Message {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match (&self.tags, &self.source) {
-            (None, None) => self.command.to_string(),
-            (None, Some(source)) => format!(":{} {}", source, self.command.to_string()),
-            (Some(tags), None) => format!("@{} {}", tags.join(";"), self.command.to_string()),
-            (Some(tags), Some(source)) => format!(
-                "@{} :{} {}",
-                tags.join(";"),
-                source,
-                self.command.to_string()
-            ),
+            (None, None) => write!(f, "{}", self.command),
+            (None, Some(source)) => write!(f, ":{} {}", source, self.command),
+            (Some(tags), None) => write!(f, "@{} {}", tags.join(";"), self.command),
+            (Some(tags), Some(source)) => {
+                write!(f, "@{} :{} {}", tags.join(";"), source, self.command)
+            }
         }
     }
 }
@@ -435,6 +852,116 @@ mod test {
         );
     }
 
+    #[test]
+    fn parse_part() {
+        let command: Command = "PART #meow,#nyaa :bye now".parse().unwrap();
+        assert_eq!(
+            command,
+            Command::PART(
+                vec!["#meow".to_string(), "#nyaa".to_string()],
+                Some("bye now".to_string())
+            )
+        );
+        assert_eq!(command.to_string(), "PART #meow,#nyaa :bye now");
+        let command: Command = "PART #meow".parse().unwrap();
+        assert_eq!(command, Command::PART(vec!["#meow".to_string()], None));
+    }
+
+    #[test]
+    fn parse_encap() {
+        let command: Command = "ENCAP 1234-* svslogin tiger :Tiger Cat".parse().unwrap();
+        assert_eq!(
+            command,
+            Command::ENCAP(
+                "1234-*".to_string(),
+                "SVSLOGIN".to_string(),
+                vec!["tiger".to_string(), "Tiger Cat".to_string()]
+            )
+        );
+        assert_eq!(
+            command.to_string(),
+            "ENCAP 1234-* SVSLOGIN tiger :Tiger Cat"
+        );
+        assert!("ENCAP *".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn parse_mode() {
+        let command: Command = "MODE #meow +qq-q a b :c".parse().unwrap();
+        assert_eq!(
+            command,
+            Command::MODE(
+                "#meow".to_string(),
+                Some("+qq-q".to_string()),
+                Some(vec!["a".to_string(), "b".to_string(), "c".to_string()])
+            )
+        );
+        assert_eq!(command.to_string(), "MODE #meow +qq-q a b c");
+        let command: Command = "MODE #meow".parse().unwrap();
+        assert_eq!(command, Command::MODE("#meow".to_string(), None, None));
+    }
+
+    #[test]
+    fn parse_whois() {
+        let command: Command = "WHOIS tiger".parse().unwrap();
+        assert_eq!(command, Command::WHOIS(None, "tiger".to_string()));
+        let command: Command = "WHOIS irc.example tiger".parse().unwrap();
+        assert_eq!(
+            command,
+            Command::WHOIS(Some("irc.example".to_string()), "tiger".to_string())
+        );
+        assert_eq!(command.to_string(), "WHOIS irc.example tiger");
+    }
+
+    #[test]
+    fn parse_topic() {
+        let command: Command = "TOPIC #meow".parse().unwrap();
+        assert_eq!(command, Command::TOPIC("#meow".to_string(), None));
+        let command: Command = "TOPIC #meow :cats only".parse().unwrap();
+        assert_eq!(
+            command,
+            Command::TOPIC("#meow".to_string(), Some("cats only".to_string()))
+        );
+        assert_eq!(command.to_string(), "TOPIC #meow :cats only");
+        // Clearing it
+        let command: Command = "TOPIC #meow :".parse().unwrap();
+        assert_eq!(
+            command,
+            Command::TOPIC("#meow".to_string(), Some(String::new()))
+        );
+
+        let command: Command = "TOPICHISTORY #meow restore 2".parse().unwrap();
+        assert_eq!(
+            command,
+            Command::TOPICHISTORY("#meow".to_string(), Some("2".to_string()))
+        );
+        assert_eq!(command.to_string(), "TOPICHISTORY #meow RESTORE 2");
+        assert!("TOPICHISTORY #meow 2".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn parse_list() {
+        let command: Command = "LIST".parse().unwrap();
+        assert_eq!(command, Command::LIST(None, None));
+        let command: Command = "LIST #a,T<60".parse().unwrap();
+        assert_eq!(
+            command,
+            Command::LIST(Some(vec!["#a".to_string(), "T<60".to_string()]), None)
+        );
+    }
+
+    #[test]
+    fn parse_names() {
+        let command: Command = "NAMES".parse().unwrap();
+        assert_eq!(command, Command::NAMES(None));
+        let command: Command = "NAMES #meow,#nyaa".parse().unwrap();
+        assert_eq!(
+            command,
+            Command::NAMES(Some(vec!["#meow".to_string(), "#nyaa".to_string()]))
+        );
+        assert_eq!(command.to_string(), "NAMES #meow,#nyaa");
+    }
+
     #[test]
     fn parse_multi_join() {
         let command: Command = "JOIN #meow,#blep nyaa,mlem".parse().unwrap();
@@ -452,8 +979,165 @@ mod test {
         let command: Command = "PONG tigercat2000.dev wuiobgv9".parse().unwrap();
         assert_eq!(
             command,
-            Command::PONG("tigercat2000.dev".to_string(), "wuiobgv9".to_string())
+            Command::PONG(Some("tigercat2000.dev".to_string()), "wuiobgv9".to_string())
         );
+        let command: Command = "PONG :wuiobgv9".parse().unwrap();
+        assert_eq!(command, Command::PONG(None, "wuiobgv9".to_string()));
+    }
+
+    #[test]
+    fn parse_kill() {
+        let command: Command = "KILL spammer :Go away".parse().unwrap();
+        assert_eq!(
+            command,
+            Command::KILL("spammer".to_string(), "Go away".to_string())
+        );
+        assert_eq!(command.to_string(), "KILL spammer :Go away");
+    }
+
+    #[test]
+    fn parse_squit() {
+        let command: Command = "SQUIT 1234-5678 :Bye now".parse().unwrap();
+        assert_eq!(
+            command,
+            Command::SQUIT("1234-5678".to_string(), Some("Bye now".to_string()))
+        );
+        assert_eq!(command.to_string(), "SQUIT 1234-5678 :Bye now");
+        let command: Command = "SQUIT 1234-5678".parse().unwrap();
+        assert_eq!(command, Command::SQUIT("1234-5678".to_string(), None));
+    }
+
+    #[test]
+    fn parse_chghost() {
+        let command: Command = "CHGHOST tiger :cat.example".parse().unwrap();
+        assert_eq!(
+            command,
+            Command::CHGHOST("tiger".to_string(), "cat.example".to_string())
+        );
+        assert_eq!(command.to_string(), "CHGHOST tiger cat.example");
+        assert!("CHGHOST tiger".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn parse_klines() {
+        let command: Command = "KLINE 30m *@10.0.0.1 :Spamming".parse().unwrap();
+        assert_eq!(
+            command,
+            Command::KLINE(
+                Some("30m".to_string()),
+                "*@10.0.0.1".to_string(),
+                "Spamming".to_string()
+            )
+        );
+        let command: Command = "DLINE 10.0.0.*".parse().unwrap();
+        assert_eq!(
+            command,
+            Command::DLINE(None, "10.0.0.*".to_string(), "No reason".to_string())
+        );
+        assert!("KLINE 30m".parse::<Command>().is_err());
+        let command: Command = "STATS k".parse().unwrap();
+        assert_eq!(command, Command::STATS("k".to_string(), None));
+    }
+
+    #[test]
+    fn parse_filter() {
+        let command: Command = "FILTER add block * substring :free crypto".parse().unwrap();
+        assert_eq!(
+            command,
+            Command::FILTER(
+                "ADD".to_string(),
+                vec![
+                    "block".to_string(),
+                    "*".to_string(),
+                    "substring".to_string(),
+                    "free crypto".to_string()
+                ]
+            )
+        );
+        assert_eq!(
+            command.to_string(),
+            "FILTER ADD block * substring :free crypto"
+        );
+        let command: Command = "FILTER LIST".parse().unwrap();
+        assert_eq!(command, Command::FILTER("LIST".to_string(), Vec::new()));
+    }
+
+    #[test]
+    fn parse_oper() {
+        let command: Command = "OPER tiger hunter2".parse().unwrap();
+        assert_eq!(
+            command,
+            Command::OPER("tiger".to_string(), "hunter2".to_string())
+        );
+        let command: Command = "GLOBOPS :split incoming".parse().unwrap();
+        assert_eq!(command, Command::OPERWALL("split incoming".to_string()));
+        assert_eq!(command.to_string(), "OPERWALL :split incoming");
+    }
+
+    #[test]
+    fn parse_pass() {
+        let command: Command = "PASS :hunter2".parse().unwrap();
+        assert_eq!(command, Command::PASS("hunter2".to_string()));
+    }
+
+    #[test]
+    fn parse_authenticate() {
+        let command: Command = "AUTHENTICATE EXTERNAL".parse().unwrap();
+        assert_eq!(command, Command::AUTHENTICATE("EXTERNAL".to_string()));
+        assert_eq!(command.to_string(), "AUTHENTICATE EXTERNAL");
+        assert!("AUTHENTICATE".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn parse_away() {
+        let command: Command = "AWAY :Gone to lunch".parse().unwrap();
+        assert_eq!(command, Command::AWAY(Some("Gone to lunch".to_string())));
+        let command: Command = "AWAY".parse().unwrap();
+        assert_eq!(command, Command::AWAY(None));
+    }
+
+    #[test]
+    fn parse_cap() {
+        let command: Command = "CAP REQ :draft/read-marker".parse().unwrap();
+        assert_eq!(
+            command,
+            Command::CAP("REQ".to_string(), vec!["draft/read-marker".to_string()])
+        );
+        let command: Command = "CAP ls 302".parse().unwrap();
+        assert_eq!(
+            command,
+            Command::CAP("LS".to_string(), vec!["302".to_string()])
+        );
+    }
+
+    #[test]
+    fn parse_markread() {
+        let command: Command = "MARKREAD #meow timestamp=2021-06-13T17:25:41.123Z"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            command,
+            Command::MARKREAD(
+                "#meow".to_string(),
+                Some("2021-06-13T17:25:41.123Z".to_string())
+            )
+        );
+        let command: Command = "MARKREAD #meow".parse().unwrap();
+        assert_eq!(command, Command::MARKREAD("#meow".to_string(), None));
+    }
+
+    #[test]
+    fn timestamps() {
+        assert!(is_timestamp("2021-06-13T17:25:41.123Z"));
+        assert!(!is_timestamp("*"));
+        assert!(!is_timestamp("2021-06-13 17:25:41.123Z"));
+        assert!(!is_timestamp("2021-06-13T17:25:41Z"));
+    }
+
+    #[test]
+    fn source_nicks() {
+        assert_eq!(source_nick("tiger!cat@127.0.0.1"), "tiger");
+        assert_eq!(source_nick("irc.example.com"), "irc.example.com");
     }
 
     #[test]
@@ -462,12 +1146,26 @@ mod test {
         assert_eq!(command, Command::DIE);
     }
 
+    #[test]
+    fn parse_defcon() {
+        let command: Command = "DEFCON 2".parse().unwrap();
+        assert_eq!(command, Command::DEFCON(Some("2".to_string())));
+        assert_eq!(command.to_string(), "DEFCON 2");
+        assert_eq!("DEFCON".parse::<Command>().unwrap(), Command::DEFCON(None));
+    }
+
     #[test]
     fn parse_rehash() {
         let command: Command = "REHASH".parse().unwrap();
         assert_eq!(command, Command::REHASH);
     }
 
+    #[test]
+    fn parse_rules() {
+        let command: Command = "RULES".parse().unwrap();
+        assert_eq!(command, Command::RULES);
+    }
+
     #[test]
     fn parse_motd() {
         let command: Command = "MOTD".parse().unwrap();
@@ -548,15 +1246,54 @@ mod test {
 
     #[test]
     fn parse_adv_motd() {
-        let command: Result<Command, std::io::Error> = "MOTD otherserver.com".parse();
-        match command {
-            // Correct
-            Ok(Command::UNIMPLEMENTED(x)) if x == "MOTD otherserver.com" => {}
-            // Incorrect
-            Ok(_) => panic!("Malformed command parsed incorrectly"),
-            // Incorrect
-            Err(e) => panic!("Malformed command incorrectly errored {}", e),
-        }
+        let command: Command = "MOTD otherserver.com".parse().unwrap();
+        assert_eq!(command, Command::MOTD(Some("otherserver.com".to_string())));
+        assert_eq!(command.to_string(), "MOTD otherserver.com");
+    }
+
+    #[test]
+    fn parse_links() {
+        let command: Command = "LINKS *.net".parse().unwrap();
+        assert_eq!(command, Command::LINKS(None, Some("*.net".to_string())));
+        assert_eq!(command.to_string(), "LINKS *.net");
+        let command: Command = "LINKS irc.example.net *.net".parse().unwrap();
+        assert_eq!(
+            command,
+            Command::LINKS(
+                Some("irc.example.net".to_string()),
+                Some("*.net".to_string())
+            )
+        );
+        assert_eq!("ADMIN".parse::<Command>().unwrap(), Command::ADMIN(None));
+    }
+
+    #[test]
+    fn parse_lusers() {
+        let command: Command = "LUSERS".parse().unwrap();
+        assert_eq!(command, Command::LUSERS(None, None));
+        assert_eq!(command.to_string(), "LUSERS");
+    }
+
+    #[test]
+    fn parse_numeric() {
+        let message: Message = ":irc.example.net 311 cat tiger tiger localhost * :Tiger Cat"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            message.command,
+            Command::NUMERIC(
+                311,
+                ["cat", "tiger", "tiger", "localhost", "*", "Tiger Cat"]
+                    .map(String::from)
+                    .to_vec()
+            )
+        );
+        assert_eq!(
+            message.to_string(),
+            ":irc.example.net 311 cat tiger tiger localhost * :Tiger Cat"
+        );
+        assert_eq!("005".parse::<Command>().unwrap().to_string(), "005");
+        assert!(matches!("31".parse::<Command>(), Ok(Command::UNKNOWN(_))));
     }
 
     #[test]
@@ -570,12 +1307,42 @@ mod test {
             Message {
                 tags: Some(vec!["meow".to_string(), "mlem".to_string()]),
                 source: Some("irc.example.com".to_string()),
-                command: Command::UNKNOWN("CAP LS * :multi-prefix extended-join sasl".to_string()),
+                command: Command::CAP(
+                    "LS".to_string(),
+                    vec![
+                        "*".to_string(),
+                        "multi-prefix".to_string(),
+                        "extended-join".to_string(),
+                        "sasl".to_string()
+                    ]
+                ),
                 side: Side::Unknown,
             }
         )
     }
 
+    #[test]
+    fn parse_tags_without_source() {
+        let message: Message = "@+typing=active;+draft/reply=abc TAGMSG #meow,tiger"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            message.tags,
+            Some(vec![
+                "+typing=active".to_string(),
+                "+draft/reply=abc".to_string()
+            ])
+        );
+        assert_eq!(
+            message.command,
+            Command::TAGMSG(vec!["#meow".to_string(), "tiger".to_string()])
+        );
+        assert_eq!(
+            message.to_string(),
+            "@+typing=active;+draft/reply=abc TAGMSG #meow,tiger"
+        );
+    }
+
     #[test]
     fn parse_full_ass_message2() {
         let message: Message = "@meow;mlem :irc.example.com USER guest 0 * :Meow Tompski"
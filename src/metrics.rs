@@ -0,0 +1,285 @@
+//! Counters for `/metrics` on the HTTP API: how long each command's handler takes, and how busy each channel is.
+//! Commands are timed around `Message::apply` for everything clients send, and the histogram's `_count` doubles
+//! as the number of times each command was used. Channel messages are counted off the event bus. Failed accepts are
+//! counted by `accept`, and failed TLS handshakes by `tls`. Connections count what they get from the server's
+//! broadcast channel, and what they missed by falling too far behind on it. Secret channels are only counted
+//! together, so their names stay out of it.
+
+use crate::{channel::Channels, event::Event, log, server::lock, Shutdown};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::broadcast;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// One per bucket in `BUCKETS`, not cumulative
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(i) = BUCKETS.iter().position(|x| seconds <= *x) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    commands: BTreeMap<&'static str, Histogram>,
+    /// Messages sent to each channel, by lowercased name
+    channel_messages: HashMap<String, u64>,
+    /// Failed accepts, by why
+    accept_failures: BTreeMap<&'static str, u64>,
+    /// Failed TLS handshakes, by how far they got
+    tls_handshake_failures: BTreeMap<&'static str, u64>,
+    /// Broadcast packets connections got
+    received: u64,
+    /// Broadcast packets connections missed because they lagged behind
+    dropped: u64,
+}
+
+/// Shared between every connection.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Metrics {
+    /// Records the handler for `command` taking `took`.
+    pub fn command(&self, command: &'static str, took: Duration) {
+        let mut inner = lock(&self.inner);
+        inner
+            .commands
+            .entry(command)
+            .or_default()
+            .observe(took.as_secs_f64());
+    }
+
+    /// Records an accept failing for `reason`.
+    pub fn accept_failure(&self, reason: &'static str) {
+        let mut inner = lock(&self.inner);
+        *inner.accept_failures.entry(reason).or_default() += 1;
+    }
+
+    /// Records a TLS handshake failing at `stage`.
+    pub fn tls_handshake_failure(&self, stage: &'static str) {
+        let mut inner = lock(&self.inner);
+        *inner.tls_handshake_failures.entry(stage).or_default() += 1;
+    }
+
+    /// Records a connection getting a packet off the broadcast channel.
+    pub fn received(&self) {
+        lock(&self.inner).received += 1;
+    }
+
+    /// Records a connection missing `count` packets on the broadcast channel.
+    pub fn dropped(&self, count: u64) {
+        lock(&self.inner).dropped += count;
+    }
+
+    fn channel_message(&self, channel: &str) {
+        let mut inner = lock(&self.inner);
+        *inner
+            .channel_messages
+            .entry(channel.to_ascii_lowercase())
+            .or_default() += 1;
+    }
+
+    /// Everything in Prometheus' text format. Channels that no longer exist are dropped.
+    pub fn render(&self, channels: &Channels) -> String {
+        let (secret, members): (Vec<_>, Vec<_>) = channels
+            .member_counts()
+            .into_iter()
+            .map(|(name, count)| (name.to_ascii_lowercase(), count))
+            .partition(|(name, _)| channels.is_secret(name));
+        let members: BTreeMap<String, usize> = members.into_iter().collect();
+        let secret: BTreeMap<String, usize> = secret.into_iter().collect();
+        let mut inner = lock(&self.inner);
+        inner
+            .channel_messages
+            .retain(|name, _| members.contains_key(name) || secret.contains_key(name));
+
+        let mut out = String::new();
+        out.push_str(
+            "# HELP rust_irc_command_duration_seconds How long handling each command took\n\
+             # TYPE rust_irc_command_duration_seconds histogram\n",
+        );
+        for (command, histogram) in &inner.commands {
+            let mut cumulative = 0;
+            for (le, count) in BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "rust_irc_command_duration_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}",
+                    command, le, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "rust_irc_command_duration_seconds_bucket{{command=\"{}\",le=\"+Inf\"}} {}\n\
+                 rust_irc_command_duration_seconds_sum{{command=\"{}\"}} {}\n\
+                 rust_irc_command_duration_seconds_count{{command=\"{}\"}} {}",
+                command, histogram.count, command, histogram.sum, command, histogram.count
+            );
+        }
+
+        out.push_str(
+            "# HELP rust_irc_channel_members How many users are in each channel\n\
+             # TYPE rust_irc_channel_members gauge\n",
+        );
+        for (name, count) in &members {
+            let _ = writeln!(
+                out,
+                "rust_irc_channel_members{{channel=\"{}\"}} {}",
+                escape(name),
+                count
+            );
+        }
+        out.push_str(
+            "# HELP rust_irc_channel_messages_total Messages sent to each channel\n\
+             # TYPE rust_irc_channel_messages_total counter\n",
+        );
+        for name in members.keys() {
+            let count = inner.channel_messages.get(name).copied().unwrap_or(0);
+            let _ = writeln!(
+                out,
+                "rust_irc_channel_messages_total{{channel=\"{}\"}} {}",
+                escape(name),
+                count
+            );
+        }
+        let secret_messages: u64 = secret
+            .keys()
+            .filter_map(|name| inner.channel_messages.get(name))
+            .sum();
+        let _ = writeln!(
+            out,
+            "# HELP rust_irc_secret_channels Secret channels, which aren't listed one by one\n\
+             # TYPE rust_irc_secret_channels gauge\n\
+             rust_irc_secret_channels {}\n\
+             # HELP rust_irc_secret_channel_members How many users are in secret channels, all together\n\
+             # TYPE rust_irc_secret_channel_members gauge\n\
+             rust_irc_secret_channel_members {}\n\
+             # HELP rust_irc_secret_channel_messages_total Messages sent to secret channels, all together\n\
+             # TYPE rust_irc_secret_channel_messages_total counter\n\
+             rust_irc_secret_channel_messages_total {}",
+            secret.len(),
+            secret.values().sum::<usize>(),
+            secret_messages
+        );
+        out.push_str(
+            "# HELP rust_irc_accept_failures_total Connections that failed to be accepted\n\
+             # TYPE rust_irc_accept_failures_total counter\n",
+        );
+        for (reason, count) in &inner.accept_failures {
+            let _ = writeln!(
+                out,
+                "rust_irc_accept_failures_total{{reason=\"{}\"}} {}",
+                reason, count
+            );
+        }
+        out.push_str(
+            "# HELP rust_irc_tls_handshake_failures_total TLS handshakes that didn't finish\n\
+             # TYPE rust_irc_tls_handshake_failures_total counter\n",
+        );
+        for (stage, count) in &inner.tls_handshake_failures {
+            let _ = writeln!(
+                out,
+                "rust_irc_tls_handshake_failures_total{{stage=\"{}\"}} {}",
+                stage, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP rust_irc_broadcast_packets_total Packets connections got from the server, or missed by lagging\n\
+             # TYPE rust_irc_broadcast_packets_total counter\n\
+             rust_irc_broadcast_packets_total{{outcome=\"received\"}} {}\n\
+             rust_irc_broadcast_packets_total{{outcome=\"dropped\"}} {}",
+            inner.received, inner.dropped
+        );
+        out
+    }
+}
+
+/// Channel names can have nearly anything in them, label values can't.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Counts channel messages off the event bus until the server shuts down.
+pub async fn watch(
+    mut events: broadcast::Receiver<Event>,
+    metrics: Metrics,
+    mut shutdown: Shutdown,
+) {
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            _ = shutdown.recv() => return,
+        };
+        match event {
+            Ok(Event::ChannelMessage { channel, .. }) => metrics.channel_message(&channel),
+            Ok(_) => {}
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                log::error!("Metrics missed {} events", missed);
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::registry::Uid;
+
+    #[test]
+    fn rendering() {
+        let metrics = Metrics::default();
+        metrics.command("JOIN", Duration::from_micros(50));
+        metrics.command("JOIN", Duration::from_millis(3));
+        let channels = Channels::default();
+        channels.join("#Meow", &Uid::new("001", 1));
+        channels.join("#hush", &Uid::new("001", 1));
+        channels.join("#hush", &Uid::new("001", 2));
+        channels.set_secret("#hush", true);
+        metrics.channel_message("#meow");
+        metrics.channel_message("#hush");
+        metrics.channel_message("#gone");
+        metrics.accept_failure("fd_limit");
+        metrics.tls_handshake_failure("hello_timeout");
+        metrics.received();
+        metrics.dropped(3);
+
+        let out = metrics.render(&channels);
+        assert!(out.contains("command=\"JOIN\",le=\"0.0001\"} 1\n"));
+        assert!(out.contains("command=\"JOIN\",le=\"0.005\"} 2\n"));
+        assert!(out.contains("rust_irc_command_duration_seconds_count{command=\"JOIN\"} 2\n"));
+        assert!(out.contains("rust_irc_channel_members{channel=\"#meow\"} 1\n"));
+        assert!(out.contains("rust_irc_channel_messages_total{channel=\"#meow\"} 1\n"));
+        assert!(!out.contains("#gone"));
+        assert!(!out.contains("#hush"));
+        assert!(out.contains("rust_irc_secret_channels 1\n"));
+        assert!(out.contains("rust_irc_secret_channel_members 2\n"));
+        assert!(out.contains("rust_irc_secret_channel_messages_total 1\n"));
+        assert!(out.contains("rust_irc_accept_failures_total{reason=\"fd_limit\"} 1\n"));
+        assert!(out.contains("rust_irc_tls_handshake_failures_total{stage=\"hello_timeout\"} 1\n"));
+        assert!(out.contains("rust_irc_broadcast_packets_total{outcome=\"received\"} 1\n"));
+        assert!(out.contains("rust_irc_broadcast_packets_total{outcome=\"dropped\"} 3\n"));
+    }
+}
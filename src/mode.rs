@@ -0,0 +1,222 @@
+//! Parsing mode strings like `+ob-v tiger *!*@spam kitty` into the changes they make, going by the CHANMODES types
+//! for which modes take a parameter:
+//!
+//! - A, lists like `b`: always take one, and without one they ask for the list
+//! - B: always take one
+//! - C: only take one when being set
+//! - D: never take one
+//!
+//! Statuses (`yaohv`) are from PREFIX rather than CHANMODES, and take a nick like type B. Both channel and user MODE
+//! go through here, and CHANMODES in ISUPPORT is made from `CHANNEL`.
+
+/// Whether and when a mode takes a parameter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    List,
+    Always,
+    WhenSet,
+    Never,
+    /// A membership status, PREFIX's modes
+    Status,
+}
+
+/// Which modes are of which kind
+#[derive(Debug)]
+pub struct Table {
+    pub list: &'static str,
+    pub always: &'static str,
+    pub when_set: &'static str,
+    pub never: &'static str,
+    pub status: &'static str,
+}
+
+/// Channel modes
+pub const CHANNEL: Table = Table {
+    list: "bq",
+    always: "",
+    when_set: "",
+    never: "Ns",
+    status: "yaohv",
+};
+
+/// User modes. `s` can be set without its snomask letters, which means all of them.
+pub const USER: Table = Table {
+    list: "",
+    always: "",
+    when_set: "s",
+    never: "BH",
+    status: "",
+};
+
+impl Table {
+    pub fn kind(&self, mode: char) -> Option<Kind> {
+        [
+            (self.list, Kind::List),
+            (self.always, Kind::Always),
+            (self.when_set, Kind::WhenSet),
+            (self.never, Kind::Never),
+            (self.status, Kind::Status),
+        ]
+        .into_iter()
+        .find(|(modes, _)| modes.contains(mode))
+        .map(|(_, kind)| kind)
+    }
+
+    /// The CHANMODES ISUPPORT token
+    pub fn chanmodes(&self) -> String {
+        format!(
+            "CHANMODES={},{},{},{}",
+            self.list, self.always, self.when_set, self.never
+        )
+    }
+
+    /// Every mode, for RPL_MYINFO
+    pub fn all(&self) -> String {
+        let mut modes: Vec<char> = [
+            self.list,
+            self.always,
+            self.when_set,
+            self.never,
+            self.status,
+        ]
+        .concat()
+        .chars()
+        .collect();
+        modes.sort_unstable();
+        modes.into_iter().collect()
+    }
+}
+
+/// One mode being set or unset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub adding: bool,
+    pub mode: char,
+    /// `None` for a list mode means asking for the list, for a type C one it means there were none left
+    pub arg: Option<String>,
+}
+
+/// What one letter of a mode string turned out to be
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Parsed {
+    Change(Change),
+    /// Not in the table, takes no parameter
+    Unknown(char),
+}
+
+/// Splits `modestring` into changes, handing out `args` to the modes that take them. Modes that need a parameter
+/// but ran out are dropped.
+pub fn parse(table: &Table, modestring: &str, args: &[String]) -> Vec<Parsed> {
+    let mut args = args.iter();
+    let mut adding = true;
+    let mut parsed = Vec::new();
+    for mode in modestring.chars() {
+        let kind = match mode {
+            '+' | '-' => {
+                adding = mode == '+';
+                continue;
+            }
+            _ => match table.kind(mode) {
+                Some(kind) => kind,
+                None => {
+                    parsed.push(Parsed::Unknown(mode));
+                    continue;
+                }
+            },
+        };
+        let arg = match kind {
+            Kind::List => args.next().cloned(),
+            Kind::Always | Kind::Status => match args.next() {
+                Some(arg) => Some(arg.clone()),
+                None => continue,
+            },
+            Kind::WhenSet if adding => args.next().cloned(),
+            Kind::WhenSet | Kind::Never => None,
+        };
+        parsed.push(Parsed::Change(Change { adding, mode, arg }));
+    }
+    parsed
+}
+
+/// Puts changes that went through back into a mode string and its parameters, to tell everyone.
+pub fn join(changes: &[Change]) -> (String, Vec<String>) {
+    let (mut modestring, mut args) = (String::new(), Vec::new());
+    let mut adding = None;
+    for change in changes {
+        if adding != Some(change.adding) {
+            modestring.push(if change.adding { '+' } else { '-' });
+            adding = Some(change.adding);
+        }
+        modestring.push(change.mode);
+        args.extend(change.arg.clone());
+    }
+    (modestring, args)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn change(adding: bool, mode: char, arg: Option<&str>) -> Parsed {
+        Parsed::Change(Change {
+            adding,
+            mode,
+            arg: arg.map(str::to_string),
+        })
+    }
+
+    #[test]
+    fn parameters() {
+        let args: Vec<String> = ["tiger", "*!*@spam"].map(String::from).to_vec();
+        assert_eq!(
+            parse(&CHANNEL, "+oxb-v", &args),
+            [
+                change(true, 'o', Some("tiger")),
+                Parsed::Unknown('x'),
+                change(true, 'b', Some("*!*@spam")),
+            ]
+        );
+        // A bare list mode asks for the list
+        assert_eq!(parse(&CHANNEL, "b", &[]), [change(true, 'b', None)]);
+
+        let args = vec!["+ck".to_string()];
+        assert_eq!(
+            parse(&USER, "-s+sB", &args),
+            [
+                change(false, 's', None),
+                change(true, 's', Some("+ck")),
+                change(true, 'B', None),
+            ]
+        );
+    }
+
+    #[test]
+    fn joining() {
+        let changes = [
+            Change {
+                adding: true,
+                mode: 'o',
+                arg: Some("tiger".to_string()),
+            },
+            Change {
+                adding: true,
+                mode: 'v',
+                arg: Some("tiger".to_string()),
+            },
+            Change {
+                adding: false,
+                mode: 'b',
+                arg: Some("*!*@*".to_string()),
+            },
+        ];
+        assert_eq!(
+            join(&changes),
+            (
+                "+ov-b".to_string(),
+                vec!["tiger".into(), "tiger".into(), "*!*@*".into()]
+            )
+        );
+        assert_eq!(CHANNEL.chanmodes(), "CHANMODES=bq,,,Ns");
+        assert_eq!(CHANNEL.all(), "Nabhoqsvy");
+    }
+}
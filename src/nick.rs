@@ -0,0 +1,87 @@
+//! Unicode nicknames, and keeping account names for their accounts. The Unicode checks are off unless the config
+//! turns them on:
+//!
+//! ```toml
+//! [nicks]
+//! # NFC-normalize nicks, so the same nick typed two ways is the same nick
+//! normalize = true
+//! # Refuse nicks that look like someone else's, like `аdmin` with a Cyrillic а
+//! strict_confusables = true
+//! # Anyone using an account's name as their nick without being logged into it gets warned, then renamed to a
+//! # Guest nick this many seconds later. NickServ GHOST and REGAIN (see `nickserv`) work either way
+//! protect = true
+//! protect_grace = 60
+//! # A client still trying to register this many seconds after its nick was first turned down gets a Guest nick
+//! # instead. Off unless this is set
+//! guest_after = 30
+//! ```
+
+use serde::Deserialize;
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct NickConfig {
+    pub normalize: bool,
+    pub strict_confusables: bool,
+    pub protect: bool,
+    /// Seconds someone gets to move off an account's nick
+    pub protect_grace: u64,
+    pub guest_after: Option<u64>,
+}
+
+impl Default for NickConfig {
+    fn default() -> Self {
+        Self {
+            normalize: false,
+            strict_confusables: false,
+            protect: true,
+            protect_grace: 60,
+            guest_after: None,
+        }
+    }
+}
+
+/// `nick` in NFC.
+pub fn normalize(nick: &str) -> String {
+    nick.nfc().collect()
+}
+
+/// What `nick` looks like, per Unicode's confusables (UTS #39). Nicks with the same skeleton are easily mistaken
+/// for each other.
+pub fn skeleton(nick: &str) -> String {
+    unicode_security::skeleton(&nick.to_lowercase())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// A `Guest#####` nick with a random number, which someone might already have.
+pub fn guest() -> String {
+    let random = RandomState::new().build_hasher().finish();
+    format!("Guest{:05}", random % 100_000)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn guests() {
+        let guest = guest();
+        assert_eq!(guest.len(), 10);
+        assert!(guest.strip_prefix("Guest").unwrap().parse::<u32>().is_ok());
+    }
+
+    #[test]
+    fn lookalikes() {
+        assert_eq!(skeleton("admin"), skeleton("\u{430}dmin"));
+        assert_eq!(skeleton("Admin"), skeleton("admin"));
+        assert_ne!(skeleton("admin"), skeleton("badmin"));
+        // e + combining acute and é
+        assert_eq!(normalize("cafe\u{301}"), "caf\u{e9}");
+    }
+}
@@ -0,0 +1,121 @@
+//! A NickServ built into the server, for getting an account's nick back from whoever's using it. It only answers
+//! while nobody actually called NickServ is around, so real services can take over.
+//!
+//! - `GHOST <nick> [password]` disconnects whoever is using `nick`
+//! - `REGAIN <nick> [password]` moves them to a Guest nick and gives `nick` to you
+//!
+//! Both need you to be logged into the account `nick` belongs to, or to give its password. Wrong passwords count
+//! against `nickserv_lockout` the same way wrong OPER passwords do, see `lockout`.
+
+use crate::{event::Event, log, message_impl::Code, ClientConnection, Result};
+use std::time::Instant;
+
+pub const NICK: &str = "NickServ";
+
+/// Something someone said to NickServ. Returns `Code::Exit` if they've had too many wrong passwords.
+pub async fn handle(cc: &mut ClientConnection, text: &str) -> Result<Code> {
+    let mut words = text.split_whitespace();
+    let command = words.next().unwrap_or_default().to_ascii_uppercase();
+    match (command.as_str(), words.next()) {
+        ("GHOST", Some(nick)) => ghost(cc, nick, words.next(), false).await,
+        ("REGAIN", Some(nick)) => ghost(cc, nick, words.next(), true).await,
+        _ => {
+            reply(
+                cc,
+                "I know GHOST <nick> [password] and REGAIN <nick> [password]",
+            )
+            .await?;
+            Ok(Code::Fine)
+        }
+    }
+}
+
+/// GHOST, or REGAIN if `regain` is set.
+async fn ghost(
+    cc: &mut ClientConnection,
+    nick: &str,
+    password: Option<&str>,
+    regain: bool,
+) -> Result<Code> {
+    let info = cc.info().clone();
+    let Some(owner) = cc.accounts.owner(nick) else {
+        reply(cc, &format!("{} isn't registered", nick)).await?;
+        return Ok(Code::Fine);
+    };
+    let (config, ip) = (cc.config.clone(), cc.connection.client_addr.ip());
+    let lockout = &config.nickserv_lockout;
+    let allowed = match password {
+        _ if info.account.as_ref() == Some(&owner) => true,
+        Some(_) if cc.nickserv_lockouts.locked(lockout, ip, Instant::now()) => {
+            reply(cc, "Too many wrong passwords, try again later").await?;
+            return Ok(Code::Fine);
+        }
+        Some(password) => cc
+            .auth
+            .authenticate(&owner, password)
+            .await
+            .unwrap_or_else(|e| {
+                log::error!("Couldn't check the password for {}: {}", owner, e);
+                false
+            }),
+        None => false,
+    };
+    if !allowed {
+        reply(cc, &format!("You don't own {}", nick)).await?;
+        if password.is_none() {
+            return Ok(Code::Fine);
+        }
+        cc.events.publish(Event::AuthFailed {
+            account: Some(owner),
+            ip: info.ip.clone(),
+        });
+        cc.nickserv_failures += 1;
+        cc.nickserv_lockouts.fail(lockout, ip, Instant::now());
+        if cc.nickserv_failures >= lockout.per_connection {
+            let reason = "Too many wrong NickServ passwords";
+            cc.connection.write_error(reason).await?;
+            cc.quit_reason = Some(reason.to_string());
+            return Ok(Code::Exit);
+        }
+        return Ok(Code::Fine);
+    }
+    let squatter = cc.users.uid(nick);
+    if squatter.as_ref() == Some(&info.uid) {
+        reply(cc, &format!("You're already {}", nick)).await?;
+        return Ok(Code::Fine);
+    }
+
+    if !regain {
+        match squatter {
+            Some(_) => {
+                let reason = format!("GHOST command used by {}", info.nickname);
+                cc.disconnect(nick, NICK, reason).await?;
+                reply(cc, &format!("{} has been ghosted", nick)).await?;
+            }
+            None => reply(cc, &format!("{} isn't online", nick)).await?,
+        }
+        return Ok(Code::Fine);
+    }
+    if let Some(uid) = squatter {
+        cc.rename_guest(&uid).await?;
+    }
+    if !(cc.cluster.claim(nick).await && cc.users.rename(&info.uid, nick)) {
+        reply(cc, &format!("Couldn't get {} back, try again", nick)).await?;
+        return Ok(Code::Fine);
+    }
+    cc.info().nickname = nick.to_string();
+    cc.announce_nick(info.to_canonical(), nick).await?;
+    cc.events.publish(Event::NickChanged {
+        old: info.nickname,
+        new: nick.to_string(),
+    });
+    // They've shown it's theirs
+    cc.nick_deadline = None;
+    reply(cc, &format!("You're {} now", nick)).await?;
+    Ok(Code::Fine)
+}
+
+async fn reply(cc: &mut ClientConnection, text: &str) -> Result<()> {
+    let info = cc.info().clone();
+    cc.connection.write_service_notice(&info, NICK, text).await
+}
@@ -0,0 +1,120 @@
+//! Keeps big replies like LIST and WHO from hogging a connection's task, and the thread it's running on, while they
+//! get written out. They go out in chunks, flushing and letting everything else run in between. Non-opers get cut off
+//! after `max_lines`, and have to wait `list_cooldown` seconds between LISTs of every channel.
+//!
+//! ```toml
+//! [big_replies]
+//! chunk = 100
+//! # 0 for no limit
+//! max_lines = 1000
+//! list_cooldown = 30
+//! ```
+
+use crate::{IrcConnection, Result};
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BigRepliesConfig {
+    /// Lines written between flushes
+    pub chunk: usize,
+    /// Most lines a non-oper gets in one reply
+    pub max_lines: usize,
+    /// Seconds a non-oper has to wait between full LISTs
+    pub list_cooldown: u64,
+}
+
+impl Default for BigRepliesConfig {
+    fn default() -> Self {
+        Self {
+            chunk: 100,
+            max_lines: 1000,
+            list_cooldown: 30,
+        }
+    }
+}
+
+impl BigRepliesConfig {
+    /// Returns `true` if someone whose last full LIST was at `last` can have another one at `now`.
+    pub fn list_allowed(&self, last: Option<Instant>, now: Instant) -> bool {
+        last.is_none_or(|x| now >= x + Duration::from_secs(self.list_cooldown))
+    }
+}
+
+/// Paces one reply
+#[derive(Debug)]
+pub struct Pacer {
+    chunk: usize,
+    max_lines: Option<usize>,
+    written: usize,
+    /// Set once it's been cut off
+    pub truncated: bool,
+}
+
+impl Pacer {
+    /// Opers don't get cut off.
+    pub fn new(config: &BigRepliesConfig, oper: bool) -> Self {
+        Self {
+            chunk: config.chunk,
+            max_lines: (!oper && config.max_lines > 0).then_some(config.max_lines),
+            written: 0,
+            truncated: false,
+        }
+    }
+
+    /// Call before writing each line. Every `chunk` lines it flushes what's been written and yields. Returns
+    /// `false` once the reply is as long as it gets, and it should stop.
+    pub async fn line(&mut self, connection: &mut IrcConnection) -> Result<bool> {
+        if self.max_lines.is_some_and(|x| self.written >= x) {
+            self.truncated = true;
+            return Ok(false);
+        }
+        if self.written > 0 && self.written.is_multiple_of(self.chunk) {
+            connection.flush().await?;
+            tokio::task::yield_now().await;
+        }
+        self.written += 1;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::SocketAddr;
+
+    #[tokio::test]
+    async fn caps_non_opers() {
+        let (ours, _theirs) = tokio::io::duplex(1 << 16);
+        let mut connection =
+            IrcConnection::new_virtual(ours, SocketAddr::from(([127, 0, 0, 1], 0)));
+        let config = BigRepliesConfig {
+            chunk: 2,
+            max_lines: 3,
+            ..Default::default()
+        };
+        let mut pacer = Pacer::new(&config, false);
+        let mut lines = 0;
+        while pacer.line(&mut connection).await.unwrap() {
+            lines += 1;
+        }
+        assert_eq!(lines, 3);
+        assert!(pacer.truncated);
+
+        let mut pacer = Pacer::new(&config, true);
+        for _ in 0..10 {
+            assert!(pacer.line(&mut connection).await.unwrap());
+        }
+        assert!(!pacer.truncated);
+    }
+
+    #[test]
+    fn list_cooldown() {
+        let config = BigRepliesConfig::default();
+        let now = Instant::now();
+        assert!(config.list_allowed(None, now));
+        assert!(!config.list_allowed(Some(now), now + Duration::from_secs(29)));
+        assert!(config.list_allowed(Some(now), now + Duration::from_secs(30)));
+    }
+}
@@ -0,0 +1,62 @@
+//! Password hashing. Every password the server stores (account and oper blocks in the config) is an argon2id hash
+//! in PHC format, never plaintext. Generate one with `rust_irc hash-password`.
+
+use crate::Result;
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2,
+};
+use std::io::{BufRead, Write};
+
+/// Hashes `password` with a fresh salt.
+pub fn hash(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt)?;
+    Ok(hash.to_string())
+}
+
+/// Returns `true` if `password` matches `hash`. Anything that isn't a hash matches nothing.
+pub fn verify(hash: &str, password: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Returns `true` if `s` is an argon2id hash, rather than (probably) a plaintext password.
+pub fn is_hash(s: &str) -> bool {
+    PasswordHash::new(s).is_ok_and(|x| x.algorithm == Algorithm::Argon2id.ident())
+}
+
+/// `rust_irc hash-password`: reads a password from stdin and prints its hash, ready to paste into the config.
+pub fn hash_password_command() -> Result<()> {
+    eprint!("Password: ");
+    std::io::stderr().flush()?;
+    let mut password = String::new();
+    std::io::stdin().lock().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err("No password given".into());
+    }
+    println!("{}", hash(password)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hashes_verify() {
+        let hashed = hash("hunter2").unwrap();
+        assert!(is_hash(&hashed));
+        assert!(verify(&hashed, "hunter2"));
+        assert!(!verify(&hashed, "hunter3"));
+        assert_ne!(hash("hunter2").unwrap(), hashed);
+
+        assert!(!is_hash("hunter2"));
+        assert!(!verify("hunter2", "hunter2"));
+    }
+}
@@ -0,0 +1,462 @@
+//! WASM plugins, so extensions can be written in anything that compiles to WebAssembly. They run sandboxed in
+//! wasmtime with no WASI, a memory cap and a fuel budget per call, so a plugin can't touch the host or hang the server.
+//!
+//! A plugin is a core WASM module exporting `memory` and `alloc(len) -> ptr`, which we use to hand it strings as
+//! (ptr, len) pairs. Allocations only need to live until the call returns. It can export any of:
+//! - `filter_message(source, target, text) -> i32`, runs on every PRIVMSG before delivery. Non-zero blocks it.
+//! - `handle_command(nick, line) -> i32`, runs for commands the server doesn't know. Non-zero means it was handled.
+//! - `tick()`, runs about once a second.
+//!
+//! And can import any of these from `env`:
+//! - `reply(ptr, len)`, sets the replacement text from `filter_message`, or the NOTICE sent back from `handle_command`
+//! - `say(channel_ptr, channel_len, text_ptr, text_len)`, queues a message to a channel, sent on the next tick
+//! - `log(ptr, len)`
+//!
+//! Plugins are reloaded when their file changes, and all of them on REHASH.
+//! This all needs the `wasm` feature, without it plugins in the config are ignored.
+
+use crate::{log, script::Verdict, Result};
+use std::{path::PathBuf, sync::Arc};
+
+#[cfg(feature = "wasm")]
+use crate::server::lock;
+#[cfg(feature = "wasm")]
+use std::{
+    path::Path,
+    sync::{Mutex, OnceLock},
+    time::SystemTime,
+};
+#[cfg(feature = "wasm")]
+use wasmtime::{Caller, Engine, Instance, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+/// How much work a plugin gets to do per call before it's cut off
+#[cfg(feature = "wasm")]
+const FUEL: u64 = 10_000_000;
+/// Most memory a plugin can have
+#[cfg(feature = "wasm")]
+const MEMORY_LIMIT: usize = 16 << 20;
+
+/// Something a plugin wants said in a channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Said {
+    /// Name of the plugin, which the message comes from
+    pub plugin: String,
+    pub channel: String,
+    pub text: String,
+}
+
+/// Shared handle to the loaded plugins, cheap to clone into each connection.
+#[derive(Debug, Clone, Default)]
+pub struct Plugins {
+    paths: Arc<Vec<PathBuf>>,
+    #[cfg(feature = "wasm")]
+    loaded: Arc<Mutex<Vec<Plugin>>>,
+}
+
+impl Plugins {
+    /// Loads every plugin in `paths`.
+    pub fn load(paths: Vec<PathBuf>) -> Result<Self> {
+        let plugins = Self {
+            paths: Arc::new(paths),
+            #[cfg(feature = "wasm")]
+            loaded: Arc::default(),
+        };
+        plugins.reload()?;
+        Ok(plugins)
+    }
+
+    /// Loads every plugin from scratch. If any plugin fails to load we keep running the old ones.
+    pub fn reload(&self) -> Result<()> {
+        #[cfg(feature = "wasm")]
+        {
+            let loaded = self
+                .paths
+                .iter()
+                .map(|path| Plugin::load(path))
+                .collect::<Result<Vec<Plugin>>>()?;
+            *lock(&self.loaded) = loaded;
+        }
+        #[cfg(not(feature = "wasm"))]
+        if !self.paths.is_empty() {
+            log::error!("Built without the wasm feature, ignoring plugins");
+        }
+        Ok(())
+    }
+
+    /// Runs every plugin's `filter_message` in turn, each one seeing whatever the last one rewrote.
+    pub fn filter_message(&self, source: &str, target: &str, text: &str) -> Verdict {
+        #[cfg(feature = "wasm")]
+        {
+            let mut text = text.to_string();
+            let mut rewritten = false;
+            for plugin in lock(&self.loaded).iter_mut() {
+                match plugin.filter_message(source, target, &text) {
+                    Verdict::Allow => {}
+                    Verdict::Block => return Verdict::Block,
+                    Verdict::Rewrite(new) => {
+                        text = new;
+                        rewritten = true;
+                    }
+                }
+            }
+            if rewritten {
+                return Verdict::Rewrite(text);
+            }
+        }
+        #[cfg(not(feature = "wasm"))]
+        let _ = (source, target, text);
+        Verdict::Allow
+    }
+
+    /// Offers a command the server doesn't know to the plugins. Returns `None` if none of them took it, or whatever
+    /// the one that did wants to tell the user (which can be empty).
+    pub fn handle_command(&self, nick: &str, line: &str) -> Option<String> {
+        #[cfg(feature = "wasm")]
+        for plugin in lock(&self.loaded).iter_mut() {
+            if let Some(reply) = plugin.handle_command(nick, line) {
+                return Some(reply);
+            }
+        }
+        #[cfg(not(feature = "wasm"))]
+        let _ = (nick, line);
+        None
+    }
+
+    /// Reloads any plugin whose file changed, runs every `tick`, and collects everything the plugins have asked to
+    /// say since the last tick.
+    pub fn tick(&self) -> Vec<Said> {
+        #[cfg(feature = "wasm")]
+        {
+            let mut said = Vec::new();
+            for plugin in lock(&self.loaded).iter_mut() {
+                plugin.reload_if_changed();
+                plugin.tick();
+                said.append(&mut plugin.store.data_mut().outbox);
+            }
+            said
+        }
+        #[cfg(not(feature = "wasm"))]
+        Vec::new()
+    }
+}
+
+/// What the host functions get to see
+#[cfg(feature = "wasm")]
+#[derive(Debug)]
+struct Host {
+    name: String,
+    limits: StoreLimits,
+    /// Set by `reply`, cleared before each call
+    reply: Option<String>,
+    outbox: Vec<Said>,
+}
+
+#[cfg(feature = "wasm")]
+#[derive(Debug)]
+struct Plugin {
+    path: PathBuf,
+    /// When the file was last changed, so we can tell when to reload it
+    modified: Option<SystemTime>,
+    store: Store<Host>,
+    instance: Instance,
+}
+
+/// Every plugin shares one engine, set up to meter fuel
+#[cfg(feature = "wasm")]
+fn engine() -> &'static Engine {
+    static ENGINE: OnceLock<Engine> = OnceLock::new();
+    ENGINE.get_or_init(|| {
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        Engine::new(&config).expect("fuel metering is always supported")
+    })
+}
+
+#[cfg(feature = "wasm")]
+impl Plugin {
+    fn load(path: &Path) -> Result<Self> {
+        let module = Module::from_file(engine(), path)?;
+        let name = path
+            .file_stem()
+            .map_or("plugin".to_string(), |x| x.to_string_lossy().into_owned());
+        let mut store = Store::new(
+            engine(),
+            Host {
+                name,
+                limits: StoreLimitsBuilder::new().memory_size(MEMORY_LIMIT).build(),
+                reply: None,
+                outbox: Vec::new(),
+            },
+        );
+        store.limiter(|host| &mut host.limits);
+        // Running the start function costs fuel too
+        store.set_fuel(FUEL)?;
+
+        let mut linker = Linker::new(engine());
+        linker.func_wrap(
+            "env",
+            "reply",
+            |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+                let text = read_str(&mut caller, ptr, len)?;
+                caller.data_mut().reply = Some(text);
+                wasmtime::Result::Ok(())
+            },
+        )?;
+        linker.func_wrap(
+            "env",
+            "say",
+            |mut caller: Caller<'_, Host>,
+             channel_ptr: i32,
+             channel_len: i32,
+             ptr: i32,
+             len: i32| {
+                let channel = read_str(&mut caller, channel_ptr, channel_len)?;
+                let text = read_str(&mut caller, ptr, len)?;
+                let plugin = caller.data().name.clone();
+                caller.data_mut().outbox.push(Said {
+                    plugin,
+                    channel,
+                    text,
+                });
+                wasmtime::Result::Ok(())
+            },
+        )?;
+        linker.func_wrap(
+            "env",
+            "log",
+            |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+                let text = read_str(&mut caller, ptr, len)?;
+                log::info!("Plugin {}: {}", caller.data().name, text);
+                wasmtime::Result::Ok(())
+            },
+        )?;
+        let instance = linker.instantiate(&mut store, &module)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            modified: modified(path),
+            store,
+            instance,
+        })
+    }
+
+    fn reload_if_changed(&mut self) {
+        let modified = modified(&self.path);
+        if modified == self.modified {
+            return;
+        }
+        match Plugin::load(&self.path) {
+            Ok(plugin) => {
+                log::info!("Reloaded plugin {}", self.path.display());
+                *self = plugin;
+            }
+            Err(e) => {
+                log::error!("Failed to reload plugin {}: {}", self.path.display(), e);
+                // Don't try again until it changes again
+                self.modified = modified;
+            }
+        }
+    }
+
+    fn filter_message(&mut self, source: &str, target: &str, text: &str) -> Verdict {
+        let res = self.call("filter_message", |plugin| {
+            let filter = match plugin
+                .instance
+                .get_typed_func::<(i32, i32, i32, i32, i32, i32), i32>(
+                    &mut plugin.store,
+                    "filter_message",
+                ) {
+                Ok(filter) => filter,
+                Err(_) => return Ok(0),
+            };
+            let (source_ptr, source_len) = plugin.write_str(source)?;
+            let (target_ptr, target_len) = plugin.write_str(target)?;
+            let (text_ptr, text_len) = plugin.write_str(text)?;
+            filter.call(
+                &mut plugin.store,
+                (
+                    source_ptr, source_len, target_ptr, target_len, text_ptr, text_len,
+                ),
+            )
+        });
+        match res {
+            Some(0) => match self.store.data_mut().reply.take() {
+                Some(text) => Verdict::Rewrite(text),
+                None => Verdict::Allow,
+            },
+            Some(_) => Verdict::Block,
+            None => Verdict::Allow,
+        }
+    }
+
+    fn handle_command(&mut self, nick: &str, line: &str) -> Option<String> {
+        let handled = self.call("handle_command", |plugin| {
+            let handle = match plugin
+                .instance
+                .get_typed_func::<(i32, i32, i32, i32), i32>(&mut plugin.store, "handle_command")
+            {
+                Ok(handle) => handle,
+                Err(_) => return Ok(0),
+            };
+            let (nick_ptr, nick_len) = plugin.write_str(nick)?;
+            let (line_ptr, line_len) = plugin.write_str(line)?;
+            handle.call(&mut plugin.store, (nick_ptr, nick_len, line_ptr, line_len))
+        });
+        let reply = self.store.data_mut().reply.take();
+        match handled {
+            Some(0) | None => None,
+            Some(_) => Some(reply.unwrap_or_default()),
+        }
+    }
+
+    fn tick(&mut self) {
+        self.call("tick", |plugin| {
+            match plugin
+                .instance
+                .get_typed_func::<(), ()>(&mut plugin.store, "tick")
+            {
+                Ok(tick) => tick.call(&mut plugin.store, ()),
+                Err(_) => Ok(()),
+            }
+        });
+    }
+
+    /// Runs `f` with a fresh fuel budget. A plugin that traps or runs out of fuel gets logged and ignored, a buggy
+    /// plugin shouldn't be able to lock everyone out.
+    fn call<T>(
+        &mut self,
+        hook: &str,
+        f: impl FnOnce(&mut Self) -> wasmtime::Result<T>,
+    ) -> Option<T> {
+        self.store.data_mut().reply = None;
+        let res = self.store.set_fuel(FUEL).and_then(|_| f(self));
+        match res {
+            Ok(res) => Some(res),
+            Err(e) => {
+                log::error!("Plugin {} {} failed: {}", self.path.display(), hook, e);
+                None
+            }
+        }
+    }
+
+    /// Copies `s` into the plugin's memory, returning where it ended up.
+    fn write_str(&mut self, s: &str) -> wasmtime::Result<(i32, i32)> {
+        let alloc = self
+            .instance
+            .get_typed_func::<i32, i32>(&mut self.store, "alloc")?;
+        let memory = self
+            .instance
+            .get_memory(&mut self.store, "memory")
+            .ok_or_else(|| wasmtime::Error::msg("plugin doesn't export memory"))?;
+        let len = s.len() as i32;
+        let ptr = alloc.call(&mut self.store, len)?;
+        memory.write(&mut self.store, ptr as u32 as usize, s.as_bytes())?;
+        Ok((ptr, len))
+    }
+}
+
+#[cfg(feature = "wasm")]
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|x| x.modified()).ok()
+}
+
+/// Reads a string the plugin handed us out of its memory.
+#[cfg(feature = "wasm")]
+fn read_str(caller: &mut Caller<'_, Host>, ptr: i32, len: i32) -> wasmtime::Result<String> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(|x| x.into_memory())
+        .ok_or_else(|| wasmtime::Error::msg("plugin doesn't export memory"))?;
+    let start = ptr as u32 as usize;
+    let bytes = memory
+        .data(&caller)
+        .get(start..start + len as u32 as usize)
+        .ok_or_else(|| wasmtime::Error::msg("string out of bounds"))?;
+    Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+#[cfg(all(test, feature = "wasm"))]
+mod test {
+    use super::*;
+
+    /// Blocks messages starting with '!', censors ones starting with 'x', answers any command with "pong", and
+    /// says "tick" in #meow every tick. Allocation is a bump pointer that never frees.
+    const PLUGIN: &str = r##"
+        (module
+            (import "env" "reply" (func $reply (param i32 i32)))
+            (import "env" "say" (func $say (param i32 i32 i32 i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 0) "censored")
+            (data (i32.const 8) "pong")
+            (data (i32.const 12) "#meow")
+            (data (i32.const 17) "tick")
+            (global $next (mut i32) (i32.const 1024))
+            (func (export "alloc") (param $len i32) (result i32)
+                (global.get $next)
+                (global.set $next (i32.add (global.get $next) (local.get $len))))
+            (func (export "filter_message") (param i32 i32 i32 i32 i32 i32) (result i32)
+                (if (i32.eq (i32.load8_u (local.get 4)) (i32.const 33)) (then (return (i32.const 1))))
+                (if (i32.eq (i32.load8_u (local.get 4)) (i32.const 120))
+                    (then (call $reply (i32.const 0) (i32.const 8))))
+                (i32.const 0))
+            (func (export "handle_command") (param i32 i32 i32 i32) (result i32)
+                (call $reply (i32.const 8) (i32.const 4))
+                (i32.const 1))
+            (func (export "tick")
+                (call $say (i32.const 12) (i32.const 5) (i32.const 17) (i32.const 4))))
+    "##;
+
+    fn plugins(source: &str) -> (Plugins, PathBuf) {
+        let path = std::env::temp_dir().join(format!(
+            "rust_irc_plugin_{}_{:?}.wat",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        std::fs::write(&path, source).unwrap();
+        (Plugins::load(vec![path.clone()]).unwrap(), path)
+    }
+
+    #[test]
+    fn hooks_filter_and_answer() {
+        let (plugins, path) = plugins(PLUGIN);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(
+            plugins.filter_message("tiger", "#meow", "hello"),
+            Verdict::Allow
+        );
+        assert_eq!(
+            plugins.filter_message("tiger", "#meow", "!spam"),
+            Verdict::Block
+        );
+        assert_eq!(
+            plugins.filter_message("tiger", "#meow", "xyzzy"),
+            Verdict::Rewrite("censored".to_string())
+        );
+        assert_eq!(
+            plugins.handle_command("tiger", "PING2 me"),
+            Some("pong".to_string())
+        );
+    }
+
+    #[test]
+    fn tick_collects_messages_and_reloads() {
+        let (plugins, path) = plugins("(module)");
+        assert!(plugins.tick().is_empty());
+
+        // Make sure the mtime actually moves
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        std::fs::write(&path, PLUGIN).unwrap();
+        let said = plugins.tick();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(said.len(), 1);
+        assert_eq!(said[0].channel, "#meow");
+        assert_eq!(said[0].text, "tick");
+    }
+
+    #[test]
+    fn runaway_plugins_run_out_of_fuel() {
+        let (plugins, path) = plugins(r#"(module (func (export "tick") (loop (br 0))))"#);
+        std::fs::remove_file(path).unwrap();
+        assert!(plugins.tick().is_empty());
+    }
+}
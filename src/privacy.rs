@@ -0,0 +1,127 @@
+//! How much WHO, WHOIS and STATS p give away about someone to people who don't share a channel with them. Their
+//! channels, idle time and host stay visible to themselves, anyone in a channel with them and opers. Anything they
+//! send still has their host on it, this is only about looking people up.
+//!
+//! ```toml
+//! [privacy]
+//! # open, channels, idle or strict, each hiding everything the ones before it do
+//! level = "strict"
+//! # What strict shows instead of their host
+//! hidden_host = "hidden"
+//! ```
+
+use crate::ClientInfo;
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Privacy {
+    /// Everything's shown
+    #[default]
+    Open,
+    /// Hides which channels they're in
+    Channels,
+    /// Hides how long they've been idle too
+    Idle,
+    /// Hides their host too
+    Strict,
+}
+
+impl Privacy {
+    pub fn hides_channels(self) -> bool {
+        self >= Privacy::Channels
+    }
+
+    pub fn hides_idle(self) -> bool {
+        self >= Privacy::Idle
+    }
+
+    pub fn hides_host(self) -> bool {
+        self >= Privacy::Strict
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PrivacyConfig {
+    pub level: Privacy,
+    pub hidden_host: String,
+}
+
+impl Default for PrivacyConfig {
+    fn default() -> Self {
+        Self {
+            level: Privacy::Open,
+            hidden_host: "hidden".to_string(),
+        }
+    }
+}
+
+impl PrivacyConfig {
+    /// How private `target` is from `viewer`.
+    pub fn level(&self, viewer: &ClientInfo, target: &ClientInfo) -> Privacy {
+        let shared = target
+            .channels
+            .iter()
+            .any(|x| viewer.channels.iter().any(|y| x.eq_ignore_ascii_case(y)));
+        if shared || viewer.uid == target.uid || viewer.oper.is_some() {
+            return Privacy::Open;
+        }
+        self.level
+    }
+
+    /// `target` as `viewer` gets to see them, along with how private they are.
+    pub fn shown(&self, viewer: &ClientInfo, mut target: ClientInfo) -> (ClientInfo, Privacy) {
+        let level = self.level(viewer, &target);
+        if level.hides_host() {
+            target.host = self.hidden_host.clone();
+        }
+        if level.hides_channels() {
+            target.channels.clear();
+        }
+        (target, level)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::registry::Uid;
+
+    #[test]
+    fn strangers_see_less() {
+        let config: PrivacyConfig = toml::from_str(r#"level = "strict""#).unwrap();
+        let user = |id, channels: &[&str]| ClientInfo {
+            uid: Uid::new("001", id),
+            host: "192.0.2.1".to_string(),
+            channels: channels.iter().map(|x| x.to_string()).collect(),
+            ..Default::default()
+        };
+        let tiger = user(1, &["#meow"]);
+
+        let (shown, level) = config.shown(&user(2, &[]), tiger.clone());
+        assert_eq!(level, Privacy::Strict);
+        assert!(level.hides_idle());
+        assert_eq!(shown.host, "hidden");
+        assert!(shown.channels.is_empty());
+
+        // Sharing a channel, being them or being an oper shows everything
+        let (shown, level) = config.shown(&user(2, &["#MEOW"]), tiger.clone());
+        assert_eq!((shown.host.as_str(), level), ("192.0.2.1", Privacy::Open));
+        assert_eq!(config.level(&tiger, &tiger), Privacy::Open);
+        let oper = ClientInfo {
+            oper: Some(Default::default()),
+            ..user(3, &[])
+        };
+        assert_eq!(config.level(&oper, &tiger), Privacy::Open);
+
+        let config = PrivacyConfig {
+            level: Privacy::Channels,
+            ..Default::default()
+        };
+        let (shown, level) = config.shown(&user(2, &[]), tiger);
+        assert!(!level.hides_idle());
+        assert_eq!(shown.host, "192.0.2.1");
+        assert!(shown.channels.is_empty());
+    }
+}
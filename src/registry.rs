@@ -0,0 +1,504 @@
+//! Every registered user by UID and nick, so a message to a nick can go straight to the connections using it
+//! instead of being broadcast to everyone.
+
+use crate::{
+    message_parse::{Command, Message, Side},
+    nick,
+    server::{lock, lock_info, SharedInfo},
+    ClientInfo,
+};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+/// Stable identifier for a user, TS6-style: the server's ID followed by six characters. Nick changes don't touch
+/// it, so it's what channels and routing key users by.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Uid(String);
+
+impl Uid {
+    /// The UID for connection `id` on the server with `sid`.
+    pub fn new(sid: &str, mut id: usize) -> Self {
+        const LETTERS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+        const ALPHANUMERIC: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+        let mut uid = [0u8; 6];
+        for x in uid[1..].iter_mut().rev() {
+            *x = ALPHANUMERIC[id % ALPHANUMERIC.len()];
+            id /= ALPHANUMERIC.len();
+        }
+        // The first one has to be a letter
+        uid[0] = LETTERS[id % LETTERS.len()];
+        Self(format!("{}{}", sid, String::from_utf8_lossy(&uid)))
+    }
+}
+
+impl std::fmt::Display for Uid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// One of a user's connections, as TRACE shows it
+#[derive(Debug, Clone)]
+pub struct Traced {
+    pub id: usize,
+    pub info: ClientInfo,
+    /// Messages waiting for the connection to get around to writing them
+    pub sendq: usize,
+    /// How long the connection's last answered PING took to come back, if one has yet
+    pub lag: Option<Duration>,
+}
+
+/// How many there are of everyone, for LUSERS
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counts {
+    pub users: usize,
+    pub opers: usize,
+    pub connections: usize,
+}
+
+#[derive(Debug)]
+struct User {
+    info: SharedInfo,
+    /// Key of the nick they're using
+    nick: String,
+    /// Every connection attached to the user, by connection id
+    connections: HashMap<usize, mpsc::Sender<Message>>,
+    /// Each connection's lag, once it's answered a PING
+    lags: HashMap<usize, Duration>,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    users: HashMap<Uid, User>,
+    /// Lowercased nick to whoever is using it
+    nicks: HashMap<String, Uid>,
+    /// What each nick looks like (see `nick::skeleton`) to whoever is using it
+    skeletons: HashMap<String, Uid>,
+}
+
+/// Shared between every connection.
+#[derive(Debug, Clone, Default)]
+pub struct Users {
+    registry: Arc<Mutex<Registry>>,
+}
+
+fn key(nick: &str) -> String {
+    nick.to_ascii_lowercase()
+}
+
+impl Users {
+    /// Claims `nick` for connection `id` of the user with `info`, adding the user if they aren't around yet.
+    /// Returns `false` if someone else has it.
+    pub fn claim(
+        &self,
+        nick: &str,
+        id: usize,
+        info: &SharedInfo,
+        tx: mpsc::Sender<Message>,
+    ) -> bool {
+        let uid = lock_info(info).uid.clone();
+        let mut registry = lock(&self.registry);
+        match registry.nicks.entry(key(nick)) {
+            Entry::Occupied(entry) if *entry.get() != uid => return false,
+            Entry::Occupied(_) => {}
+            Entry::Vacant(entry) => {
+                entry.insert(uid.clone());
+            }
+        }
+        registry.skeletons.insert(nick::skeleton(nick), uid.clone());
+        let user = registry.users.entry(uid).or_insert_with(|| User {
+            info: info.clone(),
+            nick: key(nick),
+            connections: HashMap::new(),
+            lags: HashMap::new(),
+        });
+        user.connections.insert(id, tx);
+        true
+    }
+
+    /// Moves `uid` over to `new`. Returns `false` if someone else has it.
+    pub fn rename(&self, uid: &Uid, new: &str) -> bool {
+        let mut registry = lock(&self.registry);
+        let registry = &mut *registry;
+        if registry.nicks.get(&key(new)).is_some_and(|x| x != uid) {
+            return false;
+        }
+        if let Some(user) = registry.users.get_mut(uid) {
+            registry.nicks.remove(&user.nick);
+            registry.skeletons.remove(&nick::skeleton(&user.nick));
+            user.nick = key(new);
+            registry.nicks.insert(user.nick.clone(), uid.clone());
+            registry.skeletons.insert(nick::skeleton(new), uid.clone());
+        }
+        true
+    }
+
+    /// Moves `uid` off their nick onto a free `Guest#####` one, and tells each of their connections with a NICK.
+    /// Returns their old nick and the new one.
+    pub fn rename_guest(&self, uid: &Uid) -> Option<(String, String)> {
+        let mut registry = lock(&self.registry);
+        let registry = &mut *registry;
+        let guest = loop {
+            let guest = nick::guest();
+            if !registry.nicks.contains_key(&key(&guest)) {
+                break guest;
+            }
+        };
+        let user = registry.users.get_mut(uid)?;
+        registry.nicks.remove(&user.nick);
+        registry.skeletons.remove(&nick::skeleton(&user.nick));
+        user.nick = key(&guest);
+        registry.nicks.insert(user.nick.clone(), uid.clone());
+        registry
+            .skeletons
+            .insert(nick::skeleton(&guest), uid.clone());
+
+        let mut info = lock_info(&user.info);
+        let source = info.to_canonical();
+        let old = std::mem::replace(&mut info.nickname, guest.clone());
+        let message = Message {
+            tags: None,
+            source: Some(source),
+            command: Command::NICK(guest.clone()),
+            side: Side::Server,
+        };
+        for tx in user.connections.values() {
+            let _ = tx.try_send(message.clone());
+        }
+        Some((old, guest))
+    }
+
+    /// Takes connection `id` away from `uid`. The user goes with their last connection unless they're `always_on`.
+    /// Returns `true` if the user went.
+    pub fn remove(&self, uid: &Uid, id: usize, always_on: bool) -> bool {
+        let mut registry = lock(&self.registry);
+        let user = match registry.users.get_mut(uid) {
+            Some(user) => user,
+            None => return false,
+        };
+        user.connections.remove(&id);
+        user.lags.remove(&id);
+        if !user.connections.is_empty() || always_on {
+            return false;
+        }
+        let nick = user.nick.clone();
+        registry.users.remove(uid);
+        registry.nicks.remove(&nick);
+        registry.skeletons.remove(&nick::skeleton(&nick));
+        true
+    }
+
+    /// Returns `true` if someone is using `nick`.
+    #[cfg_attr(not(feature = "redis"), allow(dead_code))]
+    pub fn contains(&self, nick: &str) -> bool {
+        lock(&self.registry).nicks.contains_key(&key(nick))
+    }
+
+    /// Returns `true` if someone other than `uid` is using a nick that looks like `nick`.
+    pub fn lookalike(&self, nick: &str, uid: &Uid) -> bool {
+        let registry = lock(&self.registry);
+        registry
+            .skeletons
+            .get(&nick::skeleton(nick))
+            .is_some_and(|x| x != uid)
+    }
+
+    /// The UID of whoever is using `nick`.
+    pub fn uid(&self, nick: &str) -> Option<Uid> {
+        lock(&self.registry).nicks.get(&key(nick)).cloned()
+    }
+
+    /// A snapshot of whoever is using `nick`.
+    pub fn info(&self, nick: &str) -> Option<ClientInfo> {
+        let registry = lock(&self.registry);
+        let user = registry.users.get(registry.nicks.get(&key(nick))?)?;
+        let info = lock_info(&user.info).clone();
+        Some(info)
+    }
+
+    /// A snapshot of the user with `uid`.
+    pub fn info_by_uid(&self, uid: &Uid) -> Option<ClientInfo> {
+        let registry = lock(&self.registry);
+        let info = lock_info(&registry.users.get(uid)?.info).clone();
+        Some(info)
+    }
+
+    /// Changes the host of whoever is using `nick`, returning a snapshot of them from before.
+    pub fn set_host(&self, nick: &str, host: &str) -> Option<ClientInfo> {
+        let registry = lock(&self.registry);
+        let user = registry.users.get(registry.nicks.get(&key(nick))?)?;
+        let mut info = lock_info(&user.info);
+        let old = info.clone();
+        info.host = host.to_string();
+        Some(old)
+    }
+
+    /// Hands `message` to every connection of every oper. Returns how many opers that was.
+    pub fn send_opers(&self, message: Message) -> usize {
+        let registry = lock(&self.registry);
+        let opers: Vec<_> = registry
+            .users
+            .values()
+            .filter(|x| lock_info(&x.info).oper.is_some())
+            .collect();
+        for tx in opers.iter().flat_map(|x| x.connections.values()) {
+            let _ = tx.try_send(message.clone());
+        }
+        opers.len()
+    }
+
+    /// Snapshots of every oper.
+    pub fn opers(&self) -> Vec<ClientInfo> {
+        let registry = lock(&self.registry);
+        registry
+            .users
+            .values()
+            .map(|x| lock_info(&x.info).clone())
+            .filter(|x| x.oper.is_some())
+            .collect()
+    }
+
+    /// How many users, opers and connections there are.
+    pub fn counts(&self) -> Counts {
+        let registry = lock(&self.registry);
+        let mut counts = Counts {
+            users: registry.users.len(),
+            ..Default::default()
+        };
+        for user in registry.users.values() {
+            if lock_info(&user.info).oper.is_some() {
+                counts.opers += 1;
+            }
+            counts.connections += user.connections.len();
+        }
+        counts
+    }
+
+    /// Notes how long connection `id` of `uid` took to answer a PING.
+    pub fn set_lag(&self, uid: &Uid, id: usize, lag: Duration) {
+        if let Some(user) = lock(&self.registry).users.get_mut(uid) {
+            user.lags.insert(id, lag);
+        }
+    }
+
+    /// Every connection of the user with `nick`, or of everyone if there's no nick, by connection id.
+    pub fn trace(&self, nick: Option<&str>) -> Vec<Traced> {
+        let registry = lock(&self.registry);
+        let mut traced: Vec<Traced> = registry
+            .users
+            .iter()
+            .filter(|(uid, _)| nick.is_none_or(|x| registry.nicks.get(&key(x)) == Some(*uid)))
+            .flat_map(|(_, user)| {
+                let info = lock_info(&user.info).clone();
+                user.connections.iter().map(move |(id, tx)| Traced {
+                    id: *id,
+                    info: info.clone(),
+                    sendq: tx.max_capacity() - tx.capacity(),
+                    lag: user.lags.get(id).copied(),
+                })
+            })
+            .collect();
+        traced.sort_by_key(|x| x.id);
+        traced
+    }
+
+    /// Hands `message` to every connection of the user with `nick`. Returns a snapshot of the user, or `None` if
+    /// nobody is using the nick.
+    pub fn send(&self, nick: &str, message: Message) -> Option<ClientInfo> {
+        let registry = lock(&self.registry);
+        let user = registry.users.get(registry.nicks.get(&key(nick))?)?;
+        for tx in user.connections.values() {
+            // A connection that's this far behind is probably dead anyway
+            let _ = tx.try_send(message.clone());
+        }
+        let info = lock_info(&user.info).clone();
+        Some(info)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::isolate;
+
+    fn user(id: usize) -> SharedInfo {
+        Arc::new(Mutex::new(ClientInfo {
+            uid: Uid::new("001", id),
+            ..Default::default()
+        }))
+    }
+
+    fn privmsg(target: &str) -> Message {
+        Message {
+            tags: None,
+            source: Some("tiger".to_string()),
+            command: Command::PRIVMSG(vec![target.to_string()], "hi".to_string()),
+            side: Side::Server,
+        }
+    }
+
+    #[test]
+    fn uids() {
+        assert_eq!(Uid::new("001", 0).to_string(), "001AAAAAA");
+        assert_eq!(Uid::new("001", 1).to_string(), "001AAAAAB");
+        assert_eq!(Uid::new("001", 36).to_string(), "001AAAABA");
+        assert_eq!(Uid::new("001", 36usize.pow(5)).to_string(), "001BAAAAA");
+    }
+
+    #[test]
+    fn messages_reach_every_connection() {
+        let users = Users::default();
+        let info = user(1);
+        let uid = info.lock().unwrap().uid.clone();
+        let (first_tx, mut first) = mpsc::channel(4);
+        let (second_tx, mut second) = mpsc::channel(4);
+        assert!(users.claim("Cat", 1, &info, first_tx));
+        assert!(users.claim("cat", 2, &info, second_tx));
+
+        assert!(users.send("CAT", privmsg("CAT")).is_some());
+        users.set_lag(&uid, 2, Duration::from_millis(30));
+        let traced = users.trace(Some("cat"));
+        assert_eq!(
+            traced
+                .iter()
+                .map(|x| (x.id, x.sendq, x.lag))
+                .collect::<Vec<_>>(),
+            [(1, 1, None), (2, 1, Some(Duration::from_millis(30)))]
+        );
+        assert_eq!(users.trace(None).len(), 2);
+        assert!(users.trace(Some("dog")).is_empty());
+        assert_eq!(first.try_recv().unwrap(), privmsg("CAT"));
+        assert_eq!(second.try_recv().unwrap(), privmsg("CAT"));
+
+        assert!(users.rename(&uid, "kitty"));
+        assert!(users.send("cat", privmsg("cat")).is_none());
+        assert_eq!(users.uid("KITTY"), Some(uid.clone()));
+        assert!(!users.remove(&uid, 1, false));
+        assert!(users.send("kitty", privmsg("kitty")).is_some());
+        assert!(users.remove(&uid, 2, false));
+        assert!(users.send("kitty", privmsg("kitty")).is_none());
+    }
+
+    #[test]
+    fn only_opers_hear_operwall() {
+        let users = Users::default();
+        let (oper, user) = (user(1), user(2));
+        oper.lock().unwrap().oper = Some(Default::default());
+        let (oper_tx, mut oper_rx) = mpsc::channel(4);
+        let (user_tx, mut user_rx) = mpsc::channel(4);
+        assert!(users.claim("oper", 1, &oper, oper_tx));
+        assert!(users.claim("user", 2, &user, user_tx));
+        assert_eq!(users.send_opers(privmsg("oper")), 1);
+        assert_eq!(users.opers().len(), 1);
+        assert_eq!(
+            users.counts(),
+            Counts {
+                users: 2,
+                opers: 1,
+                connections: 2
+            }
+        );
+        assert_eq!(oper_rx.try_recv().unwrap(), privmsg("oper"));
+        assert!(user_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn always_on_users_stay() {
+        let users = Users::default();
+        let info = user(1);
+        info.lock().unwrap().away = Some("asleep".to_string());
+        let uid = info.lock().unwrap().uid.clone();
+        let (tx, _rx) = mpsc::channel(4);
+        assert!(users.claim("tiger", 1, &info, tx));
+        assert!(!users.remove(&uid, 1, true));
+        let info = users.send("tiger", privmsg("tiger")).unwrap();
+        assert_eq!(info.away.as_deref(), Some("asleep"));
+    }
+
+    #[test]
+    fn nicks_are_claimed_once() {
+        let users = Users::default();
+        let (tiger, cat) = (user(1), user(2));
+        let (tx, _rx) = mpsc::channel(4);
+        assert!(users.claim("tiger", 1, &tiger, tx.clone()));
+        assert!(!users.claim("TIGER", 2, &cat, tx.clone()));
+        assert!(users.claim("cat", 2, &cat, tx));
+        assert!(!users.rename(&cat.lock().unwrap().uid, "Tiger"));
+        assert!(users.rename(&tiger.lock().unwrap().uid, "Tiger"));
+
+        // A Cyrillic і
+        assert!(users.lookalike("t\u{456}ger", &cat.lock().unwrap().uid));
+        assert!(!users.lookalike("t\u{456}ger", &tiger.lock().unwrap().uid));
+        assert!(users.rename(&tiger.lock().unwrap().uid, "lion"));
+        assert!(!users.lookalike("t\u{456}ger", &cat.lock().unwrap().uid));
+    }
+
+    #[tokio::test]
+    async fn panicking_handlers_release_nicks() {
+        let users = Users::default();
+        let info = user(1);
+        let uid = info.lock().unwrap().uid.clone();
+        let (tx, _rx) = mpsc::channel(4);
+        assert!(users.claim("tiger", 1, &info, tx.clone()));
+        // A handler dying partway through, with the registry and its own info still locked
+        let caught = isolate::catching(async {
+            let _registry = lock(&users.registry);
+            let _info = lock_info(&info);
+            panic!("meow");
+        })
+        .await;
+        assert!(caught.is_err() && users.registry.is_poisoned());
+
+        // Cleaning up after it like any other disconnect
+        assert!(users.remove(&uid, 1, false));
+        assert!(users.uid("tiger").is_none());
+        assert!(users.claim("tiger", 2, &user(2), tx));
+    }
+
+    #[test]
+    fn guests() {
+        let users = Users::default();
+        let info = user(1);
+        info.lock().unwrap().nickname = "tiger".to_string();
+        let uid = info.lock().unwrap().uid.clone();
+        let (tx, mut rx) = mpsc::channel(4);
+        assert!(users.claim("tiger", 1, &info, tx));
+        let (old, guest) = users.rename_guest(&uid).unwrap();
+        assert_eq!(old, "tiger");
+        assert!(guest.starts_with("Guest"));
+        assert_eq!(info.lock().unwrap().nickname, guest);
+        assert_eq!(users.uid(&guest), Some(uid));
+        assert!(users.uid("tiger").is_none());
+        assert_eq!(rx.try_recv().unwrap().command, Command::NICK(guest));
+    }
+
+    #[test]
+    fn concurrent_claims_are_unique() {
+        const CLIENTS: usize = 500;
+        let users = Users::default();
+        let barrier = Arc::new(std::sync::Barrier::new(CLIENTS));
+        let threads: Vec<_> = (0..CLIENTS)
+            .map(|id| {
+                let users = users.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    let info = user(id);
+                    let (tx, _rx) = mpsc::channel(1);
+                    barrier.wait();
+                    // Everyone fights over the same five nicks
+                    users.claim(&format!("nick{}", id % 5), id, &info, tx)
+                })
+            })
+            .collect();
+        let claimed = threads
+            .into_iter()
+            .map(|x| x.join().unwrap())
+            .filter(|x| *x)
+            .count();
+        assert_eq!(claimed, 5);
+    }
+}
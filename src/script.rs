@@ -0,0 +1,171 @@
+//! Lua scripting hooks, so operators can automate and moderate without recompiling the server.
+//!
+//! Scripts are plain Lua files listed in the config, and can define any of these globals:
+//! - `on_connect(ip)`
+//! - `on_nick(old, new)`
+//! - `on_join(nick, channel)`
+//! - `on_message(source, target, text)`, which runs before delivery and can return a string to replace the text
+//!
+//! Returning `false` from any hook blocks whatever was about to happen, anything else lets it through.
+//! REHASH reloads every script from disk.
+//!
+//! This all needs the `lua` feature, without it scripts in the config are ignored.
+
+use crate::log;
+use crate::Result;
+use std::{path::PathBuf, sync::Arc};
+
+#[cfg(feature = "lua")]
+use crate::server::lock;
+#[cfg(feature = "lua")]
+use std::sync::Mutex;
+
+/// What the scripts decided to do about something
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Block,
+    /// Only from `on_message`, deliver this text instead
+    #[cfg_attr(not(any(feature = "lua", feature = "wasm")), allow(dead_code))]
+    Rewrite(String),
+}
+
+/// Shared handle to the loaded scripts, cheap to clone into each connection.
+#[derive(Debug, Clone, Default)]
+pub struct Scripts {
+    paths: Arc<Vec<PathBuf>>,
+    #[cfg(feature = "lua")]
+    lua: Arc<Mutex<Option<mlua::Lua>>>,
+}
+
+impl Scripts {
+    /// Loads every script in `paths`.
+    pub fn load(paths: Vec<PathBuf>) -> Result<Self> {
+        let scripts = Self {
+            paths: Arc::new(paths),
+            #[cfg(feature = "lua")]
+            lua: Arc::default(),
+        };
+        scripts.reload()?;
+        Ok(scripts)
+    }
+
+    /// Throws away the current Lua state and loads every script from scratch.
+    /// If any script fails to load we keep running the old ones.
+    pub fn reload(&self) -> Result<()> {
+        #[cfg(feature = "lua")]
+        {
+            let lua = mlua::Lua::new();
+            for path in self.paths.iter() {
+                let source = std::fs::read_to_string(path)?;
+                lua.load(&source)
+                    .set_name(path.display().to_string())
+                    .exec()?;
+            }
+            *lock(&self.lua) = Some(lua);
+        }
+        #[cfg(not(feature = "lua"))]
+        if !self.paths.is_empty() {
+            log::error!("Built without the lua feature, ignoring scripts");
+        }
+        Ok(())
+    }
+
+    pub fn on_connect(&self, ip: &str) -> Verdict {
+        self.call("on_connect", &[ip])
+    }
+
+    pub fn on_nick(&self, old: &str, new: &str) -> Verdict {
+        self.call("on_nick", &[old, new])
+    }
+
+    pub fn on_join(&self, nick: &str, channel: &str) -> Verdict {
+        self.call("on_join", &[nick, channel])
+    }
+
+    pub fn on_message(&self, source: &str, target: &str, text: &str) -> Verdict {
+        self.call("on_message", &[source, target, text])
+    }
+
+    /// Runs `hook` if a script defined it. A hook that errors gets logged and lets things through, a buggy
+    /// script shouldn't be able to lock everyone out.
+    #[cfg(feature = "lua")]
+    fn call(&self, hook: &str, args: &[&str]) -> Verdict {
+        let lua = lock(&self.lua);
+        let lua = match lua.as_ref() {
+            Some(lua) => lua,
+            None => return Verdict::Allow,
+        };
+        let function = match lua.globals().get::<_, Option<mlua::Function>>(hook) {
+            Ok(Some(function)) => function,
+            _ => return Verdict::Allow,
+        };
+        let args = mlua::Variadic::from_iter(args.iter().map(|x| x.to_string()));
+        let verdict = match function.call::<_, mlua::Value>(args) {
+            Ok(mlua::Value::Boolean(false)) => Verdict::Block,
+            Ok(mlua::Value::String(text)) => match text.to_str() {
+                Ok(text) => Verdict::Rewrite(text.to_string()),
+                Err(_) => Verdict::Allow,
+            },
+            Ok(_) => Verdict::Allow,
+            Err(e) => {
+                log::error!("Script hook {} failed: {}", hook, e);
+                Verdict::Allow
+            }
+        };
+        verdict
+    }
+
+    #[cfg(not(feature = "lua"))]
+    fn call(&self, _hook: &str, _args: &[&str]) -> Verdict {
+        Verdict::Allow
+    }
+}
+
+#[cfg(all(test, feature = "lua"))]
+mod test {
+    use super::*;
+
+    fn scripts(source: &str) -> Scripts {
+        let path = std::env::temp_dir().join(format!(
+            "rust_irc_script_{}_{:?}.lua",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        std::fs::write(&path, source).unwrap();
+        let scripts = Scripts::load(vec![path.clone()]).unwrap();
+        std::fs::remove_file(path).unwrap();
+        scripts
+    }
+
+    #[test]
+    fn hooks_block_and_rewrite() {
+        let scripts = scripts(
+            r##"
+            function on_join(nick, channel) return channel ~= "#secret" end
+            function on_message(source, target, text)
+                if text:find("spam") then return false end
+                return text:gsub("heck", "h*ck")
+            end
+            "##,
+        );
+        assert_eq!(scripts.on_join("tiger", "#meow"), Verdict::Allow);
+        assert_eq!(scripts.on_join("tiger", "#secret"), Verdict::Block);
+        assert_eq!(
+            scripts.on_message("tiger", "#meow", "buy spam"),
+            Verdict::Block
+        );
+        assert_eq!(
+            scripts.on_message("tiger", "#meow", "what the heck"),
+            Verdict::Rewrite("what the h*ck".to_string())
+        );
+        // Nothing defined, nothing blocked
+        assert_eq!(scripts.on_nick("tiger", "tigercat"), Verdict::Allow);
+    }
+
+    #[test]
+    fn broken_hooks_allow() {
+        let scripts = scripts("function on_connect(ip) error('oops') end");
+        assert_eq!(scripts.on_connect("127.0.0.1"), Verdict::Allow);
+    }
+}
@@ -1,25 +1,159 @@
 use crate::{
+    accept,
+    account::AccountStore,
+    alias, audit,
+    auth::{self, AuthProvider},
+    away::AwayNotified,
+    ban::{self, Ban, BanKind, Bans, Subject},
+    bot::{Bots, ReplyBot},
+    bridge::{self, Bridge, ProcessBridge},
+    burst::{self, Part},
+    capability,
+    catalog::Catalog,
+    chanlog,
+    channel::{self, Channels},
+    cluster::{self, Answers, Cluster},
+    config::{Config, Privilege},
+    defcon::{self, Defcon},
+    event::{ConnectionSummary, Event, EventBus},
+    fakelag::Fakelag,
+    filter::{FilterAction, Filters, Hit},
+    geoip::GeoIp,
+    history::{self, History},
+    http::{self, ApiState},
+    identity::{Identity, LiveIdentity},
+    irc_connection::{Disconnected, Line},
+    isolate,
+    listeners::Listeners,
+    lockout::Lockouts,
+    log,
     message_impl::Code,
-    message_parse::{Command, Message, Side},
-    IrcConnection, Result, Shutdown,
+    message_parse::{source_nick, Command, Message, Side},
+    metrics::{self, Metrics},
+    nick,
+    plugin::{Plugins, Said},
+    registry::{Uid, Users},
+    script::{Scripts, Verdict},
+    session::Sessions,
+    snomask::{self, Snomask},
+    tags::{TagLimiter, Wanted},
+    tls, webhook, IrcConnection, Result, Shutdown,
 };
-use std::future::Future;
-use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::*,
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::{
+    collections::HashSet,
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
 };
+use tokio::{net::TcpListener, sync::*};
+
+/// Runs a message past the Lua scripts and then the plugins. Returns the text to deliver, or `None` if one of them
+/// blocked it.
+fn message_hooks(
+    scripts: &Scripts,
+    plugins: &Plugins,
+    source: &str,
+    target: &str,
+    text: &str,
+) -> Option<String> {
+    let text = match scripts.on_message(source, target, text) {
+        Verdict::Allow => text.to_string(),
+        Verdict::Block => return None,
+        Verdict::Rewrite(text) => text,
+    };
+    match plugins.filter_message(source, target, &text) {
+        Verdict::Allow => Some(text),
+        Verdict::Block => None,
+        Verdict::Rewrite(text) => Some(text),
+    }
+}
+
+/// How long K-lines set by spam filters last, in seconds
+const FILTER_KLINE_DURATION: i64 = 24 * 60 * 60;
+/// Away message for users marked away by `auto_away`
+const AUTO_AWAY: &str = "Auto-away: idle";
+/// What's sent on connect instead of the MOTD with `short_motd`
+const SHORT_MOTD: &str =
+    "This server's message of the day is only sent when asked for, use /MOTD to read it";
 
 /// Starts the IRC Server and waits for it to complete.
+/// `bridges` are linked in alongside any bridge processes from the config.
 /// `shutdown` allows you to pass in a future that will allow early termination with clean shutdowns for each connection
-pub async fn run(listener: TcpListener, shutdown: impl Future) {
+pub async fn run(
+    listener: TcpListener,
+    config: Config,
+    bridges: Vec<Box<dyn Bridge>>,
+    events: EventBus,
+    virtual_rx: mpsc::Receiver<IrcConnection>,
+    shutdown: impl Future,
+) {
     let (notify_shutdown, _) = broadcast::channel(1);
     let (shutdown_complete_tx, shutdown_complete_rx) = mpsc::channel(1);
     let (server_tx, server_rx) = mpsc::channel(20);
     let (client_tx, _) = broadcast::channel(20);
+    let (accepted_tx, accepted_rx) = mpsc::channel(20);
+
+    let scripts = Scripts::load(config.scripts.clone()).unwrap_or_else(|e| {
+        log::error!("Failed to load scripts: {}", e);
+        Scripts::default()
+    });
+    let bans = Bans::load(config.bans.clone()).unwrap_or_else(|e| {
+        log::error!("Failed to load bans: {}", e);
+        Bans::default()
+    });
+    let filters = Filters::from_config(&config.filters).unwrap_or_else(|e| {
+        log::error!("Failed to load filters: {}", e);
+        Filters::default()
+    });
+    let plugins = Plugins::load(config.plugins.clone()).unwrap_or_else(|e| {
+        log::error!("Failed to load plugins: {}", e);
+        Plugins::default()
+    });
+    let geoip = GeoIp::load(&config.geoip).unwrap_or_else(|e| {
+        log::error!("Failed to load GeoIP databases: {}", e);
+        GeoIp::default()
+    });
+    let catalog = Catalog::load(&config.language).unwrap_or_else(|e| {
+        log::error!("Failed to load language catalogs: {}", e);
+        Catalog::default()
+    });
+
+    let accounts = AccountStore::from_config(&config);
+    let connection_slots = config.max_connections.map(|x| Arc::new(Semaphore::new(x)));
+    let metrics = Metrics::default();
 
     // Initialize the listener state
     let mut server = Server {
         listener,
+        scripts,
+        plugins,
+        bans,
+        filters,
+        geoip,
+        catalog,
+        identity: LiveIdentity::new(Identity::from_config(&config)),
+        auth: auth::from_config(&config.auth, &accounts),
+        accounts,
+        sessions: Sessions::default(),
+        lockouts: Lockouts::default(),
+        nickserv_lockouts: Lockouts::default(),
+        defcon: Defcon::default(),
+        history: History::default(),
+        users: Users::default(),
+        channels: Channels::default(),
+        cluster: Cluster::default(),
+        listeners: Listeners::new(accepted_tx.clone(), metrics.clone(), events.clone()),
+        accepted_tx,
+        accepted_rx,
+        virtual_rx,
+        events,
+        metrics,
+        config: Arc::new(config),
+        started: Utc::now(),
+        // 0 is what plugins talk as
+        next_id: 1,
+        connection_slots,
         client_tx,
         server_tx,
         server_rx,
@@ -27,16 +161,23 @@ pub async fn run(listener: TcpListener, shutdown: impl Future) {
         shutdown_complete_tx,
         shutdown_complete_rx,
     };
+    server.start_bridges(bridges);
+    server.start_webhooks();
+    server.start_snomasks();
+    server.start_metrics();
+    server.start_audit();
+    server.start_channel_log();
+    server.start_history();
 
     // select! runs both tasks at the same time
     tokio::select! {
         res = server.run() => {
             if let Err(err) = res {
-                println!("Failed to accept: {}", err);
+                log::info!("Failed to accept: {}", err);
             }
         }
         _ = shutdown => {
-            println!("Shutting down");
+            log::info!("Shutting down");
         }
     }
 
@@ -44,35 +185,158 @@ pub async fn run(listener: TcpListener, shutdown: impl Future) {
         mut shutdown_complete_rx,
         shutdown_complete_tx,
         notify_shutdown,
+        server_rx,
         ..
     } = server;
 
     drop(notify_shutdown);
     drop(shutdown_complete_tx);
+    // Nobody's listening anymore, so connections on their way out mustn't wait on us
+    drop(server_rx);
 
     let _ = shutdown_complete_rx.recv().await;
 }
 
+/// A message on its way to whoever it's for, worked out once when it's sent so each connection only has to look
+/// itself up.
 #[derive(Debug, Clone)]
-enum ServerToClientPacket {
-    PrivMessage {
-        channels: Vec<String>,
-        message: Message,
-    },
-    Join {
-        message: Message,
+pub(crate) struct Route {
+    /// Id of the connection that sent it, which doesn't get it back. 0 if it's not from a connection here
+    pub origin: usize,
+    /// Everyone it's for, with channels already turned into their members
+    pub to: Arc<HashSet<Uid>>,
+    /// Connections without this cap don't get it
+    pub cap: Option<&'static str>,
+    /// Connections with this cap don't get it
+    pub lacking: Option<&'static str>,
+    pub message: Message,
+    /// `message` ready to write, `None` if connections have to apply it themselves
+    pub line: Option<Line>,
+}
+
+impl Route {
+    pub fn new(origin: usize, to: HashSet<Uid>, message: Message) -> Self {
+        Self {
+            origin,
+            to: Arc::new(to),
+            cap: None,
+            lacking: None,
+            line: Some(Line::new(&message)),
+            message,
+        }
+    }
+
+    /// For messages that do more than get written out, like KILL
+    pub fn applied(mut self) -> Self {
+        self.line = None;
+        self
+    }
+
+    /// Only for connections that asked for `cap`
+    pub fn needing(mut self, cap: &'static str) -> Self {
+        self.cap = Some(cap);
+        self
+    }
+
+    /// Only for connections that didn't ask for `cap`, the ones that need it done some older way
+    pub fn lacking(mut self, cap: &'static str) -> Self {
+        self.lacking = Some(cap);
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum ServerToClientPacket {
+    /// Channel messages, joins, KILLs and anything else for a set of users
+    Route(Route),
+    /// Anyone this covers has to go
+    Ban(Ban),
+    /// For every oper with `mask` set
+    ServerNotice { mask: Snomask, text: String },
+    /// One of `account`'s connections moved its read marker, every connection logged into it needs to hear
+    ReadMarker {
+        account: String,
+        target: String,
+        timestamp: String,
     },
 }
 
 #[derive(Debug, Clone)]
-enum ClientToServerPacket {
-    BlindBroadcast(Message),
+pub(crate) enum ClientToServerPacket {
+    /// `origin` is the id of the connection that sent it, so it doesn't get its own message back
+    BlindBroadcast { origin: usize, message: Message },
+    /// Passed straight back out to every connection as a ServerToClientPacket::ReadMarker
+    ReadMarker {
+        account: String,
+        target: String,
+        timestamp: String,
+    },
+    /// Already worked out by the connection, passed straight back out as a ServerToClientPacket::Route
+    Route(Route),
+    /// An oper used DIE
+    Die,
+    /// An oper used REHASH, these are the `listeners` in the config now
+    Listen(Vec<String>),
+    /// An oper set a ban, passed straight back out to every connection as a ServerToClientPacket::Ban
+    Ban(Ban),
 }
 
 #[derive(Debug)]
 struct Server {
     /// This is the TcpListener which new clients connect to, forming a TcpStream that is then tokio-spawned off
     listener: TcpListener,
+    /// Loaded once at startup and shared with every client
+    config: Arc<Config>,
+    /// Lua hooks
+    scripts: Scripts,
+    /// WASM plugins, ticked from the main loop
+    plugins: Plugins,
+    /// K-lines and D-lines, expired from the main loop
+    bans: Bans,
+    /// Spam filters, checked by each connection before it sends anything on
+    filters: Filters,
+    /// Country and ASN lookups, for opers
+    geoip: GeoIp,
+    /// Numeric texts in every language we have
+    catalog: Catalog,
+    /// Server name and such, reread on REHASH
+    identity: LiveIdentity,
+    /// Accounts clients can log into
+    accounts: AccountStore,
+    /// Checks PASS logins, against `accounts` unless configured otherwise
+    auth: Arc<dyn AuthProvider>,
+    /// Logged in users, which can outlive their connections in bouncer mode
+    sessions: Sessions,
+    /// Failed OPER attempts by IP
+    lockouts: Lockouts,
+    /// Failed NickServ passwords by IP
+    nickserv_lockouts: Lockouts,
+    /// The emergency protection level
+    defcon: Defcon,
+    /// Messages kept for users to export
+    history: History,
+    /// Registered users by nick
+    users: Users,
+    channels: Channels,
+    /// The other processes sharing this server, if any
+    cluster: Cluster,
+    /// Clients from every listener but `listener` come in here, TLS ones once they've finished their handshake
+    accepted_tx: mpsc::Sender<IrcConnection>,
+    accepted_rx: mpsc::Receiver<IrcConnection>,
+    /// The extra plaintext listeners, rebound on REHASH
+    listeners: Listeners,
+    /// In-process clients, see `embed`
+    virtual_rx: mpsc::Receiver<IrcConnection>,
+    /// Core handlers publish what happens here, for everything else to react to
+    events: EventBus,
+    /// Command timings and channel activity, for the HTTP API
+    metrics: Metrics,
+    /// For RPL_CREATED and uptime
+    started: DateTime<Utc>,
+    /// Handed out to each new connection so we can tell them apart
+    next_id: usize,
+    /// One permit per connection allowed, if there's a `max_connections`
+    connection_slots: Option<Arc<Semaphore>>,
     /// This is how we tell clients that we
     client_tx: broadcast::Sender<ServerToClientPacket>,
     // Server messages
@@ -92,33 +356,326 @@ impl Server {
     /// This is the main loop for the Server, it listens eternally for new clients and simultaneously listens for
     /// old clients that want to talk to it about something
     async fn run(&mut self) -> Result<()> {
+        self.start_cluster().await?;
+        self.start_http().await?;
+        self.start_tls().await?;
+        self.listeners
+            .update(&self.config.listeners, &self.notify_shutdown)
+            .await;
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+        let mut backoff = accept::Backoff::default();
         loop {
             tokio::select! {
                 // New client
-                socket = self.listener.accept() => {
-                    self.accept_client(socket?.0).await?;
+                socket = accept::next(&self.listener, &self.metrics, &mut backoff) => {
+                    self.accept_client(IrcConnection::new(socket)).await?;
+                }
+                // New client from one of the other listeners, TLS handshake and all
+                Some(connection) = self.accepted_rx.recv() => {
+                    self.accept_client(connection).await?;
+                }
+                // New virtual client, no socket involved
+                Some(connection) = self.virtual_rx.recv() => {
+                    self.accept_client(connection).await?;
                 }
                 // Established client asking us for something
                 client_message = self.server_rx.recv() => {
-                    if let Some(x) = client_message {
+                    if let Some(ClientToServerPacket::Die) = client_message {
+                        log::info!("Told to die, shutting down");
+                        return Ok(());
+                    } else if let Some(x) = client_message {
                         self.handle_client_packet(x).await?;
                     } else {
                         // Something has gone critically wrong to get to this point
                         return Err(Box::new(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "server_tx broke")));
                     }
                 }
+                _ = tick.tick() => {
+                    for said in self.plugins.tick() {
+                        self.plugin_say(said);
+                    }
+                    for ban in self.bans.expire() {
+                        log::info!("{} for {} expired", ban.kind.name(), ban.mask);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Spawns the HTTP API off if it's configured. It gets a connection id of its own so that messages posted
+    /// through it look like they came from any other client.
+    async fn start_http(&mut self) -> Result<()> {
+        let config = match &self.config.http {
+            Some(config) => config.clone(),
+            None => return Ok(()),
+        };
+        let listener = TcpListener::bind(&config.listen).await?;
+        log::info!("HTTP API listening on {}", listener.local_addr()?);
+
+        let state = ApiState {
+            token: config.token,
+            bot: config.bot,
+            origin: self.next_id,
+            server_tx: self.server_tx.clone(),
+            started: self.started,
+            metrics: self.metrics.clone(),
+            channels: self.channels.clone(),
+            auth: self.auth.clone(),
+            history: self.history.clone(),
+            events: self.events.clone(),
+            channel_log: self.config.channel_log.clone(),
+            chantypes: self.config.chantypes.clone(),
+            channel_len: self.config.limits.channel,
+        };
+        self.next_id += 1;
+        let shutdown = Shutdown::new(self.notify_shutdown.subscribe());
+        let shutdown_complete = self.shutdown_complete_tx.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = http::serve(listener, state, shutdown).await {
+                log::error!("HTTP API failed: {}", e);
             }
+            drop(shutdown_complete);
+        });
+
+        Ok(())
+    }
+
+    /// Links in every bridge, compiled in or configured. Each one gets a connection id so it doesn't hear itself.
+    /// Configured bots get a bridge of their own.
+    fn start_bridges(&mut self, mut bridges: Vec<Box<dyn Bridge>>) {
+        for config in &self.config.bridges {
+            bridges.push(Box::new(ProcessBridge {
+                name: config.name.clone(),
+                command: config.command.clone(),
+                args: config.args.clone(),
+            }));
+        }
+        let bots = self.config.bots.iter().fold(Bots::new(), |bots, config| {
+            bots.register(Box::new(ReplyBot {
+                nick: config.nick.clone(),
+                channels: config.channels.clone(),
+                replies: config.replies.clone(),
+            }))
+        });
+        if !bots.is_empty() {
+            bridges.push(Box::new(bots));
+        }
+
+        for bridge in bridges {
+            let name = bridge.name().to_string();
+            let origin = self.next_id;
+            self.next_id += 1;
+            let server_tx = self.server_tx.clone();
+            let client_rx = self.client_tx.subscribe();
+            let shutdown = Shutdown::new(self.notify_shutdown.subscribe());
+            let shutdown_complete = self.shutdown_complete_tx.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = bridge::link(bridge, origin, server_tx, client_rx, shutdown).await {
+                    log::error!("Bridge {} failed: {}", name, e);
+                }
+                log::info!("Bridge {} unlinked.", name);
+                drop(shutdown_complete);
+            });
         }
     }
 
-    /// This accepts a new TcpStream and establishes all the internal structs to control the connection before
+    /// Spawns the webhook watchers off, which listen on the event bus for channel messages and, if there's an
+    /// `account_webhook`, account changes and failed logins.
+    fn start_webhooks(&self) {
+        let events = self.events.subscribe();
+        let sessions = self.sessions.clone();
+        let accounts = self.accounts.clone();
+        let shutdown = Shutdown::new(self.notify_shutdown.subscribe());
+        let shutdown_complete = self.shutdown_complete_tx.clone();
+
+        tokio::spawn(async move {
+            webhook::watch(events, sessions, accounts, shutdown).await;
+            drop(shutdown_complete);
+        });
+
+        if let Some(config) = self.config.account_webhook.clone() {
+            let events = self.events.subscribe();
+            let shutdown = Shutdown::new(self.notify_shutdown.subscribe());
+            let shutdown_complete = self.shutdown_complete_tx.clone();
+            tokio::spawn(async move {
+                webhook::watch_accounts(events, config, shutdown).await;
+                drop(shutdown_complete);
+            });
+        }
+    }
+
+    /// Spawns the task that writes the audit log, if there is one.
+    fn start_audit(&self) {
+        let path = match &self.config.audit_log {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        let events = self.events.subscribe();
+        let shutdown = Shutdown::new(self.notify_shutdown.subscribe());
+        let shutdown_complete = self.shutdown_complete_tx.clone();
+
+        tokio::spawn(async move {
+            audit::watch(events, &path, shutdown).await;
+            drop(shutdown_complete);
+        });
+    }
+
+    /// Spawns the task that writes channel logs, if there are any.
+    fn start_channel_log(&self) {
+        let config = match &self.config.channel_log {
+            Some(config) => config.clone(),
+            None => return,
+        };
+        let events = self.events.subscribe();
+        let channels = self.channels.clone();
+        let shutdown = Shutdown::new(self.notify_shutdown.subscribe());
+        let shutdown_complete = self.shutdown_complete_tx.clone();
+
+        tokio::spawn(async move {
+            chanlog::watch(events, channels, config, shutdown).await;
+            drop(shutdown_complete);
+        });
+    }
+
+    /// Spawns the task that prunes old messages from `history`, if it's on.
+    fn start_history(&self) {
+        if !self.config.history.enabled {
+            return;
+        }
+        let history = self.history.clone();
+        let config = self.config.history.clone();
+        let shutdown = Shutdown::new(self.notify_shutdown.subscribe());
+        let shutdown_complete = self.shutdown_complete_tx.clone();
+
+        tokio::spawn(async move {
+            history::prune(history, config, shutdown).await;
+            drop(shutdown_complete);
+        });
+    }
+
+    /// Spawns the task that counts channel messages for `/metrics`.
+    fn start_metrics(&self) {
+        let events = self.events.subscribe();
+        let metrics = self.metrics.clone();
+        let shutdown = Shutdown::new(self.notify_shutdown.subscribe());
+        let shutdown_complete = self.shutdown_complete_tx.clone();
+
+        tokio::spawn(async move {
+            metrics::watch(events, metrics, shutdown).await;
+            drop(shutdown_complete);
+        });
+    }
+
+    /// Spawns the task that turns events into server notices for opers.
+    fn start_snomasks(&self) {
+        let events = self.events.subscribe();
+        let client_tx = self.client_tx.clone();
+        let geoip = self.geoip.clone();
+        let shutdown = Shutdown::new(self.notify_shutdown.subscribe());
+        let shutdown_complete = self.shutdown_complete_tx.clone();
+
+        tokio::spawn(async move {
+            snomask::watch(events, client_tx, geoip, shutdown).await;
+            drop(shutdown_complete);
+        });
+    }
+
+    /// Joins the cluster if one is configured. Has to happen before any clients connect so they all get the handle.
+    async fn start_cluster(&mut self) -> Result<()> {
+        let config = match &self.config.cluster {
+            Some(config) => config.clone(),
+            None => return Ok(()),
+        };
+        let local = cluster::Local {
+            users: self.users.clone(),
+            channels: self.channels.clone(),
+            client_tx: self.client_tx.clone(),
+            events: self.events.clone(),
+            answers: Answers {
+                identity: self.identity.clone(),
+                catalog: self.catalog.clone(),
+                motd: self.config.motd.clone(),
+                privacy: self.config.privacy.clone(),
+                server_addr: self.listener.local_addr()?,
+            },
+        };
+        self.cluster = Cluster::start(
+            &config,
+            local,
+            Shutdown::new(self.notify_shutdown.subscribe()),
+            self.shutdown_complete_tx.clone(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Spawns the TLS listener off if it's configured.
+    async fn start_tls(&mut self) -> Result<()> {
+        let config = match &self.config.tls {
+            Some(config) => config.clone(),
+            None => return Ok(()),
+        };
+        let acceptor = tls::acceptor(&config)?;
+        let listener = TcpListener::bind(&config.listen).await?;
+        log::info!("TLS listening on {}", listener.local_addr()?);
+
+        let tx = self.accepted_tx.clone();
+        let metrics = self.metrics.clone();
+        let shutdown = Shutdown::new(self.notify_shutdown.subscribe());
+        let shutdown_complete = self.shutdown_complete_tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = tls::serve(listener, acceptor, config, tx, metrics, shutdown).await {
+                log::error!("TLS listener failed: {}", e);
+            }
+            drop(shutdown_complete);
+        });
+
+        Ok(())
+    }
+
+    /// This accepts a new connection and establishes all the internal structs to control it before
     /// tokio-spawning it off to handle itself (we just talk to it with channels)
-    async fn accept_client(&mut self, socket: TcpStream) -> Result<()> {
-        let client_ip_for_logging = socket.peer_addr().unwrap().ip();
+    async fn accept_client(&mut self, mut connection: IrcConnection) -> Result<()> {
+        connection.identity = self.identity.clone();
+        connection.catalog = self.catalog.clone();
+        let client_ip_for_logging = connection.client_addr.ip();
+        if let Some(ban) = self
+            .bans
+            .find(&Subject::connecting(client_ip_for_logging.to_string()))
+        {
+            let _ = connection
+                .write_error(format!("{}: {}", ban.kind.name(), ban.reason))
+                .await;
+            return Ok(());
+        }
+        if self.scripts.on_connect(&client_ip_for_logging.to_string()) == Verdict::Block {
+            let _ = connection.write_error("Connection refused").await;
+            return Ok(());
+        }
+        // In-process clients don't take up a socket, so they don't count
+        let slot = match &self.connection_slots {
+            Some(slots) if !connection.in_process => match slots.clone().try_acquire_owned() {
+                Ok(slot) => Some(slot),
+                Err(_) => {
+                    log::info!("Turned away {}, the server is full", client_ip_for_logging);
+                    let _ = connection
+                        .write_error("Server is full, try again later")
+                        .await;
+                    return Ok(());
+                }
+            },
+            _ => None,
+        };
+        let id = self.next_id;
+        self.next_id += 1;
+        let (direct_tx, direct_rx) = mpsc::channel(64);
 
         let mut client_connection = ClientConnection {
-            // Wrapper for the IRC protocol around the tcpstream
-            connection: IrcConnection::new(socket),
+            id,
+            // Wrapper for the IRC protocol around the stream
+            connection,
             // It gets to ask us for stuff
             server_tx: self.server_tx.clone(),
             // And we get to ask it for stuff
@@ -129,15 +686,115 @@ impl Server {
             // to finish before we exit the program
             _shutdown_complete: self.shutdown_complete_tx.clone(),
             // Internal information for the connection
-            info: ClientInfo::default(),
+            info: Arc::new(Mutex::new(ClientInfo {
+                uid: Uid::new(&self.config.sid, id),
+                host: client_ip_for_logging.to_string(),
+                ip: client_ip_for_logging.to_string(),
+                ..Default::default()
+            })),
+            password: None,
+            caps: HashSet::new(),
+            cap_negotiating: false,
+            cap_version: capability::DEFAULT_VERSION,
+            registered: false,
+            config: self.config.clone(),
+            started: self.started,
+            scripts: self.scripts.clone(),
+            plugins: self.plugins.clone(),
+            bans: self.bans.clone(),
+            filters: self.filters.clone(),
+            geoip: self.geoip.clone(),
+            accounts: self.accounts.clone(),
+            auth: self.auth.clone(),
+            sessions: self.sessions.clone(),
+            lockouts: self.lockouts.clone(),
+            oper_failures: 0,
+            nickserv_lockouts: self.nickserv_lockouts.clone(),
+            nickserv_failures: 0,
+            defcon: self.defcon.clone(),
+            history: self.history.clone(),
+            last_join: None,
+            last_list: None,
+            nick_deadline: None,
+            guest_deadline: None,
+            users: self.users.clone(),
+            channels: self.channels.clone(),
+            cluster: self.cluster.clone(),
+            direct_tx,
+            direct_rx,
+            events: self.events.clone(),
+            metrics: self.metrics.clone(),
+            quit_reason: None,
+            connected_at: Instant::now(),
+            channels_joined: 0,
+            sasl: None,
+            sasl_account: None,
+            device: String::new(),
+            tags: TagLimiter::default(),
+            fakelag: Fakelag::new(std::time::Instant::now()),
+            held: None,
+            awaiting: None,
+            ping: None,
+            next_ping: Instant::now() + Duration::from_secs(self.config.ping_interval),
         };
 
         // Client can handle itself now
         tokio::spawn(async move {
-            if let Err(e) = client_connection.run().await {
-                eprintln!("ERROR: {}", e);
+            // Given back once we're done here
+            let _slot = slot;
+            match isolate::catching(client_connection.run()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => match e.downcast::<Disconnected>() {
+                    // Not our fault, they just go like they'd quit
+                    Ok(gone) => {
+                        log::info!("Client {} went away: {}", client_ip_for_logging, gone);
+                        client_connection.quit_reason.get_or_insert(gone.0);
+                    }
+                    Err(e) => log::error!("ERROR: {}", e),
+                },
+                // Whatever it was in the middle of is abandoned, but they still get cleaned up after below
+                Err(payload) => {
+                    let info = client_connection.info().clone();
+                    log::error!(
+                        "Handler for {} ({}, {}) panicked: {}",
+                        info.nickname,
+                        info.uid,
+                        client_ip_for_logging,
+                        isolate::message(&*payload)
+                    );
+                    client_connection.quit_reason = Some("Internal error".to_string());
+                    let _ = client_connection
+                        .connection
+                        .write_error("Internal error")
+                        .await;
+                }
+            }
+            let _ = client_connection.connection.flush().await;
+            client_connection.detach().await;
+            let summary = client_connection.summary();
+            let nick = client_connection.info().nickname.clone();
+            let reason = client_connection
+                .quit_reason
+                .clone()
+                .unwrap_or_else(|| "Client Quit".to_string());
+            log::info!(
+                "Client {} disconnected ({}): {}",
+                client_ip_for_logging,
+                reason,
+                summary
+            );
+            client_connection.events.publish(Event::ConnectionClosed {
+                nick: (!nick.is_empty()).then(|| nick.clone()),
+                host: client_ip_for_logging.to_string(),
+                reason,
+                summary,
+            });
+            if client_connection.registered {
+                client_connection.events.publish(Event::UserQuit {
+                    nick,
+                    reason: client_connection.quit_reason.take(),
+                });
             }
-            println!("Client {} disconnected.", client_ip_for_logging);
         });
 
         Ok(())
@@ -146,46 +803,278 @@ impl Server {
     /// This handles all messages that the client threads ask the server to do
     async fn handle_client_packet(&mut self, packet: ClientToServerPacket) -> Result<()> {
         match packet {
-            ClientToServerPacket::BlindBroadcast(broadcast) => match &broadcast.command {
-                Command::PRIVMSG(targets, _) => {
-                    self.client_tx.send(ServerToClientPacket::PrivMessage {
-                        channels: targets.clone(),
-                        message: broadcast,
-                    })?;
+            ClientToServerPacket::BlindBroadcast {
+                origin,
+                mut message,
+            } => match &message.command {
+                Command::PRIVMSG(targets, text) => {
+                    let targets = self.channels.names(targets);
+                    let source = message.source.clone().unwrap_or_default();
+                    let text = match message_hooks(
+                        &self.scripts,
+                        &self.plugins,
+                        &source,
+                        &targets.join(","),
+                        text,
+                    ) {
+                        Some(text) => text,
+                        None => return Ok(()),
+                    };
+                    for channel in &targets {
+                        self.events.publish(Event::ChannelMessage {
+                            source: source.clone(),
+                            channel: channel.clone(),
+                            text: text.clone(),
+                        });
+                        // Only whole channels, not just their ops, that haven't opted out
+                        if channel::split_status(channel).0.is_none()
+                            && self.channels.keeps_history(channel)
+                        {
+                            let seen = self.channels.members(channel).into_iter().filter_map(
+                                |(uid, _)| self.users.info_by_uid(&uid).and_then(|x| x.account),
+                            );
+                            let entry = history::Entry::new(&source, channel, &text, seen);
+                            self.history.record(&self.config.history, entry);
+                        }
+                    }
+                    let to = self.channels.resolve(&targets);
+                    message.command = Command::PRIVMSG(targets, text);
+                    self.cluster.publish(&message);
+                    self.route(Route::new(origin, to, message))?;
+                }
+                Command::JOIN(channels, _) | Command::PART(channels, _) => {
+                    let to = self.channels.resolve(channels);
+                    self.cluster.publish(&message);
+                    self.route(Route::new(origin, to, message))?;
+                }
+                Command::TAGMSG(targets) => {
+                    let targets = self.channels.names(targets);
+                    let to = self.channels.resolve(&targets);
+                    message.command = Command::TAGMSG(targets);
+                    self.cluster.publish(&message);
+                    self.route(Route::new(origin, to, message).needing(capability::MESSAGE_TAGS))?;
+                }
+                // Channel modes and topics, for everyone in the channel
+                Command::MODE(target, _, _) | Command::TOPIC(target, _) => {
+                    let to = self.channels.resolve(std::slice::from_ref(target));
+                    self.route(Route::new(origin, to, message))?;
+                }
+                // Everyone sharing a channel with them who has away-notify or account-notify
+                Command::AWAY(_) | Command::ACCOUNT(_) => {
+                    let nick = source_nick(message.source.as_deref().unwrap_or_default());
+                    let cap = match message.command {
+                        Command::AWAY(_) => capability::AWAY_NOTIFY,
+                        _ => capability::ACCOUNT_NOTIFY,
+                    };
+                    if let Some(info) = self.users.info(nick) {
+                        let to = self.channels.resolve(&info.channels);
+                        self.route(Route::new(origin, to, message).needing(cap))?;
+                    }
+                }
+                // Their other connections, and everyone sharing a channel with them
+                Command::NICK(nick) => {
+                    if let Some(info) = self.users.info(nick) {
+                        let mut to = self.channels.resolve(&info.channels);
+                        to.insert(info.uid);
+                        self.route(Route::new(origin, to, message))?;
+                    }
                 }
-                Command::JOIN(_, _) => {
-                    self.client_tx
-                        .send(ServerToClientPacket::Join { message: broadcast })?;
+                // Whoever is using the nick has to go
+                Command::KILL(nick, _) => {
+                    let to = self.users.uid(nick).into_iter().collect();
+                    self.cluster.publish(&message);
+                    self.route(Route::new(0, to, message).applied())?;
                 }
                 _ => {}
             },
+            ClientToServerPacket::ReadMarker {
+                account,
+                target,
+                timestamp,
+            } => {
+                self.client_tx.send(ServerToClientPacket::ReadMarker {
+                    account,
+                    target,
+                    timestamp,
+                })?;
+            }
+            ClientToServerPacket::Ban(ban) => {
+                self.client_tx.send(ServerToClientPacket::Ban(ban))?;
+            }
+            ClientToServerPacket::Route(route) => self.route(route)?,
+            ClientToServerPacket::Listen(addresses) => {
+                self.listeners
+                    .update(&addresses, &self.notify_shutdown)
+                    .await;
+            }
+            // Handled by the main loop
+            ClientToServerPacket::Die => {}
         }
 
         Ok(())
     }
+
+    /// Sends something a plugin said to its channel, as `<plugin>!plugin@plugins`.
+    fn plugin_say(&self, said: Said) {
+        let message = Message {
+            tags: None,
+            source: Some(format!("{}!plugin@plugins", said.plugin)),
+            command: Command::PRIVMSG(vec![said.channel.clone()], said.text),
+            side: Side::Server,
+        };
+        let to = self.channels.resolve(&[said.channel]);
+        // Plugins can talk to an empty server, that's fine
+        let _ = self.route(Route::new(0, to, message));
+    }
+
+    fn route(&self, route: Route) -> Result<()> {
+        self.client_tx.send(ServerToClientPacket::Route(route))?;
+        Ok(())
+    }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct ClientInfo {
+    /// Stays the same through nick changes, and is shared by every connection in a session
+    pub uid: Uid,
     pub nickname: String,
     pub username: String,
     pub realname: String,
+    /// Where the user is connecting from, as everyone else sees it
+    pub host: String,
+    /// The address they're really connecting from, only for opers with `spy` (and themselves). Same as `host` unless
+    /// that's been changed
+    pub ip: String,
     pub channels: Vec<String>,
+    /// Set once the client has logged in with PASS
+    pub account: Option<String>,
+    /// Set while the user is marked away
+    pub away: Option<String>,
+    /// What the user is allowed to do, set once they've successfully used OPER
+    pub oper: Option<HashSet<Privilege>>,
+    /// Server notices the oper wants, see `snomask`
+    pub snomasks: HashSet<Snomask>,
+    /// User mode +B, the client says it's a bot
+    pub bot: bool,
+    /// User mode +H, an oper keeping it from everyone but other opers in WHOIS and STATS p
+    pub hide_oper: bool,
+    /// When the user last sent a PRIVMSG or NOTICE, or registered if they haven't yet
+    pub last_message: Option<Instant>,
+    /// When they registered, as a unix timestamp
+    pub signon: i64,
+    /// Set if `away` was set by `auto_away` rather than the user
+    pub auto_away: bool,
+    /// What away-notify has said about `away`
+    pub away_notified: AwayNotified,
+}
+
+/// A ClientInfo that can be shared between every connection attached to the same bouncer session
+pub type SharedInfo = Arc<Mutex<ClientInfo>>;
+
+/// Locks state shared between connections. It's only ever poisoned by a connection's handler panicking, which gets
+/// cleaned up after like any other disconnect, so whatever it left behind is still good enough to use.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Locks a SharedInfo, see `lock`.
+pub fn lock_info(info: &SharedInfo) -> MutexGuard<'_, ClientInfo> {
+    lock(info)
 }
 
 impl ClientInfo {
-    /// Converts our struct into the canonical form of the user identifier.
-    pub fn to_canonical<S: AsRef<str>>(&self, server: S) -> String {
-        format!("{}!{}@{}", self.nickname, self.username, server.as_ref())
+    /// Converts our struct into the canonical form of the user identifier, `nick!user@host`.
+    pub fn to_canonical(&self) -> String {
+        format!("{}!{}@{}", self.nickname, self.username, self.host)
+    }
+
+    /// How long they've gone without sending a PRIVMSG or NOTICE.
+    pub fn idle(&self) -> Duration {
+        self.last_message.map_or(Duration::ZERO, |x| {
+            Instant::now().saturating_duration_since(x)
+        })
     }
 }
 
 #[derive(Debug)]
 pub struct ClientConnection {
+    /// Unique per connection, used to keep us from receiving our own broadcasts
+    id: usize,
     /// Wrapper around a TcpStream that gives us easy functions for the IRC protocol
     pub connection: IrcConnection,
-    /// Information about the connection that we need stored somewhere
-    pub info: ClientInfo,
+    /// Information about the user, shared with any other connection attached to the same session
+    pub info: SharedInfo,
+    /// Whatever the client sent with PASS, checked once registration finishes
+    pub password: Option<String>,
+    /// IRCv3 capabilities this connection has asked for
+    pub caps: HashSet<String>,
+    /// Registration waits for CAP END while this is set
+    pub cap_negotiating: bool,
+    /// From `CAP LS <version>`, 302 gets capability values and multiline lists
+    pub cap_version: u32,
+    /// Set once we've sent the welcome burst
+    pub registered: bool,
+    pub config: Arc<Config>,
+    /// When the server started
+    pub started: DateTime<Utc>,
+    pub scripts: Scripts,
+    pub plugins: Plugins,
+    pub bans: Bans,
+    pub filters: Filters,
+    pub geoip: GeoIp,
+    pub accounts: AccountStore,
+    pub auth: Arc<dyn AuthProvider>,
+    sessions: Sessions,
+    pub lockouts: Lockouts,
+    /// Wrong OPER passwords on this connection
+    pub oper_failures: usize,
+    pub nickserv_lockouts: Lockouts,
+    /// Wrong NickServ passwords on this connection
+    pub nickserv_failures: usize,
+    pub defcon: Defcon,
+    pub history: History,
+    /// When we last joined a channel, for throttling JOINs under DEFCON
+    pub last_join: Option<Instant>,
+    /// When we last listed every channel, see `pace`
+    pub last_list: Option<Instant>,
+    /// When we get renamed for using an account's nick without being logged into it
+    pub nick_deadline: Option<Instant>,
+    /// When we get a Guest nick for not managing to register with one of our own, see `nick`
+    guest_deadline: Option<Instant>,
+    pub users: Users,
+    pub channels: Channels,
+    /// Where messages to nicks we don't have go
+    pub cluster: Cluster,
+    /// Handed to `users` so messages to our nick can reach us
+    direct_tx: mpsc::Sender<Message>,
+    /// Messages sent straight to our nick
+    direct_rx: mpsc::Receiver<Message>,
+    pub events: EventBus,
+    metrics: Metrics,
+    /// Whatever the client gave with QUIT, for the UserQuit event
+    pub quit_reason: Option<String>,
+    /// For the summary when it closes
+    connected_at: Instant,
+    /// Channels joined over the whole connection, for the summary when it closes
+    pub channels_joined: usize,
+    /// SASL mechanism the client is partway through
+    pub sasl: Option<String>,
+    /// Account the client logged into with SASL, until registration attaches it
+    pub sasl_account: Option<String>,
+    /// Which of the account's devices this is in bouncer mode, from `USER account@device`. Empty if it didn't say
+    device: String,
+    /// Keeps the client from flooding everyone with client-only tags
+    pub tags: TagLimiter,
+    /// Holds the client's commands back when it sends too many
+    fakelag: Fakelag,
+    /// A line fakelag is holding back and when it can go, nothing more is read from the client until then
+    held: Option<(String, Instant)>,
+    /// The PING we're waiting on a PONG for, and when it went out
+    ping: Option<(String, Instant)>,
+    /// When the next PING goes out
+    next_ping: Instant,
+    /// A node mask we asked the cluster about, and when to give up on any node answering
+    pub awaiting: Option<(String, Instant)>,
     /// We use this to ask the server to do stuff
     server_tx: mpsc::Sender<ClientToServerPacket>,
     /// We receive on this to do stuff when the server asks us to
@@ -199,43 +1088,156 @@ pub struct ClientConnection {
 impl ClientConnection {
     /// Main loop of the client handler
     async fn run(&mut self) -> Result<()> {
+        let registration_deadline =
+            tokio::time::Instant::now() + Duration::from_secs(self.config.registration_timeout);
         // so we don't have to wait on select! between commands
         while !self.shutdown.is_shutdown() {
+            // Whatever the last command wrote goes out in one go
+            self.connection.flush().await?;
+            let auto_away_at = self.auto_away_at();
+            let away_notify_at = self
+                .info()
+                .away_notified
+                .ready_at(self.away_notify_interval());
             // This is the main branching logic for the client
             // not all branches return commands
+            let held_until = self.held.as_ref().map(|(_, at)| *at);
+            let awaiting_until = self.awaiting.as_ref().map(|(_, at)| *at);
             let maybe_command = tokio::select! {
                 // Our client sent us something, handle it
-                res = self.connection.read_line() => {
+                res = self.connection.read_line(), if held_until.is_none() => {
                     let res = res?;
                     // Indicates client hangup
                     if res.is_none() {
                         self.quit_client().await?;
                         return Ok(());
                     }
-                    let mut message: Message = res.unwrap().parse()?;
-                    message.side = Side::Client;
-                    Some(message)
+                    let line = res.unwrap();
+                    let line = alias::expand(&line, &self.config.aliases).unwrap_or(line);
+                    let now = Instant::now();
+                    let wait = if self.config.fakelag.enabled && self.info().oper.is_none() {
+                        self.fakelag.charge(&self.config.fakelag, &line, now)
+                    } else {
+                        Duration::ZERO
+                    };
+                    if wait.is_zero() {
+                        Some(self.client_message(&line).await?)
+                    } else {
+                        // Everything else keeps going while it waits
+                        self.held = Some((line, now + wait));
+                        None
+                    }
+                },
+                // Fakelag's done holding a line back
+                _ = tokio::time::sleep_until(held_until.unwrap_or_else(Instant::now).into()), if held_until.is_some() => {
+                    match self.held.take() {
+                        Some((line, _)) => Some(self.client_message(&line).await?),
+                        None => None,
+                    }
+                },
+                // No node matched a mask we asked about
+                _ = tokio::time::sleep_until(awaiting_until.unwrap_or_else(Instant::now).into()), if awaiting_until.is_some() => {
+                    if let Some((server, _)) = self.awaiting.take() {
+                        let info = self.info().clone();
+                        self.connection.write_no_such_server(&info, &server).await?;
+                    }
+                    None
                 },
                 // The server told us to do something, handle it
-                res = self.client_rx.recv() => {
-                    let command = res?;
+                res = next_packet(&mut self.client_rx) => {
+                    let (command, missed) = res?;
+                    // Fell too far behind, whatever we missed is gone but that's no reason to drop them
+                    if missed > 0 {
+                        self.lagged(missed).await?;
+                    }
+                    self.metrics.received();
                     match command {
-                        ServerToClientPacket::PrivMessage { channels, message } => {
-                            if let Some(source) = &message.source {
-                                if source != &self.info.username && self.info.channels.iter().any(|a| channels.contains(a)) {
-                                    Some(message.clone())
-                                } else {
+                        ServerToClientPacket::Route(route) => {
+                            let wanted = route.origin != self.id
+                                && route.cap.is_none_or(|x| self.caps.contains(x))
+                                && route.lacking.is_none_or(|x| !self.caps.contains(x))
+                                && route.to.contains(&self.info().uid);
+                            match route.line {
+                                Some(line) if wanted => {
+                                    let tags = self.tags_wanted();
+                                    self.connection.write_line(&line, tags).await?;
                                     None
                                 }
+                                _ => wanted.then_some(route.message),
+                            }
+                        }
+                        ServerToClientPacket::Ban(ban) => {
+                            if self.registered && ban.matches(&self.ban_subject()) {
+                                let reason = format!("{}: {}", ban.kind.name(), ban.reason);
+                                self.connection.write_error(&reason).await?;
+                                self.quit_reason = Some(reason);
+                                return Ok(());
+                            }
+                            None
+                        }
+                        ServerToClientPacket::ServerNotice { mask, text } => {
+                            if self.info().snomasks.contains(&mask) {
+                                let info = self.info().clone();
+                                self.connection.write_server_notice(&info, &text).await?;
+                            }
+                            None
+                        }
+                        ServerToClientPacket::ReadMarker { account, target, timestamp } => {
+                            if self.info().account.as_ref() == Some(&account) && self.caps.contains(capability::READ_MARKER) {
+                                Some(Message {
+                                    tags: None,
+                                    source: None,
+                                    command: Command::MARKREAD(target, Some(timestamp)),
+                                    side: Side::Server,
+                                })
                             } else {
                                 None
                             }
                         }
-                        ServerToClientPacket::Join { message } => {
-                            Some(message)
-                        }
                     }
                 },
+                // Someone messaged us directly
+                message = self.direct_rx.recv() => message,
+                // Idle for long enough, mark them away unless they've been active on another connection since
+                _ = tokio::time::sleep_until(auto_away_at.unwrap_or_else(Instant::now).into()), if auto_away_at.is_some() => {
+                    if self.auto_away_at().is_some_and(|x| x <= Instant::now()) {
+                        self.set_auto_away(true).await?;
+                    }
+                    None
+                }
+                // Never found a nick we could have
+                _ = tokio::time::sleep_until(self.guest_deadline.unwrap_or_else(Instant::now).into()), if self.guest_deadline.is_some() => {
+                    self.guest_deadline = None;
+                    if !self.registered {
+                        self.info().nickname = nick::guest();
+                        if self.can_register() && !self.register().await? {
+                            return Ok(());
+                        }
+                    }
+                    None
+                }
+                // Still on someone else's nick
+                _ = tokio::time::sleep_until(self.nick_deadline.unwrap_or_else(Instant::now).into()), if self.nick_deadline.is_some() => {
+                    self.nick_deadline = None;
+                    let uid = self.info().uid.clone();
+                    self.rename_guest(&uid).await?;
+                    None
+                }
+                // Away changes that came too fast can go out now
+                _ = tokio::time::sleep_until(away_notify_at.unwrap_or_else(Instant::now).into()), if away_notify_at.is_some() => {
+                    self.notify_away().await?;
+                    None
+                }
+                // Time to find out how far behind they are
+                _ = tokio::time::sleep_until(self.next_ping.into()), if self.registered => {
+                    self.ping().await?;
+                    None
+                }
+                // Connected, but never got around to registering
+                _ = tokio::time::sleep_until(registration_deadline), if !self.registered => {
+                    self.connection.write_error("Registration timeout").await?;
+                    return Ok(());
+                }
                 // The server told us it's dying time, handle it
                 _ = self.shutdown.recv() => {
                     self.quit_client().await?;
@@ -245,7 +1247,7 @@ impl ClientConnection {
 
             // We share this between two select branches, the client
             // only ever acts on Messages (TODO)
-            let mut command = match maybe_command {
+            let command = match maybe_command {
                 Some(command) => command,
                 None => {
                     continue;
@@ -255,19 +1257,22 @@ impl ClientConnection {
             // let mut command = Message::parse(frame, side)?;
             // println!("Message: {:?}", command);
 
+            let was_registered = self.registered;
             // Let the command do it's damage
-            match command.apply(self).await {
+            let applying = std::time::Instant::now();
+            let res = command.apply(self).await;
+            if command.side == Side::Client {
+                let took = applying.elapsed();
+                let name = command.command.name();
+                self.metrics.command(name, took);
+                let nick = self.info().nickname.clone();
+                log::command(self.id, &nick, name, took);
+            }
+            match res {
                 // It did something but we don't care
                 Ok(Code::Fine) => {}
                 // It did something and we need the server to care
-                Ok(Code::Broadcast) => {
-                    // If we're rebroadcasting, we have to set the source to our username.
-                    command.source = Some(self.info.username.clone());
-                    command.side = Side::Server;
-                    self.server_tx
-                        .send(ClientToServerPacket::BlindBroadcast(command))
-                        .await?;
-                }
+                Ok(Code::Broadcast) => self.broadcast(command).await?,
                 // It did something and we're dying now
                 Ok(Code::Exit) => return Ok(()),
                 // It did something really bad and we're dying extra hard now
@@ -275,17 +1280,984 @@ impl ClientConnection {
                     return Err(e);
                 }
             }
+            if !was_registered && self.registered {
+                self.auto_join().await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Joins a freshly registered client to the `auto_join` channels, same as if it had sent the JOIN itself.
+    async fn auto_join(&mut self) -> Result<()> {
+        let joined = self.info().channels.clone();
+        let channels: Vec<String> = self
+            .config
+            .auto_join
+            .iter()
+            .filter(|x| !joined.iter().any(|y| y.eq_ignore_ascii_case(x)))
+            .cloned()
+            .collect();
+        if channels.is_empty() {
+            return Ok(());
+        }
+        let join = Message {
+            tags: None,
+            source: None,
+            command: Command::JOIN(channels, None),
+            side: Side::Client,
+        };
+        join.apply(self).await?;
+        Ok(())
+    }
+
+    /// When we should be marked away for being idle, if we should be at all.
+    fn auto_away_at(&self) -> Option<Instant> {
+        let idle = Duration::from_secs(self.config.auto_away?);
+        let info = self.info();
+        if !self.registered || info.away.is_some() {
+            return None;
+        }
+        Some(info.last_message? + idle)
+    }
+
+    /// Sends a PING, the PONG to which tells us their lag. One still unanswered is given up on.
+    async fn ping(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let token = Utc::now().timestamp_millis().to_string();
+        self.connection.write_ping(&token).await?;
+        self.ping = Some((token, now));
+        self.next_ping = now + Duration::from_secs(self.config.ping_interval.max(1));
+        Ok(())
+    }
+
+    /// A PONG from the client, which is their lag if it answers the PING we're waiting on.
+    pub fn ponged(&mut self, token: &str) {
+        if let Some((_, sent)) = self.ping.take_if(|(x, _)| x == token) {
+            let uid = self.info().uid.clone();
+            self.users.set_lag(&uid, self.id, sent.elapsed());
+        }
+    }
+
+    /// Counts what we missed by falling behind on the broadcast channel, and lets the client know some of what it
+    /// should have seen never arrived. It gets the topic and names of every channel it's in again, since the joins,
+    /// parts and topic changes it missed would leave it with the wrong idea of them.
+    async fn lagged(&mut self, missed: u64) -> Result<()> {
+        self.metrics.dropped(missed);
+        let info = self.info().clone();
+        log::info!(
+            "{} fell behind and missed {} messages",
+            info.nickname,
+            missed
+        );
+        if self.registered {
+            self.connection
+                .write_notice(
+                    &info,
+                    format!("*** You fell behind and missed {} messages", missed),
+                )
+                .await?;
+            self.resync(&info).await?;
         }
+        Ok(())
+    }
 
+    /// Sends the topic and names of every channel we're in.
+    async fn resync(&mut self, info: &ClientInfo) -> Result<()> {
+        for channel in &info.channels {
+            let topic = self.channels.topic(channel);
+            self.connection
+                .write_topic(info, channel, topic.as_ref())
+                .await?;
+            self.names(info, channel).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends who's in `channel`, with their status prefixes.
+    pub async fn names(&mut self, info: &ClientInfo, channel: &str) -> Result<()> {
+        let names: Vec<String> = self
+            .channels
+            .members(channel)
+            .into_iter()
+            .filter_map(|(uid, status)| {
+                let nick = self.users.info_by_uid(&uid)?.nickname;
+                Some(format!(
+                    "{}{}",
+                    status.prefix().map(String::from).unwrap_or_default(),
+                    nick
+                ))
+            })
+            .collect();
+        let secret = self.channels.is_secret(channel);
+        self.connection
+            .write_names(info, channel, secret, &names)
+            .await
+    }
+
+    /// Parses a line the client sent.
+    async fn client_message(&mut self, line: &str) -> Result<Message> {
+        let mut message: Message = line.parse()?;
+        message.side = Side::Client;
+        if matches!(message.command, Command::PRIVMSG(..) | Command::NOTICE(..)) {
+            self.active().await?;
+        }
+        Ok(message)
+    }
+
+    /// Resets the idle time for `auto_away`, bringing the user back if they'd been marked away for it.
+    async fn active(&mut self) -> Result<()> {
+        let was_auto_away = {
+            let mut info = self.info();
+            info.last_message = Some(Instant::now());
+            info.auto_away
+        };
+        if was_auto_away {
+            self.set_auto_away(false).await?;
+        }
+        Ok(())
+    }
+
+    /// Marks us away for being idle, or takes it back, telling the client and anyone with away-notify.
+    async fn set_auto_away(&mut self, away: bool) -> Result<()> {
+        let message = away.then(|| AUTO_AWAY.to_string());
+        let info = {
+            let mut info = self.info();
+            info.away = message.clone();
+            info.auto_away = away;
+            info.clone()
+        };
+        self.connection.write_away_status(&info).await?;
+        self.notify_away().await
+    }
+
+    fn away_notify_interval(&self) -> Duration {
+        Duration::from_secs(self.config.away_notify_interval)
+    }
+
+    /// Tells anyone with away-notify whether we're away now, unless it's too soon since the last time, see `away`.
+    pub async fn notify_away(&mut self) -> Result<()> {
+        let interval = self.away_notify_interval();
+        let away = {
+            let mut info = self.info();
+            let away = info.away.clone();
+            if !info.away_notified.send(&away, interval, Instant::now()) {
+                return Ok(());
+            }
+            away
+        };
+        self.broadcast(Message {
+            tags: None,
+            source: None,
+            command: Command::AWAY(away),
+            side: Side::Client,
+        })
+        .await
+    }
+
+    /// Asks the server to pass `message` on to everyone else, as coming from us.
+    pub async fn broadcast(&self, mut message: Message) -> Result<()> {
+        // If we're rebroadcasting, we have to set the source to who we are.
+        message.source = Some(self.info().to_canonical());
+        message.side = Side::Server;
+        self.server_tx
+            .send(ClientToServerPacket::BlindBroadcast {
+                origin: self.id,
+                message,
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Sends something we did, like a JOIN, back to us with our full prefix and on to everyone else it's for, so we
+    /// see it exactly once and the same way they do.
+    pub async fn echo(&mut self, mut message: Message) -> Result<()> {
+        message.source = Some(self.info().to_canonical());
+        // Safety: we terminate the line ourselves.
+        unsafe {
+            self.connection
+                .write_raw(format!("{}\r\n", message))
+                .await?;
+        }
+        self.broadcast(message).await
+    }
+
+    /// Tells us, our other connections and everyone sharing a channel with us that we're `new` now. `source` is our
+    /// hostmask from before.
+    pub async fn announce_nick(&mut self, source: String, new: &str) -> Result<()> {
+        let message = Message {
+            tags: None,
+            source: Some(source),
+            command: Command::NICK(new.to_string()),
+            side: Side::Server,
+        };
+        // Safety: we terminate the line ourselves.
+        unsafe {
+            self.connection
+                .write_raw(format!("{}\r\n", message))
+                .await?;
+        }
+        self.server_tx
+            .send(ClientToServerPacket::BlindBroadcast {
+                origin: self.id,
+                message,
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Moves `uid` off their nick onto a Guest one, for nick protection or someone's REGAIN. Their own connections
+    /// are told by the registry, everyone sharing a channel with them from here.
+    pub async fn rename_guest(&self, uid: &Uid) -> Result<()> {
+        let Some((old, new)) = self.users.rename_guest(uid) else {
+            return Ok(());
+        };
+        if let Some(info) = self.users.info_by_uid(uid) {
+            let mut to = self.channels.resolve(&info.channels);
+            to.remove(uid);
+            let message = Message {
+                tags: None,
+                source: Some(format!("{}!{}@{}", old, info.username, info.host)),
+                command: Command::NICK(new.clone()),
+                side: Side::Server,
+            };
+            self.server_tx
+                .send(ClientToServerPacket::Route(Route::new(0, to, message)))
+                .await?;
+        }
+        self.events.publish(Event::NickChanged { old, new });
+        Ok(())
+    }
+
+    /// Kicks everyone `ban` covers off the server.
+    pub async fn enforce_ban(&self, ban: Ban) -> Result<()> {
+        self.server_tx.send(ClientToServerPacket::Ban(ban)).await?;
+        Ok(())
+    }
+
+    /// Deals with something the user sent tripping a spam filter. Returns what should happen to what they sent:
+    /// `Fine` to drop it, `Broadcast` to let it through anyway, or `Exit` if they're gone.
+    pub async fn filtered(&mut self, hit: Hit, text: &str) -> Result<Code> {
+        let info = self.info().clone();
+        let ip = self.connection.client_addr.ip().to_string();
+        log::info!(
+            "[filter] {} ({}) tripped a {} filter: {}",
+            info.nickname,
+            ip,
+            hit.action.as_str(),
+            text
+        );
+        self.events.publish(Event::FilterHit {
+            nick: info.nickname.clone(),
+            action: hit.action.as_str(),
+            text: text.to_string(),
+        });
+        match hit.action {
+            FilterAction::Block => Ok(Code::Fine),
+            FilterAction::Warn => {
+                self.connection.write_notice(&info, &hit.reason).await?;
+                Ok(Code::Broadcast)
+            }
+            FilterAction::Kill => {
+                self.connection.write_error(&hit.reason).await?;
+                self.quit_reason = Some(hit.reason);
+                Ok(Code::Exit)
+            }
+            FilterAction::Kline => {
+                let ban = Ban::new(
+                    BanKind::Kline,
+                    format!("*@{}", ip),
+                    hit.reason.clone(),
+                    "filter".to_string(),
+                    Some(FILTER_KLINE_DURATION),
+                );
+                self.bans.add(ban.clone());
+                self.enforce_ban(ban).await?;
+                self.connection.write_error(&hit.reason).await?;
+                self.quit_reason = Some(hit.reason);
+                Ok(Code::Exit)
+            }
+        }
+    }
+
+    /// Which tags messages come with, for message-tags or account-tag
+    pub fn tags_wanted(&self) -> Wanted {
+        if self.caps.contains(capability::MESSAGE_TAGS) {
+            Wanted::All
+        } else if self.caps.contains(capability::ACCOUNT_TAG) {
+            Wanted::Account
+        } else {
+            Wanted::Nothing
+        }
+    }
+
+    /// What bans get checked against for us
+    pub fn ban_subject(&self) -> Subject {
+        let info = self.info();
+        Subject {
+            username: info.username.clone(),
+            ip: self.connection.client_addr.ip().to_string(),
+            realname: info.realname.clone(),
+            account: info.account.clone(),
+            tls: self.connection.tls,
+            registered: self.registered,
+        }
+    }
+
+    /// Asks the server to shut down.
+    pub async fn die(&self) -> Result<()> {
+        self.server_tx.send(ClientToServerPacket::Die).await?;
+        Ok(())
+    }
+
+    /// Asks the server to listen on `addresses`, and stop listening anywhere else it was from an earlier one.
+    pub async fn listen(&self, addresses: Vec<String>) -> Result<()> {
+        self.server_tx
+            .send(ClientToServerPacket::Listen(addresses))
+            .await?;
+        Ok(())
+    }
+
+    /// Checks the user is an oper with `privilege`, telling them off if they aren't.
+    pub async fn check_privilege(&mut self, privilege: Privilege) -> Result<bool> {
+        let info = self.info().clone();
+        match &info.oper {
+            Some(privileges) if privileges.contains(&privilege) => Ok(true),
+            Some(_) => {
+                self.connection
+                    .write_no_privs(&info, privilege.as_str())
+                    .await?;
+                Ok(false)
+            }
+            None => {
+                self.connection.write_no_privileges(&info).await?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// What this connection has got up to so far, for when it closes.
+    fn summary(&self) -> ConnectionSummary {
+        let traffic = self.connection.traffic;
+        ConnectionSummary {
+            connected: self.connected_at.elapsed(),
+            bytes_in: traffic.bytes_in,
+            bytes_out: traffic.bytes_out,
+            messages: traffic.lines_in,
+            channels_joined: self.channels_joined,
+        }
+    }
+
+    /// Locks the user info for reading or writing, don't hold onto it across an await
+    pub fn info(&self) -> MutexGuard<'_, ClientInfo> {
+        lock_info(&self.info)
+    }
+
+    /// Returns `true` once we have everything we need to register: a nick, USER, and no capability negotiation
+    /// in progress.
+    pub fn can_register(&self) -> bool {
+        let info = self.info();
+        !self.registered
+            && !self.cap_negotiating
+            && !info.nickname.is_empty()
+            && !info.username.is_empty()
+    }
+
+    /// Finishes registration once USER has arrived. If the client sent PASS we log them into the account named by
+    /// their username, or they might have already logged in with SASL. Either way they get attached to that
+    /// account's session.
+    /// Returns `false` if the password was wrong and the client needs to go.
+    pub async fn register(&mut self) -> Result<bool> {
+        self.info().signon = Utc::now().timestamp();
+        if self.config.bouncer {
+            let username = self.info().username.clone();
+            if let Some((username, device)) = username.split_once('@') {
+                self.info().username = username.to_string();
+                self.device = device.to_string();
+            }
+        }
+        if self.config.require_sasl && self.sasl_account.is_none() {
+            let info = self.info().clone();
+            self.connection.write_sasl_fail(&info).await?;
+            self.connection
+                .write_error("This server needs you to log in with SASL first")
+                .await?;
+            return Ok(false);
+        }
+        let account = match self.password.take() {
+            Some(password) => {
+                let account = self.info().username.clone();
+                let ok = self
+                    .auth
+                    .authenticate(&account, &password)
+                    .await
+                    .unwrap_or_else(|e| {
+                        log::error!("Couldn't check the password for {}: {}", account, e);
+                        false
+                    });
+                if !ok {
+                    self.events.publish(Event::AuthFailed {
+                        account: Some(account),
+                        ip: self.info().ip.clone(),
+                    });
+                    self.connection.write_error("Invalid password").await?;
+                    return Ok(false);
+                }
+                Some(account)
+            }
+            None => self.sasl_account.take(),
+        };
+        // Not until now, so extbans can see the account
+        let subject = Subject {
+            account: account.clone(),
+            ..self.ban_subject()
+        };
+        if let Some(ban) = self.bans.find(&subject) {
+            let reason = format!("{}: {}", ban.kind.name(), ban.reason);
+            self.connection.write_error(&reason).await?;
+            return Ok(false);
+        }
+        let level = self.defcon_level();
+        if level <= defcon::CLOSED {
+            self.connection
+                .write_error("This server isn't taking new connections right now")
+                .await?;
+            return Ok(false);
+        }
+        if level <= defcon::ACCOUNTS_ONLY && account.is_none() {
+            self.connection
+                .write_error(
+                    "This server is only taking connections logged into an account right now",
+                )
+                .await?;
+            return Ok(false);
+        }
+        // Only once they're let in, anyone turned away above was never really here and doesn't quit
+        self.registered = true;
+        if let Some(account) = account {
+            self.info().account = Some(account.clone());
+
+            let requested = self.info().nickname.clone();
+            let (info, existed) = self.sessions.attach(&account, &self.info);
+            self.info = info;
+            if existed {
+                return self.resume(requested).await.map(|_| true);
+            }
+        }
+
+        let info = self.info().clone();
+        let claimed = self.cluster.claim(&info.nickname).await
+            && self
+                .users
+                .claim(&info.nickname, self.id, &self.info, self.direct_tx.clone());
+        if !claimed {
+            // They can try again with another NICK
+            self.registered = false;
+            self.connection
+                .write_nick_in_use(&info, &info.nickname)
+                .await?;
+            self.nick_rejected();
+            return Ok(true);
+        }
+        self.info().last_message.get_or_insert_with(Instant::now);
+        self.write_burst(None).await?;
+        self.events.publish(Event::UserRegistered {
+            nick: info.nickname,
+            account: info.account,
+            host: info.host,
+        });
+        self.check_nick_owner().await?;
+        Ok(true)
+    }
+
+    /// The DEFCON level, telling opers if it's gone back up by itself since anyone last looked.
+    pub fn defcon_level(&self) -> u8 {
+        let (level, decayed) = self.defcon.level(&self.config.defcon, Instant::now());
+        if decayed {
+            self.events
+                .publish(Event::DefconChanged { by: None, level });
+        }
+        level
+    }
+
+    /// Starts the clock on giving us a Guest nick, if we haven't registered yet and it isn't already going.
+    pub fn nick_rejected(&mut self) {
+        if let (false, None, Some(after)) = (
+            self.registered,
+            self.guest_deadline,
+            self.config.nicks.guest_after,
+        ) {
+            self.guest_deadline = Some(Instant::now() + Duration::from_secs(after));
+        }
+    }
+
+    /// Logs us into `account` after registering, with SASL. Everyone sharing a channel with us who has account-notify
+    /// hears about it, and what we send from now on has it in the `account` tag. It doesn't attach us to a bouncer
+    /// session, that still takes logging in while registering.
+    pub async fn log_in(&mut self, account: String) -> Result<()> {
+        self.info().account = Some(account.clone());
+        let nickname = self.info().nickname.clone();
+        if self.accounts.owner(&nickname).as_ref() == Some(&account) {
+            self.nick_deadline = None;
+        }
+        self.broadcast(Message {
+            tags: None,
+            source: None,
+            command: Command::ACCOUNT(account),
+            side: Side::Server,
+        })
+        .await
+    }
+
+    /// Warns us if our nick is an account's and we aren't logged into it, and sets when we'll be renamed for it.
+    pub async fn check_nick_owner(&mut self) -> Result<()> {
+        self.nick_deadline = None;
+        let info = self.info().clone();
+        if !self.config.nicks.protect {
+            return Ok(());
+        }
+        match self.accounts.owner(&info.nickname) {
+            Some(owner) if info.account.as_ref() != Some(&owner) => {}
+            _ => return Ok(()),
+        }
+        let grace = self.config.nicks.protect_grace;
+        self.nick_deadline = Some(Instant::now() + Duration::from_secs(grace));
+        self.connection
+            .write_notice(
+                &info,
+                format!(
+                    "{} is registered to someone else, change your nick within {} seconds or it'll be changed for you",
+                    info.nickname, grace
+                ),
+            )
+            .await
+    }
+
+    /// Tells everyone sharing a channel with `old` that their host is `host` now. Clients with chghost get a CHGHOST,
+    /// everyone else sees them quit and join back, getting their status back too. They hear about it themselves
+    /// with RPL_HOSTHIDDEN.
+    pub async fn announce_host(&self, old: &ClientInfo, host: &str, source: &str) -> Result<()> {
+        let new = ClientInfo {
+            host: host.to_string(),
+            ..old.clone()
+        };
+        let message = |source: String, command| Message {
+            tags: None,
+            source: Some(source),
+            command,
+            side: Side::Server,
+        };
+        let shared = self.channels.resolve(&old.channels);
+        let mut routes = vec![
+            Route::new(
+                0,
+                HashSet::from([old.uid.clone()]),
+                message(
+                    source.to_string(),
+                    Command::CHGHOST(old.nickname.clone(), host.to_string()),
+                ),
+            )
+            .applied(),
+            Route::new(
+                0,
+                shared.clone(),
+                message(
+                    old.to_canonical(),
+                    Command::CHGHOST(old.username.clone(), host.to_string()),
+                ),
+            )
+            .needing(capability::CHGHOST),
+        ];
+        let mut others = shared;
+        others.remove(&old.uid);
+        routes.push(
+            Route::new(
+                0,
+                others,
+                message(
+                    old.to_canonical(),
+                    Command::QUIT(Some("Changing host".to_string())),
+                ),
+            )
+            .lacking(capability::CHGHOST),
+        );
+        for channel in &old.channels {
+            let mut members = self.channels.resolve(std::slice::from_ref(channel));
+            members.remove(&old.uid);
+            routes.push(
+                Route::new(
+                    0,
+                    members.clone(),
+                    message(
+                        new.to_canonical(),
+                        Command::JOIN(vec![channel.clone()], None),
+                    ),
+                )
+                .lacking(capability::CHGHOST),
+            );
+            if let Some(mode) = self
+                .channels
+                .status(channel, &old.uid)
+                .and_then(|x| x.mode())
+            {
+                let mode = Command::MODE(
+                    channel.clone(),
+                    Some(format!("+{}", mode)),
+                    Some(vec![old.nickname.clone()]),
+                );
+                routes.push(
+                    Route::new(0, members, message(self.connection.server_name(), mode))
+                        .lacking(capability::CHGHOST),
+                );
+            }
+        }
+        for route in routes {
+            self.server_tx
+                .send(ClientToServerPacket::Route(route))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Disconnects whoever is using `nick` here like KILL would, as `source`.
+    pub async fn disconnect(&self, nick: &str, source: &str, reason: String) -> Result<()> {
+        let to = self.users.uid(nick).into_iter().collect();
+        let message = Message {
+            tags: None,
+            source: Some(source.to_string()),
+            command: Command::KILL(nick.to_string(), reason),
+            side: Side::Server,
+        };
+        let route = Route::new(self.id, to, message).applied();
+        self.server_tx
+            .send(ClientToServerPacket::Route(route))
+            .await?;
+        Ok(())
+    }
+
+    /// The MOTD, or with `short_motd` just where to find it.
+    async fn write_connect_motd(&mut self, info: &ClientInfo) -> Result<()> {
+        let motd = match self.config.short_motd {
+            true => SHORT_MOTD,
+            false => &self.config.motd,
+        };
+        self.connection.write_motd(info, motd).await
+    }
+
+    /// Catches a freshly attached connection up with its session: the nick it's actually using and every channel
+    /// the session is sitting in.
+    async fn resume(&mut self, requested: String) -> Result<()> {
+        let info = self.info().clone();
+        // The session already holds its nick, so this can't fail
+        self.users
+            .claim(&info.nickname, self.id, &self.info, self.direct_tx.clone());
+        self.write_burst(Some(&requested)).await?;
+        self.events.publish(Event::UserRegistered {
+            nick: info.nickname,
+            account: info.account,
+            host: info.host,
+        });
+        Ok(())
+    }
+
+    /// Writes out everything `burst::plan` says we get on registering, `requested` being the nick we asked for if
+    /// we're attaching to a session.
+    async fn write_burst(&mut self, requested: Option<&str>) -> Result<()> {
+        let info = self.info().clone();
+        for part in burst::plan(&info, &self.caps, requested) {
+            match part {
+                Part::Welcome => {
+                    self.connection
+                        .write_registration(&info, &self.isupport(), self.started)
+                        .await?
+                }
+                Part::Lusers => self.write_lusers().await?,
+                Part::Motd => self.write_connect_motd(&info).await?,
+                Part::UserModes(modes) => self.connection.write_user_mode(&info, &modes).await?,
+                Part::Nick { requested } => {
+                    self.connection
+                        .write_nick(&requested, &info.nickname)
+                        .await?
+                }
+                Part::Join(channel) => self.connection.write_join(&info, &channel).await?,
+                Part::ReadMarker(channel) => self.send_read_marker(&channel).await?,
+                Part::Playback => self.play_back().await?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Plays back what this device missed since it last detached from the session. Clients without message-tags
+    /// get the time in front of the text instead of a `time` tag.
+    async fn play_back(&mut self) -> Result<()> {
+        let Some(account) = self.info().account.clone() else {
+            return Ok(());
+        };
+        let Some(mark) = self.sessions.seen(&account, &self.device) else {
+            return Ok(());
+        };
+        for entry in self.history.since(&account, mark) {
+            let (tags, text) = match self.caps.contains(capability::MESSAGE_TAGS) {
+                true => (
+                    Some(vec![format!(
+                        "time={}",
+                        entry.time.to_rfc3339_opts(SecondsFormat::Millis, true)
+                    )]),
+                    entry.text,
+                ),
+                false => (
+                    None,
+                    format!(
+                        "[{}] {}",
+                        entry.time.format("%Y-%m-%d %H:%M:%S"),
+                        entry.text
+                    ),
+                ),
+            };
+            let message = Message {
+                tags,
+                source: Some(entry.source),
+                command: Command::PRIVMSG(vec![entry.target], text),
+                side: Side::Server,
+            };
+            // Safety: we terminate the line ourselves.
+            unsafe {
+                self.connection
+                    .write_raw(format!("{}\r\n", message))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// LUSERS, for the burst or when asked.
+    pub async fn write_lusers(&mut self) -> Result<()> {
+        let info = self.info().clone();
+        let counts = self.users.counts();
+        let channels = self.channels.member_counts().len();
+        self.connection.write_lusers(&info, &counts, channels).await
+    }
+
+    /// Sends a CAP LS or LIST reply. 302 clients get it split over several lines if it's long, with `*` on every
+    /// line but the last.
+    pub async fn write_cap_list(&mut self, subcommand: &str, caps: &[String]) -> Result<()> {
+        let info = self.info().clone();
+        if self.cap_version < 302 {
+            return self
+                .connection
+                .write_cap(&info, subcommand, caps.join(" "))
+                .await;
+        }
+        let lines = capability::lines(caps);
+        let last = lines.len() - 1;
+        for (i, line) in lines.iter().enumerate() {
+            let subcommand = match i == last {
+                true => subcommand.to_string(),
+                false => format!("{} *", subcommand),
+            };
+            self.connection.write_cap(&info, subcommand, line).await?;
+        }
+        Ok(())
+    }
+
+    /// Tells the client where its read marker for `target` is, if it cares.
+    pub async fn send_read_marker(&mut self, target: &str) -> Result<()> {
+        if !self.caps.contains(capability::READ_MARKER) {
+            return Ok(());
+        }
+        let account = self.info().account.clone();
+        let timestamp = account.and_then(|a| self.accounts.read_marker(&a, target));
+        self.connection
+            .write_markread(target, timestamp.as_deref())
+            .await?;
+        Ok(())
+    }
+
+    /// Lets every connection logged into `account` know its read marker for `target` moved.
+    pub async fn sync_read_marker(
+        &self,
+        account: String,
+        target: String,
+        timestamp: String,
+    ) -> Result<()> {
+        self.server_tx
+            .send(ClientToServerPacket::ReadMarker {
+                account,
+                target,
+                timestamp,
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Lets go of our session (if we have one) once the connection is gone. If that was the last of the user,
+    /// everyone who shared a channel with them gets told they quit.
+    async fn detach(&self) {
+        let info = self.info().clone();
+        if let Some(account) = &info.account {
+            // Only once it's registered, or it never saw anything to begin with
+            if self.config.bouncer && self.registered {
+                self.sessions
+                    .saw(account, &self.device, self.history.mark());
+            }
+            self.sessions.detach(account, self.config.bouncer);
+        }
+        if !self.registered {
+            return;
+        }
+        let always_on = info.account.is_some() && self.config.bouncer;
+        if !self.users.remove(&info.uid, self.id, always_on) {
+            return;
+        }
+        // Has to be worked out before they're out of the channels, and the server can't do it for us by then
+        let to = self.channels.resolve(&info.channels);
+        self.channels.quit(&info.uid);
+        if to.is_empty() {
+            return;
+        }
+        let reason = self.quit_reason.as_deref().unwrap_or("Client Quit");
+        let quit = Message {
+            tags: None,
+            source: Some(info.to_canonical()),
+            command: Command::QUIT(Some(reason.to_string())),
+            side: Side::Server,
+        };
+        // The server only goes away after every connection has
+        let _ = self
+            .server_tx
+            .send(ClientToServerPacket::Route(Route::new(self.id, to, quit)))
+            .await;
+    }
+
+    /// Everything we tell clients about in RPL_ISUPPORT
+    fn isupport(&self) -> Vec<String> {
+        let mut tokens = channel::isupport();
+        tokens.push(ban::isupport());
+        tokens.extend(self.config.limits.isupport());
+        tokens.push(format!("CHANTYPES={}", self.config.chantypes));
+        tokens.push("BOT=B".to_string());
+        tokens.push(format!(
+            "NETWORK={}",
+            self.connection.identity.load().network
+        ));
+        tokens
+    }
+
+    /// Sends a private message straight to whoever is using `nick`.
+    pub async fn message_user(
+        &mut self,
+        nick: &str,
+        text: &str,
+        tags: Option<Vec<String>>,
+    ) -> Result<()> {
+        let info = self.info().clone();
+        let source = info.to_canonical();
+        let text = match message_hooks(&self.scripts, &self.plugins, &source, nick, text) {
+            Some(text) => text,
+            None => return Ok(()),
+        };
+        let message = Message {
+            tags,
+            source: Some(source.clone()),
+            command: Command::PRIVMSG(vec![nick.to_string()], text.clone()),
+            side: Side::Server,
+        };
+        match self.users.send(nick, message.clone()) {
+            Some(target) => {
+                let seen = info.account.iter().chain(&target.account).cloned();
+                let entry = history::Entry::new(&source, &target.nickname, &text, seen);
+                self.history.record(&self.config.history, entry);
+                if let Some(away) = &target.away {
+                    self.connection
+                        .write_away(&info, &target.nickname, away)
+                        .await?;
+                }
+            }
+            None if self.cluster.send_direct(nick, &message).await => {}
+            None => self.connection.write_no_such_nick(&info, nick).await?,
+        }
+        Ok(())
+    }
+
+    /// Sends a TAGMSG with `tags` to `nick`, wherever they are.
+    pub async fn tag_user(&mut self, nick: &str, tags: Vec<String>) -> Result<()> {
+        let info = self.info().clone();
+        let message = Message {
+            tags: Some(tags),
+            source: Some(info.to_canonical()),
+            command: Command::TAGMSG(vec![nick.to_string()]),
+            side: Side::Server,
+        };
+        if self.users.send(nick, message.clone()).is_none()
+            && !self.cluster.send_direct(nick, &message).await
+        {
+            self.connection.write_no_such_nick(&info, nick).await?;
+        }
         Ok(())
     }
 
     /// This is a helper to clean ourselves up, we don't use Drop because we need async to interact with our async socket
     async fn quit_client(&mut self) -> Result<()> {
+        let info = self.info().clone();
         self.connection
-            .write_quit(&self.info, "Quit: Server shutting down.")
+            .write_quit(&info, "Quit: Server shutting down.")
             .await?;
         self.connection.write_error("Server shutting down.").await?;
         Ok(())
     }
 }
+
+/// The next packet from the server, with how many got dropped before it if we fell behind. Only the channel closing
+/// is an error.
+async fn next_packet(
+    rx: &mut broadcast::Receiver<ServerToClientPacket>,
+) -> Result<(ServerToClientPacket, u64)> {
+    let mut missed = 0;
+    loop {
+        match rx.recv().await {
+            Ok(packet) => return Ok((packet, missed)),
+            Err(broadcast::error::RecvError::Lagged(n)) => missed += n,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn notice(text: &str) -> ServerToClientPacket {
+        ServerToClientPacket::ServerNotice {
+            mask: Snomask::Opers,
+            text: text.to_string(),
+        }
+    }
+
+    fn text(packet: ServerToClientPacket) -> String {
+        match packet {
+            ServerToClientPacket::ServerNotice { text, .. } => text,
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_consumers() {
+        let (tx, mut rx) = broadcast::channel(2);
+        for i in 0..5 {
+            tx.send(notice(&i.to_string())).unwrap();
+        }
+        // Only the last two are still there
+        let (packet, missed) = next_packet(&mut rx).await.unwrap();
+        assert_eq!((text(packet), missed), ("3".to_string(), 3));
+        let (packet, missed) = next_packet(&mut rx).await.unwrap();
+        assert_eq!((text(packet), missed), ("4".to_string(), 0));
+
+        // Caught up, so it keeps working
+        tx.send(notice("5")).unwrap();
+        let (packet, missed) = next_packet(&mut rx).await.unwrap();
+        assert_eq!((text(packet), missed), ("5".to_string(), 0));
+
+        drop(tx);
+        assert!(next_packet(&mut rx).await.is_err());
+    }
+}
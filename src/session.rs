@@ -0,0 +1,179 @@
+use crate::{
+    server::{lock, lock_info, SharedInfo},
+    ClientInfo,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// A logged in user, which every connection to the same account attaches to.
+/// In bouncer mode it's always-on and stays in its channels whether or not anything is attached to it.
+#[derive(Debug)]
+struct Session {
+    /// The user itself, shared with every attached connection
+    info: SharedInfo,
+    /// How many connections are currently attached
+    attached: usize,
+    /// Where `history` was up to when each device last detached, by device name
+    seen: HashMap<String, u64>,
+}
+
+/// Sessions keyed by account name, shared between every connection.
+#[derive(Debug, Clone, Default)]
+pub struct Sessions {
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+}
+
+impl Sessions {
+    /// Attaches a connection to `account`'s session, creating the session from `info` if the account has never
+    /// connected before. Returns the session's info, and whether the session already existed.
+    pub fn attach(&self, account: &str, info: &SharedInfo) -> (SharedInfo, bool) {
+        let mut sessions = lock(&self.sessions);
+        match sessions.get_mut(account) {
+            Some(session) => {
+                session.attached += 1;
+                (session.info.clone(), true)
+            }
+            None => {
+                sessions.insert(
+                    account.to_string(),
+                    Session {
+                        info: info.clone(),
+                        attached: 1,
+                        seen: HashMap::new(),
+                    },
+                );
+                (info.clone(), false)
+            }
+        }
+    }
+
+    /// Detaches a connection from `account`'s session.
+    /// The session sticks around with nothing attached if `always_on` is set, otherwise the last one out ends it.
+    pub fn detach(&self, account: &str, always_on: bool) {
+        let mut sessions = lock(&self.sessions);
+        if let Some(session) = sessions.get_mut(account) {
+            session.attached = session.attached.saturating_sub(1);
+            if session.attached == 0 && !always_on {
+                sessions.remove(account);
+            }
+        }
+    }
+
+    /// Snapshots every session that has nobody looking at it, either because nothing is attached or because it's
+    /// marked away. Returns (account, info) pairs.
+    pub fn detached_or_away(&self) -> Vec<(String, ClientInfo)> {
+        let sessions = lock(&self.sessions);
+        sessions
+            .iter()
+            .filter_map(|(account, session)| {
+                let info = lock_info(&session.info);
+                if session.attached == 0 || info.away.is_some() {
+                    Some((account.clone(), info.clone()))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Remembers `device` having seen `account`'s history up to `mark`, see `History::mark`.
+    pub fn saw(&self, account: &str, device: &str, mark: u64) {
+        let mut sessions = lock(&self.sessions);
+        if let Some(session) = sessions.get_mut(account) {
+            session.seen.insert(device.to_string(), mark);
+        }
+    }
+
+    /// Where `device` was up to in `account`'s history, if it's been attached before.
+    pub fn seen(&self, account: &str, device: &str) -> Option<u64> {
+        let sessions = lock(&self.sessions);
+        sessions.get(account)?.seen.get(device).copied()
+    }
+
+    /// How many connections are attached to `account`'s session.
+    #[allow(dead_code)]
+    pub fn attached(&self, account: &str) -> usize {
+        let sessions = lock(&self.sessions);
+        sessions.get(account).map_or(0, |s| s.attached)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn attach_shares_info() {
+        let sessions = Sessions::default();
+        let first: SharedInfo = Arc::new(Mutex::new(ClientInfo::default()));
+        first.lock().unwrap().channels.push("#meow".to_string());
+
+        let (info, existed) = sessions.attach("tiger", &first);
+        assert!(!existed);
+        assert!(Arc::ptr_eq(&info, &first));
+
+        let second: SharedInfo = Arc::new(Mutex::new(ClientInfo::default()));
+        let (info, existed) = sessions.attach("tiger", &second);
+        assert!(existed);
+        assert_eq!(info.lock().unwrap().channels, vec!["#meow".to_string()]);
+        assert_eq!(sessions.attached("tiger"), 2);
+    }
+
+    #[test]
+    fn detach_keeps_session() {
+        let sessions = Sessions::default();
+        let info: SharedInfo = Arc::new(Mutex::new(ClientInfo::default()));
+        sessions.attach("tiger", &info);
+        sessions.detach("tiger", true);
+        assert_eq!(sessions.attached("tiger"), 0);
+
+        let (_, existed) = sessions.attach("tiger", &info);
+        assert!(existed);
+    }
+
+    #[test]
+    fn devices_remember_where_they_were() {
+        let sessions = Sessions::default();
+        let info: SharedInfo = Arc::new(Mutex::new(ClientInfo::default()));
+        sessions.attach("tiger", &info);
+        sessions.saw("tiger", "phone", 3);
+        sessions.detach("tiger", true);
+        assert_eq!(sessions.seen("tiger", "phone"), Some(3));
+        assert_eq!(sessions.seen("tiger", "laptop"), None);
+        assert_eq!(sessions.seen("cat", "phone"), None);
+    }
+
+    #[test]
+    fn last_detach_ends_session() {
+        let sessions = Sessions::default();
+        let info: SharedInfo = Arc::new(Mutex::new(ClientInfo::default()));
+        sessions.attach("tiger", &info);
+        sessions.detach("tiger", false);
+
+        let (_, existed) = sessions.attach("tiger", &info);
+        assert!(!existed);
+    }
+
+    #[test]
+    fn detached_or_away() {
+        let sessions = Sessions::default();
+        let here: SharedInfo = Arc::new(Mutex::new(ClientInfo::default()));
+        let away: SharedInfo = Arc::new(Mutex::new(ClientInfo::default()));
+        let gone: SharedInfo = Arc::new(Mutex::new(ClientInfo::default()));
+        away.lock().unwrap().away = Some("lunch".to_string());
+        sessions.attach("here", &here);
+        sessions.attach("away", &away);
+        sessions.attach("gone", &gone);
+        sessions.detach("gone", true);
+
+        let mut accounts: Vec<String> = sessions
+            .detached_or_away()
+            .into_iter()
+            .map(|(account, _)| account)
+            .collect();
+        accounts.sort();
+        assert_eq!(accounts, vec!["away".to_string(), "gone".to_string()]);
+    }
+}
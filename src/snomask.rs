@@ -0,0 +1,314 @@
+//! Server notice masks. Opers pick the kinds of server notices they want with `MODE <nick> +s <letters>`, like
+//! `+s +ck` or `+s -k`, and every connection with one of those letters set gets a NOTICE when it happens. The notices
+//! are made from the event bus, so anything that publishes an event can show up here.
+
+use crate::{event::Event, geoip::GeoIp, log, server::ServerToClientPacket, Shutdown};
+use std::collections::HashSet;
+use tokio::sync::broadcast;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Snomask {
+    /// `c`, clients connecting and exiting
+    Connects,
+    /// `k`, KILLs
+    Kills,
+    /// `x`, K-lines and D-lines being added and removed
+    Bans,
+    /// `l`, the cluster link going up or down
+    Links,
+    /// `f`, spam filters going off
+    Floods,
+    /// `o`, OPER attempts, REHASH and what it changes, DEFCON, and DIE
+    Opers,
+}
+
+impl Snomask {
+    pub const ALL: &'static [Snomask] = &[
+        Snomask::Connects,
+        Snomask::Kills,
+        Snomask::Bans,
+        Snomask::Links,
+        Snomask::Floods,
+        Snomask::Opers,
+    ];
+
+    pub fn letter(&self) -> char {
+        match self {
+            Snomask::Connects => 'c',
+            Snomask::Kills => 'k',
+            Snomask::Bans => 'x',
+            Snomask::Links => 'l',
+            Snomask::Floods => 'f',
+            Snomask::Opers => 'o',
+        }
+    }
+
+    pub fn from_letter(letter: char) -> Option<Self> {
+        Self::ALL.iter().find(|x| x.letter() == letter).copied()
+    }
+}
+
+/// Applies a change like `+ck-x` to `masks`. A bare `ck` adds. Returns the letters we don't know, if any.
+pub fn apply(masks: &mut HashSet<Snomask>, change: &str) -> Result<(), String> {
+    let mut adding = true;
+    let mut unknown = String::new();
+    for letter in change.chars() {
+        match letter {
+            '+' | '-' => adding = letter == '+',
+            '*' if adding => masks.extend(Snomask::ALL),
+            '*' => masks.clear(),
+            _ => match Snomask::from_letter(letter) {
+                Some(mask) if adding => {
+                    masks.insert(mask);
+                }
+                Some(mask) => {
+                    masks.remove(&mask);
+                }
+                None => unknown.push(letter),
+            },
+        }
+    }
+    if unknown.is_empty() {
+        Ok(())
+    } else {
+        Err(unknown)
+    }
+}
+
+/// `masks` as they're shown back to the oper, like `+ck`.
+pub fn to_string(masks: &HashSet<Snomask>) -> String {
+    let mut masks: Vec<_> = masks.iter().collect();
+    masks.sort();
+    std::iter::once('+')
+        .chain(masks.iter().map(|x| x.letter()))
+        .collect()
+}
+
+/// The notice for `event`, if it's one opers get told about. Connects say where they're from if `geoip` knows.
+pub fn notice(event: &Event, geoip: &GeoIp) -> Option<(Snomask, String)> {
+    let notice = match event {
+        Event::UserRegistered { nick, host, .. } => (
+            Snomask::Connects,
+            match geoip.lookup_host(host) {
+                Some(location) => format!("Client connecting: {} ({}) [{}]", nick, host, location),
+                None => format!("Client connecting: {} ({})", nick, host),
+            },
+        ),
+        Event::ConnectionClosed {
+            nick,
+            host,
+            reason,
+            summary,
+        } => (
+            Snomask::Connects,
+            format!(
+                "Client exiting: {} ({}) [{}] {}",
+                nick.as_deref().unwrap_or("*"),
+                host,
+                reason,
+                summary
+            ),
+        ),
+        Event::UserKilled { by, nick, reason } => (
+            Snomask::Kills,
+            format!("{} killed {} ({})", by, nick, reason),
+        ),
+        Event::BanAdded { ban } => (
+            Snomask::Bans,
+            format!(
+                "{} added {} {} for {}: {}",
+                ban.set_by,
+                if ban.expires.is_some() {
+                    "temporary"
+                } else {
+                    "permanent"
+                },
+                ban.kind.name(),
+                ban.mask,
+                ban.reason
+            ),
+        ),
+        Event::BanRemoved { by, kind, mask } => (
+            Snomask::Bans,
+            format!("{} removed {} for {}", by, kind.name(), mask),
+        ),
+        Event::LinkChanged { name, up } => (
+            Snomask::Links,
+            format!("Link to {} {}", name, if *up { "up" } else { "down" }),
+        ),
+        Event::OperAttempt { by, name, success } => (
+            Snomask::Opers,
+            if *success {
+                format!("{} is now an operator ({})", by, name)
+            } else {
+                format!("Failed OPER attempt by {} ({})", by, name)
+            },
+        ),
+        Event::OperLockout { by, ip, dropped } => (
+            Snomask::Opers,
+            if *dropped {
+                format!("{} was disconnected for too many failed OPER attempts", by)
+            } else {
+                format!("{} is locked out of OPER for too many failed attempts", ip)
+            },
+        ),
+        Event::RealIpShown { by, nick, command } => (
+            Snomask::Opers,
+            format!(
+                "{} looked up the real address of {} ({})",
+                by, nick, command
+            ),
+        ),
+        Event::OperSpied {
+            by,
+            command,
+            target,
+        } => (
+            Snomask::Opers,
+            format!(
+                "{} looked into secret channels with {} {}",
+                by, command, target
+            ),
+        ),
+        Event::HostChanged { by, nick, host } => (
+            Snomask::Opers,
+            format!("{} changed the host of {} to {}", by, nick, host),
+        ),
+        Event::Rehashed { by } => (Snomask::Opers, format!("{} is rehashing", by)),
+        Event::ListenerChanged { address, up } => (
+            Snomask::Opers,
+            match up {
+                true => format!("Now listening on {}", address),
+                false => format!("No longer listening on {}", address),
+            },
+        ),
+        Event::ListenFailed { address, error } => (
+            Snomask::Opers,
+            format!("Failed to listen on {}: {}", address, error),
+        ),
+        Event::DefconChanged { by, level } => (
+            Snomask::Opers,
+            match by {
+                Some(by) => format!("{} set DEFCON {}", by, level),
+                None => format!("DEFCON went back up to {}", level),
+            },
+        ),
+        Event::Died { by } => (
+            Snomask::Opers,
+            format!("{} is shutting the server down", by),
+        ),
+        Event::Squit { by, node, reason } => (
+            Snomask::Opers,
+            format!(
+                "{} split {} off the cluster ({})",
+                by,
+                node,
+                reason.as_deref().unwrap_or("no reason")
+            ),
+        ),
+        Event::FilterAdded {
+            by,
+            action,
+            pattern,
+        } => (
+            Snomask::Opers,
+            format!("{} added a {} filter: {}", by, action, pattern),
+        ),
+        Event::FilterRemoved { by, pattern } => (
+            Snomask::Opers,
+            format!("{} removed the filter {}", by, pattern),
+        ),
+        Event::FilterHit { nick, action, text } => (
+            Snomask::Floods,
+            format!("{} tripped a {} filter: {}", nick, action, text),
+        ),
+        _ => return None,
+    };
+    Some(notice)
+}
+
+/// Turns events into server notices until shutdown, passing them to every connection to pick out its own.
+pub async fn watch(
+    mut events: broadcast::Receiver<Event>,
+    client_tx: broadcast::Sender<ServerToClientPacket>,
+    geoip: GeoIp,
+    mut shutdown: Shutdown,
+) {
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            _ = shutdown.recv() => return,
+        };
+        match event {
+            Ok(event) => {
+                if let Some((mask, text)) = notice(&event, &geoip) {
+                    // Nobody connected is fine
+                    let _ = client_tx.send(ServerToClientPacket::ServerNotice { mask, text });
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                log::error!("Server notices missed {} events", missed);
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::event::ConnectionSummary;
+
+    #[test]
+    fn changes() {
+        let mut masks = HashSet::new();
+        assert_eq!(apply(&mut masks, "ck"), Ok(()));
+        assert_eq!(to_string(&masks), "+ck");
+        assert_eq!(apply(&mut masks, "+x-c"), Ok(()));
+        assert_eq!(to_string(&masks), "+kx");
+        assert_eq!(apply(&mut masks, "+qz"), Err("qz".to_string()));
+        assert_eq!(apply(&mut masks, "-*"), Ok(()));
+        assert!(masks.is_empty());
+        assert_eq!(apply(&mut masks, "*"), Ok(()));
+        assert_eq!(masks.len(), Snomask::ALL.len());
+    }
+
+    #[test]
+    fn notices() {
+        let event = Event::UserKilled {
+            by: "tiger".to_string(),
+            nick: "spammer".to_string(),
+            reason: "bye".to_string(),
+        };
+        assert_eq!(
+            notice(&event, &GeoIp::default()),
+            Some((Snomask::Kills, "tiger killed spammer (bye)".to_string()))
+        );
+        let event = Event::ConnectionClosed {
+            nick: None,
+            host: "127.0.0.1".to_string(),
+            reason: "Registration timed out".to_string(),
+            summary: ConnectionSummary {
+                connected: std::time::Duration::from_secs(60),
+                bytes_in: 12,
+                bytes_out: 345,
+                messages: 1,
+                channels_joined: 0,
+            },
+        };
+        assert_eq!(
+            notice(&event, &GeoIp::default()),
+            Some((
+                Snomask::Connects,
+                "Client exiting: * (127.0.0.1) [Registration timed out] connected 60s, 12 bytes in, 345 bytes out, \
+                 1 messages, 0 channels joined"
+                    .to_string()
+            ))
+        );
+        let event = Event::NickChanged {
+            old: "a".to_string(),
+            new: "b".to_string(),
+        };
+        assert_eq!(notice(&event, &GeoIp::default()), None);
+    }
+}
@@ -0,0 +1,156 @@
+//! Client-only message tags, the `+name=value` ones clients attach to PRIVMSG and TAGMSG for each other. We only
+//! pass on the few we know what to do with, and only to clients that asked for `message-tags`. Typing
+//! notifications would make a great flood otherwise, so each connection only gets to send so many of each tag.
+
+use std::{collections::HashMap, time::Duration, time::Instant};
+
+/// Client-only tags we pass on, anything else a client sends is dropped
+pub const RELAYED: &[&str] = &["+typing", "+draft/react", "+draft/reply"];
+
+/// Added by the server to everything sent by a client with user mode +B
+pub const BOT: &str = "bot";
+
+/// How many of one tag a connection can send per `WINDOW`, the rest are dropped
+const BURST: u32 = 5;
+const WINDOW: Duration = Duration::from_secs(10);
+
+/// The name of a raw `name=value` tag.
+fn name(tag: &str) -> &str {
+    tag.split_once('=').map_or(tag, |(name, _)| name)
+}
+
+/// Added by the server to everything sent by a client logged into an account, for account-tag
+pub const ACCOUNT: &str = "account";
+
+/// Which tags a connection gets on what it's sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wanted {
+    /// It has message-tags, so everything
+    All,
+    /// It only has account-tag, so just `account`
+    Account,
+    Nothing,
+}
+
+impl Wanted {
+    /// `tags` cut down to the ones wanted.
+    pub fn keep(self, tags: Option<Vec<String>>) -> Option<Vec<String>> {
+        match self {
+            Wanted::All => tags,
+            Wanted::Account => {
+                let kept: Vec<String> = tags?.into_iter().filter(|x| name(x) == ACCOUNT).collect();
+                (!kept.is_empty()).then_some(kept)
+            }
+            Wanted::Nothing => None,
+        }
+    }
+}
+
+/// `tags` plus the `bot` tag.
+pub fn with_bot(tags: Option<Vec<String>>) -> Vec<String> {
+    let mut tags = tags.unwrap_or_default();
+    tags.push(BOT.to_string());
+    tags
+}
+
+/// `tags` plus the `account` tag for `account`.
+pub fn with_account(tags: Option<Vec<String>>, account: &str) -> Vec<String> {
+    let mut tags = tags.unwrap_or_default();
+    tags.push(format!("{}={}", ACCOUNT, account));
+    tags
+}
+
+/// Counts the tags one connection sends.
+#[derive(Debug, Default)]
+pub struct TagLimiter {
+    /// Tag name to how many have been sent since when
+    sent: HashMap<&'static str, (u32, Instant)>,
+}
+
+impl TagLimiter {
+    /// Picks out the tags in `tags` that get passed on, leaving out any the sender has sent too many of lately.
+    /// Returns `None` if that's all of them.
+    pub fn relay(&mut self, tags: Option<&[String]>, now: Instant) -> Option<Vec<String>> {
+        let relayed: Vec<String> = tags?
+            .iter()
+            .filter(|tag| {
+                let Some(name) = RELAYED.iter().find(|x| **x == name(tag)) else {
+                    return false;
+                };
+                let (count, since) = self.sent.entry(name).or_insert((0, now));
+                if now.duration_since(*since) >= WINDOW {
+                    *count = 0;
+                    *since = now;
+                }
+                *count += 1;
+                *count <= BURST
+            })
+            .cloned()
+            .collect();
+        (!relayed.is_empty()).then_some(relayed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn relays_known_tags_until_the_limit() {
+        let mut limiter = TagLimiter::default();
+        let now = Instant::now();
+        let tags = vec![
+            "+typing=active".to_string(),
+            "+draft/reply=abc".to_string(),
+            "+secret=1".to_string(),
+            "time=2024".to_string(),
+        ];
+        assert_eq!(
+            limiter.relay(Some(&tags), now),
+            Some(vec![
+                "+typing=active".to_string(),
+                "+draft/reply=abc".to_string()
+            ])
+        );
+        assert_eq!(limiter.relay(None, now), None);
+
+        let typing = vec!["+typing=active".to_string()];
+        for _ in 1..BURST {
+            assert!(limiter.relay(Some(&typing), now).is_some());
+        }
+        assert_eq!(limiter.relay(Some(&typing), now), None);
+        // Other tags have their own count
+        assert!(limiter
+            .relay(Some(&["+draft/react=lol".to_string()]), now)
+            .is_some());
+        assert!(limiter.relay(Some(&typing), now + WINDOW).is_some());
+    }
+
+    #[test]
+    fn account_tag_alone() {
+        let tags = Some(vec![
+            "+typing=active".to_string(),
+            "msgid=abc".to_string(),
+            "bot".to_string(),
+            "account=tiger".to_string(),
+        ]);
+        assert_eq!(Wanted::All.keep(tags.clone()), tags);
+        assert_eq!(
+            Wanted::Account.keep(tags.clone()),
+            Some(vec!["account=tiger".to_string()])
+        );
+        assert_eq!(Wanted::Account.keep(Some(vec!["bot".to_string()])), None);
+        assert_eq!(Wanted::Nothing.keep(tags), None);
+    }
+
+    #[test]
+    fn account_tag_is_ours() {
+        let mut limiter = TagLimiter::default();
+        let spoofed = vec!["account=tiger".to_string()];
+        assert_eq!(limiter.relay(Some(&spoofed), Instant::now()), None);
+        assert_eq!(
+            with_account(Some(vec!["+typing=active".to_string()]), "tiger"),
+            ["+typing=active", "account=tiger"]
+        );
+    }
+}
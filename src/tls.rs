@@ -0,0 +1,214 @@
+//! TLS listener support. Clients may present a certificate, which is never checked against any CA: all we want
+//! from it is its SHA-256 fingerprint (CertFP), which accounts and oper blocks can list to log in without a password.
+//!
+//! Handshakes that stall are dropped rather than left holding a task: the client gets `hello_timeout` seconds to send
+//! its ClientHello and `handshake_timeout` for the whole thing. Handshakes that fail are counted in `/metrics` by
+//! how far they got.
+//!
+//! ```toml
+//! [tls]
+//! listen = "0.0.0.0:6697"
+//! cert = "fullchain.pem"
+//! key = "privkey.pem"
+//! # Tells clients on plaintext to come back over TLS and stick to it for this many seconds (STS)
+//! sts_duration = 2592000
+//! hello_timeout = 5
+//! handshake_timeout = 10
+//! ```
+
+use crate::{accept, log, metrics::Metrics, IrcConnection, Result, Shutdown};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::{timeout_at, Instant},
+};
+use tokio_rustls::{
+    rustls::{
+        self,
+        client::danger::HandshakeSignatureValid,
+        crypto::{self, WebPkiSupportedAlgorithms},
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, UnixTime},
+        server::danger::{ClientCertVerified, ClientCertVerifier},
+        DigitallySignedStruct, DistinguishedName, ServerConfig, SignatureScheme,
+    },
+    server::TlsStream,
+    LazyConfigAcceptor, TlsAcceptor,
+};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    /// Address the TLS listener binds to
+    pub listen: String,
+    /// PEM certificate chain
+    pub cert: PathBuf,
+    /// PEM private key
+    pub key: PathBuf,
+    /// Advertised in the sts cap, off unless this is set
+    pub sts_duration: Option<u64>,
+    /// Seconds a client gets to start its handshake
+    #[serde(default = "default_hello_timeout")]
+    pub hello_timeout: u64,
+    /// Seconds a client gets to finish its handshake, counting from when it connected
+    #[serde(default = "default_handshake_timeout")]
+    pub handshake_timeout: u64,
+}
+
+fn default_hello_timeout() -> u64 {
+    5
+}
+
+fn default_handshake_timeout() -> u64 {
+    10
+}
+
+/// Builds an acceptor from the certificate and key in `config`.
+pub fn acceptor(config: &TlsConfig) -> Result<TlsAcceptor> {
+    let provider = Arc::new(crypto::aws_lc_rs::default_provider());
+    let certs =
+        CertificateDer::pem_file_iter(&config.cert)?.collect::<std::result::Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(&config.key)?;
+    let verifier = Arc::new(AnyClientCert {
+        algorithms: provider.signature_verification_algorithms,
+    });
+    let server_config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Accepts TLS clients on `listener` until shutdown, handing each one to the server over `tx` once its handshake
+/// is done. Handshakes happen off to the side so a slow one doesn't hold up anybody else.
+pub async fn serve(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    config: TlsConfig,
+    tx: mpsc::Sender<IrcConnection>,
+    metrics: Metrics,
+    mut shutdown: Shutdown,
+) -> Result<()> {
+    let mut backoff = accept::Backoff::default();
+    loop {
+        let socket = tokio::select! {
+            socket = accept::next(&listener, &metrics, &mut backoff) => socket,
+            _ = shutdown.recv() => return Ok(()),
+        };
+        let server_config = acceptor.config().clone();
+        let timeouts = (
+            Duration::from_secs(config.hello_timeout),
+            Duration::from_secs(config.handshake_timeout),
+        );
+        let tx = tx.clone();
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            match handshake(socket, server_config, timeouts).await {
+                Ok(stream) => {
+                    let _ = tx.send(IrcConnection::new_tls(stream)).await;
+                }
+                Err((stage, e)) => {
+                    metrics.tls_handshake_failure(stage);
+                    log::trace!("TLS handshake failed ({}): {}", stage, e);
+                }
+            }
+        });
+    }
+}
+
+/// Does the handshake in two steps, so a client that never says anything is dropped sooner than one that's just
+/// slow. Fails with the stage it got to, for the metrics, and why.
+async fn handshake(
+    socket: TcpStream,
+    server_config: Arc<ServerConfig>,
+    (hello_timeout, handshake_timeout): (Duration, Duration),
+) -> std::result::Result<TlsStream<TcpStream>, (&'static str, String)> {
+    let connected = Instant::now();
+    let deadline = connected + handshake_timeout;
+    let hello = LazyConfigAcceptor::new(rustls::server::Acceptor::default(), socket);
+    let start = match timeout_at((connected + hello_timeout).min(deadline), hello).await {
+        Ok(Ok(start)) => start,
+        Ok(Err(e)) => return Err(("bad_hello", e.to_string())),
+        Err(_) => return Err(("hello_timeout", "no ClientHello in time".to_string())),
+    };
+    match timeout_at(deadline, start.into_stream(server_config)).await {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(e)) => Err(("failed", e.to_string())),
+        Err(_) => Err(("handshake_timeout", "didn't finish in time".to_string())),
+    }
+}
+
+/// The CertFP of a DER certificate: its SHA-256 as lowercase hex.
+pub fn fingerprint(cert: &[u8]) -> String {
+    Sha256::digest(cert)
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
+
+/// Fingerprints in the config can be written however, `AA:BB:..` or `aabb..`. This makes them match ours.
+pub fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint.replace(':', "").to_ascii_lowercase()
+}
+
+/// Asks for a client certificate and takes whatever it's given, as long as the client proves it holds the key.
+#[derive(Debug)]
+struct AnyClientCert {
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ClientCertVerifier for AnyClientCert {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> std::result::Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fingerprints() {
+        assert_eq!(
+            fingerprint(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(normalize_fingerprint("E3:B0:C4"), "e3b0c4");
+    }
+}
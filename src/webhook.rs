@@ -0,0 +1,254 @@
+//! Webhooks: highlights go to the account that was highlighted, if it has a webhook and isn't around, and account
+//! changes and repeated login failures go to `account_webhook` for anti-abuse tooling.
+//!
+//! ```toml
+//! [account_webhook]
+//! url = "https://abuse.example.net/irc"
+//! # How many failed logins from one IP within `window` seconds get reported
+//! failures = 5
+//! window = 600
+//! ```
+
+use crate::{
+    account::AccountStore,
+    event::Event,
+    lockout::{LockoutConfig, Lockouts},
+    log,
+    message_parse::source_nick,
+    session::Sessions,
+    Shutdown,
+};
+use chrono::{SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::{net::IpAddr, time::Instant};
+use tokio::sync::broadcast;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AccountWebhookConfig {
+    pub url: String,
+    #[serde(default = "default_failures")]
+    pub failures: usize,
+    /// Seconds a failure counts for
+    #[serde(default = "default_window")]
+    pub window: u64,
+}
+
+fn default_failures() -> usize {
+    5
+}
+
+fn default_window() -> u64 {
+    600
+}
+
+/// What gets POSTed to an account's webhook when someone highlights them while they're away or detached.
+#[derive(Debug, Serialize)]
+pub struct Highlight {
+    pub channel: String,
+    pub sender: String,
+    pub message: String,
+    /// RFC 3339, same format as IRCv3 server-time
+    pub time: String,
+}
+
+impl Highlight {
+    /// Stamps a highlight with the current time.
+    pub fn new(channel: String, sender: String, message: String) -> Self {
+        Self {
+            channel,
+            sender,
+            message,
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        }
+    }
+}
+
+/// What gets POSTed to `account_webhook`
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AccountNotice {
+    Registered {
+        account: String,
+        time: String,
+    },
+    Dropped {
+        account: String,
+        time: String,
+    },
+    /// `failures` failed logins from `ip` in the last `window` seconds, the last of them to `account`
+    AuthFailures {
+        account: Option<String>,
+        ip: String,
+        failures: usize,
+        window: u64,
+        time: String,
+    },
+}
+
+/// Returns `true` if `nickname` shows up in `text` as a whole word, ignoring case.
+pub fn mentions(text: &str, nickname: &str) -> bool {
+    if nickname.is_empty() {
+        return false;
+    }
+    text.split(|c: char| !(c.is_ascii_alphanumeric() || "[]\\`_^{|}-".contains(c)))
+        .any(|word| word.eq_ignore_ascii_case(nickname))
+}
+
+/// Watches channel messages for highlights of anyone who isn't around to see them, and POSTs those to the
+/// account's webhook.
+pub async fn watch(
+    mut events: broadcast::Receiver<Event>,
+    sessions: Sessions,
+    accounts: AccountStore,
+    mut shutdown: Shutdown,
+) {
+    let client = reqwest::Client::new();
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            _ = shutdown.recv() => return,
+        };
+        match event {
+            Ok(Event::ChannelMessage {
+                source,
+                channel,
+                text,
+            }) => {
+                let sender = source_nick(&source);
+                for (account, info) in sessions.detached_or_away() {
+                    let url = match accounts.webhook(&account) {
+                        Some(url) => url,
+                        None => continue,
+                    };
+                    if info.nickname.eq_ignore_ascii_case(sender)
+                        || !info.channels.contains(&channel)
+                        || !mentions(&text, &info.nickname)
+                    {
+                        continue;
+                    }
+                    let highlight =
+                        Highlight::new(channel.clone(), sender.to_string(), text.clone());
+                    post(&client, url, &highlight);
+                }
+            }
+            Ok(_) => {}
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                log::error!("Webhooks missed {} messages", missed);
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Watches for accounts coming and going and for logins failing too often, and POSTs them to `account_webhook`.
+/// Failures are only reported once each time an IP reaches `failures` of them, not for every one after that.
+pub async fn watch_accounts(
+    mut events: broadcast::Receiver<Event>,
+    config: AccountWebhookConfig,
+    mut shutdown: Shutdown,
+) {
+    let client = reqwest::Client::new();
+    let failures = Lockouts::default();
+    let lockout = LockoutConfig {
+        per_ip: config.failures,
+        window: config.window,
+        ..Default::default()
+    };
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            _ = shutdown.recv() => return,
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                log::error!("Account webhook missed {} events", missed);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        if let Some(notice) = account_notice(event, &failures, &lockout, Instant::now()) {
+            post(&client, config.url.clone(), &notice);
+        }
+    }
+}
+
+/// What `event` means for `account_webhook`, if anything. Failures are counted in `failures`.
+fn account_notice(
+    event: Event,
+    failures: &Lockouts,
+    config: &LockoutConfig,
+    now: Instant,
+) -> Option<AccountNotice> {
+    let time = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    let notice = match event {
+        Event::AccountRegistered { account } => AccountNotice::Registered { account, time },
+        Event::AccountDropped { account } => AccountNotice::Dropped { account, time },
+        Event::AuthFailed { account, ip } => {
+            let count = failures.fail(config, ip.parse::<IpAddr>().ok()?, now);
+            if count != config.per_ip {
+                return None;
+            }
+            AccountNotice::AuthFailures {
+                account,
+                ip,
+                failures: count,
+                window: config.window,
+                time,
+            }
+        }
+        _ => return None,
+    };
+    Some(notice)
+}
+
+/// Fires `body` off at `url` as JSON in the background, a slow endpoint shouldn't hold up message routing.
+pub fn post<T: Serialize>(client: &reqwest::Client, url: String, body: &T) {
+    let request = client.post(&url).json(body);
+    tokio::spawn(async move {
+        match request.send().await.and_then(|r| r.error_for_status()) {
+            Ok(_) => {}
+            Err(e) => log::error!("Webhook to {} failed: {}", url, e),
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mentions_whole_words() {
+        assert!(mentions("tiger: you around?", "tiger"));
+        assert!(mentions("hey TIGER", "tiger"));
+        assert!(mentions("ping [tiger]", "[tiger]"));
+        assert!(!mentions("tigers are cool", "tiger"));
+        assert!(!mentions("anything", ""));
+    }
+
+    #[test]
+    fn account_notices() {
+        let (failures, now) = (Lockouts::default(), Instant::now());
+        let config = LockoutConfig {
+            per_ip: 2,
+            ..Default::default()
+        };
+        let failed = || Event::AuthFailed {
+            account: Some("tiger".to_string()),
+            ip: "10.0.0.1".to_string(),
+        };
+        assert!(account_notice(failed(), &failures, &config, now).is_none());
+        assert!(matches!(
+            account_notice(failed(), &failures, &config, now),
+            Some(AccountNotice::AuthFailures { failures: 2, .. })
+        ));
+        // Already reported
+        assert!(account_notice(failed(), &failures, &config, now).is_none());
+        let dropped = Event::AccountDropped {
+            account: "cat".to_string(),
+        };
+        let json = serde_json::to_value(account_notice(dropped, &failures, &config, now)).unwrap();
+        assert_eq!(json["event"], "dropped");
+        assert_eq!(json["account"], "cat");
+    }
+}